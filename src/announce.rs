@@ -0,0 +1,167 @@
+//! Our node_announcement: alias, color and the addresses to reach us on
+//!
+//! rust-lightning 0.0.9 never announces the node. Once one of our channels is in the network
+//! graph we sign a node_announcement ourselves, with the node features of our init messages, and
+//! the PeerManager hands it to our Router, which only takes those of nodes with channels, and
+//! sends it to our peers. Peers connecting later get it with the rest of the network graph. It's
+//! signed again with a later timestamp when the alias, color or addresses change.
+
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use lightning::chain::keysinterface::NodeSigner;
+use lightning::ln::msgs::{self, GlobalFeatures, NetAddress};
+use lightning::util::ser::{Readable, Writeable};
+use secp256k1::key::PublicKey;
+use secp256k1::Message;
+
+use crate::graph::NetworkGraph;
+use crate::net::PeerManager;
+
+/// node_announcement has 32 bytes for the alias
+pub const MAX_ALIAS_LEN: usize = 32;
+
+/// What our node_announcement says
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeInfo {
+    pub alias: String,
+    pub rgb: [u8; 3],
+    /// One of each type at most
+    pub addresses: Vec<NetAddress>,
+}
+
+/// An alias that fits in a node_announcement
+pub fn check_alias(alias: &str) -> Result<(), String> {
+    if alias.len() > MAX_ALIAS_LEN {
+        return Err(format!("An alias has up to {} bytes, '{}' has {}", MAX_ALIAS_LEN, alias, alias.len()));
+    }
+    Ok(())
+}
+
+/// A color like 'ff8000' or '#ff8000'
+pub fn parse_rgb(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    match Vec::<u8>::from_hex(hex) {
+        Ok(ref bytes) if bytes.len() == 3 => Ok([bytes[0], bytes[1], bytes[2]]),
+        _ => Err(format!("'{}' is not a color like ff8000", value)),
+    }
+}
+
+pub fn rgb_to_string(rgb: &[u8; 3]) -> String {
+    rgb.to_hex()
+}
+
+// The type node_announcement gives the address, they go in that order
+fn address_type(address: &NetAddress) -> u8 {
+    match address {
+        NetAddress::IPv4 { .. } => 1,
+        NetAddress::IPv6 { .. } => 2,
+        NetAddress::OnionV2 { .. } => 3,
+        NetAddress::OnionV3 { .. } => 4,
+    }
+}
+
+/// A node_announcement with features, signed with the node secret
+pub fn sign_node_announcement(node_signer: &dyn NodeSigner, features: &GlobalFeatures, info: &NodeInfo, timestamp: u32) -> Result<msgs::NodeAnnouncement, String> {
+    check_alias(&info.alias)?;
+    // The length prefixed features, then the timestamp, node_id, rgb, alias and the length
+    // prefixed addresses
+    let mut contents = features.encode();
+    contents.extend_from_slice(&timestamp.to_be_bytes());
+    contents.extend_from_slice(&node_signer.get_node_id().serialize());
+    contents.extend_from_slice(&info.rgb);
+    let mut alias = [0; MAX_ALIAS_LEN];
+    alias[..info.alias.len()].copy_from_slice(info.alias.as_bytes());
+    contents.extend_from_slice(&alias);
+    let mut addresses = info.addresses.clone();
+    addresses.sort_by_key(address_type);
+    let mut encoded_addresses = Vec::new();
+    for (i, address) in addresses.iter().enumerate() {
+        if i > 0 && address_type(&addresses[i - 1]) == address_type(address) {
+            return Err("Only one address of each type can be announced".to_string());
+        }
+        encoded_addresses.extend_from_slice(&address.encode());
+    }
+    contents.extend_from_slice(&(encoded_addresses.len() as u16).to_be_bytes());
+    contents.extend_from_slice(&encoded_addresses);
+
+    let hash = Sha256dHash::hash(&contents);
+    let signature = node_signer.sign_with_node_secret(&Message::from_slice(&hash[..]).unwrap());
+    let mut encoded = signature.serialize_compact().to_vec();
+    encoded.extend_from_slice(&contents);
+    msgs::NodeAnnouncement::read(&mut Cursor::new(encoded)).map_err(|e| format!("Bad node_announcement: {:?}", e))
+}
+
+/// The node_id, timestamp, rgb and alias of a node_announcement, its addresses are in
+/// Router::get_addresses
+pub fn node_announcement_fields(msg: &msgs::NodeAnnouncement) -> (PublicKey, u32, [u8; 3], String) {
+    // signature and the length prefixed features first
+    let encoded = msg.encode();
+    let start = 64 + 2 + u16::from_be_bytes([encoded[64], encoded[65]]) as usize;
+    let timestamp = u32::from_be_bytes([encoded[start], encoded[start + 1], encoded[start + 2], encoded[start + 3]]);
+    let node_id = PublicKey::from_slice(&encoded[start + 4..start + 37]).unwrap();
+    let rgb = [encoded[start + 37], encoded[start + 38], encoded[start + 39]];
+    let alias = &encoded[start + 40..start + 40 + MAX_ALIAS_LEN];
+    let alias_len = alias.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    (node_id, timestamp, rgb, String::from_utf8_lossy(&alias[..alias_len]).to_string())
+}
+
+pub struct NodeAnnouncer {
+    node_signer: Arc<dyn NodeSigner>,
+    our_node_id: PublicKey,
+    peer_manager: Arc<PeerManager>,
+    graph: Arc<NetworkGraph>,
+    info: Mutex<NodeInfo>,
+    // What we announced last and its timestamp
+    announced: Mutex<Option<(NodeInfo, u32)>>,
+}
+
+impl NodeAnnouncer {
+
+    pub fn new(node_signer: Arc<dyn NodeSigner>, peer_manager: Arc<PeerManager>, graph: Arc<NetworkGraph>) -> NodeAnnouncer {
+        NodeAnnouncer {
+            our_node_id: node_signer.get_node_id(),
+            node_signer,
+            peer_manager,
+            graph,
+            info: Mutex::new(NodeInfo::default()),
+            announced: Mutex::new(None),
+        }
+    }
+
+    /// What the next node_announcement says, announce sends it if it's not what we announced
+    pub fn set_info(&self, info: NodeInfo) -> Result<(), String> {
+        check_alias(&info.alias)?;
+        *self.info.lock().unwrap() = info;
+        Ok(())
+    }
+
+    pub fn info(&self) -> NodeInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    /// Announces our node if one of our channels is in the network graph and we haven't
+    /// announced what it says yet, true if it did
+    pub fn announce(&self) -> Result<bool, String> {
+        if !self.graph.nodes().iter().any(|node| node.node_id == self.our_node_id) {
+            return Ok(false);
+        }
+        let info = self.info();
+        let mut announced = self.announced.lock().unwrap();
+        let last_timestamp = match &*announced {
+            Some((announced_info, _)) if *announced_info == info => return Ok(false),
+            Some((_, timestamp)) => Some(*timestamp),
+            None => None,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let timestamp = last_timestamp.map_or(now, |last| now.max(last + 1));
+        let msg = sign_node_announcement(&*self.node_signer, &self.peer_manager.node_features(), &info, timestamp)?;
+        self.peer_manager.broadcast_node_announcement(&msg).map_err(|e| format!("Our node_announcement: {}", e.err))?;
+        *announced = Some((info, timestamp));
+        Ok(true)
+    }
+}
@@ -10,6 +10,9 @@ enum ArgType {
     ArgStr,
 }
 
+/// Returns an explanation of what's wrong with the value, if anything
+pub type ArgValidator = fn(&str) -> Result<(), String>;
+
 #[derive(Debug)]
 struct ArgumentHelp {
     description: String,
@@ -17,6 +20,11 @@ struct ArgumentHelp {
     default: Option<String>,
     default_multi: Vec<String>,
     default_map: HashMap<String, String>,
+    // Multi args only: repeated values are only kept once
+    unique: bool,
+    validator: Option<ArgValidator>,
+    // Passwords and such, never printed
+    sensitive: bool,
 }
 
 pub struct ArgMan {
@@ -44,6 +52,9 @@ impl ArgMan {
             default_multi: vec![],
             default_map: HashMap::new(),
             description: description.to_string(),
+            unique: false,
+            validator: None,
+            sensitive: false,
         });
     }
 
@@ -54,6 +65,9 @@ impl ArgMan {
             default_multi: vec![],
             default_map: HashMap::new(),
             description: description.to_string(),
+            unique: false,
+            validator: None,
+            sensitive: false,
         });
     }
 
@@ -68,6 +82,9 @@ impl ArgMan {
             default_multi: vec![],
             default_map: HashMap::new(),
             arg_type: ArgType::ArgBool,
+            unique: false,
+            validator: None,
+            sensitive: false,
         });
     }
 
@@ -78,9 +95,48 @@ impl ArgMan {
             default_multi,
             default_map: HashMap::new(),
             arg_type: ArgType::ArgMultistr,
+            unique: false,
+            validator: None,
+            sensitive: false,
         });
     }
 
+    /// Like add_arg_multi, but repeating a value doesn't add it twice
+    pub fn add_arg_multi_unique(&mut self, name: &str, default_multi: Vec<String>, description: &str) {
+        self.add_arg_multi(name, default_multi, description);
+        self.args_help.get_mut(name).unwrap().unique = true;
+    }
+
+    /// Values for the argument will be rejected by parse_args when the validator says so
+    pub fn set_validator(&mut self, name: &str, validator: ArgValidator) {
+        match self.args_help.get_mut(name) {
+            Some(arg_help) => arg_help.validator = Some(validator),
+            None => panic!("Argument {} is not defined.", name),
+        }
+    }
+
+    /// The argument's values won't show up in what ArgMan prints
+    pub fn set_sensitive(&mut self, name: &str) {
+        match self.args_help.get_mut(name) {
+            Some(arg_help) => arg_help.sensitive = true,
+            None => panic!("Argument {} is not defined.", name),
+        }
+    }
+
+    fn is_sensitive(&self, name: &str) -> bool {
+        self.args_help.get(name).is_some_and(|arg_help| arg_help.sensitive)
+    }
+
+    fn printable_raw_arg(&self, raw_arg: &str) -> String {
+        let name = raw_arg.split('=').next().unwrap();
+        let parsed_name = name.rsplit('.').next().unwrap();
+        if raw_arg.contains('=') && self.is_sensitive(parsed_name) {
+            format!("{}=<hidden>", name)
+        } else {
+            raw_arg.to_string()
+        }
+    }
+
     pub fn add_arg_with_category(&mut self, name: &str, default_map: HashMap<String, String>, description: &str) {
         self.args_help.insert(name.to_string(), ArgumentHelp{
             description: description.to_string(),
@@ -88,6 +144,9 @@ impl ArgMan {
             default_multi: vec![],
             default_map,
             arg_type: ArgType::ArgMapStr,
+            unique: false,
+            validator: None,
+            sensitive: false,
         });
     }
 
@@ -111,7 +170,19 @@ impl ArgMan {
             return false;
         }
 
-        match self.args_help.get(parsed_name).unwrap().arg_type {
+        let arg_help = self.args_help.get(parsed_name).unwrap();
+        if let Some(validator) = arg_help.validator {
+            if let Err(e) = validator(&value_to_add) {
+                if arg_help.sensitive {
+                    println!("Invalid value for argument {}: {}", parsed_name, e);
+                } else {
+                    println!("Invalid value '{}' for argument {}: {}", value_to_add, parsed_name, e);
+                }
+                return false;
+            }
+        }
+
+        match arg_help.arg_type {
 
             ArgType::ArgStr => {
                 self.args.insert(parsed_name.to_string(), value_to_add);
@@ -131,7 +202,12 @@ impl ArgMan {
 
             ArgType::ArgMultistr => {
                 if self.args_multi.contains_key(parsed_name) {
-                    self.args_multi.get_mut(parsed_name).unwrap().push(value_to_add);
+                    let values = self.args_multi.get_mut(parsed_name).unwrap();
+                    if arg_help.unique && values.contains(&value_to_add) {
+                        println!("Ignoring repeated value '{}' for argument {}", value_to_add, parsed_name);
+                        return true;
+                    }
+                    values.push(value_to_add);
                 } else {
                     self.args_multi.insert(parsed_name.to_string(), vec![value_to_add]);
                 }
@@ -187,12 +263,11 @@ impl ArgMan {
                 },
 
                 ArgType::ArgMapStr => {
-                    if !self.args_multi_map.contains_key(name) {
-                        self.args_multi_map.insert(name.to_string(), arg_help.default_map.clone());
-                    } else {
-                        // TODO set each default independently if not set
-                        // for category, cat_val in self.args_multi_map.get(name).items() {
-                        // }
+                    let per_name_map = self.args_multi_map.entry(name.to_string()).or_default();
+                    for (category, default_value) in &arg_help.default_map {
+                        if !per_name_map.contains_key(category) {
+                            per_name_map.insert(category.to_string(), default_value.to_string());
+                        }
                     }
                 },
             }
@@ -230,7 +305,8 @@ impl ArgMan {
 
     pub fn parse_args_vec(&mut self, raw_args: Vec<String>) -> bool {
 
-        println!("\nraw_args: {:?}", raw_args);
+        let printable_args: Vec<String> = raw_args.iter().map(|raw_arg| self.printable_raw_arg(raw_arg)).collect();
+        println!("\nraw_args: {:?}", printable_args);
         for raw_arg in raw_args.iter().skip(1) {
 
             if raw_arg == "--help" {
@@ -240,7 +316,7 @@ impl ArgMan {
 
             let raw_arg_split : Vec<&str> = raw_arg.split("=").collect();
             if raw_arg_split.len() != 1 && raw_arg_split.len() != 2 {
-                println!("Incorrect argument syntax: {}\n", raw_arg);
+                println!("Incorrect argument syntax: {}\n", self.printable_raw_arg(raw_arg));
                 println!("There cannot be more than one '=' symbol per argument.");
                 println!("Try '{} --help'\n", raw_args[0]);
                 return false;
@@ -361,6 +437,15 @@ impl ArgMan {
         }
     }
 
+    /// The value for category, or the one given without a category ("-aaa=1" for every category
+    /// but the ones with their own "cat1.-aaa=2")
+    pub fn get_by_category_or_common(&self, category: &str, arg_name: &str) -> &str {
+        match self.args_multi_map.get(arg_name) {
+            Some(per_name_map) if !per_name_map.contains_key(category) && per_name_map.contains_key("") => &per_name_map[""][..],
+            _ => self.get_by_category(category, arg_name),
+        }
+    }
+
     pub fn get_bool(&self, arg_name: &str) -> bool {
         self._common_get(arg_name);
 
@@ -370,7 +455,7 @@ impl ArgMan {
                 match str_val {
                     "0" => return false,
                     "1" => return true,
-                    _ => panic!("Argument {} is a bool and can only be 0 or 1"),
+                    _ => panic!("Argument {} is a bool and can only be 0 or 1", arg_name),
                 }
             },
             _ => panic!("get_bool is being used for {}, which is not defined as a bool arg", arg_name),
@@ -396,7 +481,11 @@ impl ArgMan {
     pub fn dev_print_selected_args(&self) {
         println!("\nThe following args were selected:\n");
         for (name, arg) in &self.args {
-            println!("{}: {:?}", name, arg);
+            if self.is_sensitive(name) {
+                println!("{}: <hidden>", name);
+            } else {
+                println!("{}: {:?}", name, arg);
+            }
         }
         println!("\nThe following args_multi were selected:\n");
         for (name, arg) in &self.args_multi {
@@ -0,0 +1,43 @@
+//! Standard base64 with padding (RFC 4648)
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_CHARS[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+    let encoded = encoded.trim().as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return Err(format!("Base64 of {} characters", encoded.len()));
+    }
+    let mut data = Vec::new();
+    for (chunk_index, chunk) in encoded.chunks(4).enumerate() {
+        let last = chunk_index == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err("Bad base64 padding".to_string());
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64_CHARS.iter().position(|b| b == c)
+                .ok_or(format!("Bad base64 character '{}'", *c as char))?;
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..3 - padding {
+            data.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(data)
+}
@@ -0,0 +1,108 @@
+//! Holds the node's seed for a rustlnd daemon started with -signer
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{thread, time};
+
+use lightning::util::logger::Level;
+
+use rustlnd::argman;
+use rustlnd::keys;
+use rustlnd::logger::PrintLogger;
+use rustlnd::signer::{self, Signer, SigningPolicy};
+
+fn create_signer_args() -> argman::ArgMan {
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_multi_unique("-chain", vec!["regtest".to_string()],
+                   "Chain to sign for (Can be repeated)");
+    let default_datadir = match env::var("HOME") {
+        Ok(home) => format!("{}/.rustlnd-signer", home),
+        Err(_) => ".rustlnd-signer".to_string(),
+    };
+    g_args.add_arg("-datadir", default_datadir,
+                   "Directory with the seed, created on first start");
+    g_args.add_arg_unset("-walletpassfile",
+                         "File with the passphrase the seed is encrypted with (otherwise it's read from stdin)");
+    g_args.add_arg_unset("-mnemonic-passphrase",
+                         "Optional BIP39 passphrase for the seed, the same one is needed on every start");
+    g_args.set_sensitive("-mnemonic-passphrase");
+    g_args.add_arg_unset("-signersocket",
+                         "Unix socket to serve the daemon on, signer.sock in -datadir by default");
+    g_args.add_arg("-maxfeerate", "25000".to_string(),
+                   "Refuse to sign on-chain transactions paying more satoshis per 1000 weight than this");
+    g_args.set_validator("-maxfeerate", |rate| rate.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()));
+    g_args
+}
+
+fn read_passphrase(g_args: &argman::ArgMan) -> Result<String, String> {
+    if !g_args.is_none("-walletpassfile") {
+        return keys::read_passphrase_file(Path::new(g_args.get("-walletpassfile")));
+    }
+    println!("Wallet passphrase:");
+    let mut passphrase = String::new();
+    io::stdin().lock().read_line(&mut passphrase).map_err(|e| format!("Cannot read the passphrase: {}", e))?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn main() {
+    let mut g_args = create_signer_args();
+    if !g_args.parse_args() {
+        println!("\nThe signer is not running.");
+        return;
+    }
+
+    let datadir = PathBuf::from(g_args.get("-datadir"));
+    let bip39_passphrase = if g_args.is_none("-mnemonic-passphrase") { "" } else { g_args.get("-mnemonic-passphrase") };
+    let seed = fs::create_dir_all(&datadir)
+        .map_err(|e| format!("Cannot create datadir {:?}: {}", datadir, e))
+        .and_then(|_| read_passphrase(&g_args))
+        .and_then(|passphrase| keys::load_or_generate_seed(&datadir, &passphrase));
+    let seed = match seed {
+        Ok(seed) => seed,
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("\nThe signer is not running.");
+            return;
+        },
+    };
+
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let mut signers: HashMap<String, Arc<dyn Signer>> = HashMap::new();
+    for chain in g_args.get_multi("-chain") {
+        match seed.node_keys(bip39_passphrase, chain, logger.clone()) {
+            Ok(keys) => signers.insert(chain.to_string(), Arc::new(keys)),
+            Err(e) => {
+                println!("Error: {}", e);
+                println!("\nThe signer is not running.");
+                return;
+            },
+        };
+    }
+    let policy = match SigningPolicy::new(g_args.get("-maxfeerate").parse().unwrap(), Some(datadir.join("revocations"))) {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("\nThe signer is not running.");
+            return;
+        },
+    };
+
+    let socket = if g_args.is_none("-signersocket") { datadir.join("signer.sock") } else { PathBuf::from(g_args.get("-signersocket")) };
+    // Left behind by a previous run
+    if fs::metadata(&socket).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+        let _ = fs::remove_file(&socket);
+    }
+    if let Err(e) = signer::listen(signers, policy, &socket) {
+        println!("Error: Cannot listen on {:?}: {}", socket, e);
+        println!("\nThe signer is not running.");
+        return;
+    }
+    println!("Signing for chains {:?} on {:?}", g_args.get_multi("-chain"), socket);
+    loop {
+        thread::sleep(time::Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,97 @@
+//! BIP39 mnemonics with the english wordlist
+//!
+//! Mnemonics and passphrases are only accepted in ASCII: BIP39 wants them NFKD normalized and
+//! for ASCII that's a no-op.
+
+use bitcoin_hashes::hmac::{Hmac, HmacEngine};
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::sha512::Hash as Sha512;
+use bitcoin_hashes::{Hash, HashEngine};
+
+// sha256 2f5eed53a4727b4bf8880d8f3f199efc90e58503646d9ff8eff3a2ed3b24dbda, like the one in the BIPs repo
+const ENGLISH: &str = include_str!("bip39_english.txt");
+const PBKDF2_ROUNDS: u32 = 2048;
+
+fn wordlist() -> Vec<&'static str> {
+    ENGLISH.lines().collect()
+}
+
+/// Entropy must be 16, 20, 24, 28 or 32 bytes, for 12 to 24 words
+pub fn entropy_to_mnemonic(entropy: &[u8]) -> Result<String, String> {
+    if entropy.len() < 16 || entropy.len() > 32 || !entropy.len().is_multiple_of(4) {
+        return Err(format!("Cannot make a mnemonic out of {} bytes of entropy", entropy.len()));
+    }
+    let checksum = Sha256::hash(entropy).into_inner()[0];
+    let checksum_bits = entropy.len() / 4;
+
+    let mut bits: Vec<bool> = Vec::new();
+    for byte in entropy {
+        bits.extend((0..8).rev().map(|i| (byte >> i) & 1 == 1));
+    }
+    bits.extend((0..checksum_bits).map(|i| (checksum >> (7 - i)) & 1 == 1));
+
+    let words = wordlist();
+    let mnemonic: Vec<&str> = bits.chunks(11).map(|chunk| {
+        let index = chunk.iter().fold(0, |index, bit| (index << 1) | *bit as usize);
+        words[index]
+    }).collect();
+    Ok(mnemonic.join(" "))
+}
+
+/// The inverse of entropy_to_mnemonic, fails on unknown words or a wrong checksum
+pub fn mnemonic_to_entropy(mnemonic: &str) -> Result<Vec<u8>, String> {
+    let words = wordlist();
+    let mnemonic: Vec<&str> = mnemonic.split_whitespace().collect();
+    if mnemonic.len() < 12 || mnemonic.len() > 24 || !mnemonic.len().is_multiple_of(3) {
+        return Err(format!("A mnemonic has 12, 15, 18, 21 or 24 words, not {}", mnemonic.len()));
+    }
+
+    let mut bits: Vec<bool> = Vec::new();
+    for word in &mnemonic {
+        let index = match words.binary_search(&word.to_lowercase().as_str()) {
+            Ok(index) => index,
+            Err(_) => return Err(format!("'{}' is not in the BIP39 english wordlist", word)),
+        };
+        bits.extend((0..11).rev().map(|i| (index >> i) & 1 == 1));
+    }
+
+    let entropy_bits = bits.len() * 32 / 33;
+    let entropy: Vec<u8> = bits[..entropy_bits].chunks(8).map(|chunk| {
+        chunk.iter().fold(0, |byte, bit| (byte << 1) | *bit as u8)
+    }).collect();
+    let checksum = Sha256::hash(&entropy).into_inner()[0];
+    let expected: Vec<bool> = (0..bits.len() - entropy_bits).map(|i| (checksum >> (7 - i)) & 1 == 1).collect();
+    if bits[entropy_bits..] != expected[..] {
+        return Err("Wrong mnemonic checksum, check the words and their order".to_string());
+    }
+    Ok(entropy)
+}
+
+// A single block is enough for the 64 bytes we need
+fn pbkdf2_hmac_sha512(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 64] {
+    let mut engine = HmacEngine::<Sha512>::new(password);
+    engine.input(salt);
+    engine.input(&1u32.to_be_bytes());
+    let mut block = Hmac::from_engine(engine).into_inner();
+    let mut result = block;
+    for _ in 1..rounds {
+        let mut engine = HmacEngine::<Sha512>::new(password);
+        engine.input(&block);
+        block = Hmac::from_engine(engine).into_inner();
+        for (r, b) in result.iter_mut().zip(block.iter()) {
+            *r ^= b;
+        }
+    }
+    result
+}
+
+/// The BIP32 seed for the mnemonic and an optional (empty) passphrase
+pub fn mnemonic_to_seed(mnemonic: &str, passphrase: &str) -> Result<[u8; 64], String> {
+    if !mnemonic.is_ascii() || !passphrase.is_ascii() {
+        return Err("Only ASCII mnemonics and passphrases are supported".to_string());
+    }
+    // The same words as mnemonic_to_entropy accepts, whatever their case
+    let mnemonic: Vec<String> = mnemonic.split_whitespace().map(|word| word.to_lowercase()).collect();
+    let salt = format!("mnemonic{}", passphrase);
+    Ok(pbkdf2_hmac_sha512(mnemonic.join(" ").as_bytes(), salt.as_bytes(), PBKDF2_ROUNDS))
+}
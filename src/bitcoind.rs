@@ -0,0 +1,285 @@
+//! ChainBackend on a chain's bitcoind, through its JSON-RPC interface
+//!
+//! One HTTP/1.1 connection per call with basic authentication (-rpcuser/-rpcpass), and only the
+//! few calls the daemon needs. The JSON parser is just enough for bitcoind's answers.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode;
+use bitcoin_hashes::hex::FromHex;
+
+use crate::base64;
+use crate::chain::ChainBackend;
+
+// bitcoind's error for transactions that are already confirmed
+const RPC_VERIFY_ALREADY_IN_CHAIN: i64 = -27;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// As it was written, to lose no precision
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Compact JSON text
+    pub fn to_json(&self) -> String {
+        match self {
+            Json::Null => "null".to_string(),
+            Json::Bool(b) => b.to_string(),
+            Json::Number(n) => n.clone(),
+            Json::String(s) => {
+                let mut quoted = "\"".to_string();
+                for c in s.chars() {
+                    match c {
+                        '"' => quoted.push_str("\\\""),
+                        '\\' => quoted.push_str("\\\\"),
+                        '\n' => quoted.push_str("\\n"),
+                        c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => quoted.push(c),
+                    }
+                }
+                quoted.push('"');
+                quoted
+            },
+            Json::Array(items) => format!("[{}]", items.iter().map(Json::to_json).collect::<Vec<_>>().join(",")),
+            Json::Object(members) => {
+                let members: Vec<String> = members.iter()
+                    .map(|(key, value)| format!("{}:{}", Json::String(key.clone()).to_json(), value.to_json())).collect();
+                format!("{{{}}}", members.join(","))
+            },
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { chars: text.chars().collect(), position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.chars.len() {
+            return Err(format!("Unexpected '{}' after the JSON value", parser.chars[parser.position]));
+        }
+        Ok(value)
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl JsonParser {
+    fn skip_whitespace(&mut self) {
+        while self.position < self.chars.len() && self.chars[self.position].is_whitespace() {
+            self.position += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = *self.chars.get(self.position).ok_or("Unexpected end of JSON")?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    fn expect_word(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return Err(format!("Expected {}", word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match *self.chars.get(self.position).ok_or("Unexpected end of JSON")? {
+            'n' => self.expect_word("null", Json::Null),
+            't' => self.expect_word("true", Json::Bool(true)),
+            'f' => self.expect_word("false", Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.position += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&']') {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Ok(Json::Array(items)),
+                        c => return Err(format!("Unexpected '{}' in array", c)),
+                    }
+                }
+            },
+            '{' => {
+                self.position += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.next()? != ':' {
+                        return Err("Expected ':' in object".to_string());
+                    }
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Ok(Json::Object(members)),
+                        c => return Err(format!("Unexpected '{}' in object", c)),
+                    }
+                }
+            },
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = self.position;
+                while self.position < self.chars.len() && "+-.eE0123456789".contains(self.chars[self.position]) {
+                    self.position += 1;
+                }
+                Ok(Json::Number(self.chars[start..self.position].iter().collect()))
+            },
+            c => Err(format!("Unexpected '{}' in JSON", c)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.next()? != '"' {
+            return Err("Expected a string".to_string());
+        }
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => match self.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| self.next()).collect::<Result<_, _>>()?;
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("Bad escape \\u{}", hex))?;
+                        // Surrogate pairs don't show up in bitcoind's answers
+                        s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    },
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+pub struct BitcoindRpc {
+    host: String,
+    authorization: String,
+}
+
+impl BitcoindRpc {
+    /// host is host:port
+    pub fn new(host: &str, user: &str, password: &str) -> BitcoindRpc {
+        BitcoindRpc {
+            host: host.to_string(),
+            authorization: base64::encode(format!("{}:{}", user, password).as_bytes()),
+        }
+    }
+
+    /// The result of a call, or the error bitcoind answered with as (code, message)
+    pub fn call(&self, method: &str, params: Vec<Json>) -> Result<Result<Json, (i64, String)>, String> {
+        let request = Json::Object(vec![
+            ("jsonrpc".to_string(), Json::String("1.0".to_string())),
+            ("id".to_string(), Json::String("rustlnd".to_string())),
+            ("method".to_string(), Json::String(method.to_string())),
+            ("params".to_string(), Json::Array(params)),
+        ]).to_json();
+
+        let mut stream = TcpStream::connect(&self.host).map_err(|e| format!("Cannot connect to bitcoind at {}: {}", self.host, e))?;
+        stream.set_read_timeout(Some(Duration::from_secs(60))).map_err(|e| e.to_string())?;
+        write!(stream, "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               self.host, self.authorization, request.len(), request).map_err(|e| e.to_string())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map_err(|e| format!("Cannot read bitcoind's answer: {}", e))?;
+        let response = String::from_utf8_lossy(&response);
+
+        let (head, body) = match response.find("\r\n\r\n") {
+            Some(end) => (&response[..end], &response[end + 4..]),
+            None => return Err("Bad HTTP answer from bitcoind".to_string()),
+        };
+        let status = head.lines().next().unwrap_or("");
+        if status.contains(" 401") {
+            return Err("bitcoind refused our -rpcuser and -rpcpass".to_string());
+        }
+        // Errors come with a 500 or 404 status and a JSON body
+        let answer = Json::parse(body).map_err(|e| format!("Bad answer from bitcoind ({}): {}", status, e))?;
+        match answer.get("error") {
+            Some(Json::Null) | None => Ok(Ok(answer.get("result").cloned().unwrap_or(Json::Null))),
+            Some(error) => Ok(Err((
+                error.get("code").and_then(Json::as_i64).unwrap_or(0),
+                error.get("message").and_then(Json::as_str).unwrap_or("").to_string(),
+            ))),
+        }
+    }
+
+    fn call_result(&self, method: &str, params: Vec<Json>) -> Result<Json, String> {
+        self.call(method, params)?.map_err(|(code, message)| format!("bitcoind's {} failed: {} ({})", method, message, code))
+    }
+}
+
+impl ChainBackend for BitcoindRpc {
+    fn get_height(&self) -> Result<u32, String> {
+        let count = self.call_result("getblockcount", vec![])?;
+        count.as_i64().map(|count| count as u32).ok_or(format!("Bad block count {}", count.to_json()))
+    }
+
+    fn get_block(&self, height: u32) -> Result<Block, String> {
+        let hash = self.call_result("getblockhash", vec![Json::Number(height.to_string())])?;
+        let hex = self.call_result("getblock", vec![hash, Json::Number("0".to_string())])?;
+        let bytes = hex.as_str().and_then(|hex| Vec::<u8>::from_hex(hex).ok()).ok_or("Bad block hex from bitcoind")?;
+        encode::deserialize(&bytes).map_err(|e| format!("Bad block from bitcoind: {}", e))
+    }
+
+    fn send_transaction(&self, tx: &Transaction) -> Result<(), String> {
+        match self.call("sendrawtransaction", vec![Json::String(encode::serialize_hex(tx))])? {
+            Ok(_) => Ok(()),
+            Err((code, _)) if code == RPC_VERIFY_ALREADY_IN_CHAIN => Ok(()),
+            Err((_, message)) if message.contains("txn-already-in-mempool") => Ok(()),
+            Err((code, message)) => Err(format!("bitcoind refused {}: {} ({})", tx.txid(), message, code)),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Per chain interfaces rust-lightning needs from the outside world
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::constants::Network;
+use bitcoin::consensus::encode;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+
+/// rust-lightning only knows about bitcoin's networks, any other chain is treated like regtest
+pub fn network_for_chain(chain: &str) -> Network {
+    match chain {
+        "main" => Network::Bitcoin,
+        "test" => Network::Testnet,
+        _ => Network::Regtest,
+    }
+}
+
+/// The genesis block hash rust-lightning puts in its messages for chain
+pub fn internal_chain_hash(chain: &str) -> Sha256dHash {
+    genesis_block(network_for_chain(chain)).header.bitcoin_hash()
+}
+
+/// The chain_hash identifying chain to other nodes
+///
+/// For bitcoin's networks that's the genesis block hash, other chains are identified by the
+/// hash of their name so that they don't all look like regtest.
+pub fn chain_hash(chain: &str) -> Sha256dHash {
+    match chain {
+        "main" | "test" | "regtest" => internal_chain_hash(chain),
+        _ => Sha256dHash::hash(chain.as_bytes()),
+    }
+}
+
+/// The human readable part of the chain's bech32 addresses, the chain's name for non bitcoin chains
+pub fn bech32_hrp(chain: &str) -> String {
+    match chain {
+        "main" => "bc".to_string(),
+        "test" => "tb".to_string(),
+        "regtest" => "bcrt".to_string(),
+        _ => chain.to_string(),
+    }
+}
+
+/// What the daemon needs from a chain's full node
+pub trait ChainBackend: Send + Sync {
+    /// Height of the best block, the genesis block is 0
+    fn get_height(&self) -> Result<u32, String>;
+
+    fn get_block(&self, height: u32) -> Result<Block, String>;
+
+    /// Fails if the transaction can't go into the next block
+    fn send_transaction(&self, tx: &Transaction) -> Result<(), String>;
+}
+
+pub struct FixedFeeEstimator {
+    sat_per_1000_weight: u64,
+}
+
+impl FixedFeeEstimator {
+    pub fn new(sat_per_1000_weight: u64) -> FixedFeeEstimator {
+        FixedFeeEstimator { sat_per_1000_weight }
+    }
+}
+
+impl FeeEstimator for FixedFeeEstimator {
+    fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u64 {
+        self.sat_per_1000_weight
+    }
+}
+
+/// Sends what rust-lightning broadcasts to the chain's backend, keeping what comes before there's
+/// one (channels force closed while loading them) to send it then
+pub struct Broadcaster {
+    chain: String,
+    backend: RwLock<Option<Arc<dyn ChainBackend>>>,
+    waiting: Mutex<Vec<Transaction>>,
+}
+
+impl Broadcaster {
+    pub fn new(chain: &str) -> Broadcaster {
+        Broadcaster { chain: chain.to_string(), backend: RwLock::new(None), waiting: Mutex::new(Vec::new()) }
+    }
+
+    pub fn set_backend(&self, backend: Arc<dyn ChainBackend>) {
+        *self.backend.write().unwrap() = Some(backend);
+        for tx in self.waiting.lock().unwrap().drain(..) {
+            self.broadcast_transaction(&tx);
+        }
+    }
+}
+
+impl BroadcasterInterface for Broadcaster {
+    fn broadcast_transaction(&self, tx: &Transaction) {
+        match &*self.backend.read().unwrap() {
+            Some(backend) => match backend.send_transaction(tx) {
+                Ok(()) => println!("Broadcast {} to chain {}", tx.txid(), self.chain),
+                Err(e) => println!("Cannot broadcast {} to chain {}: {}", tx.txid(), self.chain, e),
+            },
+            None => {
+                println!("Broadcast to chain {} once we follow it: {}", self.chain, encode::serialize_hex(tx));
+                self.waiting.lock().unwrap().push(tx.clone());
+            },
+        }
+    }
+}
@@ -0,0 +1,804 @@
+//! Which chains our peers support, keeping channels on chains we don't operate with out and
+//! handing every channel message to the channel manager of the channel's chain
+//!
+//! Our init message lists the chain hashes of our chains in BOLT1's networks TLV (the vendored
+//! rust-lightning is patched for it), and the PeerManager disconnects peers whose list has none
+//! of them. Peers that list their chains are known from their init message. For the others we
+//! learn from the chain_hash of the open_channel messages they send us and from how they answer
+//! ours: an accept_channel means the chain is supported, an error to an open on a chain they never
+//! accepted means it isn't. Channel opens on a chain a peer is known not to support are refused
+//! right away, and a peer known to support none of our chains is disconnected.
+//! The open_channel messages of our peers also have to pass our ChannelPolicy.
+//!
+//! Chains other than bitcoin's networks are regtest for rust-lightning, so their open_channel
+//! messages get their chain_hash rewritten on the way in and out.
+//!
+//! Every other channel message starts with the channel_id, which we learn the chain of from the
+//! open_channel and funding_created messages going through.
+//!
+//! The channels our failed payments blame on the way to the Router are kept for Channels::pay,
+//! both with the NetworkGraph's ids for the Router. Our channel_updates carry the chain_hash of
+//! their chain for other nodes, see chain::chain_hash, so that they know which chain's channel
+//! they're about.
+//!
+//! rust-lightning only forwards HTLCs on the chain they came in on. An HTLC whose onion sends it
+//! on through a channel of another of our chains gets an onion addressed to us instead, so that
+//! its channel manager hands it to Channels as a payment received, and the forward is kept for
+//! Channels to send on the other chain. The onions rust-lightning builds for routes on a single
+//! chain are swapped on the way out for the ones set with set_onion.
+//! A forward is in flight from the PaymentReceived Channels takes it for until its HTLC is claimed
+//! or failed, and HTLCs with the payment hash of one aren't forwarded. The forwards in flight are
+//! lines in <datadir>/forwards, the payment hash, the two chains and the preimage once the payment
+//! we sent is fulfilled ("-" before), written durably before the payment goes out and before the
+//! update_fulfill_htlc is handed to the channel manager, which saves it before its PaymentSent
+//! event is handled. Channels claims the ones with a preimage when it starts.
+//!
+//! Our channel_updates go out with the rates we quote to their channel's chain, see rates.rs,
+//! signed again, and again with a later timestamp whenever our rates change.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use lightning::chain::keysinterface::NodeSigner;
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::ln::msgs::{self, ChannelMessageHandler, ErrorAction, HandleError};
+use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+use lightning::util::ser::{Readable, Writeable};
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Message;
+
+use crate::chain;
+use crate::gossip;
+use crate::graph::NetworkGraph;
+use crate::manager::ManagerStore;
+use crate::monitor;
+use crate::onion::{self, HopPayload, Peeled};
+use crate::policy::ChannelPolicy;
+use crate::rates::{self, RateTable};
+
+/// What we know about the chains of a peer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerChains {
+    pub supported: HashSet<Sha256dHash>,
+    pub unsupported: HashSet<Sha256dHash>,
+    /// Whether they listed their chains in their init message
+    pub from_init: bool,
+}
+
+impl PeerChains {
+    fn set(&mut self, chain_hash: Sha256dHash, supported: bool) {
+        if supported {
+            self.unsupported.remove(&chain_hash);
+            self.supported.insert(chain_hash);
+        } else {
+            self.supported.remove(&chain_hash);
+            self.unsupported.insert(chain_hash);
+        }
+    }
+}
+
+/// An HTLC that came in on one of our chains to be forwarded on another
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrossChainForward {
+    pub from_chain: String,
+    pub to_chain: String,
+    /// The channel to forward on, on to_chain, and the peer at its other end
+    pub short_channel_id: u64,
+    pub node_id: PublicKey,
+    /// The incoming HTLC's
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+    /// What the onion asks for on to_chain
+    pub amt_to_forward: u64,
+    pub outgoing_cltv_value: u32,
+    /// The onion for the next hop
+    pub onion: Vec<u8>,
+}
+
+/// A payment we send on to_chain for an HTLC that came in on from_chain
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForwardInFlight {
+    pub from_chain: String,
+    pub to_chain: String,
+    /// Once the payment we sent is fulfilled
+    pub preimage: Option<[u8; 32]>,
+}
+
+// The index of a chain and a payment hash
+type ChainPayment = (usize, [u8; 32]);
+
+pub struct ChainFilter {
+    // Name, chain_hash and channel manager of each of our chains
+    chains: Vec<(String, Sha256dHash, Arc<ChannelManager>)>,
+    peer_chains: Mutex<HashMap<PublicKey, PeerChains>>,
+    // The peers to disconnect for having no chain in common with us
+    pending_disconnects: Mutex<Vec<PublicKey>>,
+    // Our open_channel messages without an answer yet, by temporary_channel_id
+    pending_opens: Mutex<HashMap<[u8; 32], (PublicKey, Sha256dHash)>>,
+    // Which of our chains each channel is on, by temporary and by final channel_id
+    channel_chains: Mutex<HashMap<[u8; 32], usize>>,
+    // Funding outpoints from funding_created messages, by temporary_channel_id
+    fundings: Mutex<HashMap<[u8; 32], OutPoint>>,
+    // The channels failed payments blamed, by the index of their chain, with the Router's id
+    failed_channels: Mutex<Vec<(usize, u64)>>,
+    graph: Arc<NetworkGraph>,
+    node_signer: Arc<dyn NodeSigner>,
+    // The HTLCs to forward on another chain, by payment hash
+    forwards: Mutex<HashMap<[u8; 32], CrossChainForward>>,
+    // The forwards Channels took, by payment hash, kept in forwards_path
+    in_flight: Mutex<HashMap<[u8; 32], ForwardInFlight>>,
+    forwards_path: Option<PathBuf>,
+    // The cltv_expiry of the HTLCs paying us, in the order they came, by the index of their chain
+    // and payment hash
+    received_expiries: Mutex<HashMap<ChainPayment, Vec<u32>>>,
+    // The onions our update_add_htlc messages go with instead of rust-lightning's, by the index
+    // of their chain and payment hash
+    onions: Mutex<HashMap<ChainPayment, Vec<u8>>>,
+    rates: Arc<RateTable>,
+    // The last channel_update rust-lightning made for each of our announced channels and the
+    // timestamp of the last one we sent, by the index of its chain and short_channel_id
+    own_updates: Mutex<HashMap<(usize, u64), (msgs::ChannelUpdate, u32)>>,
+    // The version of our rates in the channel_updates we sent
+    quoted_version: Mutex<u64>,
+    // Saving each chain's channel manager after the messages change it
+    stores: Vec<Arc<ManagerStore>>,
+    policy: ChannelPolicy,
+}
+
+// Message fields are private to rust-lightning, but their serialization isn't
+
+fn first_32_bytes<M: Writeable>(msg: &M) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(&msg.encode()[..32]);
+    bytes
+}
+
+fn open_channel_chain_hash(msg: &msgs::OpenChannel) -> Sha256dHash {
+    let encoded = msg.encode();
+    Sha256dHash::from_slice(&encoded[..32]).unwrap()
+}
+
+fn open_channel_temporary_id(msg: &msgs::OpenChannel) -> [u8; 32] {
+    let mut temporary_channel_id = [0; 32];
+    temporary_channel_id.copy_from_slice(&msg.encode()[32..64]);
+    temporary_channel_id
+}
+
+fn open_channel_funding_satoshis(msg: &msgs::OpenChannel) -> u64 {
+    // chain_hash, temporary_channel_id, funding_satoshis
+    let encoded = msg.encode();
+    let mut funding_satoshis = [0; 8];
+    funding_satoshis.copy_from_slice(&encoded[64..72]);
+    u64::from_be_bytes(funding_satoshis)
+}
+
+fn funding_created_outpoint(msg: &msgs::FundingCreated) -> OutPoint {
+    // temporary_channel_id, funding_txid, funding_output_index
+    let encoded = msg.encode();
+    let txid = Sha256dHash::from_slice(&encoded[32..64]).unwrap();
+    OutPoint::new(txid, u16::from_be_bytes([encoded[64], encoded[65]]))
+}
+
+fn channel_update_short_channel_id(msg: &msgs::ChannelUpdate) -> u64 {
+    // signature, chain_hash, short_channel_id
+    let encoded = msg.encode();
+    let mut short_channel_id = [0; 8];
+    short_channel_id.copy_from_slice(&encoded[96..104]);
+    u64::from_be_bytes(short_channel_id)
+}
+
+fn update_add_htlc_fields(msg: &msgs::UpdateAddHTLC) -> (u64, [u8; 32], u32) {
+    // channel_id, htlc_id, amount_msat, payment_hash, cltv_expiry, onion_routing_packet
+    let encoded = msg.encode();
+    let mut amount_msat = [0; 8];
+    amount_msat.copy_from_slice(&encoded[40..48]);
+    let mut payment_hash = [0; 32];
+    payment_hash.copy_from_slice(&encoded[48..80]);
+    let cltv_expiry = u32::from_be_bytes([encoded[80], encoded[81], encoded[82], encoded[83]]);
+    (u64::from_be_bytes(amount_msat), payment_hash, cltv_expiry)
+}
+
+fn update_fulfill_htlc_preimage(msg: &msgs::UpdateFulfillHTLC) -> [u8; 32] {
+    // channel_id, htlc_id, payment_preimage
+    let mut preimage = [0; 32];
+    preimage.copy_from_slice(&msg.encode()[40..72]);
+    preimage
+}
+
+fn update_add_htlc_onion(msg: &msgs::UpdateAddHTLC) -> Vec<u8> {
+    msg.encode()[84..84 + onion::PACKET_LEN].to_vec()
+}
+
+fn with_onion(msg: &msgs::UpdateAddHTLC, packet: &[u8]) -> msgs::UpdateAddHTLC {
+    let mut encoded = msg.encode();
+    encoded[84..84 + onion::PACKET_LEN].copy_from_slice(packet);
+    msgs::UpdateAddHTLC::read(&mut Cursor::new(encoded)).unwrap()
+}
+
+fn with_chain_hash(msg: &msgs::OpenChannel, chain_hash: &Sha256dHash) -> msgs::OpenChannel {
+    let mut encoded = msg.encode();
+    encoded[..32].copy_from_slice(&chain_hash[..]);
+    msgs::OpenChannel::read(&mut Cursor::new(encoded)).unwrap()
+}
+
+fn error_message(channel_id: [u8; 32], data: &str) -> msgs::ErrorMessage {
+    let mut encoded = channel_id.to_vec();
+    encoded.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    encoded.extend_from_slice(data.as_bytes());
+    msgs::ErrorMessage::read(&mut Cursor::new(encoded)).unwrap()
+}
+
+// A line of the forwards file
+fn parse_forward(line: &str) -> Result<([u8; 32], ForwardInFlight), String> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() != 4 {
+        return Err(format!("{} fields instead of 4", fields.len()));
+    }
+    let hash = |hex: &str| -> Result<[u8; 32], String> {
+        let bytes = Vec::<u8>::from_hex(hex).map_err(|e| e.to_string())?;
+        if bytes.len() != 32 {
+            return Err(format!("{} isn't 32 bytes", hex));
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&bytes);
+        Ok(hash)
+    };
+    let preimage = if fields[3] == "-" { None } else { Some(hash(fields[3])?) };
+    Ok((hash(fields[0])?, ForwardInFlight { from_chain: fields[1].to_string(), to_chain: fields[2].to_string(), preimage }))
+}
+
+impl ChainFilter {
+
+    /// chains are the -chain names with the channel manager of each, policy is for the channels
+    /// peers open to us, node_signer peels the onions of the HTLCs we forward across chains and
+    /// signs the channel_updates quoting rates. The forwards in flight are kept in forwards_path if
+    /// there's one. The channels failed payments blame go to the Router with graph's ids.
+    pub fn new<S: AsRef<str>>(chains: &[S], stores: Vec<Arc<ManagerStore>>, policy: ChannelPolicy, node_signer: Arc<dyn NodeSigner>,
+                              rates: Arc<RateTable>, graph: Arc<NetworkGraph>, forwards_path: Option<&Path>) -> Result<ChainFilter, String> {
+        let mut in_flight = HashMap::new();
+        if let Some(path) = forwards_path {
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(format!("Cannot read {:?}: {}", path, e)),
+            };
+            for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                let (payment_hash, forward) = parse_forward(line).map_err(|e| format!("Bad line in {:?}: {}", path, e))?;
+                in_flight.insert(payment_hash, forward);
+            }
+        }
+        Ok(ChainFilter {
+            chains: chains.iter().zip(&stores)
+                .map(|(c, store)| (c.as_ref().to_string(), chain::chain_hash(c.as_ref()), store.channel_manager.clone())).collect(),
+            peer_chains: Mutex::new(HashMap::new()),
+            pending_disconnects: Mutex::new(Vec::new()),
+            pending_opens: Mutex::new(HashMap::new()),
+            channel_chains: Mutex::new(HashMap::new()),
+            fundings: Mutex::new(HashMap::new()),
+            failed_channels: Mutex::new(Vec::new()),
+            graph,
+            node_signer,
+            forwards: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(in_flight),
+            forwards_path: forwards_path.map(Path::to_path_buf),
+            received_expiries: Mutex::new(HashMap::new()),
+            onions: Mutex::new(HashMap::new()),
+            rates,
+            own_updates: Mutex::new(HashMap::new()),
+            quoted_version: Mutex::new(0),
+            stores,
+            policy,
+        })
+    }
+
+    /// The chain's name if it's one of ours
+    pub fn chain_name(&self, chain_hash: &Sha256dHash) -> Option<&str> {
+        self.chains.iter().find(|(_, hash, _)| hash == chain_hash).map(|(name, _, _)| name.as_str())
+    }
+
+    pub fn get_peer_chains(&self, node_id: &PublicKey) -> PeerChains {
+        self.peer_chains.lock().unwrap().get(node_id).cloned().unwrap_or_default()
+    }
+
+    /// Whether the peer could be the other side of a channel with us, None if we don't know yet
+    pub fn has_common_chain(&self, node_id: &PublicKey) -> Option<bool> {
+        let peer_chains = self.get_peer_chains(node_id);
+        if self.chains.iter().any(|(_, hash, _)| peer_chains.supported.contains(hash)) {
+            Some(true)
+        } else if self.chains.iter().all(|(_, hash, _)| peer_chains.unsupported.contains(hash)) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Refuses channel opens that are bound to fail because of the chain
+    pub fn check_open(&self, node_id: &PublicKey, chain: &str) -> Result<(), String> {
+        let chain_hash = match self.chains.iter().find(|(name, _, _)| name == chain) {
+            Some((_, chain_hash, _)) => *chain_hash,
+            None => return Err(format!("We don't operate on chain {}", chain)),
+        };
+        if self.get_peer_chains(node_id).unsupported.contains(&chain_hash) {
+            return Err(format!("Peer {} doesn't support chain {}", node_id, chain));
+        }
+        Ok(())
+    }
+
+    /// The channel_id a channel got when it was funded, from its temporary_channel_id
+    pub fn funded_channel_id(&self, temporary_channel_id: &[u8; 32]) -> Option<[u8; 32]> {
+        self.fundings.lock().unwrap().get(temporary_channel_id).map(|funding_txo| funding_txo.to_channel_id())
+    }
+
+    /// The funding outpoint of a channel we saw being funded
+    pub fn funding_txo(&self, channel_id: &[u8; 32]) -> Option<OutPoint> {
+        self.fundings.lock().unwrap().values().find(|funding_txo| funding_txo.to_channel_id() == *channel_id).cloned()
+    }
+
+    /// The channels on chain failed payments blamed since the last call, by the Router's id
+    pub fn take_failed_channels(&self, chain: &str) -> Vec<u64> {
+        let index = match self.chains.iter().position(|(name, _, _)| name == chain) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        let mut failed_channels = self.failed_channels.lock().unwrap();
+        let taken = failed_channels.iter().filter(|(i, _)| *i == index).map(|(_, id)| *id).collect();
+        failed_channels.retain(|(i, _)| *i != index);
+        taken
+    }
+
+    /// The HTLC with payment_hash we got to forward on another chain, if there's one. The forward is
+    /// in flight from then on, until end_forward.
+    pub fn take_forward(&self, payment_hash: &[u8; 32]) -> Option<CrossChainForward> {
+        let mut forwards = self.forwards.lock().unwrap();
+        let forward = forwards.remove(payment_hash)?;
+        self.in_flight.lock().unwrap().insert(*payment_hash, ForwardInFlight {
+            from_chain: forward.from_chain.clone(),
+            to_chain: forward.to_chain.clone(),
+            preimage: None,
+        });
+        Some(forward)
+    }
+
+    /// Writes the forwards in flight to the datadir, before the payment of a new one is sent
+    pub fn save_forwards(&self) -> Result<(), String> {
+        self.write_forwards(&self.in_flight.lock().unwrap())
+    }
+
+    fn write_forwards(&self, in_flight: &HashMap<[u8; 32], ForwardInFlight>) -> Result<(), String> {
+        let path = match &self.forwards_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        for (payment_hash, forward) in in_flight {
+            let preimage = forward.preimage.map_or("-".to_string(), |preimage| preimage.to_hex());
+            contents.push_str(&format!("{} {} {} {}\n", payment_hash.to_hex(), forward.from_chain, forward.to_chain, preimage));
+        }
+        monitor::write_durably(path, contents.as_bytes()).map_err(|e| format!("Cannot save the forwards to {:?}: {}", path, e))
+    }
+
+    /// The forward in flight with payment_hash, if there's one
+    pub fn forward_in_flight(&self, payment_hash: &[u8; 32]) -> Option<ForwardInFlight> {
+        self.in_flight.lock().unwrap().get(payment_hash).cloned()
+    }
+
+    /// The forwards in flight whose payment was fulfilled, their HTLC can be claimed
+    pub fn fulfilled_forwards(&self) -> Vec<([u8; 32], ForwardInFlight)> {
+        self.in_flight.lock().unwrap().iter().filter(|(_, forward)| forward.preimage.is_some())
+            .map(|(payment_hash, forward)| (*payment_hash, forward.clone())).collect()
+    }
+
+    /// Forgets the forward with payment_hash once its HTLC is claimed or failed
+    pub fn end_forward(&self, payment_hash: &[u8; 32]) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.remove(payment_hash).is_some() {
+            if let Err(e) = self.write_forwards(&in_flight) {
+                println!("{}", e);
+            }
+        }
+    }
+
+    // Keeps the preimage of a forward whose payment on chain index is fulfilled, before the
+    // channel manager takes it
+    fn keep_preimage(&self, index: usize, msg: &msgs::UpdateFulfillHTLC) -> Result<(), String> {
+        let preimage = update_fulfill_htlc_preimage(msg);
+        let payment_hash = Sha256::hash(&preimage).into_inner();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        match in_flight.get_mut(&payment_hash) {
+            Some(forward) if forward.to_chain == self.chains[index].0 && forward.preimage.is_none() => forward.preimage = Some(preimage),
+            _ => return Ok(()),
+        }
+        self.write_forwards(&in_flight)
+    }
+
+    /// The cltv_expiry of the next HTLC with payment_hash paying us on chain, for the
+    /// PaymentReceived event rust-lightning has for it
+    pub fn take_received_expiry(&self, chain: &str, payment_hash: &[u8; 32]) -> Option<u32> {
+        let index = self.chains.iter().position(|(name, _, _)| name == chain)?;
+        let mut received_expiries = self.received_expiries.lock().unwrap();
+        let expiries = received_expiries.get_mut(&(index, *payment_hash))?;
+        let cltv_expiry = expiries.remove(0);
+        if expiries.is_empty() {
+            received_expiries.remove(&(index, *payment_hash));
+        }
+        Some(cltv_expiry)
+    }
+
+    /// Sends packet as the onion of our HTLCs with payment_hash on chain, until forget_onion
+    pub fn set_onion(&self, chain: &str, payment_hash: [u8; 32], packet: Vec<u8>) {
+        if let Some(index) = self.chains.iter().position(|(name, _, _)| name == chain) {
+            self.onions.lock().unwrap().insert((index, payment_hash), packet);
+        }
+    }
+
+    pub fn forget_onion(&self, chain: &str, payment_hash: &[u8; 32]) {
+        if let Some(index) = self.chains.iter().position(|(name, _, _)| name == chain) {
+            self.onions.lock().unwrap().remove(&(index, *payment_hash));
+        }
+    }
+
+    // The HTLC with an onion addressed to us if it goes on through a channel of another of our
+    // chains, None to leave it to the channel manager of its own. The expiry of HTLCs paying us
+    // is kept for take_received_expiry.
+    fn cross_chain(&self, index: usize, msg: &msgs::UpdateAddHTLC) -> Option<msgs::UpdateAddHTLC> {
+        let (amount_msat, payment_hash, cltv_expiry) = update_add_htlc_fields(msg);
+        let (payload, next_packet) = match onion::peel(&*self.node_signer, &update_add_htlc_onion(msg), &payment_hash) {
+            Ok(Peeled::Forward { payload, next_packet }) => (payload, next_packet),
+            Ok(Peeled::Final(_)) => {
+                self.received_expiries.lock().unwrap().entry((index, payment_hash)).or_default().push(cltv_expiry);
+                return None;
+            },
+            Err(_) => return None,
+        };
+        // Not back through the channel it came in on, that short_channel_id can be on another chain too
+        let incoming_channel_id = first_32_bytes(msg);
+        let channel_on = |channel_manager: &ChannelManager| {
+            channel_manager.list_channels().into_iter()
+                .find(|c| c.short_channel_id == Some(payload.short_channel_id) && c.channel_id != incoming_channel_id)
+        };
+        if channel_on(&self.chains[index].2).is_some() {
+            return None;
+        }
+        let (to_index, channel) = self.chains.iter().enumerate()
+            .find_map(|(i, (_, _, channel_manager))| channel_on(channel_manager).map(|channel| (i, channel)))?;
+
+        let session_key = SecretKey::from_slice(&rand::thread_rng().gen::<[u8; 32]>()).ok()?;
+        let to_us = HopPayload { short_channel_id: 0, amt_to_forward: amount_msat, outgoing_cltv_value: cltv_expiry };
+        let packet = onion::construct(&session_key, &[(self.node_signer.get_node_id(), to_us)], &payment_hash).ok()?;
+        let mut forwards = self.forwards.lock().unwrap();
+        if forwards.contains_key(&payment_hash) || self.in_flight.lock().unwrap().contains_key(&payment_hash) {
+            println!("HTLC {} on chain {} not forwarded, we're already forwarding one with its payment hash", payment_hash.to_hex(), self.chains[index].0);
+            return None;
+        }
+        println!("HTLC {} on chain {} to be forwarded on chain {}", payment_hash.to_hex(), self.chains[index].0, self.chains[to_index].0);
+        forwards.insert(payment_hash, CrossChainForward {
+            from_chain: self.chains[index].0.clone(),
+            to_chain: self.chains[to_index].0.clone(),
+            short_channel_id: payload.short_channel_id,
+            node_id: channel.remote_network_id,
+            amount_msat,
+            cltv_expiry,
+            amt_to_forward: payload.amt_to_forward,
+            outgoing_cltv_value: payload.outgoing_cltv_value,
+            onion: next_packet,
+        });
+        Some(with_onion(msg, &packet))
+    }
+
+    // A channel_update of ours with the rates we quote to its chain, if any
+    fn with_quotes(&self, index: usize, msg: &msgs::ChannelUpdate) -> msgs::ChannelUpdate {
+        let to = &self.chains[index].0;
+        let quotes: Vec<_> = self.rates.list().into_iter()
+            .filter(|(from, chain, _)| chain == to && self.chains.iter().any(|(name, _, _)| name == from))
+            .map(|(from, _, rate)| (chain::chain_hash(&from), chain::chain_hash(to), rate)).collect();
+        let (short_channel_id, _, _) = gossip::channel_update_fields(msg);
+        // chain_hash, short_channel_id, timestamp, then the rest without excess data
+        let mut contents = msg.encode()[64..128].to_vec();
+        let made_at = u32::from_be_bytes([contents[40], contents[41], contents[42], contents[43]]);
+        let mut own_updates = self.own_updates.lock().unwrap();
+        let timestamp = match own_updates.get(&(index, short_channel_id)) {
+            Some((_, sent)) => made_at.max(sent + 1),
+            None => made_at,
+        };
+        own_updates.insert((index, short_channel_id), (msg.clone(), timestamp));
+        let chain_hash = chain::chain_hash(to);
+        if quotes.is_empty() && timestamp == made_at && contents[..32] == chain_hash[..] {
+            return msg.clone();
+        }
+        contents[..32].copy_from_slice(&chain_hash[..]);
+        contents[40..44].copy_from_slice(&timestamp.to_be_bytes());
+        contents.extend_from_slice(&rates::encode_quotes(&quotes));
+        let hash = Sha256dHash::hash(&contents);
+        let signature = self.node_signer.sign_with_node_secret(&Message::from_slice(&hash[..]).unwrap());
+        let mut encoded = signature.serialize_compact().to_vec();
+        encoded.extend_from_slice(&contents);
+        msgs::ChannelUpdate::read(&mut Cursor::new(encoded)).unwrap()
+    }
+
+    fn set_peer_chain(&self, node_id: &PublicKey, chain_hash: Sha256dHash, supported: bool) {
+        let mut peer_chains = self.peer_chains.lock().unwrap();
+        peer_chains.entry(*node_id).or_default().set(chain_hash, supported);
+        if !supported {
+            self.check_common_chain(node_id, &peer_chains[node_id]);
+        }
+    }
+
+    fn check_common_chain(&self, node_id: &PublicKey, peer: &PeerChains) {
+        if self.chains.iter().all(|(_, hash, _)| peer.unsupported.contains(hash)) {
+            println!("Disconnecting peer {}, it has no chain in common with us", node_id);
+            self.pending_disconnects.lock().unwrap().push(*node_id);
+        }
+    }
+
+    fn set_funding(&self, msg: &msgs::FundingCreated, chain_index: usize) {
+        let funding_txo = funding_created_outpoint(msg);
+        self.fundings.lock().unwrap().insert(first_32_bytes(msg), funding_txo);
+        self.channel_chains.lock().unwrap().insert(funding_txo.to_channel_id(), chain_index);
+    }
+
+    // The index of the chain a channel is on, the first one if we don't know the channel (its
+    // channel manager will answer with an error)
+    fn chain_index(&self, their_node_id: &PublicKey, channel_id: &[u8; 32]) -> usize {
+        if let Some(index) = self.channel_chains.lock().unwrap().get(channel_id) {
+            return *index;
+        }
+        // Channels from before a restart
+        self.chains.iter().position(|(_, _, channel_manager)| {
+            channel_manager.list_channels().iter().any(|c| c.channel_id == *channel_id && c.remote_network_id == *their_node_id)
+        }).unwrap_or(0)
+    }
+
+    // Hands a message to the channel manager of the channel's chain
+    fn handle<M: Writeable, F: FnOnce(&ChannelManager) -> Result<(), HandleError>>(&self, their_node_id: &PublicKey, msg: &M, handle: F) -> Result<(), HandleError> {
+        self.handle_on(self.chain_index(their_node_id, &first_32_bytes(msg)), handle)
+    }
+
+    fn handle_on<T, F: FnOnce(&ChannelManager) -> T>(&self, index: usize, handle: F) -> T {
+        let result = handle(&self.chains[index].2);
+        self.stores[index].save();
+        result
+    }
+}
+
+impl MessageSendEventsProvider for ChainFilter {
+    fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+        let mut all_events: Vec<MessageSendEvent> = self.pending_disconnects.lock().unwrap().drain(..).map(|node_id| {
+            MessageSendEvent::HandleError {
+                node_id,
+                action: Some(ErrorAction::DisconnectPeer { msg: Some(error_message([0; 32], "No chain in common")) }),
+            }
+        }).collect();
+        for (index, (_, chain_hash, channel_manager)) in self.chains.iter().enumerate() {
+            let mut events = channel_manager.get_and_clear_pending_msg_events();
+            let mut announced_updates = Vec::new();
+            for event in events.iter_mut() {
+                match event {
+                    MessageSendEvent::SendOpenChannel { node_id, msg } => {
+                        if open_channel_chain_hash(msg) != *chain_hash {
+                            *msg = with_chain_hash(msg, chain_hash);
+                        }
+                        let temporary_channel_id = open_channel_temporary_id(msg);
+                        self.pending_opens.lock().unwrap().insert(temporary_channel_id, (*node_id, *chain_hash));
+                        self.channel_chains.lock().unwrap().insert(temporary_channel_id, index);
+                    },
+                    MessageSendEvent::SendFundingCreated { msg, .. } => self.set_funding(msg, index),
+                    MessageSendEvent::BroadcastChannelAnnouncement { update_msg, .. } => {
+                        announced_updates.push(update_msg.clone());
+                        *update_msg = self.with_quotes(index, update_msg);
+                    },
+                    MessageSendEvent::BroadcastChannelUpdate { msg } => *msg = self.with_quotes(index, msg),
+                    MessageSendEvent::UpdateHTLCs { updates, .. } => {
+                        let onions = self.onions.lock().unwrap();
+                        for msg in updates.update_add_htlcs.iter_mut() {
+                            if let Some(packet) = onions.get(&(index, update_add_htlc_fields(msg).1)) {
+                                *msg = with_onion(msg, packet);
+                            }
+                        }
+                    },
+                    MessageSendEvent::PaymentFailureNetworkUpdate { update } => {
+                        let router_id = match update {
+                            msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { msg } => self.graph.router_id(index, channel_update_short_channel_id(msg)),
+                            msgs::HTLCFailChannelUpdate::ChannelClosed { short_channel_id, .. } => {
+                                *short_channel_id = self.graph.router_id(index, *short_channel_id);
+                                *short_channel_id
+                            },
+                            msgs::HTLCFailChannelUpdate::NodeFailure { .. } => continue,
+                        };
+                        self.failed_channels.lock().unwrap().push((index, router_id));
+                    },
+                    _ => {},
+                }
+            }
+            // rust-lightning 0.0.9's Router panics with node failures, it can't mark nodes bad yet
+            events.retain(|event| {
+                !matches!(event, MessageSendEvent::PaymentFailureNetworkUpdate { update: msgs::HTLCFailChannelUpdate::NodeFailure { .. } })
+            });
+            all_events.append(&mut events);
+            // The PeerManager doesn't send a channel's announcement to the other end of the channel,
+            // nor the channel_update with it, so that goes out again on its own with a later
+            // timestamp
+            for msg in announced_updates {
+                all_events.push(MessageSendEvent::BroadcastChannelUpdate { msg: self.with_quotes(index, &msg) });
+            }
+        }
+        let version = self.rates.version();
+        let mut quoted_version = self.quoted_version.lock().unwrap();
+        if *quoted_version != version {
+            *quoted_version = version;
+            let own_updates: Vec<(usize, msgs::ChannelUpdate)> = self.own_updates.lock().unwrap().iter()
+                .map(|((index, _), (msg, _))| (*index, msg.clone())).collect();
+            for (index, msg) in own_updates {
+                all_events.push(MessageSendEvent::BroadcastChannelUpdate { msg: self.with_quotes(index, &msg) });
+            }
+        }
+        all_events
+    }
+}
+
+impl ChannelMessageHandler for ChainFilter {
+    fn handle_open_channel(&self, their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<(), HandleError> {
+        let chain_hash = open_channel_chain_hash(msg);
+        self.set_peer_chain(their_node_id, chain_hash, true);
+        let index = match self.chains.iter().position(|(_, hash, _)| *hash == chain_hash) {
+            Some(index) => index,
+            None => return Err(HandleError {
+                err: "open_channel for a chain we don't operate on",
+                action: Some(ErrorAction::SendErrorMessage {
+                    msg: error_message(open_channel_temporary_id(msg), &format!("Unknown chain_hash {}", chain_hash)),
+                }),
+            }),
+        };
+        let (chain, _, channel_manager) = &self.chains[index];
+        let pending = channel_manager.list_channels().iter()
+            .filter(|c| c.remote_network_id == *their_node_id && c.short_channel_id.is_none()).count();
+        if let Err(reason) = self.policy.check_open(chain, their_node_id, open_channel_funding_satoshis(msg), pending) {
+            println!("Refusing a channel from {} on chain {}: {}", their_node_id, chain, reason);
+            return Err(HandleError {
+                err: "open_channel refused by our channel policy",
+                action: Some(ErrorAction::SendErrorMessage { msg: error_message(open_channel_temporary_id(msg), &reason) }),
+            });
+        }
+        self.channel_chains.lock().unwrap().insert(open_channel_temporary_id(msg), index);
+        let internal_chain_hash = chain::internal_chain_hash(&self.chains[index].0);
+        self.handle_on(index, |channel_manager| channel_manager.handle_open_channel(their_node_id, &with_chain_hash(msg, &internal_chain_hash)))
+    }
+
+    fn handle_accept_channel(&self, their_node_id: &PublicKey, msg: &msgs::AcceptChannel) -> Result<(), HandleError> {
+        let pending_open = self.pending_opens.lock().unwrap().remove(&first_32_bytes(msg));
+        if let Some((node_id, chain_hash)) = pending_open {
+            if node_id == *their_node_id {
+                self.set_peer_chain(their_node_id, chain_hash, true);
+            }
+        }
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_accept_channel(their_node_id, msg))
+    }
+
+    fn handle_funding_created(&self, their_node_id: &PublicKey, msg: &msgs::FundingCreated) -> Result<(), HandleError> {
+        let index = self.chain_index(their_node_id, &first_32_bytes(msg));
+        self.set_funding(msg, index);
+        self.handle_on(index, |channel_manager| channel_manager.handle_funding_created(their_node_id, msg))
+    }
+
+    fn handle_funding_signed(&self, their_node_id: &PublicKey, msg: &msgs::FundingSigned) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_funding_signed(their_node_id, msg))
+    }
+
+    fn handle_funding_locked(&self, their_node_id: &PublicKey, msg: &msgs::FundingLocked) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_funding_locked(their_node_id, msg))
+    }
+
+    fn handle_shutdown(&self, their_node_id: &PublicKey, msg: &msgs::Shutdown) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_shutdown(their_node_id, msg))
+    }
+
+    fn handle_closing_signed(&self, their_node_id: &PublicKey, msg: &msgs::ClosingSigned) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_closing_signed(their_node_id, msg))
+    }
+
+    fn handle_update_add_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateAddHTLC) -> Result<(), HandleError> {
+        let index = self.chain_index(their_node_id, &first_32_bytes(msg));
+        let msg = self.cross_chain(index, msg).unwrap_or_else(|| msg.clone());
+        self.handle_on(index, |channel_manager| channel_manager.handle_update_add_htlc(their_node_id, &msg))
+    }
+
+    fn handle_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFulfillHTLC) -> Result<(), HandleError> {
+        let index = self.chain_index(their_node_id, &first_32_bytes(msg));
+        // They send it again once reconnected
+        if let Err(e) = self.keep_preimage(index, msg) {
+            println!("{}", e);
+            return Err(HandleError { err: "Cannot save the preimage of a forward", action: Some(ErrorAction::DisconnectPeer { msg: None }) });
+        }
+        self.handle_on(index, |channel_manager| channel_manager.handle_update_fulfill_htlc(their_node_id, msg))
+    }
+
+    fn handle_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailHTLC) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_update_fail_htlc(their_node_id, msg))
+    }
+
+    fn handle_update_fail_malformed_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailMalformedHTLC) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_update_fail_malformed_htlc(their_node_id, msg))
+    }
+
+    fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &msgs::CommitmentSigned) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_commitment_signed(their_node_id, msg))
+    }
+
+    fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &msgs::RevokeAndACK) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_revoke_and_ack(their_node_id, msg))
+    }
+
+    fn handle_update_fee(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFee) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_update_fee(their_node_id, msg))
+    }
+
+    fn handle_announcement_signatures(&self, their_node_id: &PublicKey, msg: &msgs::AnnouncementSignatures) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_announcement_signatures(their_node_id, msg))
+    }
+
+    fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
+        self.pending_opens.lock().unwrap().retain(|_, (node_id, _)| node_id != their_node_id);
+        self.pending_disconnects.lock().unwrap().retain(|node_id| node_id != their_node_id);
+        for index in 0..self.chains.len() {
+            self.handle_on(index, |channel_manager| channel_manager.peer_disconnected(their_node_id, no_connection_possible));
+        }
+    }
+
+    fn peer_connected(&self, their_node_id: &PublicKey) {
+        for (_, chain_hash, channel_manager) in &self.chains {
+            // Any channel we already have with them is on this chain
+            if channel_manager.list_channels().iter().any(|c| c.remote_network_id == *their_node_id) {
+                self.set_peer_chain(their_node_id, *chain_hash, true);
+            }
+            channel_manager.peer_connected(their_node_id);
+        }
+        // What we learnt about a peer that didn't list its chains is kept across connections
+        let peer_chains = self.get_peer_chains(their_node_id);
+        if !peer_chains.from_init {
+            self.check_common_chain(their_node_id, &peer_chains);
+        }
+    }
+
+    fn handle_peer_networks(&self, their_node_id: &PublicKey, networks: &[Sha256dHash]) {
+        // The PeerManager already disconnected them if none of our chains is in there
+        let (supported, unsupported) = self.chains.iter().map(|(_, hash, _)| *hash).partition(|hash| networks.contains(hash));
+        self.peer_chains.lock().unwrap().insert(*their_node_id, PeerChains { supported, unsupported, from_init: true });
+    }
+
+    fn get_genesis_hashes(&self) -> Option<Vec<Sha256dHash>> {
+        Some(self.chains.iter().map(|(_, hash, _)| *hash).collect())
+    }
+
+    fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &msgs::ChannelReestablish) -> Result<(), HandleError> {
+        self.handle(their_node_id, msg, |channel_manager| channel_manager.handle_channel_reestablish(their_node_id, msg))
+    }
+
+    fn handle_error(&self, their_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
+        let channel_id = first_32_bytes(msg);
+        let pending_open = self.pending_opens.lock().unwrap().remove(&channel_id);
+        if let Some((node_id, chain_hash)) = pending_open {
+            // Whatever the reason they give, unless their init message or an earlier channel
+            // says they do the chain
+            let peer_chains = self.get_peer_chains(their_node_id);
+            if node_id == *their_node_id && !peer_chains.from_init && !peer_chains.supported.contains(&chain_hash) {
+                self.set_peer_chain(their_node_id, chain_hash, false);
+            }
+        }
+        // All zeros is about all the channels with them
+        if channel_id == [0; 32] {
+            for index in 0..self.chains.len() {
+                self.handle_on(index, |channel_manager| channel_manager.handle_error(their_node_id, msg));
+            }
+        } else {
+            self.handle_on(self.chain_index(their_node_id, &channel_id), |channel_manager| channel_manager.handle_error(their_node_id, msg));
+        }
+    }
+}
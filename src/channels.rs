@@ -0,0 +1,1046 @@
+//! Channels on every chain of the node, on top of rust-lightning's ChannelManager
+//!
+//! Each chain has a ChainBackend to follow blocks from and an on-chain wallet funding the channels
+//! we open. Channels::sync, which start calls every second, feeds new blocks to rust-lightning
+//! and the wallet and handles the events that come out of them. Reorgs aren't handled yet.
+//!
+//! rust-lightning doesn't tell much about a channel, the state we report is:
+//!
+//! - pending: the funding transaction isn't deep enough yet, or doesn't even exist
+//! - open: both sides sent funding_locked, rust-lightning can use it
+//! - closing: we asked for a close and rust-lightning still has the channel
+//! - closed: rust-lightning forgot the channel
+//!
+//! A batch of channels, to several peers and chains, is opened all or nothing: the wallet of each
+//! chain funds the batch's channels there with one transaction, and none is broadcast before
+//! every peer signed its channel. If a peer refuses its channel the others are closed before
+//! they're funded. Batches don't survive a restart. Dual-funded channels would need the
+//! interactive channel establishment rust-lightning 0.0.9 doesn't have.
+//!
+//! Payments go through the routes rust-lightning's Router finds in the network graph. The next
+//! attempt after a failure goes without the channel the failure blamed, or without the first hop
+//! and the invoice's hint if the Router comes up with a route that failed already. rust-lightning
+//! 0.0.9 only takes the last hop of the invoice's private routes.
+//!
+//! Routes can cross chains at the nodes with channels on several of them, chain hops. The chain
+//! of each channel in a route is the one the NetworkGraph found its funding output on. Going
+//! back from the payee, the amount a chain hop gets is what it forwards and its fee converted
+//! at the rate it quotes, or ours, and the blocks left before the HTLC expires are counted on
+//! each chain, converted at the chains' block intervals.
+//! We build the onion of such a route ourselves and send it as a payment to the first hop. The
+//! errors of a failed cross-chain payment can't be read, the attempt goes without its first hop.
+//!
+//! As a chain hop, we forward the HTLCs ChainFilter hands us if at our rate they're worth what
+//! they ask for on the other chain plus our spread, and leave us CROSS_CHAIN_CLTV_EXPIRY_DELTA
+//! blocks more than the time left on the other chain, and claim or fail them with the payment we sent. The forwards in flight are in the
+//! datadir before their payment goes out, with the preimage once it's fulfilled, and the ones
+//! fulfilled are claimed when we start. A payment that failed while we were down leaves its HTLC
+//! waiting for its expiry.
+//!
+//! The payments we receive are claimed if they pay one of the node's open invoices, on the
+//! invoice's chain and for at least its amount, and failed back otherwise. The payments of hold
+//! invoices are held until sync finds the invoice settled or canceled.
+//!
+//! Once closed we follow the transaction spending the funding output, and the ones spending its
+//! outputs, to tell when what the channel pays us is in the wallet. Our outputs in our own
+//! commitment transaction, after a force close, wait for their CSV delay before the wallet can
+//! sweep them. rust-lightning 0.0.9 doesn't report that output when the one who funded a channel
+//! force closes it before any payment went through it.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use lightning::chain::chaininterface::ChainWatchInterfaceUtil;
+use lightning::chain::keysinterface::SpendableOutputDescriptor;
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::channelmanager::{ChannelDetails, ChannelManager, PaymentHash, PaymentPreimage};
+use lightning::ln::msgs::{HTLCFailChannelUpdate, RoutingMessageHandler};
+use lightning::ln::router::{Route, RouteHint, RouteHop, Router};
+use lightning::util::events::{Event, EventsProvider};
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+
+use crate::announce::NodeAnnouncer;
+use crate::chain::ChainBackend;
+use crate::chainfilter::{ChainFilter, CrossChainForward};
+use crate::graph::NetworkGraph;
+use crate::invoice::Invoice;
+use crate::manager::ManagerStore;
+use crate::monitor::PersistentMonitor;
+use crate::net;
+use crate::node::Node;
+use crate::onion::{self, HopPayload};
+use crate::rates::RateTable;
+use crate::registry::InvoiceRegistry;
+use crate::wallet::Wallet;
+
+/// The blocks of the incoming chain between the expiry of an HTLC we forward across chains and
+/// the one we send, what rust-lightning 0.0.9 asks of the HTLCs it forwards
+pub const CROSS_CHAIN_CLTV_EXPIRY_DELTA: u32 = 72;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelState {
+    Pending,
+    Open,
+    Closing,
+    Closed,
+}
+
+impl fmt::Display for ChannelState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelState::Pending => write!(f, "pending"),
+            ChannelState::Open => write!(f, "open"),
+            ChannelState::Closing => write!(f, "closing"),
+            ChannelState::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelInfo {
+    pub chain: String,
+    /// The temporary one until the channel is funded
+    pub channel_id: [u8; 32],
+    pub node_id: PublicKey,
+    /// Satoshis
+    pub value: u64,
+    /// What open_channel returned, 0 for the channels the peer opened
+    pub user_channel_id: u64,
+    pub short_channel_id: Option<u64>,
+    pub funding_txo: Option<OutPoint>,
+    /// Of the funding transaction, 0 while it's not in a block
+    pub confirmations: u32,
+    pub state: ChannelState,
+    /// The transaction that spent the funding output and the height of its block
+    pub closing_tx: Option<(Sha256dHash, u32)>,
+    /// What the channel paid us on chain once closed
+    pub outputs: Vec<ClosedOutput>,
+}
+
+/// An output of a closed channel's transactions that is ours
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClosedOutput {
+    pub outpoint: BitcoinOutPoint,
+    pub value: u64,
+    /// The first block a transaction spending it can be in, its CSV delay after the one it's in
+    pub available_at: u32,
+    /// Height of the block with the wallet's sweep, or the output's for those that already pay to the wallet
+    pub swept_at: Option<u32>,
+}
+
+impl ChannelInfo {
+    /// The height the last output not swept yet can be swept at, None with nothing left to sweep
+    pub fn funds_available_at(&self) -> Option<u32> {
+        self.outputs.iter().filter(|output| output.swept_at.is_none()).map(|output| output.available_at).max()
+    }
+}
+
+/// '<block>x<transaction>x<output>'
+pub fn short_channel_id_to_string(short_channel_id: u64) -> String {
+    format!("{}x{}x{}", short_channel_id >> 40, (short_channel_id >> 16) & 0xff_ffff, short_channel_id & 0xffff)
+}
+
+impl fmt::Display for ChannelInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {} {}", self.chain, self.channel_id.to_hex(), self.node_id, self.value, self.state)?;
+        if self.user_channel_id != 0 {
+            write!(f, " user_channel_id {}", self.user_channel_id)?;
+        }
+        match (self.state, self.short_channel_id) {
+            (ChannelState::Pending, _) if self.funding_txo.is_some() => write!(f, " confirmations {}", self.confirmations),
+            (ChannelState::Open, Some(short_channel_id)) => write!(f, " {}", short_channel_id_to_string(short_channel_id)),
+            (ChannelState::Closed, _) if self.funding_txo.is_some() => match self.closing_tx {
+                None => write!(f, " waiting for the closing transaction"),
+                Some((txid, height)) => {
+                    write!(f, " closing_tx {} at height {}", txid, height)?;
+                    match self.funds_available_at() {
+                        Some(available_at) => write!(f, " funds available at height {}", available_at),
+                        None if !self.outputs.is_empty() => write!(f, " swept"),
+                        None => Ok(()),
+                    }
+                },
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+struct ChainState {
+    synced_height: Option<u32>,
+    // Height of the block each funding transaction of a channel we have is in
+    funding_heights: HashMap<OutPoint, u32>,
+    // The channels rust-lightning had last time we looked, by channel_id
+    known: HashMap<[u8; 32], ChannelInfo>,
+    closing: HashSet<[u8; 32]>,
+    closed: Vec<ChannelInfo>,
+    // The closing transactions and the ones spending their outputs, with their channel and height
+    close_txs: HashMap<Sha256dHash, ([u8; 32], u32)>,
+}
+
+struct ChainChannels {
+    chain: String,
+    channel_manager: Arc<ChannelManager>,
+    monitor: Arc<PersistentMonitor>,
+    store: Arc<ManagerStore>,
+    // The last block the ChannelManager saw before a restart
+    manager_height: Option<u32>,
+    chain_watcher: Arc<ChainWatchInterfaceUtil>,
+    backend: Arc<dyn ChainBackend>,
+    wallet: Arc<Wallet>,
+    state: Mutex<ChainState>,
+    // When the HTLCs rust-lightning is holding can be forwarded, it ignores us before that
+    forward_at: Mutex<Option<Instant>>,
+}
+
+impl ChainChannels {
+    // The last block we gave rust-lightning
+    fn height(&self) -> u32 {
+        self.state.lock().unwrap().synced_height.or(self.manager_height).unwrap_or(0)
+    }
+
+    // Adds the outputs rust-lightning found for us to their channel
+    fn track_outputs(&self, descriptors: &[SpendableOutputDescriptor]) {
+        let mut state = self.state.lock().unwrap();
+        let ChainState { known, closed, close_txs, .. } = &mut *state;
+        for descriptor in descriptors {
+            let (outpoint, value, delay, in_wallet) = match descriptor {
+                SpendableOutputDescriptor::StaticOutput { outpoint, output } => (*outpoint, output.value, 0, true),
+                SpendableOutputDescriptor::DynamicOutputP2WSH { outpoint, output, to_self_delay, .. } => (*outpoint, output.value, *to_self_delay as u32, false),
+                SpendableOutputDescriptor::DynamicOutputP2WPKH { outpoint, output, .. } => (*outpoint, output.value, 0, false),
+            };
+            let (channel_id, height) = match close_txs.get(&outpoint.txid) {
+                Some(close_tx) => *close_tx,
+                None => {
+                    println!("Output {} on chain {} isn't from a closing transaction we saw", outpoint, self.chain);
+                    continue;
+                },
+            };
+            let channel = match known.values_mut().chain(closed.iter_mut()).find(|c| c.channel_id == channel_id) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            if channel.outputs.iter().any(|output| output.outpoint == outpoint) {
+                continue;
+            }
+            let available_at = height + delay;
+            println!("Channel {} on chain {} pays us {} satoshis in {}, available at height {}", channel_id.to_hex(), self.chain,
+                     value, outpoint, available_at);
+            channel.outputs.push(ClosedOutput { outpoint, value, available_at, swept_at: if in_wallet { Some(height) } else { None } });
+        }
+    }
+}
+
+/// Why a payment failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaymentFailure {
+    /// We can't pay the invoice: bad, expired, on a chain we don't operate or without an amount
+    Invoice(String),
+    /// No route left to the payee after the attempts that failed
+    NoRoute { attempts: u32, reason: String },
+    /// The payee refused the payment, another route wouldn't help
+    Rejected { attempts: u32 },
+    /// Out of time, the last attempt may still go through if it's pending
+    Timeout { attempts: u32, pending: bool },
+}
+
+impl fmt::Display for PaymentFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaymentFailure::Invoice(e) => write!(f, "{}", e),
+            PaymentFailure::NoRoute { attempts, reason } => write!(f, "no route after {} attempts: {}", attempts, reason),
+            PaymentFailure::Rejected { attempts } => write!(f, "rejected by the payee after {} attempts", attempts),
+            PaymentFailure::Timeout { attempts, pending: true } => write!(f, "timed out after {} attempts, the last one is still pending", attempts),
+            PaymentFailure::Timeout { attempts, pending: false } => write!(f, "timed out after {} attempts", attempts),
+        }
+    }
+}
+
+// What became of the last attempt of a payment we're making
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PaymentStatus {
+    Pending,
+    Sent([u8; 32]),
+    Failed { rejected_by_dest: bool },
+}
+
+/// A channel to open in a batch
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchOpen {
+    pub chain: String,
+    pub node_id: PublicKey,
+    pub value: u64,
+    pub push_msat: u64,
+}
+
+pub struct Channels {
+    chains: Vec<ChainChannels>,
+    chain_filter: Arc<ChainFilter>,
+    router: Arc<Router>,
+    graph: Arc<NetworkGraph>,
+    announcer: Arc<NodeAnnouncer>,
+    peer_manager: Arc<net::PeerManager>,
+    invoices: Arc<InvoiceRegistry>,
+    rates: Arc<RateTable>,
+    // The payments pay is waiting for, by payment hash
+    payments: Mutex<HashMap<[u8; 32], PaymentStatus>>,
+    next_user_channel_id: Mutex<u64>,
+    // The index of the chain and the user_channel_id of each channel of the batches not broadcast
+    // yet, by the first user_channel_id
+    batches: Mutex<HashMap<u64, Vec<(usize, u64)>>>,
+}
+
+impl Channels {
+
+    /// backends are the node's chains' ones, in the same order
+    pub fn new(node: &Node, backends: Vec<Arc<dyn ChainBackend>>) -> Result<Channels, String> {
+        assert_eq!(node.chains.len(), backends.len());
+        let mut chains = Vec::new();
+        for (index, (chain_node, backend)) in node.chains.iter().zip(backends).enumerate() {
+            chain_node.broadcaster.set_backend(backend.clone());
+            node.graph.set_backend(index, backend.clone());
+            let wallet = Wallet::with_fundings_file(&chain_node.chain, chain_node.signer.clone(), backend.clone(), chain_node.fee_estimator.clone(),
+                                                    chain_node.fundings_path.as_deref())?;
+            chains.push(ChainChannels {
+                chain: chain_node.chain.clone(),
+                channel_manager: chain_node.channel_manager.clone(),
+                monitor: chain_node.monitor.clone(),
+                store: chain_node.manager_store.clone(),
+                manager_height: chain_node.manager_height,
+                chain_watcher: chain_node.chain_watcher.clone(),
+                backend,
+                wallet: Arc::new(wallet),
+                state: Mutex::new(ChainState {
+                    synced_height: None,
+                    funding_heights: HashMap::new(),
+                    known: HashMap::new(),
+                    closing: HashSet::new(),
+                    closed: Vec::new(),
+                    close_txs: HashMap::new(),
+                }),
+                forward_at: Mutex::new(None),
+            });
+        }
+        // rust-lightning doesn't save its events, HTLCs received before a restart are still waiting
+        // for their PendingHTLCsForwardable
+        for chain_channels in chains.iter().filter(|c| c.manager_height.is_some()) {
+            chain_channels.channel_manager.process_pending_htlc_forwards();
+        }
+        let last_user_channel_id = chains.iter().flat_map(|c| c.channel_manager.list_channels()).map(|c| c.user_id).max().unwrap_or(0);
+        let channels = Channels {
+            chains,
+            chain_filter: node.chain_filter.clone(),
+            router: node.router.clone(),
+            graph: node.graph.clone(),
+            announcer: node.announcer.clone(),
+            peer_manager: node.peer_manager.clone(),
+            invoices: node.invoices.clone(),
+            rates: node.rates.clone(),
+            payments: Mutex::new(HashMap::new()),
+            next_user_channel_id: Mutex::new(last_user_channel_id + 1),
+            batches: Mutex::new(HashMap::new()),
+        };
+        // The forwards whose payment went through before a restart, their HTLC may be claimed already
+        for (payment_hash, forward) in channels.chain_filter.fulfilled_forwards() {
+            channels.claim_forward(&payment_hash, &forward.from_chain, PaymentPreimage(forward.preimage.unwrap()));
+        }
+        Ok(channels)
+    }
+
+    fn chain(&self, chain: &str) -> Result<&ChainChannels, String> {
+        self.chains.iter().find(|c| c.chain == chain).ok_or(format!("We don't operate on chain {}", chain))
+    }
+
+    pub fn get_wallet(&self, chain: &str) -> Result<Arc<Wallet>, String> {
+        Ok(self.chain(chain)?.wallet.clone())
+    }
+
+    /// Opens a channel of value satoshis funded by the chain's wallet, or waiting for a PSBT
+    /// from an external one (see funding_psbt). Returns the user_channel_id it gets.
+    pub fn open_channel(&self, chain: &str, node_id: PublicKey, value: u64, push_msat: u64, with_psbt: bool) -> Result<u64, String> {
+        let chain_channels = self.chain(chain)?;
+        self.chain_filter.check_open(&node_id, chain)?;
+        if !self.peer_manager.get_peer_node_ids().contains(&node_id) {
+            return Err(format!("Not connected to {}", node_id));
+        }
+        let user_channel_id = {
+            let mut next_user_channel_id = self.next_user_channel_id.lock().unwrap();
+            *next_user_channel_id += 1;
+            *next_user_channel_id - 1
+        };
+        if with_psbt {
+            chain_channels.wallet.fund_with_psbt(user_channel_id);
+        }
+        chain_channels.channel_manager.create_channel(node_id, value, push_msat, user_channel_id)
+            .map_err(|e| format!("Cannot open the channel: {:?}", e))?;
+        chain_channels.store.save();
+        self.peer_manager.process_events();
+        Ok(user_channel_id)
+    }
+
+    /// Opens all the channels or none, see the module documentation. Returns their
+    /// user_channel_ids, in the same order.
+    pub fn open_batch(&self, opens: &[BatchOpen]) -> Result<Vec<u64>, String> {
+        if opens.is_empty() {
+            return Err("No channels to open".to_string());
+        }
+        let mut indexes = Vec::new();
+        for open in opens {
+            let index = self.chains.iter().position(|c| c.chain == open.chain).ok_or(format!("We don't operate on chain {}", open.chain))?;
+            self.chain_filter.check_open(&open.node_id, &open.chain)?;
+            if !self.peer_manager.get_peer_node_ids().contains(&open.node_id) {
+                return Err(format!("Not connected to {}", open.node_id));
+            }
+            indexes.push(index);
+        }
+        let user_channel_ids: Vec<u64> = {
+            let mut next_user_channel_id = self.next_user_channel_id.lock().unwrap();
+            *next_user_channel_id += opens.len() as u64;
+            (*next_user_channel_id - opens.len() as u64..*next_user_channel_id).collect()
+        };
+        let batch_id = user_channel_ids[0];
+        let members: Vec<(usize, u64)> = indexes.iter().cloned().zip(user_channel_ids.iter().cloned()).collect();
+        for (index, chain_channels) in self.chains.iter().enumerate() {
+            let on_chain: Vec<u64> = members.iter().filter(|(i, _)| *i == index).map(|(_, user_channel_id)| *user_channel_id).collect();
+            if !on_chain.is_empty() {
+                chain_channels.wallet.fund_in_batch(batch_id, &on_chain);
+            }
+        }
+        for (open, (index, user_channel_id)) in opens.iter().zip(members.iter().cloned()) {
+            let chain_channels = &self.chains[index];
+            if let Err(e) = chain_channels.channel_manager.create_channel(open.node_id, open.value, open.push_msat, user_channel_id) {
+                self.cancel_batch(batch_id, &members);
+                return Err(format!("Cannot open the channel to {} on chain {}: {:?}", open.node_id, open.chain, e));
+            }
+            chain_channels.store.save();
+        }
+        self.batches.lock().unwrap().insert(batch_id, members);
+        self.peer_manager.process_events();
+        Ok(user_channel_ids)
+    }
+
+    // Closes what's left of the batch's channels and gives the coins back to the wallets
+    fn cancel_batch(&self, batch_id: u64, members: &[(usize, u64)]) {
+        for (index, user_channel_id) in members {
+            let chain_channels = &self.chains[*index];
+            chain_channels.wallet.cancel_batch(batch_id);
+            let channel = chain_channels.channel_manager.list_channels().into_iter().find(|c| c.user_id == *user_channel_id);
+            if let Some(channel) = channel {
+                chain_channels.channel_manager.force_close_channel(&channel.channel_id);
+                chain_channels.store.save();
+            }
+        }
+    }
+
+    // Broadcasts the funding transactions of the batches every peer signed, cancels the batches
+    // with a channel rust-lightning dropped
+    fn check_batches(&self) {
+        let batches = self.batches.lock().unwrap().clone();
+        for (batch_id, members) in batches {
+            let dropped = members.iter().find(|(index, user_channel_id)| {
+                !self.chains[*index].channel_manager.list_channels().iter().any(|c| c.user_id == *user_channel_id)
+            });
+            if let Some((index, user_channel_id)) = dropped {
+                println!("Cancelling channel batch {}, channel {} on chain {} failed", batch_id, user_channel_id, self.chains[*index].chain);
+                self.batches.lock().unwrap().remove(&batch_id);
+                self.cancel_batch(batch_id, &members);
+                continue;
+            }
+            if !members.iter().all(|(index, _)| self.chains[*index].wallet.batch_funded(batch_id)) {
+                continue;
+            }
+            self.batches.lock().unwrap().remove(&batch_id);
+            let mut indexes: Vec<usize> = members.iter().map(|(index, _)| *index).collect();
+            indexes.sort();
+            indexes.dedup();
+            for index in indexes {
+                match self.chains[index].wallet.broadcast_batch(batch_id) {
+                    Ok(tx) => println!("Channel batch {} funded on chain {} by {}", batch_id, self.chains[index].chain, tx.txid()),
+                    Err(e) => println!("Cannot broadcast the funding transaction of channel batch {}: {}", batch_id, e),
+                }
+            }
+        }
+    }
+
+    /// The PSBT for the external wallet, once the peer accepted the channel
+    pub fn funding_psbt(&self, chain: &str, user_channel_id: u64) -> Result<String, String> {
+        self.process_events();
+        self.chain(chain)?.wallet.funding_psbt(user_channel_id)
+            .ok_or(format!("Channel {} isn't waiting for a PSBT", user_channel_id))
+    }
+
+    pub fn complete_psbt_funding(&self, chain: &str, user_channel_id: u64, signed_psbt: &str) -> Result<(), String> {
+        let chain_channels = self.chain(chain)?;
+        chain_channels.wallet.complete_psbt_funding(&chain_channels.channel_manager, user_channel_id, signed_psbt)?;
+        chain_channels.store.save();
+        self.peer_manager.process_events();
+        Ok(())
+    }
+
+    /// Cooperative close, or broadcasting our commitment transaction with force
+    pub fn close_channel(&self, channel_id: &[u8; 32], force: bool) -> Result<(), String> {
+        let chain_channels = self.chains.iter()
+            .find(|c| c.channel_manager.list_channels().iter().any(|details| details.channel_id == *channel_id))
+            .ok_or(format!("No channel {}", channel_id.to_hex()))?;
+        if force {
+            chain_channels.channel_manager.force_close_channel(channel_id);
+        } else {
+            chain_channels.channel_manager.close_channel(channel_id).map_err(|e| format!("Cannot close the channel: {:?}", e))?;
+        }
+        chain_channels.state.lock().unwrap().closing.insert(*channel_id);
+        chain_channels.store.save();
+        self.peer_manager.process_events();
+        self.refresh(chain_channels);
+        Ok(())
+    }
+
+    /// Every channel on every chain, the closed ones too
+    pub fn list_channels(&self) -> Vec<ChannelInfo> {
+        let mut channels = Vec::new();
+        for chain_channels in &self.chains {
+            self.refresh(chain_channels);
+            let state = chain_channels.state.lock().unwrap();
+            let mut known: Vec<ChannelInfo> = state.known.values().cloned().collect();
+            known.sort_by_key(|c| (c.user_channel_id, c.channel_id));
+            channels.extend(known);
+            channels.extend(state.closed.iter().cloned());
+        }
+        channels
+    }
+
+    // Updates the state of the channels with what rust-lightning has now
+    fn refresh(&self, chain_channels: &ChainChannels) {
+        let details = chain_channels.channel_manager.list_channels();
+        let usable: HashSet<[u8; 32]> = chain_channels.channel_manager.list_usable_channels().iter().map(|c| c.channel_id).collect();
+        let mut state = chain_channels.state.lock().unwrap();
+        let mut current = HashMap::new();
+        for channel in details {
+            // Only the monitors know about the channels from before a restart
+            let funding_txo = self.chain_filter.funding_txo(&channel.channel_id).or_else(|| chain_channels.monitor.funding_txo(&channel.channel_id));
+            // The funding block of a channel from before a restart is in its short_channel_id
+            let funding_height = funding_txo.and_then(|txo| state.funding_heights.get(&txo).cloned())
+                .or_else(|| channel.short_channel_id.map(|short_channel_id| (short_channel_id >> 40) as u32));
+            let confirmations = match (funding_height, state.synced_height) {
+                // 0 for a funding block we haven't synced to yet
+                (Some(height), Some(synced_height)) => (synced_height + 1).saturating_sub(height),
+                _ => 0,
+            };
+            // The closing transaction can confirm before rust-lightning is done with the channel
+            let (closing_tx, outputs) = match state.known.get(&channel.channel_id) {
+                Some(known) => (known.closing_tx, known.outputs.clone()),
+                None => (None, Vec::new()),
+            };
+            let channel_state = if state.closing.contains(&channel.channel_id) {
+                ChannelState::Closing
+            } else if usable.contains(&channel.channel_id) {
+                ChannelState::Open
+            } else {
+                ChannelState::Pending
+            };
+            current.insert(channel.channel_id, ChannelInfo {
+                chain: chain_channels.chain.clone(),
+                channel_id: channel.channel_id,
+                node_id: channel.remote_network_id,
+                value: channel.channel_value_satoshis,
+                user_channel_id: channel.user_id,
+                short_channel_id: channel.short_channel_id,
+                funding_txo,
+                confirmations,
+                state: channel_state,
+                closing_tx,
+                outputs,
+            });
+        }
+        let gone: Vec<[u8; 32]> = state.known.keys().filter(|id| !current.contains_key(*id)).cloned().collect();
+        for channel_id in gone {
+            let mut channel = state.known.remove(&channel_id).unwrap();
+            // Not gone, it got its final channel_id
+            if self.chain_filter.funded_channel_id(&channel_id).is_some() {
+                continue;
+            }
+            channel.state = ChannelState::Closed;
+            state.closing.remove(&channel_id);
+            state.closed.push(channel);
+        }
+        state.known = current;
+    }
+
+    // Feeds rust-lightning the blocks it hasn't seen yet, starting from the tip on first start, or
+    // from where the channel monitors and the ChannelManager we loaded have to see the chain again
+    fn follow_blocks(&self, chain_channels: &ChainChannels) -> Result<(), String> {
+        let tip = chain_channels.backend.get_height()?;
+        loop {
+            let height = match chain_channels.state.lock().unwrap().synced_height {
+                Some(height) if height >= tip => break,
+                Some(height) => height + 1,
+                None => {
+                    let after_manager = chain_channels.manager_height.map(|height| height + 1);
+                    let start = match (chain_channels.monitor.rescan_height(), after_manager) {
+                        (Some(monitors), Some(manager)) => Some(monitors.min(manager)),
+                        (monitors, manager) => monitors.or(manager),
+                    };
+                    start.map_or(tip, |height| height.min(tip))
+                },
+            };
+            let block = chain_channels.backend.get_block(height)?;
+            match chain_channels.manager_height {
+                Some(manager_height) if height <= manager_height => chain_channels.monitor.rescan_block(&block, height),
+                _ => chain_channels.chain_watcher.block_connected_with_filtering(&block, height),
+            }
+
+            let mut state = chain_channels.state.lock().unwrap();
+            let ChainState { funding_heights, known, closed, close_txs, .. } = &mut *state;
+            for tx in &block.txdata {
+                let txid = tx.txid();
+                for funding_txo in known.values().filter_map(|c| c.funding_txo).filter(|txo| txo.txid == txid) {
+                    funding_heights.insert(funding_txo, height);
+                }
+                for input in &tx.input {
+                    let spent = input.previous_output;
+                    let channels = known.values_mut().chain(closed.iter_mut());
+                    let mut closing = None;
+                    for channel in channels {
+                        if channel.funding_txo.map(OutPoint::into_bitcoin_outpoint) == Some(spent) {
+                            println!("Channel {} on chain {} closed by {}", channel.channel_id.to_hex(), chain_channels.chain, txid);
+                            channel.closing_tx = Some((txid, height));
+                            closing = Some(channel.channel_id);
+                        }
+                        for output in channel.outputs.iter_mut().filter(|output| output.outpoint == spent) {
+                            output.swept_at = Some(height);
+                        }
+                    }
+                    // HTLC transactions and sweeps, spending the closing transaction's outputs
+                    let channel_id = closing.or_else(|| close_txs.get(&spent.txid).map(|(channel_id, _)| *channel_id));
+                    if let Some(channel_id) = channel_id {
+                        close_txs.insert(txid, (channel_id, height));
+                    }
+                }
+            }
+            state.synced_height = Some(height);
+        }
+        Ok(())
+    }
+
+    /// Pays invoice, or amount_msat of it if it's for any amount, trying other routes until
+    /// timeout. Returns the payment preimage. Someone else must be syncing meanwhile, as start does.
+    pub fn pay(&self, invoice: &str, amount_msat: Option<u64>, timeout: Duration) -> Result<[u8; 32], PaymentFailure> {
+        let chain_names: Vec<&str> = self.chains.iter().map(|c| c.chain.as_str()).collect();
+        let invoice = Invoice::decode(invoice, &chain_names).map_err(PaymentFailure::Invoice)?;
+        let invoice_chain = self.chains.iter().position(|c| c.chain == invoice.chain)
+            .ok_or(PaymentFailure::Invoice(format!("We don't operate on chain {}", invoice.chain)))?;
+        let amount_msat = match (invoice.amount_msat, amount_msat) {
+            (Some(invoice_msat), Some(msat)) if invoice_msat != msat => {
+                return Err(PaymentFailure::Invoice(format!("The invoice is for {} msat", invoice_msat)));
+            },
+            (Some(msat), _) | (None, Some(msat)) => msat,
+            (None, None) => return Err(PaymentFailure::Invoice("The invoice is for any amount, say how much to pay".to_string())),
+        };
+        if invoice.expires_at() < SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() {
+            return Err(PaymentFailure::Invoice("The invoice expired".to_string()));
+        }
+        {
+            let mut payments = self.payments.lock().unwrap();
+            if payments.contains_key(&invoice.payment_hash) {
+                return Err(PaymentFailure::Invoice("We're already paying the invoice".to_string()));
+            }
+            payments.insert(invoice.payment_hash, PaymentStatus::Pending);
+        }
+        let result = self.try_routes(invoice_chain, &invoice, amount_msat, Instant::now() + timeout);
+        self.payments.lock().unwrap().remove(&invoice.payment_hash);
+        result
+    }
+
+    fn try_routes(&self, invoice_chain: usize, invoice: &Invoice, amount_msat: u64, deadline: Instant) -> Result<[u8; 32], PaymentFailure> {
+        // The Router's ids all along, see graph.rs
+        let mut hints: Vec<RouteHint> = invoice.route_hints.iter().filter_map(|route| route.last()).map(|hop| RouteHint {
+            src_node_id: hop.pubkey,
+            short_channel_id: self.graph.router_id(invoice_chain, hop.short_channel_id),
+            fee_base_msat: hop.fee_base_msat,
+            fee_proportional_millionths: hop.fee_proportional_millionths,
+            cltv_expiry_delta: hop.cltv_expiry_delta,
+            htlc_minimum_msat: 0,
+        }).collect();
+        let mut excluded = HashSet::new();
+        let mut failed_routes: Vec<Vec<u64>> = Vec::new();
+        let mut attempts = 0;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(PaymentFailure::Timeout { attempts, pending: false });
+            }
+            let route = loop {
+                let first_hops: Vec<ChannelDetails> = self.first_hops().into_iter()
+                    .filter(|channel| !excluded.contains(&channel.short_channel_id.unwrap())).collect();
+                let route = self.find_route(invoice, &first_hops, &hints, amount_msat)
+                    .map_err(|reason| PaymentFailure::NoRoute { attempts, reason })?;
+                let channels: Vec<u64> = route.hops.iter().map(|hop| hop.short_channel_id).collect();
+                if !failed_routes.contains(&channels) {
+                    break route;
+                }
+                excluded.insert(channels[0]);
+                hints.retain(|hint| !channels.contains(&hint.short_channel_id));
+            };
+            let channels: Vec<u64> = route.hops.iter().map(|hop| hop.short_channel_id).collect();
+            let path: Vec<String> = channels.iter().map(|id| short_channel_id_to_string(self.graph.short_channel_id(*id))).collect();
+            attempts += 1;
+            *self.payments.lock().unwrap().get_mut(&invoice.payment_hash).unwrap() = PaymentStatus::Pending;
+            let hop_chains = self.hop_chains(&route, invoice_chain);
+            let cross_chain = hop_chains.as_ref().is_ok_and(|hop_chains| hop_chains.iter().any(|index| *index != hop_chains[0]));
+            let sent = hop_chains.and_then(|hop_chains| {
+                let chain_channels = &self.chains[hop_chains[0]];
+                let route = if cross_chain { self.cross_chain_route(&route, &hop_chains, &invoice.payment_hash)? } else { self.chain_route(&route) };
+                chain_channels.channel_manager.send_payment(route, PaymentHash(invoice.payment_hash)).map_err(|e| {
+                    self.chain_filter.forget_onion(&chain_channels.chain, &invoice.payment_hash);
+                    (channels[0], format!("{:?}", e))
+                })?;
+                chain_channels.store.save();
+                Ok(chain_channels)
+            });
+            let chain_channels = match sent {
+                Ok(chain_channels) => chain_channels,
+                Err((short_channel_id, e)) => {
+                    println!("Cannot send payment {} through {}: {}", invoice.payment_hash.to_hex(), path.join(" "), e);
+                    excluded.insert(short_channel_id);
+                    hints.retain(|hint| hint.short_channel_id != short_channel_id);
+                    failed_routes.push(channels);
+                    continue;
+                },
+            };
+            self.peer_manager.process_events();
+
+            let status = loop {
+                let status = self.payments.lock().unwrap()[&invoice.payment_hash];
+                if status != PaymentStatus::Pending {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    return Err(PaymentFailure::Timeout { attempts, pending: true });
+                }
+                thread::sleep(Duration::from_millis(50));
+            };
+            match status {
+                PaymentStatus::Sent(payment_preimage) => return Ok(payment_preimage),
+                // Every failure of a cross-chain payment looks like the first hop's
+                PaymentStatus::Failed { rejected_by_dest: true } if !cross_chain => return Err(PaymentFailure::Rejected { attempts }),
+                _ => {
+                    println!("Payment {} through {} failed, trying another route", invoice.payment_hash.to_hex(), path.join(" "));
+                    // The failure's channel_update may be in the messages not handed to the Router yet
+                    self.peer_manager.process_events();
+                    let failed_channels = self.chain_filter.take_failed_channels(&chain_channels.chain);
+                    for short_channel_id in channels.iter().filter(|id| failed_channels.contains(id)) {
+                        excluded.insert(*short_channel_id);
+                        hints.retain(|hint| hint.short_channel_id != *short_channel_id);
+                        // An update no newer than the one it has doesn't change the Router's mind
+                        self.router.handle_htlc_fail_channel_update(&HTLCFailChannelUpdate::ChannelClosed {
+                            short_channel_id: *short_channel_id,
+                            is_permanent: false,
+                        });
+                    }
+                    failed_routes.push(channels);
+                },
+            }
+        }
+    }
+
+    // Our usable channels on every chain, with the Router's ids
+    fn first_hops(&self) -> Vec<ChannelDetails> {
+        self.chains.iter().enumerate().flat_map(|(index, c)| {
+            c.channel_manager.list_usable_channels().into_iter().map(move |mut channel| {
+                channel.short_channel_id = channel.short_channel_id.map(|short_channel_id| self.graph.router_id(index, short_channel_id));
+                channel
+            })
+        }).collect()
+    }
+
+    // route with the short_channel_ids the channel managers and the other nodes know
+    fn chain_route(&self, route: &Route) -> Route {
+        Route { hops: route.hops.iter().map(|hop| RouteHop {
+            pubkey: hop.pubkey,
+            short_channel_id: self.graph.short_channel_id(hop.short_channel_id),
+            fee_msat: hop.fee_msat,
+            cltv_expiry_delta: hop.cltv_expiry_delta,
+        }).collect() }
+    }
+
+    // The index of the chain of each channel of route, or a channel not to use and why
+    fn hop_chains(&self, route: &Route, invoice_chain: usize) -> Result<Vec<usize>, (u64, String)> {
+        let first_hop = route.hops[0].short_channel_id;
+        let mut hop_chains = vec![(0..self.chains.len()).find(|index| {
+            self.chains[*index].channel_manager.list_usable_channels().iter()
+                .any(|channel| channel.short_channel_id.map(|id| self.graph.router_id(*index, id)) == Some(first_hop))
+        }).ok_or((first_hop, "No such channel of ours".to_string()))?];
+        for hop in &route.hops[1..] {
+            hop_chains.push(self.graph.channel_chain(hop.short_channel_id)
+                .ok_or((hop.short_channel_id, format!("Unknown chain for channel {}", short_channel_id_to_string(self.graph.short_channel_id(hop.short_channel_id)))))?);
+        }
+        let last = route.hops.last().unwrap().short_channel_id;
+        if hop_chains[hop_chains.len() - 1] != invoice_chain {
+            return Err((last, format!("Channel {} isn't on chain {}", short_channel_id_to_string(self.graph.short_channel_id(last)), self.chains[invoice_chain].chain)));
+        }
+        Ok(hop_chains)
+    }
+
+    // The route to the first hop of a route crossing chains, with the onion taking the payment
+    // on from there, see the module documentation
+    fn cross_chain_route(&self, route: &Route, hop_chains: &[usize], payment_hash: &[u8; 32]) -> Result<Route, (u64, String)> {
+        let hops = &route.hops;
+        let last = hops.len() - 1;
+        let mut amount_msat = hops[last].fee_msat;
+        let mut cltv_expiry = self.chains[hop_chains[last]].height() + 1 + hops[last].cltv_expiry_delta;
+        // What the Router thought each hop forwards
+        let mut routed_msat = hops[last].fee_msat;
+        let mut payloads = vec![HopPayload { short_channel_id: 0, amt_to_forward: amount_msat, outgoing_cltv_value: cltv_expiry }];
+        for i in (0..last).rev() {
+            let (from, to) = (&self.chains[hop_chains[i]], &self.chains[hop_chains[i + 1]]);
+            let scaled_fee_msat = u64::try_from((hops[i].fee_msat as u128 * amount_msat as u128).div_ceil(routed_msat as u128))
+                .map_err(|_| (hops[i].short_channel_id, format!("A fee of {} msat on {} msat is too much", hops[i].fee_msat, amount_msat)))?;
+            let fee_msat = hops[i].fee_msat.max(scaled_fee_msat);
+            let asked_msat = amount_msat.checked_add(fee_msat).ok_or((hops[i].short_channel_id, format!("{} msat is too much", amount_msat)))?;
+            routed_msat += hops[i].fee_msat;
+            // The hop quotes its rates in the channel_updates of its side of the channel
+            let direction = if hops[i].pubkey.serialize()[..] < hops[i + 1].pubkey.serialize()[..] { 0 } else { 1 };
+            let rate = self.rates.quote(hops[i + 1].short_channel_id, direction, &from.chain, &to.chain)
+                .ok_or((hops[i + 1].short_channel_id, format!("No rate from chain {} to {}", from.chain, to.chain)))?;
+            let incoming_msat = rate.cost(asked_msat).map_err(|e| (hops[i + 1].short_channel_id, e))?;
+            payloads.push(HopPayload { short_channel_id: self.graph.short_channel_id(hops[i + 1].short_channel_id), amt_to_forward: amount_msat, outgoing_cltv_value: cltv_expiry });
+            let blocks_left = self.rates.convert_blocks(&to.chain, &from.chain, cltv_expiry.saturating_sub(to.height()));
+            cltv_expiry = from.height() + blocks_left + hops[i].cltv_expiry_delta;
+            amount_msat = incoming_msat;
+        }
+        payloads.reverse();
+
+        let onion_hops: Vec<(PublicKey, HopPayload)> = hops.iter().map(|hop| hop.pubkey).zip(payloads).collect();
+        let session_key = SecretKey::from_slice(&rand::thread_rng().gen::<[u8; 32]>()).map_err(|e| (hops[0].short_channel_id, e.to_string()))?;
+        let packet = onion::construct(&session_key, &onion_hops, payment_hash).map_err(|e| (hops[0].short_channel_id, e))?;
+        let first = &self.chains[hop_chains[0]];
+        self.chain_filter.set_onion(&first.chain, *payment_hash, packet);
+        Ok(Route { hops: vec![RouteHop {
+            pubkey: hops[0].pubkey,
+            short_channel_id: self.graph.short_channel_id(hops[0].short_channel_id),
+            fee_msat: amount_msat,
+            cltv_expiry_delta: cltv_expiry.saturating_sub(first.height() + 1),
+        }] })
+    }
+
+    // Sends on its chain what came in to be forwarded there, or fails it back
+    fn forward_cross_chain(&self, from: &ChainChannels, payment_hash: PaymentHash, forward: CrossChainForward) {
+        let sent = self.chain(&forward.to_chain).and_then(|to| {
+            let rate = self.rates.get(&from.chain, &to.chain).ok_or(format!("No rate from chain {} to {}", from.chain, to.chain))?;
+            let worth_msat = rate.convert(forward.amount_msat)?;
+            let asked_msat = forward.amt_to_forward.checked_add(rate.spread(forward.amt_to_forward)?)
+                .ok_or(format!("Forwarding {} msat is too much", forward.amt_to_forward))?;
+            if worth_msat < asked_msat {
+                return Err(format!("It's worth {} msat on chain {}, forwarding {} takes {}", worth_msat, to.chain, forward.amt_to_forward, asked_msat));
+            }
+            let blocks_in = forward.cltv_expiry.saturating_sub(from.height());
+            let blocks_out = forward.outgoing_cltv_value.saturating_sub(to.height());
+            // In blocks of the incoming chain, the outgoing HTLC can't outlast the incoming one
+            let blocks_out_in = self.rates.convert_blocks(&to.chain, &from.chain, blocks_out);
+            if blocks_out == 0 || blocks_in < blocks_out_in.saturating_add(CROSS_CHAIN_CLTV_EXPIRY_DELTA) {
+                return Err(format!("It expires in {} blocks, {} on chain {} which last {} blocks here", blocks_in, blocks_out, to.chain, blocks_out_in));
+            }
+            let route = Route { hops: vec![RouteHop {
+                pubkey: forward.node_id,
+                short_channel_id: forward.short_channel_id,
+                fee_msat: forward.amt_to_forward,
+                cltv_expiry_delta: blocks_out - 1,
+            }] };
+            self.chain_filter.save_forwards()?;
+            self.chain_filter.set_onion(&to.chain, payment_hash.0, forward.onion.clone());
+            if let Err(e) = to.channel_manager.send_payment(route, payment_hash) {
+                self.chain_filter.forget_onion(&to.chain, &payment_hash.0);
+                return Err(format!("Cannot send it: {:?}", e));
+            }
+            to.store.save();
+            Ok(())
+        });
+        match sent {
+            Ok(()) => {
+                println!("Forwarding payment {} of {} msat on chain {} as {} msat on chain {}", payment_hash.0.to_hex(), forward.amount_msat,
+                         from.chain, forward.amt_to_forward, forward.to_chain);
+            },
+            Err(e) => {
+                println!("Failing payment {} of {} msat on chain {} to forward on chain {}: {}", payment_hash.0.to_hex(), forward.amount_msat,
+                         from.chain, forward.to_chain, e);
+                from.channel_manager.fail_htlc_backwards(&payment_hash);
+                self.chain_filter.end_forward(&payment_hash.0);
+            },
+        }
+    }
+
+    // Claims the HTLC a forward came with, and forgets the forward once its channel manager is saved
+    fn claim_forward(&self, payment_hash: &[u8; 32], from_chain: &str, preimage: PaymentPreimage) {
+        let from = match self.chain(from_chain) {
+            Ok(from) => from,
+            Err(e) => return println!("Cannot claim forwarded payment {}: {}", payment_hash.to_hex(), e),
+        };
+        println!("Claiming forwarded payment {} on chain {}", payment_hash.to_hex(), from_chain);
+        from.channel_manager.claim_funds(preimage);
+        from.store.save();
+        self.chain_filter.end_forward(payment_hash);
+    }
+
+    fn find_route(&self, invoice: &Invoice, first_hops: &[ChannelDetails], hints: &[RouteHint], amount_msat: u64) -> Result<Route, String> {
+        if first_hops.is_empty() {
+            return Err("No usable channels".to_string());
+        }
+        // rust-lightning 0.0.9 panics looking for a route through the graph without us in it
+        let our_node_id = self.chains[0].channel_manager.get_our_node_id();
+        if !first_hops.iter().any(|channel| channel.remote_network_id == invoice.payee) && self.router.get_addresses(&our_node_id).is_none() {
+            return Err("The payee isn't a peer and we have no announced channels".to_string());
+        }
+        self.router.get_route(&invoice.payee, Some(first_hops), hints, amount_msat, invoice.min_final_cltv_expiry as u32)
+            .map_err(|e| e.err.to_string())
+    }
+
+    /// Handles rust-lightning's events on every chain and sends the messages they produced
+    pub fn process_events(&self) {
+        for chain_channels in &self.chains {
+            let mut events = chain_channels.channel_manager.get_and_clear_pending_events();
+            events.append(&mut chain_channels.monitor.get_and_clear_pending_events());
+            for event in events {
+                if let Event::SpendableOutputs { outputs } = &event {
+                    chain_channels.track_outputs(outputs);
+                }
+                if chain_channels.wallet.handle_event(&chain_channels.channel_manager, &event) {
+                    continue;
+                }
+                match event {
+                    Event::PendingHTLCsForwardable { time_forwardable } => {
+                        let mut forward_at = chain_channels.forward_at.lock().unwrap();
+                        *forward_at = Some(forward_at.map_or(time_forwardable, |at| at.min(time_forwardable)));
+                    },
+                    Event::PaymentReceived { payment_hash, amt } => {
+                        if let Some(forward) = self.chain_filter.take_forward(&payment_hash.0) {
+                            self.forward_cross_chain(chain_channels, payment_hash, forward);
+                            continue;
+                        }
+                        let height = chain_channels.height();
+                        let cltv_expiry = self.chain_filter.take_received_expiry(&chain_channels.chain, &payment_hash.0);
+                        match self.invoices.receive(&chain_channels.chain, &payment_hash.0, amt, height, cltv_expiry) {
+                            Ok(Some(preimage)) => {
+                                println!("Claiming payment {} of {} msat on chain {}", payment_hash.0.to_hex(), amt, chain_channels.chain);
+                                chain_channels.channel_manager.claim_funds(PaymentPreimage(preimage));
+                            },
+                            Ok(None) => println!("Holding payment {} of {} msat on chain {}", payment_hash.0.to_hex(), amt, chain_channels.chain),
+                            Err(e) => {
+                                println!("Failing payment {} of {} msat on chain {}: {}", payment_hash.0.to_hex(), amt, chain_channels.chain, e);
+                                chain_channels.channel_manager.fail_htlc_backwards(&payment_hash);
+                            },
+                        }
+                    },
+                    Event::PaymentSent { payment_preimage } => {
+                        let payment_hash = Sha256::hash(&payment_preimage.0).into_inner();
+                        println!("Payment {} sent on chain {}", payment_hash.to_hex(), chain_channels.chain);
+                        self.chain_filter.forget_onion(&chain_channels.chain, &payment_hash);
+                        if let Some(forward) = self.chain_filter.forward_in_flight(&payment_hash) {
+                            self.claim_forward(&payment_hash, &forward.from_chain, payment_preimage);
+                        }
+                        if let Some(status) = self.payments.lock().unwrap().get_mut(&payment_hash) {
+                            *status = PaymentStatus::Sent(payment_preimage.0);
+                        }
+                    },
+                    Event::PaymentFailed { payment_hash, rejected_by_dest } => {
+                        println!("Payment {} failed on chain {}{}", payment_hash.0.to_hex(), chain_channels.chain,
+                                 if rejected_by_dest { ", rejected by the payee" } else { "" });
+                        self.chain_filter.forget_onion(&chain_channels.chain, &payment_hash.0);
+                        if let Some(forward) = self.chain_filter.forward_in_flight(&payment_hash.0) {
+                            println!("Failing forwarded payment {} on chain {}", payment_hash.0.to_hex(), forward.from_chain);
+                            if let Ok(from) = self.chain(&forward.from_chain) {
+                                from.channel_manager.fail_htlc_backwards(&payment_hash);
+                                from.store.save();
+                            }
+                            self.chain_filter.end_forward(&payment_hash.0);
+                        }
+                        if let Some(status) = self.payments.lock().unwrap().get_mut(&payment_hash.0) {
+                            if *status == PaymentStatus::Pending {
+                                *status = PaymentStatus::Failed { rejected_by_dest };
+                            }
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            let mut forward_at = chain_channels.forward_at.lock().unwrap();
+            if forward_at.is_some_and(|at| at <= Instant::now()) {
+                *forward_at = None;
+                chain_channels.channel_manager.process_pending_htlc_forwards();
+            }
+        }
+        self.check_batches();
+        self.peer_manager.process_events();
+    }
+
+    /// Follows every chain up to its tip and handles what came out of the new blocks
+    pub fn sync(&self) -> Result<(), String> {
+        match self.rates.refresh() {
+            Ok(true) => println!("Read the rate file again"),
+            Ok(false) => {},
+            Err(e) => println!("Cannot refresh the rates: {}", e),
+        }
+        self.process_events();
+        let mut errors = Vec::new();
+        for chain_channels in &self.chains {
+            self.refresh(chain_channels);
+            let synced = self.follow_blocks(chain_channels).and_then(|_| chain_channels.wallet.sync());
+            chain_channels.wallet.check_fundings(&chain_channels.channel_manager);
+            if let Err(e) = synced {
+                errors.push(format!("chain {}: {}", chain_channels.chain, e));
+            }
+            self.resolve_held_payments(chain_channels);
+        }
+        self.process_events();
+        for chain_channels in &self.chains {
+            self.refresh(chain_channels);
+        }
+        match self.announcer.announce() {
+            Ok(true) => println!("Announced our node"),
+            Ok(false) => {},
+            Err(e) => println!("Cannot announce our node: {}", e),
+        }
+        self.save();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join(", ")) }
+    }
+
+    // Claims or fails the payments of hold invoices settled or canceled since last time
+    fn resolve_held_payments(&self, chain_channels: &ChainChannels) {
+        self.invoices.cancel_expiring(&chain_channels.chain, chain_channels.height());
+        for (payment_hash, preimage) in self.invoices.take_resolved(&chain_channels.chain) {
+            match preimage {
+                Some(preimage) => {
+                    println!("Claiming held payment {} on chain {}", payment_hash.to_hex(), chain_channels.chain);
+                    chain_channels.channel_manager.claim_funds(PaymentPreimage(preimage));
+                },
+                None => {
+                    println!("Failing held payment {} on chain {}", payment_hash.to_hex(), chain_channels.chain);
+                    chain_channels.channel_manager.fail_htlc_backwards(&PaymentHash(payment_hash));
+                },
+            }
+        }
+    }
+
+    /// Writes the ChannelManager of every chain to the datadir, if it changed
+    pub fn save(&self) {
+        for chain_channels in &self.chains {
+            chain_channels.store.save();
+        }
+    }
+
+    /// Syncs in the background
+    pub fn start(channels: Arc<Channels>, interval: Duration) {
+        thread::spawn(move || loop {
+            if let Err(e) = channels.sync() {
+                println!("Cannot sync: {}", e);
+            }
+            thread::sleep(interval);
+        });
+    }
+}
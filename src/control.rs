@@ -0,0 +1,441 @@
+//! Control interface to tell a running daemon what to do
+//!
+//! Plain text over the control socket, a Unix socket in the datadir that only the daemon's user
+//! can connect to: one command per line, the answer is one or more lines followed by an empty
+//! line. Answers to commands that fail start with "error: ".
+//!
+//! A daemon started without -walletpassfile is locked until "unlock <passphrase>" opens (or
+//! creates) the seed. A wrong passphrase is answered after UNLOCK_RETRY_DELAY, one attempt at a
+//! time.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+
+use crate::chain;
+use crate::chainfilter::ChainFilter;
+use crate::channels::{self, BatchOpen, Channels};
+use crate::graph::NetworkGraph;
+use crate::invoice::{self, Description};
+use crate::node::Node;
+use crate::peers::{self, PeerAddress, PeerConnector};
+use crate::registry::InvoiceRegistry;
+use crate::signer;
+
+/// How long pay keeps trying routes
+pub const PAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long waitinvoice waits without a timeout
+pub const WAIT_INVOICE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// How long a wrong passphrase takes to be answered
+pub const UNLOCK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub const CONTROL_SOCKET: &str = "control.sock";
+
+pub fn socket_path(datadir: &Path) -> PathBuf {
+    datadir.join(CONTROL_SOCKET)
+}
+
+// What the commands act on, once unlocked
+struct Running {
+    peer_connector: Arc<PeerConnector>,
+    chain_filter: Arc<ChainFilter>,
+    graph: Arc<NetworkGraph>,
+    invoices: Arc<InvoiceRegistry>,
+    // None without chain backends
+    channels: Option<Arc<Channels>>,
+}
+
+// A passphrase and where to send whether it worked
+type UnlockRequest = (String, mpsc::Sender<Result<(), String>>);
+
+pub struct Control {
+    running: RwLock<Option<Running>>,
+    unlock_requests: Mutex<Option<mpsc::Sender<UnlockRequest>>>,
+    // Whether we got a stop command
+    stop: (Mutex<bool>, Condvar),
+}
+
+impl Control {
+
+    pub fn new(node: &Node, peer_connector: Arc<PeerConnector>, channels: Option<Arc<Channels>>) -> Control {
+        let control = Control::new_locked();
+        control.set_running(node, peer_connector, channels);
+        control
+    }
+
+    /// Only unlock works until set_running
+    pub fn new_locked() -> Control {
+        Control { running: RwLock::new(None), unlock_requests: Mutex::new(None), stop: (Mutex::new(false), Condvar::new()) }
+    }
+
+    /// Waits for a stop command up to timeout, true if there was one
+    pub fn wait_for_stop(&self, timeout: Duration) -> bool {
+        let (stopped, condvar) = &self.stop;
+        let stopped = condvar.wait_timeout_while(stopped.lock().unwrap(), timeout, |stopped| !*stopped).unwrap().0;
+        *stopped
+    }
+
+    /// The channel commands answer with an error without channels
+    pub fn set_running(&self, node: &Node, peer_connector: Arc<PeerConnector>, channels: Option<Arc<Channels>>) {
+        *self.running.write().unwrap() = Some(Running {
+            peer_connector,
+            chain_filter: node.chain_filter.clone(),
+            graph: node.graph.clone(),
+            invoices: node.invoices.clone(),
+            channels,
+        });
+    }
+
+    /// Blocks until an unlock command brings a passphrase unlock accepts
+    pub fn wait_for_unlock<T, F: FnMut(&str) -> Result<T, String>>(&self, mut unlock: F) -> T {
+        let (sender, receiver) = mpsc::channel::<UnlockRequest>();
+        *self.unlock_requests.lock().unwrap() = Some(sender);
+        loop {
+            let (passphrase, reply) = receiver.recv().unwrap();
+            match unlock(&passphrase) {
+                Ok(unlocked) => {
+                    *self.unlock_requests.lock().unwrap() = None;
+                    let _ = reply.send(Ok(()));
+                    return unlocked;
+                },
+                Err(e) => {
+                    thread::sleep(UNLOCK_RETRY_DELAY);
+                    let _ = reply.send(Err(e));
+                },
+            }
+        }
+    }
+
+    fn unlock(&self, passphrase: &str) -> String {
+        let (reply_sender, reply) = mpsc::channel();
+        match &*self.unlock_requests.lock().unwrap() {
+            Some(sender) => {
+                if sender.send((passphrase.to_string(), reply_sender)).is_err() {
+                    return "error: not waiting for a passphrase".to_string();
+                }
+            },
+            None => return "error: not waiting for a passphrase".to_string(),
+        }
+        match reply.recv() {
+            Ok(Ok(())) => "ok".to_string(),
+            Ok(Err(e)) => format!("error: {}", e),
+            Err(_) => "error: not waiting for a passphrase".to_string(),
+        }
+    }
+
+    pub fn help() -> String {
+        [
+            "connect <pubkey>@<host:port>: Connect to a peer and keep reconnecting to it",
+            "connect <pubkey>: Like above, with the addresses from the peer's node_announcement",
+            "listpeers: List the peers we want to be connected to and their state",
+            "peerchains <pubkey>: The chains we know the peer supports and doesn't support",
+            "listgraph [chain]: List the nodes of the network graph and their chains, or the channels on the chain",
+            "openchannel <chain> <pubkey> <satoshis> [psbt]: Open a channel with a connected peer, funded by the chain's wallet or by a PSBT",
+            "openchannels <chain> <pubkey> <satoshis> [<chain> <pubkey> <satoshis> ...]: Open all these channels or none, one funding transaction per chain",
+            "fundingpsbt <chain> <user_channel_id>: The PSBT to fund a channel opened with psbt, once the peer accepted it",
+            "finishpsbt <chain> <user_channel_id> <psbt>: Fund the channel with the signed PSBT",
+            "listchannels: List the channels on every chain and their state",
+            "closechannel <channel_id> [force]: Close a channel, force broadcasts our commitment transaction",
+            "pay <invoice> [msat]: Pay a BOLT11 invoice, the amount is for invoices of any amount",
+            "invoice <chain> <msat|any> [description]: A new invoice to be paid on the chain",
+            "holdinvoice <chain> <payment_hash> <msat|any> [description]: An invoice whose payment is held until settleinvoice or cancelinvoice",
+            "settleinvoice <preimage>: Claim the held payment of a hold invoice",
+            "listinvoices: List our invoices and their state",
+            "waitinvoice <payment_hash> [seconds]: Wait for the invoice to be paid, canceled or expired",
+            "cancelinvoice <payment_hash>: Fail the payments of an open invoice, or the held payment of a hold invoice",
+            "newaddress <chain>: An address to receive coins in the chain's wallet",
+            "getbalance <chain>: Satoshis in the chain's wallet",
+            "unlock <passphrase>: Open the seed to start a locked daemon, creating it if there's none",
+            "stop: Save the channels and exit",
+            "help: This text",
+        ].join("\n")
+    }
+
+    /// Executes a command line and returns the answer
+    pub fn handle_command(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return "error: empty command".to_string();
+        }
+        match words[0] {
+            // The passphrase is the rest of the line, it may have spaces
+            "unlock" if words.len() > 1 => return self.unlock(line.trim()["unlock".len()..].trim()),
+            "unlock" => return "error: unlock <passphrase>".to_string(),
+            "help" => return Control::help(),
+            _ => {},
+        }
+        if words == ["stop"] && self.running.read().unwrap().is_some() {
+            let (stopped, condvar) = &self.stop;
+            *stopped.lock().unwrap() = true;
+            condvar.notify_all();
+            return "ok".to_string();
+        }
+        match &*self.running.read().unwrap() {
+            Some(running) => running.handle_command(&words, line),
+            None => "error: the daemon is locked, use 'unlock <passphrase>'".to_string(),
+        }
+    }
+}
+
+impl Running {
+    fn chain_names<'a, I: Iterator<Item = &'a Sha256dHash>>(&self, chain_hashes: I) -> String {
+        let names: Vec<String> = chain_hashes.map(|hash| match self.chain_filter.chain_name(hash) {
+            Some(name) => name.to_string(),
+            None => hash.to_string(),
+        }).collect();
+        names.join(" ")
+    }
+
+    fn handle_command(&self, words: &[&str], line: &str) -> String {
+        match (words[0], words.len()) {
+            ("connect", 2) if !words[1].contains('@') => {
+                let node_id = match peers::parse_node_id(words[1]) {
+                    Ok(node_id) => node_id,
+                    Err(e) => return format!("error: {}", e),
+                };
+                match self.peer_connector.connect_announced(node_id) {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => format!("error: {}", e),
+                }
+            },
+            ("connect", 2) => {
+                let address = match PeerAddress::parse(words[1]) {
+                    Ok(address) => address,
+                    Err(e) => return format!("error: {}", e),
+                };
+                match self.peer_connector.connect(address) {
+                    Ok(()) => "ok".to_string(),
+                    // The peer is remembered anyway, we will keep trying
+                    Err(e) => format!("error: {}", e),
+                }
+            },
+            ("listpeers", 1) => {
+                let peers = self.peer_connector.list_peers();
+                let lines: Vec<String> = peers.iter().map(|(address, state)| format!("{} {}", address, state)).collect();
+                lines.join("\n")
+            },
+            ("peerchains", 2) => {
+                let node_id = match peers::parse_node_id(words[1]) {
+                    Ok(node_id) => node_id,
+                    Err(e) => return format!("error: {}", e),
+                };
+                let peer_chains = self.chain_filter.get_peer_chains(&node_id);
+                format!("supported: {}\nunsupported: {}", self.chain_names(peer_chains.supported.iter()),
+                        self.chain_names(peer_chains.unsupported.iter()))
+            },
+            ("listgraph", 1) => {
+                let lines: Vec<String> = self.graph.nodes().iter().map(|node| {
+                    format!("{} {}{}", node.node_id, node.chains.join(" "), if node.is_chain_hop() { " hop" } else { "" })
+                }).collect();
+                lines.join("\n")
+            },
+            ("listgraph", 2) => {
+                if self.chain_filter.chain_name(&chain::chain_hash(words[1])).is_none() {
+                    return format!("error: We don't operate on chain {}", words[1]);
+                }
+                let lines: Vec<String> = self.graph.channels(words[1]).iter().map(|channel| {
+                    format!("{} {} {} {}", channels::short_channel_id_to_string(channel.short_channel_id), channel.node_ids[0],
+                            channel.node_ids[1], channel.value)
+                }).collect();
+                lines.join("\n")
+            },
+            ("invoice", n) if n >= 3 => {
+                let description = Description::Direct(words[3..].join(" "));
+                match parse_amount(words[2]).and_then(|amount_msat| self.invoices.create(words[1], amount_msat, description, invoice::DEFAULT_EXPIRY)) {
+                    Ok(entry) => format!("payment_hash {}\ninvoice {}", entry.invoice.payment_hash.to_hex(), entry.encoded),
+                    Err(e) => format!("error: {}", e),
+                }
+            },
+            ("holdinvoice", n) if n >= 4 => {
+                let description = Description::Direct(words[4..].join(" "));
+                let created = parse_hash(words[2], "payment_hash").and_then(|payment_hash| Ok((payment_hash, parse_amount(words[3])?)))
+                    .and_then(|(payment_hash, amount_msat)| self.invoices.create_hold(words[1], payment_hash, amount_msat, description, invoice::DEFAULT_EXPIRY));
+                match created {
+                    Ok(entry) => format!("invoice {}", entry.encoded),
+                    Err(e) => format!("error: {}", e),
+                }
+            },
+            ("settleinvoice", 2) => match parse_hash(words[1], "preimage").and_then(|preimage| self.invoices.settle(preimage)) {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error: {}", e),
+            },
+            ("listinvoices", 1) => {
+                let lines: Vec<String> = self.invoices.list().iter().map(|entry| entry.to_string()).collect();
+                lines.join("\n")
+            },
+            ("waitinvoice", 2) | ("waitinvoice", 3) => {
+                let timeout = match words.get(2).map(|seconds| seconds.parse()) {
+                    None => WAIT_INVOICE_TIMEOUT,
+                    Some(Ok(seconds)) => Duration::from_secs(seconds),
+                    Some(Err(_)) => return format!("error: '{}' is not a number", words[2]),
+                };
+                match parse_hash(words[1], "payment_hash").and_then(|payment_hash| self.invoices.wait(&payment_hash, timeout)) {
+                    Ok(entry) => entry.to_string(),
+                    Err(e) => format!("error: {}", e),
+                }
+            },
+            ("cancelinvoice", 2) => match parse_hash(words[1], "payment_hash").and_then(|payment_hash| self.invoices.cancel(&payment_hash)) {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error: {}", e),
+            },
+            ("openchannel", _) | ("openchannels", _) | ("fundingpsbt", _) | ("finishpsbt", _) | ("listchannels", _) | ("closechannel", _) |
+            ("pay", _) | ("newaddress", _) | ("getbalance", _) => match &self.channels {
+                Some(channels) => match Running::handle_channel_command(channels, words) {
+                    Some(Ok(answer)) => answer,
+                    Some(Err(e)) => format!("error: {}", e),
+                    None => format!("error: wrong number of arguments: '{}'\n{}", line.trim(), Control::help()),
+                },
+                None => "error: the daemon has no chain backends to operate channels".to_string(),
+            },
+            _ => format!("error: unknown command or wrong number of arguments: '{}'\n{}", line.trim(), Control::help()),
+        }
+    }
+
+    // None for a wrong number of arguments
+    fn handle_channel_command(channels: &Channels, words: &[&str]) -> Option<Result<String, String>> {
+        let parse_u64 = |word: &str| word.parse::<u64>().map_err(|_| format!("'{}' is not a number", word));
+        let answer = match (words[0], words.len()) {
+            ("openchannel", 4) | ("openchannel", 5) => {
+                let with_psbt = match words.get(4) {
+                    None => false,
+                    Some(&"psbt") => true,
+                    Some(_) => return None,
+                };
+                peers::parse_node_id(words[2])
+                    .and_then(|node_id| Ok((node_id, parse_u64(words[3])?)))
+                    .and_then(|(node_id, value)| channels.open_channel(words[1], node_id, value, 0, with_psbt))
+                    .map(|user_channel_id| format!("user_channel_id {}", user_channel_id))
+            },
+            ("openchannels", n) if n > 1 && (n - 1) % 3 == 0 => {
+                let opens: Result<Vec<BatchOpen>, String> = words[1..].chunks(3).map(|open| Ok(BatchOpen {
+                    chain: open[0].to_string(),
+                    node_id: peers::parse_node_id(open[1])?,
+                    value: parse_u64(open[2])?,
+                    push_msat: 0,
+                })).collect();
+                opens.and_then(|opens| channels.open_batch(&opens)).map(|user_channel_ids| {
+                    let ids: Vec<String> = user_channel_ids.iter().map(|id| id.to_string()).collect();
+                    format!("user_channel_ids {}", ids.join(" "))
+                })
+            },
+            ("fundingpsbt", 3) => parse_u64(words[2]).and_then(|id| channels.funding_psbt(words[1], id)),
+            ("finishpsbt", 4) => parse_u64(words[2])
+                .and_then(|id| channels.complete_psbt_funding(words[1], id, words[3]))
+                .map(|_| "ok".to_string()),
+            ("listchannels", 1) => {
+                let lines: Vec<String> = channels.list_channels().iter().map(|channel| channel.to_string()).collect();
+                Ok(lines.join("\n"))
+            },
+            ("closechannel", 2) | ("closechannel", 3) => {
+                let force = match words.get(2) {
+                    None => false,
+                    Some(&"force") => true,
+                    Some(_) => return None,
+                };
+                match Vec::<u8>::from_hex(words[1]) {
+                    Ok(ref bytes) if bytes.len() == 32 => {
+                        let mut channel_id = [0; 32];
+                        channel_id.copy_from_slice(bytes);
+                        channels.close_channel(&channel_id, force).map(|_| "ok".to_string())
+                    },
+                    _ => Err(format!("'{}' is not a channel_id", words[1])),
+                }
+            },
+            ("pay", 2) | ("pay", 3) => words.get(2).map(|msat| parse_u64(msat)).transpose()
+                .and_then(|amount_msat| channels.pay(words[1], amount_msat, PAY_TIMEOUT).map_err(|e| e.to_string()))
+                .map(|payment_preimage| format!("preimage {}", payment_preimage.to_hex())),
+            ("newaddress", 2) => channels.get_wallet(words[1]).map(|wallet| wallet.new_address()),
+            ("getbalance", 2) => channels.get_wallet(words[1]).map(|wallet| wallet.get_balance().to_string()),
+            _ => return None,
+        };
+        Some(answer)
+    }
+}
+
+// A payment_hash or preimage, what is for the error
+fn parse_hash(word: &str, what: &str) -> Result<[u8; 32], String> {
+    match Vec::<u8>::from_hex(word) {
+        Ok(ref bytes) if bytes.len() == 32 => {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(bytes);
+            Ok(hash)
+        },
+        _ => Err(format!("'{}' is not a {}", word, what)),
+    }
+}
+
+// msat, or any for invoices of any amount
+fn parse_amount(word: &str) -> Result<Option<u64>, String> {
+    match word {
+        "any" => Ok(None),
+        msat => msat.parse().map(Some).map_err(|_| format!("'{}' is not a number", msat)),
+    }
+}
+
+fn serve_client<S: Read, W: Write, F: Fn(&str) -> String>(stream: S, mut writer: W, handle_command: F) -> std::io::Result<()> {
+    for line in BufReader::new(stream).lines() {
+        let answer = handle_command(&line?);
+        writer.write_all(format!("{}\n\n", answer.trim_end()).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Serves control connections on a Unix socket at path in the background, for our own user only
+pub fn listen(control: Arc<Control>, path: &Path) -> std::io::Result<()> {
+    // Left behind by a previous run
+    if fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    let our_uid = unsafe { libc::getuid() };
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            match signer::peer_uid(&stream) {
+                Ok(uid) if uid == our_uid => {},
+                Ok(uid) => {
+                    println!("Refusing a control connection from uid {}", uid);
+                    continue;
+                },
+                Err(e) => {
+                    println!("Refusing a control connection without credentials: {}", e);
+                    continue;
+                },
+            }
+            let client_control = control.clone();
+            thread::spawn(move || {
+                let writer = stream.try_clone()?;
+                serve_client(stream, writer, |line| client_control.handle_command(line))
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Sends a command to the daemon listening on the control socket at path and waits for the answer
+pub fn call(path: &Path, command: &str) -> std::io::Result<String> {
+    call_on(UnixStream::connect(path)?, command)
+}
+
+fn call_on<S: Read + Write>(mut stream: S, command: &str) -> std::io::Result<String> {
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut answer = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        answer.push(line);
+    }
+    Ok(answer.join("\n"))
+}
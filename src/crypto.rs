@@ -0,0 +1,33 @@
+//! The few primitives we need to keep secrets at rest: scrypt (RFC 7914) to turn a passphrase
+//! into a key and ChaCha20-Poly1305 (RFC 8439) to encrypt with it, from the RustCrypto crates.
+//! BOLT4's onions use the ChaCha20 part too.
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+
+/// scrypt with N = 2^log_n, it takes 128 * r * N bytes of memory
+pub fn scrypt(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32, output: &mut [u8]) -> Result<(), String> {
+    // Also bounds the memory the parameters in a file can make us use
+    if log_n == 0 || log_n > 20 || r == 0 || r > 16 || p == 0 || p > 16 {
+        return Err(format!("Unsupported scrypt parameters: log_n {} r {} p {}", log_n, r, p));
+    }
+    let params = scrypt::Params::new(log_n, r, p, output.len()).map_err(|e| format!("Unsupported scrypt parameters: {}", e))?;
+    scrypt::scrypt(password, salt, &params, output).map_err(|e| format!("Unsupported scrypt output length: {}", e))
+}
+
+/// XORs data with ChaCha20's stream for key, the zero nonce and from the first block, as onions do
+pub fn chacha20(key: &[u8; 32], data: &mut [u8]) {
+    ChaCha20::new(key.into(), &[0; 12].into()).apply_keystream(data);
+}
+
+/// ChaCha20-Poly1305, returns the ciphertext followed by the 16 byte tag
+pub fn encrypt(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), Payload { msg: plaintext, aad }).expect("Our secrets are small")
+}
+
+/// The inverse of encrypt, None if the key is wrong or anything was tampered with
+pub fn decrypt(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), Payload { msg: sealed, aad }).ok()
+}
@@ -0,0 +1,155 @@
+//! What we do with our peers' gossip before rust-lightning's Router gets it
+//!
+//! The rates chain hops quote in the excess data of their channel_updates go to the node's
+//! RateTable. The Router doesn't keep channel_updates with excess data, so we keep every
+//! channel's announcement and last channel_updates and hand them out to the peers asking for
+//! the network graph ourselves, for the channels we have both channel_updates of.
+//!
+//! Channel announcements only reach the Router once the NetworkGraph found their funding output
+//! on one of our chains, under the id the NetworkGraph gives them there. A channel_update goes to
+//! the channel of its chain_hash's chains with its short_channel_id, the ones for chains we don't
+//! follow are dropped.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use lightning::ln::msgs::{self, ErrorAction, HTLCFailChannelUpdate, HandleError, RoutingMessageHandler};
+use lightning::ln::router::Router;
+use lightning::util::ser::Writeable;
+use secp256k1::key::PublicKey;
+
+use crate::chain;
+use crate::graph::NetworkGraph;
+use crate::rates::{self, RateTable};
+
+struct GossipChannel {
+    announcement: msgs::ChannelAnnouncement,
+    // By direction, 0 from node_id_1
+    updates: [Option<msgs::ChannelUpdate>; 2],
+}
+
+pub struct Gossip {
+    router: Arc<Router>,
+    graph: Arc<NetworkGraph>,
+    rates: Arc<RateTable>,
+    // The chain_hash and name of each of our chains
+    chains: Vec<(Sha256dHash, String)>,
+    // By short_channel_id and the index of their chain
+    channels: Mutex<BTreeMap<(u64, usize), GossipChannel>>,
+}
+
+// Message fields are private to rust-lightning, but their serialization isn't
+
+// The chain_hash, short_channel_id, node ids and bitcoin keys of a channel_announcement
+fn announcement_fields(msg: &msgs::ChannelAnnouncement) -> (Sha256dHash, u64, [PublicKey; 2], [PublicKey; 2]) {
+    // 4 signatures, the length prefixed features, then chain_hash, short_channel_id, the node ids
+    // and the bitcoin keys
+    let encoded = msg.encode();
+    let start = 4 * 64 + 2 + u16::from_be_bytes([encoded[256], encoded[257]]) as usize;
+    let chain_hash = Sha256dHash::from_slice(&encoded[start..start + 32]).unwrap();
+    let mut short_channel_id = [0; 8];
+    short_channel_id.copy_from_slice(&encoded[start + 32..start + 40]);
+    let key = |i: usize| PublicKey::from_slice(&encoded[start + 40 + 33 * i..start + 40 + 33 * (i + 1)]).unwrap();
+    (chain_hash, u64::from_be_bytes(short_channel_id), [key(0), key(1)], [key(2), key(3)])
+}
+
+/// The short_channel_id of a channel_update, its direction (0 from node_id_1) and excess data
+pub fn channel_update_fields(msg: &msgs::ChannelUpdate) -> (u64, u8, Vec<u8>) {
+    // signature, chain_hash, short_channel_id, timestamp, flags, then 18 bytes of fees and limits
+    let encoded = msg.encode();
+    let mut short_channel_id = [0; 8];
+    short_channel_id.copy_from_slice(&encoded[96..104]);
+    (u64::from_be_bytes(short_channel_id), encoded[109] & 1, encoded[128..].to_vec())
+}
+
+fn channel_update_chain_hash(msg: &msgs::ChannelUpdate) -> Sha256dHash {
+    Sha256dHash::from_slice(&msg.encode()[64..96]).unwrap()
+}
+
+impl Gossip {
+
+    /// chains are the -chain names
+    pub fn new<S: AsRef<str>>(chains: &[S], router: Arc<Router>, graph: Arc<NetworkGraph>, rates: Arc<RateTable>) -> Gossip {
+        Gossip {
+            router,
+            graph,
+            rates,
+            chains: chains.iter().map(|chain| (chain::chain_hash(chain.as_ref()), chain.as_ref().to_string())).collect(),
+            channels: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn chain_name(&self, chain_hash: &Sha256dHash) -> Option<String> {
+        self.chains.iter().find(|(hash, _)| hash == chain_hash).map(|(_, name)| name.clone())
+    }
+}
+
+impl RoutingMessageHandler for Gossip {
+    fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<bool, HandleError> {
+        self.router.handle_node_announcement(msg)
+    }
+
+    fn handle_channel_announcement(&self, msg: &msgs::ChannelAnnouncement) -> Result<bool, HandleError> {
+        let (chain_hash, short_channel_id, node_ids, bitcoin_keys) = announcement_fields(msg);
+        let (index, router_id) = self.graph.check_funding(&chain_hash, short_channel_id, &bitcoin_keys)
+            .map_err(|err| HandleError { err, action: Some(ErrorAction::IgnoreError) })?;
+        let relay = self.router.handle_channel_announcement_as(msg, router_id)?;
+        self.graph.add_channel(router_id, node_ids);
+        self.channels.lock().unwrap().insert((short_channel_id, index), GossipChannel { announcement: msg.clone(), updates: [None, None] });
+        Ok(relay)
+    }
+
+    fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, HandleError> {
+        let chain_hash = channel_update_chain_hash(msg);
+        if self.graph.chains_with_hash(&chain_hash).is_empty() {
+            return Err(HandleError { err: "Channel update on a chain we don't follow", action: Some(ErrorAction::IgnoreError) });
+        }
+        let (short_channel_id, direction, excess_data) = channel_update_fields(msg);
+        let router_id = self.graph.update_router_id(&chain_hash, short_channel_id)
+            .ok_or(HandleError { err: "Couldn't find channel for update", action: Some(ErrorAction::IgnoreError) })?;
+        let relay = self.router.handle_channel_update_as(msg, router_id)?;
+        // Quotes between chains we don't operate on are no use to us
+        let quotes = rates::decode_quotes(&excess_data).into_iter()
+            .filter_map(|(from, to, rate)| Some((self.chain_name(&from)?, self.chain_name(&to)?, rate))).collect();
+        self.rates.set_quotes(router_id, direction, quotes);
+        if let Some(index) = self.graph.channel_chain(router_id) {
+            if let Some(channel) = self.channels.lock().unwrap().get_mut(&(short_channel_id, index)) {
+                channel.updates[direction as usize] = Some(msg.clone());
+            }
+        }
+        Ok(relay)
+    }
+
+    // ChannelClosed comes with the Router's id, ChainFilter puts it in, and the channel_update
+    // of a failure goes through handle_channel_update like the others
+    fn handle_htlc_fail_channel_update(&self, update: &HTLCFailChannelUpdate) {
+        match update {
+            HTLCFailChannelUpdate::ChannelUpdateMessage { msg } => {
+                let _ = self.handle_channel_update(msg);
+            },
+            HTLCFailChannelUpdate::ChannelClosed { short_channel_id: router_id, is_permanent } => {
+                if *is_permanent {
+                    if let Some(index) = self.graph.channel_chain(*router_id) {
+                        self.channels.lock().unwrap().remove(&(self.graph.short_channel_id(*router_id), index));
+                    }
+                    self.graph.remove_channel(*router_id);
+                }
+                self.router.handle_htlc_fail_channel_update(update)
+            },
+            HTLCFailChannelUpdate::NodeFailure { .. } => self.router.handle_htlc_fail_channel_update(update),
+        }
+    }
+
+    fn get_next_channel_announcements(&self, starting_point: u64, batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, msgs::ChannelUpdate, msgs::ChannelUpdate)> {
+        self.channels.lock().unwrap().range((starting_point, 0)..).filter_map(|(_, channel)| match &channel.updates {
+            [Some(one), Some(two)] => Some((channel.announcement.clone(), one.clone(), two.clone())),
+            _ => None,
+        }).take(batch_amount as usize).collect()
+    }
+
+    fn get_next_node_announcements(&self, starting_point: Option<&PublicKey>, batch_amount: u8) -> Vec<msgs::NodeAnnouncement> {
+        self.router.get_next_node_announcements(starting_point, batch_amount)
+    }
+}
@@ -0,0 +1,259 @@
+//! The channels and nodes of the network graph, by chain
+//!
+//! rust-lightning's Router keeps a single network graph and only knows bitcoin's networks, a
+//! channel_announcement's chain_hash on one of our other chains is regtest's genesis block hash
+//! (or the hash of the chain's name, see chain::chain_hash). Before the Router gets an
+//! announcement, its funding output is looked up on the chains with that chain_hash: where its
+//! short_channel_id points there must be the P2WSH of the announced bitcoin keys' 2-of-2. The
+//! channel then belongs to that chain, announcements for chains we don't follow, or whose
+//! funding output isn't there, are dropped. Whether the output was spent isn't checked.
+//!
+//! The same short_channel_id can point at funding outputs on several of our chains. The Router
+//! keeps every channel under its own id: the short_channel_id, unless a channel on another chain
+//! has it already, then one with a block height no chain reaches. Everything handed to the Router
+//! or taken from it goes by these ids, router_id and short_channel_id translate.
+//!
+//! The NetworkGraph is the Router's ChainWatchInterface, it hands it the funding outputs it found.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use lightning::chain::chaininterface::{ChainError, ChainListener, ChainWatchInterface};
+use secp256k1::key::PublicKey;
+
+use crate::chain::{self, ChainBackend};
+
+/// A channel of the network graph
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphChannel {
+    pub short_channel_id: u64,
+    pub node_ids: [PublicKey; 2],
+    /// Of the funding output, in satoshis
+    pub value: u64,
+}
+
+/// A node of the network graph and the chains it has channels on
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphNode {
+    pub node_id: PublicKey,
+    pub chains: Vec<String>,
+}
+
+impl GraphNode {
+    /// With channels on several chains payments can cross chains there
+    pub fn is_chain_hop(&self) -> bool {
+        self.chains.len() > 1
+    }
+}
+
+// The Router's ids are from there on, block height 0xffffff
+const FIRST_ALIAS: u64 = 0xff_ffff << 40;
+
+// A funding output check_funding found
+struct FundingOutput {
+    // The index of its chain
+    chain: usize,
+    short_channel_id: u64,
+    // As announced
+    bitcoin_keys: [PublicKey; 2],
+    value: u64,
+}
+
+pub struct NetworkGraph {
+    chains: Vec<String>,
+    // Set once we follow the chains, in the same order
+    backends: RwLock<Vec<Option<Arc<dyn ChainBackend>>>>,
+    funding: Mutex<Fundings>,
+    // By chain index, by short_channel_id
+    channels: RwLock<Vec<BTreeMap<u64, GraphChannel>>>,
+}
+
+#[derive(Default)]
+struct Fundings {
+    // By the Router's id
+    outputs: HashMap<u64, FundingOutput>,
+    // The Router's id by chain index and short_channel_id
+    router_ids: HashMap<(usize, u64), u64>,
+    next_alias: u64,
+}
+
+fn multisig_p2wsh(first: &PublicKey, second: &PublicKey) -> Script {
+    Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+        .push_slice(&first.serialize())
+        .push_slice(&second.serialize())
+        .push_opcode(opcodes::all::OP_PUSHNUM_2)
+        .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh()
+}
+
+/// The P2WSH funding output of a channel with these bitcoin keys, the lesser one first
+pub fn funding_script(bitcoin_keys: &[PublicKey; 2]) -> Script {
+    if bitcoin_keys[0].serialize()[..] <= bitcoin_keys[1].serialize()[..] {
+        multisig_p2wsh(&bitcoin_keys[0], &bitcoin_keys[1])
+    } else {
+        multisig_p2wsh(&bitcoin_keys[1], &bitcoin_keys[0])
+    }
+}
+
+impl NetworkGraph {
+
+    /// chains are the -chain names
+    pub fn new<S: AsRef<str>>(chains: &[S]) -> NetworkGraph {
+        NetworkGraph {
+            chains: chains.iter().map(|chain| chain.as_ref().to_string()).collect(),
+            backends: RwLock::new(vec![None; chains.len()]),
+            funding: Mutex::new(Fundings { next_alias: FIRST_ALIAS, ..Default::default() }),
+            channels: RwLock::new(vec![BTreeMap::new(); chains.len()]),
+        }
+    }
+
+    /// The backend to look funding outputs up on the chain with this index
+    pub fn set_backend(&self, index: usize, backend: Arc<dyn ChainBackend>) {
+        self.backends.write().unwrap()[index] = Some(backend);
+    }
+
+    /// The indexes of our chains with chain_hash, as rust-lightning or other nodes put it
+    pub fn chains_with_hash(&self, chain_hash: &Sha256dHash) -> Vec<usize> {
+        self.chains.iter().enumerate().filter(|(_, chain)| {
+            chain::chain_hash(chain) == *chain_hash || chain::internal_chain_hash(chain) == *chain_hash
+        }).map(|(index, _)| index).collect()
+    }
+
+    /// Looks up the funding output of an announced channel on our chains with chain_hash, and
+    /// returns the index of the chain it's on and the Router's id for it
+    pub fn check_funding(&self, chain_hash: &Sha256dHash, short_channel_id: u64, bitcoin_keys: &[PublicKey; 2]) -> Result<(usize, u64), &'static str> {
+        let candidates = self.chains_with_hash(chain_hash);
+        if candidates.is_empty() {
+            return Err("Channel announced on a chain we don't follow");
+        }
+        let script = funding_script(bitcoin_keys);
+        let height = (short_channel_id >> 40) as u32;
+        let tx_index = ((short_channel_id >> 16) & 0xff_ffff) as usize;
+        let vout = (short_channel_id & 0xffff) as usize;
+        let backends = self.backends.read().unwrap();
+        let mut funding = self.funding.lock().unwrap();
+        for index in candidates {
+            if let Some(router_id) = funding.router_ids.get(&(index, short_channel_id)) {
+                if funding.outputs[router_id].bitcoin_keys == *bitcoin_keys {
+                    return Ok((index, *router_id));
+                }
+                continue;
+            }
+            let backend = match &backends[index] {
+                Some(backend) => backend,
+                None => continue,
+            };
+            if !backend.get_height().is_ok_and(|tip| height <= tip) {
+                continue;
+            }
+            let output = backend.get_block(height).ok()
+                .and_then(|block| block.txdata.get(tx_index).and_then(|tx| tx.output.get(vout)).cloned());
+            if let Some(output) = output.filter(|output| output.script_pubkey == script) {
+                let router_id = if funding.outputs.contains_key(&short_channel_id) {
+                    funding.next_alias += 1;
+                    funding.next_alias - 1
+                } else {
+                    short_channel_id
+                };
+                funding.outputs.insert(router_id, FundingOutput { chain: index, short_channel_id, bitcoin_keys: *bitcoin_keys, value: output.value });
+                funding.router_ids.insert((index, short_channel_id), router_id);
+                return Ok((index, router_id));
+            }
+        }
+        Err("Channel announced without its funding output on its chain")
+    }
+
+    /// A channel whose funding output check_funding found, once the Router took it
+    pub fn add_channel(&self, router_id: u64, node_ids: [PublicKey; 2]) {
+        let (index, short_channel_id, value) = match self.funding.lock().unwrap().outputs.get(&router_id) {
+            Some(funding) => (funding.chain, funding.short_channel_id, funding.value),
+            None => return,
+        };
+        self.channels.write().unwrap()[index].insert(short_channel_id, GraphChannel { short_channel_id, node_ids, value });
+    }
+
+    pub fn remove_channel(&self, router_id: u64) {
+        let mut funding = self.funding.lock().unwrap();
+        if let Some(output) = funding.outputs.remove(&router_id) {
+            funding.router_ids.remove(&(output.chain, output.short_channel_id));
+            self.channels.write().unwrap()[output.chain].remove(&output.short_channel_id);
+        }
+    }
+
+    /// The index of the chain of a channel in the graph, by the Router's id
+    pub fn channel_chain(&self, router_id: u64) -> Option<usize> {
+        let funding = self.funding.lock().unwrap();
+        let output = funding.outputs.get(&router_id)?;
+        self.channels.read().unwrap()[output.chain].contains_key(&output.short_channel_id).then_some(output.chain)
+    }
+
+    /// The Router's id for the channel with short_channel_id on the chain with this index, the
+    /// short_channel_id itself for channels the graph doesn't have
+    pub fn router_id(&self, chain: usize, short_channel_id: u64) -> u64 {
+        self.funding.lock().unwrap().router_ids.get(&(chain, short_channel_id)).cloned().unwrap_or(short_channel_id)
+    }
+
+    /// The short_channel_id of the channel the Router has under router_id
+    pub fn short_channel_id(&self, router_id: u64) -> u64 {
+        self.funding.lock().unwrap().outputs.get(&router_id).map_or(router_id, |funding| funding.short_channel_id)
+    }
+
+    /// The Router's id for the channel a channel_update with chain_hash and short_channel_id is
+    /// about, looked for on the chains identified by chain_hash before the ones rust-lightning
+    /// puts it in the messages of
+    pub fn update_router_id(&self, chain_hash: &Sha256dHash, short_channel_id: u64) -> Option<u64> {
+        let funding = self.funding.lock().unwrap();
+        let on = |index: &usize| funding.router_ids.get(&(*index, short_channel_id)).cloned();
+        let identified: Vec<usize> = (0..self.chains.len()).filter(|index| chain::chain_hash(&self.chains[*index]) == *chain_hash).collect();
+        identified.iter().find_map(on).or_else(|| self.chains_with_hash(chain_hash).iter().find_map(on))
+    }
+
+    /// The channels on a chain, by short_channel_id
+    pub fn channels(&self, chain: &str) -> Vec<GraphChannel> {
+        match self.chains.iter().position(|c| c == chain) {
+            Some(index) => self.channels.read().unwrap()[index].values().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The nodes with channels on a chain
+    pub fn chain_nodes(&self, chain: &str) -> Vec<PublicKey> {
+        let node_ids: BTreeSet<PublicKey> = self.channels(chain).iter().flat_map(|channel| channel.node_ids.iter().cloned()).collect();
+        node_ids.into_iter().collect()
+    }
+
+    /// The nodes of every chain, with the chains each one has channels on
+    pub fn nodes(&self) -> Vec<GraphNode> {
+        let mut nodes: BTreeMap<PublicKey, Vec<String>> = BTreeMap::new();
+        for chain in &self.chains {
+            for node_id in self.chain_nodes(chain) {
+                nodes.entry(node_id).or_default().push(chain.clone());
+            }
+        }
+        nodes.into_iter().map(|(node_id, chains)| GraphNode { node_id, chains }).collect()
+    }
+}
+
+impl ChainWatchInterface for NetworkGraph {
+    // The Router watches nothing
+    fn install_watch_tx(&self, _txid: &Sha256dHash, _script_pub_key: &Script) {}
+
+    fn install_watch_outpoint(&self, _outpoint: (Sha256dHash, u32), _out_script: &Script) {}
+
+    fn watch_all_txn(&self) {}
+
+    fn register_listener(&self, _listener: Weak<dyn ChainListener>) {}
+
+    // rust-lightning 0.0.9's Router expects the keys in the order they're announced, not sorted
+    fn get_chain_utxo(&self, chain_hash: Sha256dHash, short_channel_id: u64) -> Result<(Script, u64), ChainError> {
+        match self.funding.lock().unwrap().outputs.get(&short_channel_id) {
+            Some(funding) if self.chains_with_hash(&chain_hash).contains(&funding.chain) => {
+                Ok((multisig_p2wsh(&funding.bitcoin_keys[0], &funding.bitcoin_keys[1]), funding.value))
+            },
+            Some(_) => Err(ChainError::NotWatched),
+            None => Err(ChainError::UnknownTx),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! BOLT11 invoices, with the chain in their prefix: "ln" and the chain's bech32 human readable
+//! part (lnbc, lntb, lnbcrt, lnaaa...)
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bech32::{u5, Bech32, FromBase32, ToBase32};
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+use lightning::chain::keysinterface::NodeSigner;
+use secp256k1::key::PublicKey;
+use secp256k1::{Message, RecoverableSignature, RecoveryId, Secp256k1};
+
+use crate::chain;
+
+pub const DEFAULT_EXPIRY: u64 = 3600;
+pub const DEFAULT_MIN_FINAL_CLTV_EXPIRY: u64 = 9;
+/// A tagged field's data is at most 1023 5 bit words
+const MAX_DESCRIPTION_LEN: usize = 639;
+
+const TAG_PAYMENT_HASH: u8 = 1;
+const TAG_ROUTE: u8 = 3;
+const TAG_EXPIRY: u8 = 6;
+const TAG_FALLBACK: u8 = 9;
+const TAG_DESCRIPTION: u8 = 13;
+const TAG_PAYEE: u8 = 19;
+const TAG_DESCRIPTION_HASH: u8 = 23;
+const TAG_MIN_FINAL_CLTV_EXPIRY: u8 = 24;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Description {
+    Direct(String),
+    /// sha256 of a description too long for the invoice
+    Hash([u8; 32]),
+}
+
+/// A channel of a private route to the payee, which the payer can't know about
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteHop {
+    pub pubkey: PublicKey,
+    pub short_channel_id: u64,
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    pub cltv_expiry_delta: u16,
+}
+
+/// An on-chain address to pay to instead, by witness version (17 for P2PKH and 18 for P2SH)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fallback {
+    pub version: u8,
+    pub program: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invoice {
+    pub chain: String,
+    /// Any amount if None
+    pub amount_msat: Option<u64>,
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    pub payment_hash: [u8; 32],
+    pub description: Description,
+    pub payee: PublicKey,
+    /// Seconds after timestamp
+    pub expiry: u64,
+    pub min_final_cltv_expiry: u64,
+    pub fallbacks: Vec<Fallback>,
+    pub route_hints: Vec<Vec<RouteHop>>,
+}
+
+impl Invoice {
+    /// An invoice from payee created now, for any amount and with the default expiries
+    pub fn new(chain: &str, payee: PublicKey, payment_hash: [u8; 32], description: Description) -> Invoice {
+        Invoice {
+            chain: chain.to_string(),
+            amount_msat: None,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            payment_hash,
+            description,
+            payee,
+            expiry: DEFAULT_EXPIRY,
+            min_final_cltv_expiry: DEFAULT_MIN_FINAL_CLTV_EXPIRY,
+            fallbacks: Vec::new(),
+            route_hints: Vec::new(),
+        }
+    }
+
+    pub fn expires_at(&self) -> u64 {
+        self.timestamp + self.expiry
+    }
+
+    /// The invoice string, signed with the payee's node secret
+    pub fn encode(&self, node_signer: &dyn NodeSigner) -> Result<String, String> {
+        if node_signer.get_node_id() != self.payee {
+            return Err("The invoice must be signed by its payee".to_string());
+        }
+        if self.timestamp >= 1 << 35 {
+            return Err(format!("Timestamp {} too far in the future", self.timestamp));
+        }
+        let hrp = prefix(&self.chain) + &self.amount_msat.map(encode_amount).unwrap_or_default();
+
+        let mut data = int_to_base32(self.timestamp, 7);
+        push_field(&mut data, TAG_PAYMENT_HASH, self.payment_hash.to_base32());
+        match &self.description {
+            Description::Direct(description) => {
+                if description.len() > MAX_DESCRIPTION_LEN {
+                    return Err(format!("Description of {} bytes, the maximum is {}", description.len(), MAX_DESCRIPTION_LEN));
+                }
+                push_field(&mut data, TAG_DESCRIPTION, description.as_bytes().to_base32());
+            },
+            Description::Hash(hash) => push_field(&mut data, TAG_DESCRIPTION_HASH, hash.to_base32()),
+        }
+        if self.expiry != DEFAULT_EXPIRY {
+            push_field(&mut data, TAG_EXPIRY, int_to_base32(self.expiry, 0));
+        }
+        if self.min_final_cltv_expiry != DEFAULT_MIN_FINAL_CLTV_EXPIRY {
+            push_field(&mut data, TAG_MIN_FINAL_CLTV_EXPIRY, int_to_base32(self.min_final_cltv_expiry, 0));
+        }
+        for fallback in &self.fallbacks {
+            let mut field = vec![u5::try_from_u8(fallback.version).map_err(|_| format!("Bad fallback version {}", fallback.version))?];
+            field.extend(fallback.program.to_base32());
+            push_field(&mut data, TAG_FALLBACK, field);
+        }
+        for route in &self.route_hints {
+            let mut bytes = Vec::new();
+            for hop in route {
+                bytes.extend_from_slice(&hop.pubkey.serialize());
+                bytes.extend_from_slice(&hop.short_channel_id.to_be_bytes());
+                bytes.extend_from_slice(&hop.fee_base_msat.to_be_bytes());
+                bytes.extend_from_slice(&hop.fee_proportional_millionths.to_be_bytes());
+                bytes.extend_from_slice(&hop.cltv_expiry_delta.to_be_bytes());
+            }
+            push_field(&mut data, TAG_ROUTE, bytes.to_base32());
+        }
+        // The node signer gives no recovery id, it's the one recovering the payee
+        let msg = signature_hash(&hrp, &data);
+        let compact = node_signer.sign_with_node_secret(&msg).serialize_compact();
+        let secp_ctx = Secp256k1::verification_only();
+        let recovery_id = (0..4).find(|id| {
+            RecoverableSignature::from_compact(&compact, RecoveryId::from_i32(*id).unwrap())
+                .and_then(|signature| secp_ctx.recover(&msg, &signature)) == Ok(self.payee)
+        }).ok_or("The node signer signed with another key")?;
+        let mut signature_bytes = compact.to_vec();
+        signature_bytes.push(recovery_id as u8);
+        data.extend(signature_bytes.to_base32());
+        Ok(Bech32::new(hrp, data).map_err(|e| e.to_string())?.to_string())
+    }
+
+    /// The invoice in encoded, which must be for one of chains and signed by its payee
+    pub fn decode<S: AsRef<str>>(encoded: &str, chains: &[S]) -> Result<Invoice, String> {
+        let bech32: Bech32 = encoded.trim().parse().map_err(|e| format!("Bad invoice: {}", e))?;
+        let hrp = bech32.hrp();
+        // The longest prefix followed by the amount, lnbc would also match lnbcrt
+        let chain = chains.iter().map(|chain| chain.as_ref())
+            .filter(|chain| {
+                let prefix = prefix(chain);
+                hrp.starts_with(&prefix) && hrp[prefix.len()..].chars().next().is_none_or(|c| c.is_ascii_digit())
+            })
+            .max_by_key(|chain| prefix(chain).len())
+            .ok_or(format!("Invoice with unknown prefix {}", hrp))?;
+        let amount = &hrp[prefix(chain).len()..];
+        let amount_msat = if amount.is_empty() { None } else { Some(decode_amount(amount)?) };
+
+        let data = bech32.data();
+        if data.len() < 7 + 104 {
+            return Err("Invoice too short".to_string());
+        }
+        let (data, signature) = data.split_at(data.len() - 104);
+        let signature = Vec::<u8>::from_base32(signature).map_err(|e| format!("Bad invoice signature: {}", e))?;
+        let recovery_id = RecoveryId::from_i32(signature[64] as i32).map_err(|e| format!("Bad invoice signature: {}", e))?;
+        let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id).map_err(|e| format!("Bad invoice signature: {}", e))?;
+        let secp_ctx = Secp256k1::verification_only();
+        let msg = signature_hash(hrp, data);
+
+        let mut payment_hash = None;
+        let mut description = None;
+        let mut payee = None;
+        let mut expiry = DEFAULT_EXPIRY;
+        let mut min_final_cltv_expiry = DEFAULT_MIN_FINAL_CLTV_EXPIRY;
+        let mut fallbacks = Vec::new();
+        let mut route_hints = Vec::new();
+        let mut fields = &data[7..];
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err("Truncated invoice field".to_string());
+            }
+            let len = base32_to_int(&fields[1..3]) as usize;
+            if fields.len() < 3 + len {
+                return Err("Truncated invoice field".to_string());
+            }
+            let (tag, field) = (fields[0].to_u8(), &fields[3..3 + len]);
+            fields = &fields[3 + len..];
+            let bytes = || Vec::<u8>::from_base32(field).map_err(|e| format!("Bad invoice field: {}", e));
+            // Fields of unexpected length and unknown fields are skipped
+            match tag {
+                TAG_PAYMENT_HASH if len == 52 => payment_hash = Some(to_hash(&bytes()?)),
+                TAG_DESCRIPTION => {
+                    let text = String::from_utf8(bytes()?).map_err(|_| "Invoice description isn't UTF-8".to_string())?;
+                    description = Some(Description::Direct(text));
+                },
+                TAG_DESCRIPTION_HASH if len == 52 => description = Some(Description::Hash(to_hash(&bytes()?))),
+                TAG_PAYEE if len == 53 => payee = Some(PublicKey::from_slice(&bytes()?).map_err(|e| format!("Bad invoice payee: {}", e))?),
+                TAG_EXPIRY => expiry = base32_to_int(field),
+                TAG_MIN_FINAL_CLTV_EXPIRY => min_final_cltv_expiry = base32_to_int(field),
+                TAG_FALLBACK if len > 0 => fallbacks.push(Fallback {
+                    version: field[0].to_u8(),
+                    program: Vec::<u8>::from_base32(&field[1..]).map_err(|e| format!("Bad invoice fallback: {}", e))?,
+                }),
+                TAG_ROUTE => {
+                    let bytes = bytes()?;
+                    if bytes.is_empty() || bytes.len() % 51 != 0 {
+                        return Err(format!("Invoice route of {} bytes", bytes.len()));
+                    }
+                    let mut route = Vec::new();
+                    for hop in bytes.chunks(51) {
+                        route.push(RouteHop {
+                            pubkey: PublicKey::from_slice(&hop[..33]).map_err(|e| format!("Bad invoice route: {}", e))?,
+                            short_channel_id: u64::from_be_bytes(to_array(&hop[33..41])),
+                            fee_base_msat: u32::from_be_bytes(to_array(&hop[41..45])),
+                            fee_proportional_millionths: u32::from_be_bytes(to_array(&hop[45..49])),
+                            cltv_expiry_delta: u16::from_be_bytes(to_array(&hop[49..51])),
+                        });
+                    }
+                    route_hints.push(route);
+                },
+                _ => {},
+            }
+        }
+
+        // Without the payee field the signature tells who it is
+        let payee = match payee {
+            Some(payee) => {
+                secp_ctx.verify(&msg, &signature.to_standard(), &payee).map_err(|_| "Invoice not signed by its payee".to_string())?;
+                payee
+            },
+            None => secp_ctx.recover(&msg, &signature).map_err(|e| format!("Bad invoice signature: {}", e))?,
+        };
+        Ok(Invoice {
+            chain: chain.to_string(),
+            amount_msat,
+            timestamp: base32_to_int(&data[..7]),
+            payment_hash: payment_hash.ok_or("Invoice without a payment hash")?,
+            description: description.ok_or("Invoice without a description")?,
+            payee,
+            expiry,
+            min_final_cltv_expiry,
+            fallbacks,
+            route_hints,
+        })
+    }
+}
+
+/// "ln" and the chain's bech32 human readable part
+pub fn prefix(chain: &str) -> String {
+    format!("ln{}", chain::bech32_hrp(chain))
+}
+
+/// The amount in the shortest form: bitcoins, or milli, micro, nano and pico bitcoins
+fn encode_amount(amount_msat: u64) -> String {
+    for (multiplier, msat) in [("", 100_000_000_000u64), ("m", 100_000_000), ("u", 100_000), ("n", 100)].iter() {
+        if amount_msat.is_multiple_of(*msat) {
+            return format!("{}{}", amount_msat / msat, multiplier);
+        }
+    }
+    format!("{}p", amount_msat * 10)
+}
+
+fn decode_amount(amount: &str) -> Result<u64, String> {
+    let (number, multiplier) = match amount.chars().last() {
+        Some(c) if c.is_ascii_digit() => (amount, None),
+        Some(c) => (&amount[..amount.len() - 1], Some(c)),
+        None => return Err("Empty invoice amount".to_string()),
+    };
+    let value: u64 = number.parse().map_err(|_| format!("Bad invoice amount {}", amount))?;
+    let amount_msat = match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') if value.is_multiple_of(10) => Some(value / 10),
+        Some('p') => return Err(format!("Invoice amount {} isn't a whole millisatoshi", amount)),
+        Some(c) => return Err(format!("Bad invoice amount multiplier {}", c)),
+    };
+    amount_msat.ok_or(format!("Invoice amount {} too big", amount))
+}
+
+fn push_field(data: &mut Vec<u5>, tag: u8, field: Vec<u5>) {
+    data.push(u5::try_from_u8(tag).unwrap());
+    data.extend(int_to_base32(field.len() as u64, 2));
+    data.extend(field);
+}
+
+/// Big endian in 5 bit words, as few as value needs but at least len
+fn int_to_base32(value: u64, len: usize) -> Vec<u5> {
+    let mut words = Vec::new();
+    let mut rest = value;
+    while rest > 0 || words.len() < len {
+        words.push(u5::try_from_u8((rest & 31) as u8).unwrap());
+        rest >>= 5;
+    }
+    words.reverse();
+    words
+}
+
+fn base32_to_int(words: &[u5]) -> u64 {
+    words.iter().fold(0, |value, word| value << 5 | word.to_u8() as u64)
+}
+
+/// sha256 of the human readable part and the data, padded to whole bytes
+fn signature_hash(hrp: &str, data: &[u5]) -> Message {
+    let mut preimage = hrp.as_bytes().to_vec();
+    preimage.extend(bech32::convert_bits(data, 5, 8, true).unwrap());
+    Message::from_slice(&Sha256::hash(&preimage).into_inner()).unwrap()
+}
+
+fn to_hash(bytes: &[u8]) -> [u8; 32] {
+    to_array(bytes)
+}
+
+fn to_array<A: Default + AsMut<[u8]>>(bytes: &[u8]) -> A {
+    let mut array = A::default();
+    array.as_mut().copy_from_slice(bytes);
+    array
+}
@@ -0,0 +1,323 @@
+//! The node's seed and the keys derived from it
+//!
+//! The seed file in the datadir holds BIP39 entropy, generated on first start or restored from
+//! a mnemonic, encrypted with ChaCha20-Poly1305 under a key derived from the wallet passphrase
+//! with scrypt. The mnemonic and the optional BIP39 passphrase (not the same thing as the wallet
+//! passphrase) give the BIP32 master key, from which everything else is derived with hardened
+//! steps only:
+//!
+//! - m/1017'/0': the node secret, the same on every chain
+//! - m/1017'/1'/<chain>'/0': on-chain wallet account for the chain, with non-hardened receive
+//!   (0/i), change (1/i) and channel close (2/0) keys under it
+//! - m/1017'/1'/<chain>'/1': seed for the chain's channel keys
+//!
+//! where <chain> is the first 4 bytes of the chain's chain_hash, big endian, without the top bit.
+//!
+//! The mnemonic is never written anywhere, -show-mnemonic prints it to back it up.
+//!
+//! A daemon using a rustlnd-signer has no seed: its node secret and channel keys are the
+//! signer's, derived as above on the signer's side.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::constants::Network;
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin_hashes::hash160::Hash as Hash160;
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::Hash;
+use lightning::chain::keysinterface::{ChannelKeys, ChannelSigner, KeysInterface, KeysManager, NodeSigner};
+use lightning::util::logger::Logger;
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::{Message, Secp256k1, Signature, Signing, Verification};
+
+use crate::bip39;
+use crate::chain;
+use crate::crypto;
+
+pub const SEED_FILE: &str = "seed";
+
+const PURPOSE: u32 = 1017;
+
+pub const RECEIVE_BRANCH: u32 = 0;
+pub const CHANGE_BRANCH: u32 = 1;
+/// Where closed channels pay us, as destination script and shutdown key
+pub const CLOSE_BRANCH: u32 = 2;
+
+pub fn seed_path(datadir: &Path) -> PathBuf {
+    datadir.join(SEED_FILE)
+}
+
+/// What the seed file holds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Seed {
+    /// BIP39 entropy
+    pub entropy: Vec<u8>,
+}
+
+impl Seed {
+    pub fn mnemonic(&self) -> Result<String, String> {
+        bip39::entropy_to_mnemonic(&self.entropy)
+    }
+
+    /// The keys for a chain
+    pub fn node_keys(&self, bip39_passphrase: &str, chain_name: &str, logger: Arc<dyn Logger>) -> Result<NodeKeys, String> {
+        Ok(NodeKeys::new(&bip32_seed(&self.entropy, bip39_passphrase)?, chain_name, logger))
+    }
+}
+
+const SEED_FORMAT: &str = "scrypt-chacha20poly1305";
+const SEED_AAD: &[u8] = b"rustlnd seed";
+// 16MB, a fraction of a second in release builds
+const SCRYPT_LOG_N: u8 = 14;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+fn passphrase_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<[u8; 32], String> {
+    let mut key = [0; 32];
+    crypto::scrypt(passphrase.as_bytes(), salt, log_n, r, p, &mut key)?;
+    Ok(key)
+}
+
+fn seal_seed(seed: &Seed, passphrase: &str) -> Result<String, String> {
+    if passphrase.is_empty() {
+        return Err("The wallet passphrase cannot be empty".to_string());
+    }
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let nonce: [u8; 12] = rand::thread_rng().gen();
+    let key = passphrase_key(passphrase, &salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)?;
+    let sealed = crypto::encrypt(&key, &nonce, SEED_AAD, &seed.entropy);
+    Ok(format!("{} {} {} {} {} {} {}\n", SEED_FORMAT, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P,
+               salt.to_hex(), nonce.to_hex(), sealed.to_hex()))
+}
+
+fn open_seed(contents: &str, passphrase: &str) -> Result<Seed, String> {
+    let fields: Vec<&str> = contents.split_whitespace().collect();
+    if fields.len() != 7 || fields[0] != SEED_FORMAT {
+        return Err("unknown format".to_string());
+    }
+    let number = |field: &str| field.parse::<u32>().map_err(|_| format!("'{}' is not a number", field));
+    let hex = |field: &str| Vec::<u8>::from_hex(field).map_err(|_| format!("'{}' is not hex", field));
+    let (log_n, r, p) = (number(fields[1])?, number(fields[2])?, number(fields[3])?);
+    let (salt, nonce, sealed) = (hex(fields[4])?, hex(fields[5])?, hex(fields[6])?);
+    if nonce.len() != 12 || log_n > 255 {
+        return Err("bad parameters".to_string());
+    }
+    let key = passphrase_key(passphrase, &salt, log_n as u8, r, p)?;
+    let mut nonce_bytes = [0; 12];
+    nonce_bytes.copy_from_slice(&nonce);
+    match crypto::decrypt(&key, &nonce_bytes, SEED_AAD, &sealed) {
+        Some(entropy) => Ok(Seed { entropy }),
+        None => Err("wrong passphrase".to_string()),
+    }
+}
+
+// A 0600 file that doesn't exist yet
+fn create_private_file(path: &Path, contents: &str) -> Result<(), String> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).map_err(|e| format!("Cannot create {:?}: {}", path, e))?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Cannot write {:?}: {}", path, e))
+}
+
+fn write_seed(path: &Path, seed: &Seed, passphrase: &str) -> Result<(), String> {
+    create_private_file(path, &seal_seed(seed, passphrase)?)
+}
+
+/// Writes a new random seed (24 words) to path, never replacing an existing file
+pub fn generate_seed(path: &Path, passphrase: &str) -> Result<Seed, String> {
+    let entropy: [u8; 32] = rand::thread_rng().gen();
+    let seed = Seed { entropy: entropy.to_vec() };
+    write_seed(path, &seed, passphrase)?;
+    Ok(seed)
+}
+
+/// Writes the seed of a mnemonic to path, never replacing an existing file
+pub fn restore_seed(path: &Path, mnemonic: &str, passphrase: &str) -> Result<Seed, String> {
+    let seed = Seed { entropy: bip39::mnemonic_to_entropy(mnemonic)? };
+    write_seed(path, &seed, passphrase)?;
+    Ok(seed)
+}
+
+pub fn load_seed(path: &Path, passphrase: &str) -> Result<Seed, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read seed file {:?}: {}", path, e))?;
+    let seed = open_seed(&contents, passphrase).map_err(|e| format!("Cannot open seed file {:?}: {}", path, e))?;
+    bip39::entropy_to_mnemonic(&seed.entropy).map_err(|e| format!("Seed file {:?} is corrupt: {}", path, e))?;
+    Ok(seed)
+}
+
+/// Loads the seed in datadir, creating it on first start
+pub fn load_or_generate_seed(datadir: &Path, passphrase: &str) -> Result<Seed, String> {
+    let path = seed_path(datadir);
+    if path.exists() {
+        return load_seed(&path, passphrase);
+    }
+    println!("Generating a new seed in {:?}", path);
+    let seed = generate_seed(&path, passphrase)?;
+    println!("Back up its words with -show-mnemonic, they are all it takes to restore the node (and to steal its funds)");
+    Ok(seed)
+}
+
+/// The first line of a file, for -walletpassfile
+pub fn read_passphrase_file(path: &Path) -> Result<String, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read passphrase file {:?}: {}", path, e))?;
+    Ok(contents.lines().next().unwrap_or("").to_string())
+}
+
+/// The BIP32 seed for the seed file's entropy
+pub fn bip32_seed(entropy: &[u8], passphrase: &str) -> Result<[u8; 64], String> {
+    bip39::mnemonic_to_seed(&bip39::entropy_to_mnemonic(entropy)?, passphrase)
+}
+
+pub fn chain_index(chain_name: &str) -> u32 {
+    let chain_hash = chain::chain_hash(chain_name);
+    u32::from_be_bytes([chain_hash[0], chain_hash[1], chain_hash[2], chain_hash[3]]) & 0x7fff_ffff
+}
+
+fn derive(master: &ExtendedPrivKey, path: &[u32]) -> ExtendedPrivKey {
+    let path: Vec<ChildNumber> = path.iter().map(|i| ChildNumber::from_hardened_idx(*i).unwrap()).collect();
+    master.derive_priv(&Secp256k1::new(), &path).expect("Your RNG is busted")
+}
+
+/// The non-hardened key account/branch/index of a wallet account
+pub fn wallet_key<C: Signing>(secp_ctx: &Secp256k1<C>, account: &ExtendedPrivKey, branch: u32, index: u32) -> SecretKey {
+    let path = [ChildNumber::from_normal_idx(branch).unwrap(), ChildNumber::from_normal_idx(index).unwrap()];
+    account.derive_priv(secp_ctx, &path).expect("Your RNG is busted").private_key.key
+}
+
+/// The public key of wallet_key, from the account's public half
+pub fn wallet_pubkey<C: Verification>(secp_ctx: &Secp256k1<C>, account: &ExtendedPubKey, branch: u32, index: u32) -> PublicKey {
+    let path = [ChildNumber::from_normal_idx(branch).unwrap(), ChildNumber::from_normal_idx(index).unwrap()];
+    account.derive_pub(secp_ctx, &path).expect("Your RNG is busted").public_key.key
+}
+
+/// The P2WPKH script paying to key
+pub fn p2wpkh_script(key: &PublicKey) -> Script {
+    Builder::new().push_int(0).push_slice(&Hash160::hash(&key.serialize())[..]).into_script()
+}
+
+/// The script code that signatures of an input spending a P2WPKH output to key commit to
+pub fn p2wpkh_script_code(key: &PublicKey) -> Script {
+    Builder::new()
+        .push_opcode(opcodes::all::OP_DUP)
+        .push_opcode(opcodes::all::OP_HASH160)
+        .push_slice(&Hash160::hash(&key.serialize())[..])
+        .push_opcode(opcodes::all::OP_EQUALVERIFY)
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .into_script()
+}
+
+/// A SIGHASH_ALL signature of a segwit input spending value
+pub fn sign_input<C: Signing>(secp_ctx: &Secp256k1<C>, tx: &Transaction, index: usize, key: &SecretKey, script_code: &Script, value: u64) -> Vec<u8> {
+    let sighash = SighashComponents::new(tx).sighash_all(&tx.input[index], script_code, value);
+    witness_signature(&secp_ctx.sign(&Message::from_slice(&sighash[..]).unwrap(), key))
+}
+
+/// signature as a witness element, DER with SIGHASH_ALL
+pub fn witness_signature(signature: &Signature) -> Vec<u8> {
+    let mut signature = signature.serialize_der();
+    signature.push(0x01); // SIGHASH_ALL
+    signature
+}
+
+/// The witness of an input spending a P2WPKH output to key
+pub fn p2wpkh_witness<C: Signing>(secp_ctx: &Secp256k1<C>, tx: &Transaction, index: usize, key: &SecretKey, value: u64) -> Vec<Vec<u8>> {
+    let public_key = PublicKey::from_secret_key(secp_ctx, key);
+    let signature = sign_input(secp_ctx, tx, index, key, &p2wpkh_script_code(&public_key), value);
+    vec![signature, public_key.serialize().to_vec()]
+}
+
+/// KeysInterface with the node secret of the seed and everything else specific to one chain
+pub struct NodeKeys {
+    node_secret: SecretKey,
+    wallet_account_key: ExtendedPrivKey,
+    close_key: PublicKey,
+    chain_keys: KeysManager,
+}
+
+impl NodeKeys {
+    /// seed is the BIP32 seed
+    pub fn new(seed: &[u8], chain_name: &str, logger: Arc<dyn Logger>) -> NodeKeys {
+        let network = chain::network_for_chain(chain_name);
+        // The network only matters for serialization, which the node key never goes through
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, seed).expect("Seeds are 16 to 64 bytes");
+        let chain = chain_index(chain_name);
+
+        let mut wallet_account_key = derive(&master, &[PURPOSE, 1, chain, 0]);
+        wallet_account_key.network = network;
+        wallet_account_key.private_key.network = network;
+        let channel_seed = derive(&master, &[PURPOSE, 1, chain, 1]).private_key.key;
+        let mut channel_seed_bytes = [0; 32];
+        channel_seed_bytes.copy_from_slice(&channel_seed[..]);
+
+        let secp_ctx = Secp256k1::new();
+        let close_key = PublicKey::from_secret_key(&secp_ctx, &wallet_key(&secp_ctx, &wallet_account_key, CLOSE_BRANCH, 0));
+
+        NodeKeys {
+            node_secret: derive(&master, &[PURPOSE, 0]).private_key.key,
+            wallet_account_key,
+            close_key,
+            chain_keys: KeysManager::new(&channel_seed_bytes, network, logger),
+        }
+    }
+
+    /// m/1017'/1'/<chain>'/0', for the on-chain wallet
+    pub fn wallet_account_key(&self) -> ExtendedPrivKey {
+        self.wallet_account_key
+    }
+}
+
+impl NodeSigner for NodeKeys {
+    fn get_node_id(&self) -> PublicKey {
+        self.node_secret.get_node_id()
+    }
+
+    fn node_ecdh(&self, other: &PublicKey) -> [u8; 32] {
+        self.node_secret.node_ecdh(other)
+    }
+
+    fn sign_with_node_secret(&self, msg: &Message) -> Signature {
+        self.node_secret.sign_with_node_secret(msg)
+    }
+}
+
+impl KeysInterface for NodeKeys {
+    // Closed channels pay to the wallet, so that it spends them like any other coins
+    fn get_destination_script(&self) -> Script {
+        p2wpkh_script(&self.close_key)
+    }
+
+    fn get_shutdown_pubkey(&self) -> PublicKey {
+        self.close_key
+    }
+
+    fn get_channel_keys(&self, inbound: bool) -> ChannelKeys {
+        self.chain_keys.get_channel_keys(inbound)
+    }
+
+    fn get_channel_signer(&self, keys_id: &[u8; 32]) -> Arc<dyn ChannelSigner> {
+        self.chain_keys.get_channel_signer(keys_id)
+    }
+
+    fn get_session_key(&self) -> SecretKey {
+        self.chain_keys.get_session_key()
+    }
+
+    fn get_channel_id(&self) -> [u8; 32] {
+        self.chain_keys.get_channel_id()
+    }
+}
@@ -2,4 +2,33 @@
 //!
 //! `rustld` is a rust lightning crate meant to pass tests
 
+pub mod announce;
 pub mod argman;
+pub mod base64;
+pub mod bip39;
+pub mod bitcoind;
+pub mod chain;
+pub mod chainfilter;
+pub mod channels;
+pub mod control;
+pub mod crypto;
+pub mod gossip;
+pub mod graph;
+pub mod invoice;
+pub mod keys;
+pub mod logger;
+pub mod manager;
+pub mod memorychain;
+pub mod monitor;
+pub mod net;
+pub mod node;
+pub mod onion;
+pub mod peers;
+pub mod policy;
+pub mod psbt;
+pub mod rates;
+pub mod registry;
+pub mod signer;
+pub mod socks5;
+pub mod tor;
+pub mod wallet;
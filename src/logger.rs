@@ -0,0 +1,22 @@
+//! Logger handed to rust-lightning, prints to stdout like the rest of the daemon
+
+use lightning::util::logger::{Level, Logger, Record};
+
+pub struct PrintLogger {
+    level: Level,
+}
+
+impl PrintLogger {
+    pub fn new(level: Level) -> PrintLogger {
+        PrintLogger { level }
+    }
+}
+
+impl Logger for PrintLogger {
+    fn log(&self, record: &Record) {
+        if record.level > self.level {
+            return;
+        }
+        println!("{} [{}:{}] {}", record.level, record.module_path, record.line, record.args);
+    }
+}
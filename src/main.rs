@@ -1,8 +1,32 @@
 
-mod argman;
-
 use std::collections::HashMap;
-use std::{thread, time};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time;
+
+use lightning::util::logger::Level;
+
+use rustlnd::announce::{self, NodeInfo};
+use rustlnd::argman;
+use rustlnd::bip39;
+use rustlnd::bitcoind::BitcoindRpc;
+use rustlnd::chain::ChainBackend;
+use rustlnd::channels::Channels;
+use rustlnd::control::{self, Control};
+use rustlnd::keys;
+use rustlnd::logger::PrintLogger;
+use rustlnd::net;
+use rustlnd::node::Node;
+use rustlnd::peers::{self, PeerConnector};
+use rustlnd::policy::{ChainPolicy, ChannelPolicy};
+use rustlnd::rates;
+#[cfg(unix)]
+use rustlnd::signer::RemoteSigner;
+use rustlnd::signer::Signer;
+use rustlnd::socks5;
+use rustlnd::tor;
 
 fn create_global_args() -> argman::ArgMan {
     let mut g_args = argman::ArgMan::new();
@@ -10,13 +34,58 @@ fn create_global_args() -> argman::ArgMan {
                    "Selected chain to operate with (Can be repeated to operate with several chains simultaneously)");
 
     // Common arguments:
-    g_args.add_arg("-p2phost", "localhost:9999".to_string(),
-                   "Address to listen to as a p2p lightning node");
+    g_args.add_arg_multi_unique("-p2phost", vec!["localhost:9999".to_string()],
+                   "Address to listen to as a p2p lightning node (Can be repeated to listen on several addresses)");
+    g_args.set_validator("-p2phost", |addr| socks5::split_host_port(addr).map(|_| ()));
+    g_args.add_arg_multi_unique("-announceaddr", vec![],
+                   "Address (ip:port or onion:port) to announce instead of the ones we listen to, e.g. behind NAT (Can be repeated)");
+    g_args.set_validator("-announceaddr", |addr| net::parse_netaddress(addr).map(|_| ()));
     g_args.add_arg_bool("-daemon", "0".to_string(),
                         "Run in background");
+    let default_datadir = match env::var("HOME") {
+        Ok(home) => format!("{}/.rustlnd", home),
+        Err(_) => ".rustlnd".to_string(),
+    };
+    g_args.add_arg("-datadir", default_datadir,
+                   "Directory to keep the node's data in");
+    g_args.add_arg_unset("-walletpassfile",
+                         "File with the passphrase the seed is encrypted with, to start unattended (otherwise the daemon waits for 'unlock <passphrase>' on the control socket)");
+    g_args.add_arg_unset("-signer",
+                         "Unix socket of a rustlnd-signer holding the seed, instead of keeping it in -datadir");
+    g_args.add_arg_unset("-restore-mnemonic",
+                         "Create the node's seed from these BIP39 words (quoted, space separated) instead of a random one, there must be no seed in -datadir yet");
+    g_args.set_validator("-restore-mnemonic", |mnemonic| bip39::mnemonic_to_entropy(mnemonic).map(|_| ()));
+    g_args.set_sensitive("-restore-mnemonic");
+    g_args.add_arg_unset("-mnemonic-passphrase",
+                         "Optional BIP39 passphrase for the seed, the same one is needed on every start");
+    g_args.set_sensitive("-mnemonic-passphrase");
+    g_args.add_arg_bool("-show-mnemonic", "0".to_string(),
+                        "Print the BIP39 words of the node's seed, to back them up, and exit");
+    g_args.add_arg_unset("-proxy",
+                         "SOCKS5 proxy (host:port) for outbound p2p connections, required for .onion peers");
+    g_args.add_arg_unset("-torcontrol",
+                         "Tor control port (host:port) to publish the p2p port as an onion service");
+    g_args.add_arg_unset("-torpassword",
+                         "Password for -torcontrol");
+    g_args.set_sensitive("-torpassword");
+    g_args.add_arg_multi_unique("-rate", vec![],
+                   "What a msat on a chain is worth on another when forwarding between them, and the spread we keep: <from>:<to>:<rate>[:<spread>], like aaa:bbb:1.05:0.001");
+    g_args.set_validator("-rate", |rate| rates::parse_rate(rate).map(|_| ()));
+    g_args.add_arg_unset("-ratefile", "A file with one <from>:<to>:<rate>[:<spread>] per line, over -rate and read again when it changes");
+    let mut default_interval: HashMap<String, String> = HashMap::new();
+    default_interval.insert(String::new(), rates::DEFAULT_BLOCK_INTERVAL.to_string());
+    g_args.add_arg_with_category("-blockinterval", default_interval,
+                   "Seconds between the blocks of a chain, to compare the expiries of payments forwarded across chains (\"aaa.-blockinterval=150\")");
+    g_args.set_validator("-blockinterval", |value| match value.parse::<u32>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    });
+    g_args.add_arg_unset("-alias", "Name in our node_announcement, up to 32 bytes");
+    g_args.set_validator("-alias", announce::check_alias);
     // This software doesn't set a colour by default. Intelligence services are welcome to review code and give feedback
-    // REM Ignoring this argument and just always using pink by default beats actually implementing it in the initial benchmarks
-    g_args.add_arg_unset("-rgb_color", "bolt7: Allow intelligence services to assign nodes colors like black");
+    g_args.add_arg_unset("-rgb_color", "bolt7: Allow intelligence services to assign nodes colors like black (hex, like ff8000)");
+    g_args.set_validator("-rgb_color", |color| announce::parse_rgb(color).map(|_| ()));
 
     // Per chain arguments:
     let default_empty: HashMap<String, String> = HashMap::new();
@@ -24,6 +93,7 @@ fn create_global_args() -> argman::ArgMan {
                    "bitcoind RPC username");
     g_args.add_arg_with_category("-rpcpass", default_empty,
                    "bitcoind RPC password");
+    g_args.set_sensitive("-rpcpass");
     let mut default_host: HashMap<String, String> = HashMap::new();
     default_host.insert("main".to_string(), "localhost:8332".to_string());
     default_host.insert("test".to_string(), "localhost:18332".to_string());
@@ -31,18 +101,73 @@ fn create_global_args() -> argman::ArgMan {
     g_args.add_arg_with_category("-rpchost", default_host,
                    "bitcoind RPC host to connect to");
 
+    // Channels peers open to us, for every chain ("-minchansize=50000") or per chain ("aaa.-minchansize=50000"):
+    let default_policy = ChainPolicy::default();
+    let common_default = |value: String| -> HashMap<String, String> {
+        let mut default_map = HashMap::new();
+        default_map.insert(String::new(), value);
+        default_map
+    };
+    g_args.add_arg_with_category("-acceptchannels", common_default("1".to_string()),
+                   "Whether peers can open channels to us (0 or 1)");
+    g_args.set_validator("-acceptchannels", |value| if value == "0" || value == "1" { Ok(()) } else { Err("must be 0 or 1".to_string()) });
+    g_args.add_arg_with_category("-minchansize", common_default(default_policy.min_channel_size.to_string()),
+                   "Smallest channel peers can open to us, in satoshis");
+    g_args.add_arg_with_category("-maxchansize", common_default(default_policy.max_channel_size.to_string()),
+                   "Largest channel peers can open to us, in satoshis");
+    g_args.add_arg_with_category("-chanconfs", common_default(default_policy.confirmations.to_string()),
+                   "Confirmations of the funding transaction before a channel opened to us can be used");
+    g_args.add_arg_with_category("-maxpendingchans", common_default(default_policy.max_pending.to_string()),
+                   "Channels a peer can have waiting for their funding transaction with us before opening another one");
+    for name in &["-minchansize", "-maxchansize"] {
+        g_args.set_validator(name, |value| value.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()));
+    }
+    g_args.set_validator("-chanconfs", |value| match value.parse::<u32>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    });
+    g_args.set_validator("-maxpendingchans", |value| value.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()));
+    g_args.add_arg_multi_unique("-acceptfrom", vec![],
+                   "Node id of a peer that can open channels to us, no other can (Can be repeated)");
+    g_args.set_validator("-acceptfrom", |node_id| peers::parse_node_id(node_id).map(|_| ()));
+    g_args.add_arg_multi_unique("-rejectfrom", vec![],
+                   "Node id of a peer that can't open channels to us (Can be repeated)");
+    g_args.set_validator("-rejectfrom", |node_id| peers::parse_node_id(node_id).map(|_| ()));
+
     // Dev arguments:
     g_args.add_arg("-dev_sleep", "10".to_string(),
-                   "Sleep for this many milliseconds before exiting (dev)");
+                   "Sleep for this many milliseconds before exiting, 0 to run until killed (dev)");
 
     g_args
 }
 
-fn sleep_for_milliseconds(milliseconds: u64) {
-    let future_millis = time::Duration::from_millis(milliseconds);
-    let now = time::Instant::now();
-    thread::sleep(future_millis);
-    assert!(now.elapsed() >= future_millis);
+// The arguments were validated by ArgMan
+fn channel_policy(g_args: &argman::ArgMan, chains: &[String]) -> ChannelPolicy {
+    let mut policy = ChannelPolicy::default();
+    for chain in chains {
+        let get = |name| g_args.get_by_category_or_common(chain, name);
+        policy.chains.insert(chain.to_string(), ChainPolicy {
+            accept: get("-acceptchannels") == "1",
+            min_channel_size: get("-minchansize").parse().unwrap(),
+            max_channel_size: get("-maxchansize").parse().unwrap(),
+            confirmations: get("-chanconfs").parse().unwrap(),
+            max_pending: get("-maxpendingchans").parse().unwrap(),
+        });
+    }
+    policy.allow = g_args.get_multi("-acceptfrom").iter().map(|node_id| peers::parse_node_id(node_id).unwrap()).collect();
+    policy.deny = g_args.get_multi("-rejectfrom").iter().map(|node_id| peers::parse_node_id(node_id).unwrap()).collect();
+    policy
+}
+
+#[cfg(unix)]
+fn remote_signer(path: &Path, chain: &str) -> Result<Arc<dyn Signer>, String> {
+    Ok(Arc::new(RemoteSigner::new(path, chain)))
+}
+
+#[cfg(not(unix))]
+fn remote_signer(_path: &Path, _chain: &str) -> Result<Arc<dyn Signer>, String> {
+    Err("-signer needs Unix sockets".to_string())
 }
 
 fn main() {
@@ -62,15 +187,188 @@ fn main() {
     }
 
     let chains = g_args.get_multi("-chain");
+    let mut backends: Vec<Arc<dyn ChainBackend>> = Vec::new();
     for chain in chains {
         println!("\nConnecting to chain {}'s daemon in host {}", chain, g_args.get_by_category(chain, "-rpchost"));
-        println!("rpchost: {:?}", g_args.get_by_category(chain, "-rpchost"));
-        println!("rpcuser: {:?}", g_args.get_by_category(chain, "-rpcuser"));
-        println!("rpcpass: {:?}", g_args.get_by_category(chain, "-rpcpass"));
-        // TODO ping the daemon for every chain via rpc and store things for convenience
+        let backend = BitcoindRpc::new(g_args.get_by_category(chain, "-rpchost"), g_args.get_by_category(chain, "-rpcuser"),
+                                       g_args.get_by_category(chain, "-rpcpass"));
+        match backend.get_height() {
+            Ok(height) => println!("Chain {} is at height {}", chain, height),
+            // Channels keep trying to sync
+            Err(e) => println!("Warning: chain {}: {}", chain, e),
+        }
+        backends.push(Arc::new(backend));
+    }
+
+    let datadir = PathBuf::from(g_args.get("-datadir"));
+    if let Err(e) = fs::create_dir_all(&datadir) {
+        println!("Error: Cannot create datadir {:?}: {}", datadir, e);
+        println!("\nThe daemon is not running.");
+        return;
+    }
+
+    let control = Arc::new(Control::new_locked());
+    let control_socket = control::socket_path(&datadir);
+    match control::listen(control.clone(), &control_socket) {
+        Ok(()) => println!("Listening for control commands on {:?}", control_socket),
+        Err(e) => {
+            println!("Error: Cannot listen on {:?}: {}", control_socket, e);
+            println!("\nThe daemon is not running.");
+            return;
+        },
     }
 
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let signers: Result<Vec<Arc<dyn Signer>>, String> = if g_args.is_none("-signer") {
+        let bip39_passphrase = if g_args.is_none("-mnemonic-passphrase") { "" } else { g_args.get("-mnemonic-passphrase") };
+        let open_seed = |passphrase: &str| {
+            let seed = if g_args.is_none("-restore-mnemonic") {
+                keys::load_or_generate_seed(&datadir, passphrase)?
+            } else {
+                println!("Restoring the seed from the mnemonic");
+                keys::restore_seed(&keys::seed_path(&datadir), g_args.get("-restore-mnemonic"), passphrase)?
+            };
+            if g_args.get_bool("-show-mnemonic") {
+                println!("\nWrite down these words, they are all it takes to restore the node (and to steal its funds):");
+                println!("{}", seed.mnemonic()?);
+            }
+            Ok(seed)
+        };
+        let seed = if g_args.is_none("-walletpassfile") {
+            println!("\nThe daemon is locked, waiting for 'unlock <passphrase>' on {:?}", control_socket);
+            control.wait_for_unlock(open_seed)
+        } else {
+            match keys::read_passphrase_file(Path::new(g_args.get("-walletpassfile"))).and_then(|passphrase| open_seed(&passphrase)) {
+                Ok(seed) => seed,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    println!("\nThe daemon is not running.");
+                    return;
+                },
+            }
+        };
+        if g_args.get_bool("-show-mnemonic") {
+            return;
+        }
+        chains.iter().map(|chain| {
+            let signer: Arc<dyn Signer> = Arc::new(seed.node_keys(bip39_passphrase, chain, logger.clone())?);
+            Ok(signer)
+        }).collect()
+    } else {
+        println!("Using the signer at {}", g_args.get("-signer"));
+        chains.iter().map(|chain| remote_signer(Path::new(g_args.get("-signer")), chain)).collect()
+    };
+    let node = match signers.and_then(|signers| Node::with_policy(chains, signers, Some(&datadir), channel_policy(&g_args, chains), logger)) {
+        Ok(node) => node,
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("\nThe daemon is not running.");
+            return;
+        },
+    };
+    println!("\nOur node id: {}", node.get_our_node_id());
+    for rate in g_args.get_multi("-rate") {
+        let (from, to, rate) = rates::parse_rate(rate).unwrap();
+        node.rates.set(&from, &to, rate);
+    }
+    for chain in chains {
+        node.rates.set_block_interval(chain, g_args.get_by_category_or_common(chain, "-blockinterval").parse().unwrap());
+    }
+    if !g_args.is_none("-ratefile") {
+        if let Err(e) = node.rates.set_file(Path::new(g_args.get("-ratefile"))) {
+            println!("Error: {}", e);
+            println!("\nThe daemon is not running.");
+            return;
+        }
+    }
+
+    // Before any peer, the network graph looks funding outputs up on the backends
+    let channels = match Channels::new(&node, backends) {
+        Ok(channels) => Arc::new(channels),
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("\nThe daemon is not running.");
+            return;
+        },
+    };
+
+    let mut listeners = Vec::new();
+    for p2phost in g_args.get_multi("-p2phost") {
+        match net::listen(node.peer_manager.clone(), p2phost) {
+            Ok(listener) => {
+                println!("Listening for p2p connections on {}", listener.local_addr());
+                listeners.push(listener);
+            },
+            Err(e) => {
+                println!("Error: Cannot listen on {}: {}", p2phost, e);
+                println!("\nThe daemon is not running.");
+                return;
+            },
+        }
+    }
+    // The onion service forwards to the first one
+    let listener = &listeners[0];
+
+    let onion_service = if g_args.is_none("-torcontrol") {
+        None
+    } else {
+        let password = if g_args.is_none("-torpassword") { None } else { Some(g_args.get("-torpassword")) };
+        let port = listener.local_addr().port();
+        match tor::add_onion_service(g_args.get("-torcontrol"), password, port, &listener.local_addr().to_string(),
+                                     &datadir.join("onion_key")) {
+            Ok(onion_service) => {
+                println!("Reachable as onion service {}:{}", onion_service.onion_host, onion_service.port);
+                Some(onion_service)
+            },
+            Err(e) => {
+                println!("Error: Cannot publish onion service: {}", e);
+                println!("\nThe daemon is not running.");
+                return;
+            },
+        }
+    };
+
+    let bound: Vec<_> = listeners.iter().map(|l| l.local_addr()).collect();
+    let onion = onion_service.as_ref().and_then(|o| tor::onion_netaddress(&o.onion_host, o.port));
+    match net::announce_addresses(g_args.get_multi("-announceaddr"), &bound, onion) {
+        Ok(addresses) => {
+            println!("Addresses to announce: {:?}", addresses.iter().map(net::netaddress_to_string).collect::<Vec<String>>());
+            let info = NodeInfo {
+                alias: if g_args.is_none("-alias") { String::new() } else { g_args.get("-alias").to_string() },
+                rgb: if g_args.is_none("-rgb_color") { [0; 3] } else { announce::parse_rgb(g_args.get("-rgb_color")).unwrap() },
+                addresses,
+            };
+            // Checked by the validators
+            node.announcer.set_info(info).unwrap();
+        },
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("\nThe daemon is not running.");
+            return;
+        },
+    }
+
+    let mut peer_connector = PeerConnector::new(&node, Some(datadir.join("peers")));
+    if !g_args.is_none("-proxy") {
+        peer_connector.set_proxy(Some(g_args.get("-proxy").to_string()));
+    }
+    let peer_connector = Arc::new(peer_connector);
+    PeerConnector::start(peer_connector.clone(), time::Duration::from_millis(500));
+
+    Channels::start(channels.clone(), time::Duration::from_secs(1));
+
+    control.set_running(&node, peer_connector.clone(), Some(channels.clone()));
+
     let dev_sleep = g_args.get("-dev_sleep").parse::<u64>().unwrap();
-    println!("Sleep {:?} milliseconds for development purposes", dev_sleep);
-    sleep_for_milliseconds(dev_sleep);
+    if dev_sleep == 0 {
+        while !control.wait_for_stop(time::Duration::from_secs(60)) {
+            node.peer_manager.process_events();
+        }
+    } else {
+        println!("Sleep {:?} milliseconds for development purposes", dev_sleep);
+        control.wait_for_stop(time::Duration::from_millis(dev_sleep));
+    }
+    peer_connector.stop();
+    channels.save();
+    println!("\nThe daemon stopped.");
 }
@@ -0,0 +1,70 @@
+//! Keeps rust-lightning's ChannelManager on disk
+//!
+//! The ChannelManager of each chain is written to <datadir>/channel_managers/<chain> after
+//! anything that can change it: the messages of our peers, our own commands and the blocks and
+//! events Channels handles. On start it's read back with the channel monitors. A ChannelManager
+//! behind its monitors (a crash between a monitor update and the next write) gets the channels
+//! it's behind on force closed by rust-lightning, the others go on as if there was no restart.
+//!
+//! The ChannelManager remembers the last block it saw, it must get the ones after it and no
+//! other. Its height isn't public but its serialization is, it's right after the version bytes
+//! and the genesis block hash.
+
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use lightning::ln::channelmanager::{ChannelManager, ChannelManagerReadArgs};
+use lightning::util::ser::{ReadableArgs, Writeable};
+
+use crate::monitor;
+
+/// Writes a chain's ChannelManager to path, when there's one
+pub struct ManagerStore {
+    chain: String,
+    path: Option<PathBuf>,
+    pub channel_manager: Arc<ChannelManager>,
+    // What's on disk, there's no need to write it again
+    saved: Mutex<Vec<u8>>,
+}
+
+impl ManagerStore {
+    pub fn new(chain: &str, path: Option<&Path>, channel_manager: Arc<ChannelManager>) -> ManagerStore {
+        ManagerStore { chain: chain.to_string(), path: path.map(Path::to_path_buf), channel_manager, saved: Mutex::new(Vec::new()) }
+    }
+
+    /// Writes the ChannelManager if it changed, the daemon goes on if it can't
+    pub fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut saved = self.saved.lock().unwrap();
+        let contents = self.channel_manager.encode();
+        if contents == *saved {
+            return;
+        }
+        match monitor::write_durably(path, &contents) {
+            Ok(()) => *saved = contents,
+            Err(e) => println!("Error: Cannot save the channels of chain {} to {:?}: {}", self.chain, path, e),
+        }
+    }
+}
+
+/// The ChannelManager in path, with the height of the last block it saw, None if there's none
+pub fn read_manager(path: &Path, args: ChannelManagerReadArgs) -> Result<Option<(u32, ChannelManager)>, String> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Cannot read {:?}: {}", path, e)),
+    };
+    if contents.len() < 38 {
+        return Err(format!("Corrupt channel manager {:?}", path));
+    }
+    let height = u32::from_be_bytes([contents[34], contents[35], contents[36], contents[37]]);
+    let (_, channel_manager) = <(Sha256dHash, ChannelManager)>::read(&mut Cursor::new(&contents), args)
+        .map_err(|e| format!("Corrupt channel manager {:?}: {}", path, e))?;
+    Ok(Some((height, channel_manager)))
+}
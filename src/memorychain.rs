@@ -0,0 +1,241 @@
+//! A chain that only lives in memory, for tests and development
+//!
+//! Blocks are mined on request with whatever is in the mempool. Transactions are checked for
+//! missing or double spent inputs, value, lock times and, for P2WPKH and P2WSH inputs, witness
+//! programs and P2WPKH signatures; other scripts aren't run.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::hash::{bitcoin_merkle_root, BitcoinHash};
+use bitcoin_hashes::hash160::Hash as Hash160;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use lightning::chain::chaininterface::BroadcasterInterface;
+use secp256k1::key::PublicKey;
+use secp256k1::{Message, Secp256k1, Signature};
+
+use crate::chain::{self, ChainBackend};
+
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+struct MemoryChainState {
+    blocks: Vec<Block>,
+    mempool: Vec<Transaction>,
+    // With the height they were mined at, None for the mempool
+    utxos: HashMap<OutPoint, (TxOut, Option<u32>)>,
+    faucet_count: u32,
+}
+
+pub struct MemoryChain {
+    chain: String,
+    state: Mutex<MemoryChainState>,
+}
+
+impl MemoryChain {
+
+    /// Starts with the genesis block rust-lightning expects for chain
+    pub fn new(chain: &str) -> MemoryChain {
+        let genesis = genesis_block(chain::network_for_chain(chain));
+        MemoryChain {
+            chain: chain.to_string(),
+            state: Mutex::new(MemoryChainState {
+                blocks: vec![genesis],
+                mempool: Vec::new(),
+                utxos: HashMap::new(),
+                faucet_count: 0,
+            }),
+        }
+    }
+
+    /// Puts a transaction paying value to script out of thin air in the mempool
+    pub fn faucet(&self, script: &Script, value: u64) -> Transaction {
+        let mut state = self.state.lock().unwrap();
+        state.faucet_count += 1;
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(state.faucet_count as i64).into_script(),
+                sequence: 0xffff_ffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut { value, script_pubkey: script.clone() }],
+        };
+        state.add_to_mempool(tx.clone());
+        tx
+    }
+
+    /// Mines count blocks, the first one with the whole mempool
+    pub fn mine_blocks(&self, count: u32) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..count {
+            let txdata: Vec<Transaction> = state.mempool.drain(..).collect();
+            let height = state.blocks.len() as u32;
+            for tx in &txdata {
+                let txid = tx.txid();
+                for vout in 0..tx.output.len() as u32 {
+                    if let Some(utxo) = state.utxos.get_mut(&OutPoint { txid, vout }) {
+                        utxo.1 = Some(height);
+                    }
+                }
+            }
+            let prev = state.blocks.last().unwrap().header;
+            let header = BlockHeader {
+                version: prev.version,
+                prev_blockhash: prev.bitcoin_hash(),
+                merkle_root: bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid()).collect()),
+                time: prev.time + 600,
+                bits: prev.bits,
+                nonce: 0,
+            };
+            state.blocks.push(Block { header, txdata });
+        }
+    }
+
+    pub fn get_mempool(&self) -> Vec<Transaction> {
+        self.state.lock().unwrap().mempool.clone()
+    }
+
+    /// The transaction and the height of the block it's in, if it's in one
+    pub fn get_transaction(&self, txid: &Sha256dHash) -> Option<(Transaction, Option<u32>)> {
+        let state = self.state.lock().unwrap();
+        if let Some(tx) = state.mempool.iter().find(|tx| tx.txid() == *txid) {
+            return Some((tx.clone(), None));
+        }
+        for (height, block) in state.blocks.iter().enumerate() {
+            if let Some(tx) = block.txdata.iter().find(|tx| tx.txid() == *txid) {
+                return Some((tx.clone(), Some(height as u32)));
+            }
+        }
+        None
+    }
+
+    /// Whether the output exists and nothing in the chain or the mempool spends it
+    pub fn is_unspent(&self, outpoint: &OutPoint) -> bool {
+        self.state.lock().unwrap().utxos.contains_key(outpoint)
+    }
+}
+
+impl MemoryChainState {
+
+    fn add_to_mempool(&mut self, tx: Transaction) {
+        for input in &tx.input {
+            self.utxos.remove(&input.previous_output);
+        }
+        let txid = tx.txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            self.utxos.insert(OutPoint { txid, vout: vout as u32 }, (output.clone(), None));
+        }
+        self.mempool.push(tx);
+    }
+
+    fn is_known(&self, txid: &Sha256dHash) -> bool {
+        self.mempool.iter().any(|tx| tx.txid() == *txid)
+            || self.blocks.iter().any(|block| block.txdata.iter().any(|tx| tx.txid() == *txid))
+    }
+
+    fn check_transaction(&self, tx: &Transaction) -> Result<(), String> {
+        let next_height = self.blocks.len() as u32;
+        if tx.lock_time < LOCKTIME_THRESHOLD && tx.lock_time >= next_height
+            && tx.input.iter().any(|input| input.sequence != 0xffff_ffff) {
+            return Err(format!("Locked until height {}", tx.lock_time));
+        }
+
+        let mut input_value = 0;
+        for (index, input) in tx.input.iter().enumerate() {
+            let (prev_output, height) = match self.utxos.get(&input.previous_output) {
+                Some(utxo) => utxo,
+                None => return Err(format!("Input {} is missing or already spent", index)),
+            };
+            input_value += prev_output.value;
+
+            if tx.version >= 2 && input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0
+                && input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG == 0 {
+                let confirmations = height.map(|height| next_height - height).unwrap_or(0);
+                if confirmations < input.sequence & 0xffff {
+                    return Err(format!("Input {} needs {} confirmations, it has {}",
+                                       index, input.sequence & 0xffff, confirmations));
+                }
+            }
+            check_witness(tx, index, prev_output).map_err(|e| format!("Input {}: {}", index, e))?;
+        }
+
+        let output_value: u64 = tx.output.iter().map(|output| output.value).sum();
+        if output_value > input_value {
+            return Err(format!("Outputs are worth {}, more than the {} of the inputs", output_value, input_value));
+        }
+        Ok(())
+    }
+}
+
+fn check_witness(tx: &Transaction, index: usize, prev_output: &TxOut) -> Result<(), String> {
+    let witness = &tx.input[index].witness;
+    if prev_output.script_pubkey.is_v0_p2wsh() {
+        let program = &prev_output.script_pubkey.as_bytes()[2..];
+        match witness.last() {
+            Some(script) if Sha256::hash(script)[..] == *program => Ok(()),
+            _ => Err("witness script doesn't match".to_string()),
+        }
+    } else if prev_output.script_pubkey.is_v0_p2wpkh() {
+        let program = &prev_output.script_pubkey.as_bytes()[2..];
+        if witness.len() != 2 || witness[0].is_empty() || Hash160::hash(&witness[1])[..] != *program {
+            return Err("witness doesn't match the key hash".to_string());
+        }
+        let key = PublicKey::from_slice(&witness[1]).map_err(|_| "bad public key".to_string())?;
+        let signature = Signature::from_der(&witness[0][..witness[0].len() - 1]).map_err(|_| "bad signature".to_string())?;
+        let script_code = Builder::new()
+            .push_opcode(opcodes::all::OP_DUP)
+            .push_opcode(opcodes::all::OP_HASH160)
+            .push_slice(program)
+            .push_opcode(opcodes::all::OP_EQUALVERIFY)
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script();
+        let sighash = SighashComponents::new(tx).sighash_all(&tx.input[index], &script_code, prev_output.value);
+        Secp256k1::verification_only().verify(&Message::from_slice(&sighash[..]).unwrap(), &signature, &key)
+            .map_err(|_| "wrong signature".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+impl ChainBackend for MemoryChain {
+    fn get_height(&self) -> Result<u32, String> {
+        Ok(self.state.lock().unwrap().blocks.len() as u32 - 1)
+    }
+
+    fn get_block(&self, height: u32) -> Result<Block, String> {
+        match self.state.lock().unwrap().blocks.get(height as usize) {
+            Some(block) => Ok(block.clone()),
+            None => Err(format!("No block at height {}", height)),
+        }
+    }
+
+    fn send_transaction(&self, tx: &Transaction) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_known(&tx.txid()) {
+            return Ok(());
+        }
+        state.check_transaction(tx).map_err(|e| format!("Transaction {} rejected: {}", tx.txid(), e))?;
+        state.add_to_mempool(tx.clone());
+        Ok(())
+    }
+}
+
+impl BroadcasterInterface for MemoryChain {
+    fn broadcast_transaction(&self, tx: &Transaction) {
+        if let Err(e) = self.send_transaction(tx) {
+            println!("Broadcast to chain {} failed: {}", self.chain, e);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Keeps rust-lightning's channel monitors on disk
+//!
+//! A ChannelMonitor is what answers a revoked commitment transaction with a justice transaction,
+//! and rust-lightning only keeps them in memory. PersistentMonitor writes every monitor update to
+//! <datadir>/monitors/<chain>/<funding txid>_<index> before rust-lightning goes on with the
+//! channel, and puts the monitors it finds there back to watching the chain on start.
+//!
+//! A file is the height of the last block connected when it was written, followed by the monitor
+//! as rust-lightning serializes it. rust-lightning can't give back what a monitor learnt from the
+//! chain, so the blocks from the lowest of those heights are fed again on start.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use lightning::chain::chaininterface::{BroadcasterInterface, ChainListener, ChainWatchInterface, FeeEstimator};
+use lightning::chain::keysinterface::KeysInterface;
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::channelmonitor::{ChannelMonitor, ChannelMonitorUpdateErr, HTLCUpdate, ManyChannelMonitor, SimpleManyChannelMonitor};
+use lightning::util::events::{Event, EventsProvider};
+use lightning::util::logger::Logger;
+use lightning::util::ser::ReadableArgs;
+
+/// A SimpleManyChannelMonitor saving every monitor it gets, when it has a directory
+pub struct PersistentMonitor {
+    chain: String,
+    inner: Arc<SimpleManyChannelMonitor<OutPoint>>,
+    dir: Option<PathBuf>,
+    // The monitors as inner has them, combined with their updates, to write them
+    monitors: Mutex<HashMap<OutPoint, ChannelMonitor>>,
+    height: Mutex<u32>,
+    rescan_height: Option<u32>,
+}
+
+impl PersistentMonitor {
+    /// Loads the monitors in dir, if any, and starts watching the chain for them
+    pub fn new(chain: &str, dir: Option<&Path>, chain_watcher: Arc<dyn ChainWatchInterface>, broadcaster: Arc<dyn BroadcasterInterface>,
+               logger: Arc<dyn Logger>, fee_estimator: Arc<dyn FeeEstimator>, keys_manager: Arc<dyn KeysInterface>) -> Result<Arc<PersistentMonitor>, String> {
+        let inner = SimpleManyChannelMonitor::<OutPoint>::new(chain_watcher.clone(), broadcaster, logger.clone(), fee_estimator);
+        let mut monitors = HashMap::new();
+        let mut rescan_height = None;
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir).map_err(|e| format!("Cannot create {:?}: {}", dir, e))?;
+            let entries = fs::read_dir(dir).map_err(|e| format!("Cannot read {:?}: {}", dir, e))?;
+            for entry in entries {
+                let path = entry.map_err(|e| format!("Cannot read {:?}: {}", dir, e))?.path();
+                if path.extension().is_some() {
+                    // Left by a write that didn't finish, the previous version is still there
+                    continue;
+                }
+                let (height, monitor) = read_monitor(&path, logger.clone(), keys_manager.clone())?;
+                let funding_txo = monitor.get_funding_txo().ok_or(format!("The monitor in {:?} has no funding output", path))?;
+                inner.add_update_monitor_by_key(funding_txo, monitor.clone())
+                    .map_err(|e| format!("Cannot watch the chain for {:?}: {}", path, e.0))?;
+                monitors.insert(funding_txo, monitor);
+                rescan_height = Some(rescan_height.map_or(height, |h: u32| h.min(height)));
+            }
+            println!("Loaded {} channel monitors on chain {}", monitors.len(), chain);
+        }
+        let monitor = Arc::new(PersistentMonitor {
+            chain: chain.to_string(),
+            inner,
+            dir: dir.map(Path::to_path_buf),
+            monitors: Mutex::new(monitors),
+            height: Mutex::new(rescan_height.unwrap_or(0)),
+            rescan_height,
+        });
+        let listener: Arc<dyn ChainListener> = monitor.clone();
+        chain_watcher.register_listener(Arc::downgrade(&listener));
+        Ok(monitor)
+    }
+
+    /// The height the chain must be fed again from for the monitors loaded, if any
+    pub fn rescan_height(&self) -> Option<u32> {
+        self.rescan_height
+    }
+
+    /// Copies of the monitors, to read the ChannelManager with
+    pub fn get_monitors(&self) -> HashMap<OutPoint, ChannelMonitor> {
+        self.monitors.lock().unwrap().clone()
+    }
+
+    /// The funding outpoint of a channel with a monitor
+    pub fn funding_txo(&self, channel_id: &[u8; 32]) -> Option<OutPoint> {
+        self.monitors.lock().unwrap().keys().find(|funding_txo| funding_txo.to_channel_id() == *channel_id).cloned()
+    }
+
+    /// Feeds a block to the monitors only, for the ones that have to see blocks the ChannelManager
+    /// already saw
+    pub fn rescan_block(&self, block: &Block, height: u32) {
+        let txn: Vec<&Transaction> = block.txdata.iter().collect();
+        let indexes: Vec<u32> = (0..txn.len() as u32).collect();
+        self.inner.block_connected(&block.header, height, &txn, &indexes);
+        *self.height.lock().unwrap() = height;
+    }
+
+    fn write(&self, funding_txo: &OutPoint, monitor: &ChannelMonitor) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let mut contents = self.height.lock().unwrap().to_be_bytes().to_vec();
+        monitor.write_for_disk(&mut contents)?;
+        write_durably(&dir.join(format!("{}_{}", funding_txo.txid, funding_txo.index)), &contents)
+    }
+}
+
+fn read_monitor(path: &Path, logger: Arc<dyn Logger>, keys_manager: Arc<dyn KeysInterface>) -> Result<(u32, ChannelMonitor), String> {
+    let contents = fs::read(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+    if contents.len() < 4 {
+        return Err(format!("Corrupt channel monitor {:?}", path));
+    }
+    let height = u32::from_be_bytes([contents[0], contents[1], contents[2], contents[3]]);
+    let (_, monitor) = <(Sha256dHash, ChannelMonitor)>::read(&mut Cursor::new(&contents[4..]), (logger, keys_manager))
+        .map_err(|e| format!("Corrupt channel monitor {:?}: {}", path, e))?;
+    Ok((height, monitor))
+}
+
+/// Replaces path with contents, which are on disk once it returns, keeping the old contents if it fails
+pub fn write_durably(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // The rename itself
+    fs::File::open(path.parent().unwrap())?.sync_all()
+}
+
+impl ManyChannelMonitor for PersistentMonitor {
+    fn add_update_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr> {
+        let mut monitors = self.monitors.lock().unwrap();
+        let mut updated = monitor.clone();
+        if let Some(current) = monitors.get(&funding_txo) {
+            updated = current.clone();
+            if updated.insert_combine(monitor.clone()).is_err() {
+                return Err(ChannelMonitorUpdateErr::PermanentFailure);
+            }
+        }
+        self.inner.add_update_monitor(funding_txo, monitor)?;
+        // The channel gets closed, with what inner has in memory, rather than go on unsaved
+        if let Err(e) = self.write(&funding_txo, &updated) {
+            println!("Error: Cannot save the monitor of channel {} on chain {}: {}", funding_txo.txid, self.chain, e);
+            return Err(ChannelMonitorUpdateErr::PermanentFailure);
+        }
+        monitors.insert(funding_txo, updated);
+        Ok(())
+    }
+
+    fn fetch_pending_htlc_updated(&self) -> Vec<HTLCUpdate> {
+        self.inner.fetch_pending_htlc_updated()
+    }
+}
+
+impl ChainListener for PersistentMonitor {
+    fn block_connected(&self, _header: &BlockHeader, height: u32, _txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+        *self.height.lock().unwrap() = height;
+    }
+
+    fn block_disconnected(&self, _header: &BlockHeader) {}
+}
+
+impl EventsProvider for PersistentMonitor {
+    fn get_and_clear_pending_events(&self) -> Vec<Event> {
+        self.inner.get_and_clear_pending_events()
+    }
+}
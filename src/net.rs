@@ -0,0 +1,395 @@
+//! Lightning p2p sockets
+//!
+//! rust-lightning's PeerManager does all the protocol work, including the BOLT8 Noise_XK
+//! handshake. Here we only move bytes: every connection gets a reader thread that feeds
+//! PeerManager::read_event and a writer thread that drains what PeerManager hands to send_data.
+
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use lightning::ln::msgs::NetAddress;
+use lightning::ln::peer_handler;
+use secp256k1::key::PublicKey;
+
+use crate::socks5;
+use crate::tor;
+
+pub type PeerManager = peer_handler::PeerManager<SocketDescriptor>;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_SOCKET_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Connection {
+    stream: TcpStream,
+    outbound_data: Mutex<mpsc::Sender<Vec<u8>>>,
+    read_paused: Mutex<bool>,
+    read_resumed: Condvar,
+    // Set once either side decided to drop the connection
+    disconnected: AtomicBool,
+    // Set once the PeerManager forgot about the peer on its own (disconnect_socket or a
+    // read_event error), it must get no disconnect_event for it then
+    forgotten: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct SocketDescriptor {
+    id: u64,
+    conn: Arc<Connection>,
+}
+
+impl PartialEq for SocketDescriptor {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for SocketDescriptor {}
+
+impl Hash for SocketDescriptor {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl SocketDescriptor {
+    fn new(stream: &TcpStream) -> std::io::Result<(SocketDescriptor, mpsc::Receiver<Vec<u8>>)> {
+        let (sender, receiver) = mpsc::channel();
+        let descriptor = SocketDescriptor {
+            id: NEXT_SOCKET_ID.fetch_add(1, Ordering::AcqRel),
+            conn: Arc::new(Connection {
+                stream: stream.try_clone()?,
+                outbound_data: Mutex::new(sender),
+                read_paused: Mutex::new(false),
+                read_resumed: Condvar::new(),
+                disconnected: AtomicBool::new(false),
+                forgotten: AtomicBool::new(false),
+            }),
+        };
+        Ok((descriptor, receiver))
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.conn.stream.peer_addr().ok()
+    }
+
+    /// False once the socket was closed by either side
+    pub fn is_connected(&self) -> bool {
+        !self.conn.disconnected.load(Ordering::Acquire)
+    }
+
+    fn pause_read(&self) {
+        *self.conn.read_paused.lock().unwrap() = true;
+    }
+
+    fn resume_read(&self) {
+        *self.conn.read_paused.lock().unwrap() = false;
+        self.conn.read_resumed.notify_all();
+    }
+
+    fn wait_while_read_paused(&self) {
+        let mut paused = self.conn.read_paused.lock().unwrap();
+        while *paused && !self.conn.disconnected.load(Ordering::Acquire) {
+            paused = self.conn.read_resumed.wait(paused).unwrap();
+        }
+    }
+
+    fn mark_disconnected(&self) {
+        self.conn.disconnected.store(true, Ordering::Release);
+        let _ = self.conn.stream.shutdown(Shutdown::Both);
+        self.resume_read();
+    }
+
+    fn mark_forgotten(&self) {
+        self.conn.forgotten.store(true, Ordering::Release);
+        self.mark_disconnected();
+    }
+
+    /// Closes the socket, true if the PeerManager still has to be told with disconnect_event
+    /// (once per connection)
+    fn close(&self) -> bool {
+        let first = !self.conn.disconnected.swap(true, Ordering::AcqRel);
+        self.mark_disconnected();
+        first && !self.conn.forgotten.load(Ordering::Acquire)
+    }
+}
+
+impl peer_handler::SocketDescriptor for SocketDescriptor {
+    fn send_data(&mut self, data: &Vec<u8>, write_offset: usize, resume_read: bool) -> usize {
+        if resume_read {
+            self.resume_read();
+        }
+        if self.conn.disconnected.load(Ordering::Acquire) {
+            return 0;
+        }
+        // The writer thread takes care of the actual (blocking) write, so from the point of view
+        // of the PeerManager everything is always sent at once.
+        let pending = data[write_offset..].to_vec();
+        let len = pending.len();
+        match self.conn.outbound_data.lock().unwrap().send(pending) {
+            Ok(()) => len,
+            Err(_) => 0,
+        }
+    }
+
+    fn disconnect_socket(&mut self) {
+        self.mark_forgotten();
+    }
+}
+
+fn write_loop(mut stream: TcpStream, receiver: mpsc::Receiver<Vec<u8>>) {
+    for data in receiver.iter() {
+        if stream.write_all(&data).is_err() {
+            // The reader thread will notice and tell the PeerManager
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+fn read_loop(peer_manager: Arc<PeerManager>, mut descriptor: SocketDescriptor, mut stream: TcpStream) {
+    let mut buf = [0; 8192];
+    loop {
+        descriptor.wait_while_read_paused();
+        if descriptor.conn.disconnected.load(Ordering::Acquire) {
+            break;
+        }
+        let read_len = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(read_len) => read_len,
+        };
+        match peer_manager.read_event(&mut descriptor, buf[..read_len].to_vec()) {
+            Ok(pause_read) => {
+                if pause_read {
+                    descriptor.pause_read();
+                }
+            },
+            Err(e) => {
+                // The PeerManager already forgot about the peer, no disconnect_event for it
+                println!("Disconnecting peer {:?}: {:?}", descriptor.peer_addr(), e);
+                descriptor.mark_forgotten();
+                return;
+            },
+        }
+        peer_manager.process_events();
+    }
+    if descriptor.close() {
+        peer_manager.disconnect_event(&descriptor);
+    }
+}
+
+fn spawn_connection_threads(peer_manager: Arc<PeerManager>, descriptor: SocketDescriptor,
+                            stream: TcpStream, receiver: mpsc::Receiver<Vec<u8>>) -> std::io::Result<()> {
+    let write_stream = stream.try_clone()?;
+    thread::spawn(move || write_loop(write_stream, receiver));
+    thread::spawn(move || read_loop(peer_manager, descriptor, stream));
+    Ok(())
+}
+
+/// Hands an accepted socket to the PeerManager, which waits for the remote to start the handshake
+pub fn setup_inbound(peer_manager: Arc<PeerManager>, stream: TcpStream) -> std::io::Result<Option<SocketDescriptor>> {
+    let (descriptor, receiver) = SocketDescriptor::new(&stream)?;
+    if peer_manager.new_inbound_connection(descriptor.clone()).is_err() {
+        let _ = stream.shutdown(Shutdown::Both);
+        return Ok(None);
+    }
+    spawn_connection_threads(peer_manager, descriptor.clone(), stream, receiver)?;
+    Ok(Some(descriptor))
+}
+
+/// Opens a tcp connection to the first address addr resolves to that answers
+pub fn tcp_connect(addr: &str) -> std::io::Result<TcpStream> {
+    let mut last_err = std::io::Error::other(format!("{} doesn't resolve to any address", addr));
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Connects to a node we know the public key of and starts the handshake (act one)
+pub fn connect_outbound(peer_manager: Arc<PeerManager>, their_node_id: PublicKey, addr: &str) -> std::io::Result<SocketDescriptor> {
+    let stream = tcp_connect(addr)?;
+    setup_outbound(peer_manager, their_node_id, stream)
+}
+
+/// Like connect_outbound, going through a SOCKS5 proxy if there's one (required for .onion)
+pub fn connect_outbound_via(peer_manager: Arc<PeerManager>, their_node_id: PublicKey, addr: &str,
+                            proxy: Option<&str>) -> std::io::Result<SocketDescriptor> {
+    let stream = match proxy {
+        Some(proxy) => socks5::connect(proxy, addr)?,
+        None => {
+            let (host, _) = socks5::split_host_port(addr).map_err(std::io::Error::other)?;
+            if tor::is_onion(&host) {
+                return Err(std::io::Error::other(format!("Cannot connect to {} without -proxy", addr)));
+            }
+            tcp_connect(addr)?
+        },
+    };
+    setup_outbound(peer_manager, their_node_id, stream)
+}
+
+/// 'host:port' for the addresses nodes announce
+pub fn netaddress_to_string(addr: &NetAddress) -> String {
+    match addr {
+        NetAddress::IPv4 { addr, port } => format!("{}:{}", std::net::Ipv4Addr::from(*addr), port),
+        NetAddress::IPv6 { addr, port } => format!("[{}]:{}", std::net::Ipv6Addr::from(*addr), port),
+        NetAddress::OnionV2 { port, .. } | NetAddress::OnionV3 { port, .. } => {
+            format!("{}:{}", tor::onion_host(addr).unwrap(), port)
+        },
+    }
+}
+
+/// Parses an address we can announce: an IP literal or a .onion host, with a port
+pub fn parse_netaddress(addr: &str) -> Result<NetAddress, String> {
+    let (host, port) = socks5::split_host_port(addr)?;
+    if tor::is_onion(&host) {
+        return match tor::onion_netaddress(&host, port) {
+            Some(netaddress) => Ok(netaddress),
+            None => Err(format!("'{}' is not a valid onion address", host)),
+        };
+    }
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => Ok(NetAddress::IPv4 { addr: ip.octets(), port }),
+        Ok(IpAddr::V6(ip)) => Ok(NetAddress::IPv6 { addr: ip.octets(), port }),
+        // node_announcement has no room for DNS names
+        Err(_) => Err(format!("'{}' is neither an IP address nor a .onion host", host)),
+    }
+}
+
+/// Whether other nodes could reach us on addr without being in our network
+pub fn is_publicly_routable(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                            || ip.is_broadcast() || ip.is_documentation()),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Unique local (fc00::/7) and link local (fe80::/10) addresses
+            !(ip.is_loopback() || ip.is_unspecified() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+        },
+    }
+}
+
+fn same_type(a: &NetAddress, b: &NetAddress) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+/// The addresses to put in our node_announcement
+///
+/// The -announceaddr ones if there are any, otherwise the publicly routable addresses we are
+/// bound to plus our onion service. node_announcement allows one address of each type only.
+pub fn announce_addresses(announce: &[String], bound: &[SocketAddr], onion: Option<NetAddress>) -> Result<Vec<NetAddress>, String> {
+    let mut addresses: Vec<NetAddress> = Vec::new();
+    if !announce.is_empty() {
+        for addr in announce {
+            let netaddress = parse_netaddress(addr)?;
+            if addresses.iter().any(|a| same_type(a, &netaddress)) {
+                return Err(format!("Only one address of each type can be announced, '{}' is one too many", addr));
+            }
+            addresses.push(netaddress);
+        }
+        return Ok(addresses);
+    }
+    for socket_addr in bound.iter().filter(|a| is_publicly_routable(a)) {
+        let netaddress = parse_netaddress(&socket_addr.to_string())?;
+        if !addresses.iter().any(|a| same_type(a, &netaddress)) {
+            addresses.push(netaddress);
+        }
+    }
+    if let Some(onion) = onion {
+        addresses.push(onion);
+    }
+    Ok(addresses)
+}
+
+/// Like connect_outbound for a socket that is already connected (e.g. through a proxy)
+pub fn setup_outbound(peer_manager: Arc<PeerManager>, their_node_id: PublicKey, stream: TcpStream) -> std::io::Result<SocketDescriptor> {
+    stream.set_nodelay(true)?;
+    let (mut descriptor, receiver) = SocketDescriptor::new(&stream)?;
+    let act_one = match peer_manager.new_outbound_connection(their_node_id, descriptor.clone()) {
+        Ok(act_one) => act_one,
+        Err(e) => {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(std::io::Error::other(format!("{:?}", e)));
+        },
+    };
+    peer_handler::SocketDescriptor::send_data(&mut descriptor, &act_one, 0, false);
+    spawn_connection_threads(peer_manager, descriptor.clone(), stream, receiver)?;
+    Ok(descriptor)
+}
+
+/// Drops a connection we decided to close ourselves, the remote will see the socket closing
+pub fn disconnect(peer_manager: &PeerManager, descriptor: &SocketDescriptor) {
+    if descriptor.close() {
+        peer_manager.disconnect_event(descriptor);
+    }
+}
+
+/// Accepts p2p connections in the background until shutdown
+pub struct Listener {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    inbound: Arc<Mutex<Vec<SocketDescriptor>>>,
+    peer_manager: Arc<PeerManager>,
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections and drops the ones we accepted
+    pub fn shutdown(&self) {
+        if self.stopped.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // Wake up the thread blocked in accept so that it sees the flag and frees the port
+        let _ = TcpStream::connect(self.local_addr);
+        for descriptor in self.inbound.lock().unwrap().drain(..) {
+            disconnect(&self.peer_manager, &descriptor);
+        }
+    }
+}
+
+/// Binds to addr and accepts p2p connections in the background
+pub fn listen<A: ToSocketAddrs>(peer_manager: Arc<PeerManager>, addr: A) -> std::io::Result<Listener> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let inbound: Arc<Mutex<Vec<SocketDescriptor>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let thread_stopped = stopped.clone();
+    let thread_inbound = inbound.clone();
+    let thread_peer_manager = peer_manager.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if thread_stopped.load(Ordering::Acquire) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    match setup_inbound(thread_peer_manager.clone(), stream) {
+                        Ok(Some(descriptor)) => {
+                            let mut inbound = thread_inbound.lock().unwrap();
+                            inbound.retain(|d| d.is_connected());
+                            inbound.push(descriptor);
+                        },
+                        Ok(None) => {},
+                        Err(e) => println!("Error setting up inbound connection: {}", e),
+                    }
+                },
+                Err(e) => println!("Error accepting p2p connection on {}: {}", local_addr, e),
+            }
+        }
+    });
+    Ok(Listener { local_addr, stopped, inbound, peer_manager })
+}
@@ -0,0 +1,231 @@
+//! Puts together the rust-lightning objects a node needs to talk to its peers
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bitcoin::network::constants::Network;
+use lightning::chain::chaininterface::{BroadcasterInterface, ChainWatchInterface, ChainWatchInterfaceUtil, FeeEstimator};
+use lightning::chain::keysinterface::NodeSigner;
+use lightning::ln::channelmanager::{ChannelManager, ChannelManagerReadArgs};
+use lightning::ln::peer_handler::MessageHandler;
+use lightning::ln::router::Router;
+use lightning::util::config::UserConfig;
+use lightning::util::logger::Logger;
+use rand::Rng;
+use secp256k1::key::PublicKey;
+
+use crate::announce::NodeAnnouncer;
+use crate::chain;
+use crate::chainfilter::ChainFilter;
+use crate::gossip::Gossip;
+use crate::graph::NetworkGraph;
+use crate::invoice::Invoice;
+use crate::keys::NodeKeys;
+use crate::manager::{self, ManagerStore};
+use crate::monitor::PersistentMonitor;
+use crate::net;
+use crate::policy::ChannelPolicy;
+use crate::rates::RateTable;
+use crate::registry::InvoiceRegistry;
+use crate::signer::{Signer, SignerKeys};
+
+/// rust-lightning's objects for one of the node's chains
+pub struct ChainNode {
+    pub chain: String,
+    pub signer: Arc<dyn Signer>,
+    pub keys_manager: Arc<SignerKeys>,
+    pub fee_estimator: Arc<dyn FeeEstimator>,
+    pub broadcaster: Arc<chain::Broadcaster>,
+    pub chain_watcher: Arc<ChainWatchInterfaceUtil>,
+    pub monitor: Arc<PersistentMonitor>,
+    pub channel_manager: Arc<ChannelManager>,
+    pub manager_store: Arc<ManagerStore>,
+    /// Height of the last block the ChannelManager saw before a restart
+    pub manager_height: Option<u32>,
+    /// Where the wallet keeps the funding transactions not broadcast yet
+    pub fundings_path: Option<PathBuf>,
+}
+
+impl ChainNode {
+    fn new(chain_name: &str, signer: Arc<dyn Signer>, datadir: Option<&Path>, policy: &ChannelPolicy, logger: Arc<dyn Logger>) -> Result<ChainNode, String> {
+        let mut config = UserConfig::new();
+        config.own_channel_config.minimum_depth = policy.chain(chain_name).confirmations;
+        // Our channels go in the network graph for others to route through, private ones are fine too
+        config.channel_options.announced_channel = true;
+        config.peer_channel_config_limits.force_announced_channel_preference = false;
+        let network: Network = chain::network_for_chain(chain_name);
+        let keys_manager = Arc::new(SignerKeys::new(signer.clone())?);
+
+        let fee_estimator: Arc<dyn FeeEstimator> = Arc::new(chain::FixedFeeEstimator::new(253));
+        let broadcaster = Arc::new(chain::Broadcaster::new(chain_name));
+        let broadcaster_interface: Arc<dyn BroadcasterInterface> = broadcaster.clone();
+        let chain_watcher = Arc::new(ChainWatchInterfaceUtil::new(network, logger.clone()));
+        let chain_watch_interface: Arc<dyn ChainWatchInterface> = chain_watcher.clone();
+        let monitors_dir = datadir.map(|datadir| datadir.join("monitors").join(chain_name));
+        let monitor = PersistentMonitor::new(chain_name, monitors_dir.as_deref(), chain_watch_interface.clone(),
+                                             broadcaster_interface.clone(), logger.clone(), fee_estimator.clone(), keys_manager.clone())?;
+
+        let manager_path = match datadir {
+            Some(datadir) => {
+                let dir = datadir.join("channel_managers");
+                fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {:?}: {}", dir, e))?;
+                Some(dir.join(chain_name))
+            },
+            None => None,
+        };
+        let loaded = match &manager_path {
+            Some(path) => {
+                let monitors = monitor.get_monitors();
+                let channel_monitors: HashMap<_, _> = monitors.iter().map(|(funding_txo, monitor)| (*funding_txo, monitor)).collect();
+                manager::read_manager(path, ChannelManagerReadArgs {
+                    keys_manager: keys_manager.clone(),
+                    fee_estimator: fee_estimator.clone(),
+                    monitor: monitor.clone(),
+                    chain_monitor: chain_watch_interface.clone(),
+                    tx_broadcaster: broadcaster_interface.clone(),
+                    logger: logger.clone(),
+                    default_config: config.clone(),
+                    channel_monitors: &channel_monitors,
+                })?
+            },
+            None => None,
+        };
+        let (channel_manager, manager_height) = match loaded {
+            Some((height, channel_manager)) => {
+                println!("Loaded {} channels on chain {}, last block seen {}", channel_manager.list_channels().len(), chain_name, height);
+                (Arc::new(channel_manager), Some(height))
+            },
+            None => (ChannelManager::new(network, fee_estimator.clone(), monitor.clone(), chain_watch_interface, broadcaster_interface,
+                                         logger, keys_manager.clone(), config).unwrap(), None),
+        };
+        let manager_store = Arc::new(ManagerStore::new(chain_name, manager_path.as_deref(), channel_manager.clone()));
+        let fundings_path = match datadir {
+            Some(datadir) => {
+                let dir = datadir.join("fundings");
+                fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {:?}: {}", dir, e))?;
+                Some(dir.join(chain_name))
+            },
+            None => None,
+        };
+
+        Ok(ChainNode {
+            chain: chain_name.to_string(),
+            signer,
+            keys_manager,
+            fee_estimator,
+            broadcaster,
+            chain_watcher,
+            monitor,
+            channel_manager,
+            manager_store,
+            manager_height,
+            fundings_path,
+        })
+    }
+}
+
+/// In-process signers for chains, all from seed
+pub fn seed_signers<S: AsRef<str>>(chains: &[S], seed: &[u8], logger: Arc<dyn Logger>) -> Vec<Arc<dyn Signer>> {
+    chains.iter().map(|chain| {
+        let signer: Arc<dyn Signer> = Arc::new(NodeKeys::new(seed, chain.as_ref(), logger.clone()));
+        signer
+    }).collect()
+}
+
+pub struct Node {
+    pub logger: Arc<dyn Logger>,
+    /// In the order of -chain
+    pub chains: Vec<ChainNode>,
+    pub chain_filter: Arc<ChainFilter>,
+    pub router: Arc<Router>,
+    /// The channels the Router has, by chain
+    pub graph: Arc<NetworkGraph>,
+    /// Our node_announcement
+    pub announcer: Arc<NodeAnnouncer>,
+    pub peer_manager: Arc<net::PeerManager>,
+    /// The invoices we handed out, kept in datadir/invoices
+    pub invoices: Arc<InvoiceRegistry>,
+    /// What our chains are worth in each other, for the payments crossing them
+    pub rates: Arc<RateTable>,
+}
+
+impl Node {
+
+    pub fn new<S: AsRef<str>>(chains: &[S], seed: &[u8], logger: Arc<dyn Logger>) -> Node {
+        Node::with_signers(chains, seed_signers(chains, seed, logger.clone()), logger).expect("In-process signers don't fail")
+    }
+
+    /// A node whose keys come from signers, one for each chain
+    pub fn with_signers<S: AsRef<str>>(chains: &[S], signers: Vec<Arc<dyn Signer>>, logger: Arc<dyn Logger>) -> Result<Node, String> {
+        Node::with_policy(chains, signers, None, ChannelPolicy::default(), logger)
+    }
+
+    /// A node keeping its channels' state in datadir, picking up the one already there
+    pub fn with_datadir<S: AsRef<str>>(chains: &[S], signers: Vec<Arc<dyn Signer>>, datadir: &Path, logger: Arc<dyn Logger>) -> Result<Node, String> {
+        Node::with_policy(chains, signers, Some(datadir), ChannelPolicy::default(), logger)
+    }
+
+    /// A node accepting the channels policy allows, keeping their state in datadir if there's one
+    pub fn with_policy<S: AsRef<str>>(chains: &[S], signers: Vec<Arc<dyn Signer>>, datadir: Option<&Path>, policy: ChannelPolicy,
+                                      logger: Arc<dyn Logger>) -> Result<Node, String> {
+        assert_eq!(chains.len(), signers.len());
+        let mut chain_nodes = Vec::new();
+        for (chain, signer) in chains.iter().zip(signers) {
+            chain_nodes.push(ChainNode::new(chain.as_ref(), signer, datadir, &policy, logger.clone())?);
+        }
+        // The node secret is the same on every chain, the first chain's signer uses it for all
+        let node_signer: Arc<dyn NodeSigner> = chain_nodes[0].keys_manager.clone();
+        let our_node_id = node_signer.get_node_id();
+        if chain_nodes.iter().any(|chain_node| chain_node.keys_manager.get_node_id() != our_node_id) {
+            return Err("The signers have different node keys".to_string());
+        }
+
+        let graph = Arc::new(NetworkGraph::new(chains));
+        let router = Arc::new(Router::new(our_node_id, graph.clone(), logger.clone()));
+
+        let rates = Arc::new(RateTable::new());
+        let chain_filter = Arc::new(ChainFilter::new(chains, chain_nodes.iter().map(|c| c.manager_store.clone()).collect(), policy,
+                                                     node_signer.clone(), rates.clone(), graph.clone(), datadir.map(|datadir| datadir.join("forwards")).as_deref())?);
+        let peer_manager = Arc::new(net::PeerManager::with_node_signer(MessageHandler {
+            chan_handler: chain_filter.clone(),
+            route_handler: Arc::new(Gossip::new(chains, router.clone(), graph.clone(), rates.clone())),
+        }, node_signer.clone(), logger.clone()));
+        let announcer = Arc::new(NodeAnnouncer::new(node_signer.clone(), peer_manager.clone(), graph.clone()));
+        let invoices = Arc::new(InvoiceRegistry::new(chains, node_signer, datadir.map(|datadir| datadir.join("invoices")).as_deref())?);
+
+        Ok(Node {
+            logger,
+            chains: chain_nodes,
+            chain_filter,
+            router,
+            graph,
+            announcer,
+            peer_manager,
+            invoices,
+            rates,
+        })
+    }
+
+    /// A node with a throwaway identity
+    pub fn new_random<S: AsRef<str>>(chains: &[S], logger: Arc<dyn Logger>) -> Node {
+        let seed: [u8; 32] = rand::thread_rng().gen();
+        Node::new(chains, &seed, logger)
+    }
+
+    pub fn get_our_node_id(&self) -> PublicKey {
+        self.chains[0].channel_manager.get_our_node_id()
+    }
+
+    /// rust-lightning's objects for chain, if it's one of ours
+    pub fn chain(&self, chain: &str) -> Option<&ChainNode> {
+        self.chains.iter().find(|chain_node| chain_node.chain == chain)
+    }
+
+    /// invoice signed with our node key, for one of our chains
+    pub fn sign_invoice(&self, invoice: &Invoice) -> Result<String, String> {
+        let chain_node = self.chain(&invoice.chain).ok_or(format!("Unknown chain {}", invoice.chain))?;
+        invoice.encode(&*chain_node.keys_manager)
+    }
+}
@@ -0,0 +1,161 @@
+//! BOLT4 onions with the fixed size realm 0 hop payloads, the ones rust-lightning 0.0.9 knows
+//!
+//! rust-lightning builds and peels onions itself, but only for routes on a single chain. Chain
+//! hops peel the onion of a payment arriving on one chain to forward it on another, and payers
+//! build the onions of routes whose amounts change at those hops.
+
+use bitcoin_hashes::hmac::{Hmac, HmacEngine};
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::{Hash, HashEngine};
+use lightning::chain::keysinterface::NodeSigner;
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+
+use crate::crypto;
+
+pub const MAX_HOPS: usize = 20;
+const HOP_LEN: usize = 65;
+const ROUTING_INFO_LEN: usize = MAX_HOPS * HOP_LEN;
+/// Version, ephemeral key, routing info and HMAC
+pub const PACKET_LEN: usize = 1 + 33 + ROUTING_INFO_LEN + 32;
+
+/// What a hop reads in the onion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HopPayload {
+    /// The channel to forward on, 0 for the last hop
+    pub short_channel_id: u64,
+    pub amt_to_forward: u64,
+    pub outgoing_cltv_value: u32,
+}
+
+impl HopPayload {
+    // Realm 0, the payload, 12 bytes of padding and the HMAC for the next hop
+    fn encode(&self, next_hmac: &[u8; 32]) -> [u8; HOP_LEN] {
+        let mut hop = [0; HOP_LEN];
+        hop[1..9].copy_from_slice(&self.short_channel_id.to_be_bytes());
+        hop[9..17].copy_from_slice(&self.amt_to_forward.to_be_bytes());
+        hop[17..21].copy_from_slice(&self.outgoing_cltv_value.to_be_bytes());
+        hop[33..].copy_from_slice(next_hmac);
+        hop
+    }
+
+    fn decode(hop: &[u8]) -> HopPayload {
+        let mut short_channel_id = [0; 8];
+        short_channel_id.copy_from_slice(&hop[1..9]);
+        let mut amt_to_forward = [0; 8];
+        amt_to_forward.copy_from_slice(&hop[9..17]);
+        HopPayload {
+            short_channel_id: u64::from_be_bytes(short_channel_id),
+            amt_to_forward: u64::from_be_bytes(amt_to_forward),
+            outgoing_cltv_value: u32::from_be_bytes([hop[17], hop[18], hop[19], hop[20]]),
+        }
+    }
+}
+
+/// An onion with its outer layer peeled
+#[derive(Clone, Debug, PartialEq)]
+pub enum Peeled {
+    /// The payment goes on through payload.short_channel_id, with next_packet
+    Forward { payload: HopPayload, next_packet: Vec<u8> },
+    /// We're the last hop
+    Final(HopPayload),
+}
+
+fn key(label: &[u8], shared_secret: &[u8]) -> [u8; 32] {
+    let mut hmac = HmacEngine::<Sha256>::new(label);
+    hmac.input(shared_secret);
+    Hmac::from_engine(hmac).into_inner()
+}
+
+fn packet_hmac(mu: &[u8; 32], routing_info: &[u8], payment_hash: &[u8; 32]) -> [u8; 32] {
+    let mut hmac = HmacEngine::<Sha256>::new(mu);
+    hmac.input(routing_info);
+    hmac.input(payment_hash);
+    Hmac::from_engine(hmac).into_inner()
+}
+
+fn blinding_factor(ephemeral_key: &PublicKey, shared_secret: &[u8]) -> [u8; 32] {
+    let mut sha = Sha256::engine();
+    sha.input(&ephemeral_key.serialize());
+    sha.input(shared_secret);
+    Sha256::from_engine(sha).into_inner()
+}
+
+/// The onion taking a payment through hops, their node_id and what each of them reads
+pub fn construct(session_key: &SecretKey, hops: &[(PublicKey, HopPayload)], payment_hash: &[u8; 32]) -> Result<Vec<u8>, String> {
+    if hops.is_empty() || hops.len() > MAX_HOPS {
+        return Err(format!("An onion for {} hops", hops.len()));
+    }
+    // The ephemeral key each hop gets and its shared secret with us
+    let secp_ctx = Secp256k1::new();
+    let mut blinded_key = *session_key;
+    let mut secrets = Vec::new();
+    for (node_id, _) in hops {
+        let ephemeral_key = PublicKey::from_secret_key(&secp_ctx, &blinded_key);
+        let shared_secret = SharedSecret::new(node_id, &blinded_key);
+        blinded_key.mul_assign(&blinding_factor(&ephemeral_key, &shared_secret[..])).map_err(|e| e.to_string())?;
+        secrets.push((ephemeral_key, shared_secret));
+    }
+
+    // What the hops before the last one shift in, so that the last one's HMAC covers it
+    let mut filler = vec![0; (hops.len() - 1) * HOP_LEN];
+    for (i, (_, shared_secret)) in secrets[..hops.len() - 1].iter().enumerate() {
+        let mut stream = [0; ROUTING_INFO_LEN + HOP_LEN];
+        crypto::chacha20(&key(b"rho", &shared_secret[..]), &mut stream);
+        for (f, s) in filler[..(i + 1) * HOP_LEN].iter_mut().zip(&stream[(MAX_HOPS - i) * HOP_LEN..]) {
+            *f ^= s;
+        }
+    }
+
+    let mut routing_info = [0; ROUTING_INFO_LEN];
+    let mut next_hmac = [0; 32];
+    for (i, ((_, payload), (_, shared_secret))) in hops.iter().zip(&secrets).enumerate().rev() {
+        routing_info.copy_within(..ROUTING_INFO_LEN - HOP_LEN, HOP_LEN);
+        routing_info[..HOP_LEN].copy_from_slice(&payload.encode(&next_hmac));
+        crypto::chacha20(&key(b"rho", &shared_secret[..]), &mut routing_info);
+        if i == hops.len() - 1 {
+            routing_info[ROUTING_INFO_LEN - filler.len()..].copy_from_slice(&filler);
+        }
+        next_hmac = packet_hmac(&key(b"mu", &shared_secret[..]), &routing_info, payment_hash);
+    }
+
+    let mut packet = vec![0];
+    packet.extend_from_slice(&secrets[0].0.serialize());
+    packet.extend_from_slice(&routing_info);
+    packet.extend_from_slice(&next_hmac);
+    Ok(packet)
+}
+
+/// What the onion says to the node whose secret node_signer keeps
+pub fn peel(node_signer: &dyn NodeSigner, packet: &[u8], payment_hash: &[u8; 32]) -> Result<Peeled, String> {
+    if packet.len() != PACKET_LEN || packet[0] != 0 {
+        return Err("Unknown onion version".to_string());
+    }
+    let ephemeral_key = PublicKey::from_slice(&packet[1..34]).map_err(|_| "Bad onion key".to_string())?;
+    let shared_secret = node_signer.node_ecdh(&ephemeral_key);
+    let routing_info = &packet[34..34 + ROUTING_INFO_LEN];
+    if packet_hmac(&key(b"mu", &shared_secret), routing_info, payment_hash)[..] != packet[34 + ROUTING_INFO_LEN..] {
+        return Err("Bad onion HMAC".to_string());
+    }
+
+    let mut decrypted = [0; ROUTING_INFO_LEN + HOP_LEN];
+    decrypted[..ROUTING_INFO_LEN].copy_from_slice(routing_info);
+    crypto::chacha20(&key(b"rho", &shared_secret), &mut decrypted);
+    if decrypted[0] != 0 {
+        return Err(format!("Unknown realm {}", decrypted[0]));
+    }
+    let payload = HopPayload::decode(&decrypted[..HOP_LEN]);
+    let next_hmac = &decrypted[33..HOP_LEN];
+    if next_hmac == [0; 32] {
+        return Ok(Peeled::Final(payload));
+    }
+
+    let mut next_key = ephemeral_key;
+    next_key.mul_assign(&Secp256k1::verification_only(), &blinding_factor(&ephemeral_key, &shared_secret)).map_err(|e| e.to_string())?;
+    let mut next_packet = vec![0];
+    next_packet.extend_from_slice(&next_key.serialize());
+    next_packet.extend_from_slice(&decrypted[HOP_LEN..]);
+    next_packet.extend_from_slice(next_hmac);
+    Ok(Peeled::Forward { payload, next_packet })
+}
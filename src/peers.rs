@@ -0,0 +1,367 @@
+//! Outbound connections to the peers we were asked to connect to
+//!
+//! The list of peers is persisted in the datadir, on startup we reconnect to all of them and
+//! when a connection drops we keep retrying with exponential backoff. Peers we have channels with
+//! are tried first and never wait longer than CHANNEL_PEER_MAX_BACKOFF.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin_hashes::hex::FromHex;
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::ln::router::Router;
+use secp256k1::key::PublicKey;
+
+use crate::net;
+use crate::node::Node;
+use crate::tor;
+
+const DEFAULT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+// We don't want to wait that long for peers we have channels with
+const CHANNEL_PEER_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerAddress {
+    pub node_id: PublicKey,
+    pub addr: String,
+}
+
+pub fn parse_node_id(node_id_str: &str) -> Result<PublicKey, String> {
+    match Vec::<u8>::from_hex(node_id_str) {
+        Ok(bytes) => match PublicKey::from_slice(&bytes) {
+            Ok(node_id) => Ok(node_id),
+            Err(_) => Err(format!("'{}' is not a valid public key", node_id_str)),
+        },
+        Err(_) => Err(format!("'{}' is not hex", node_id_str)),
+    }
+}
+
+impl PeerAddress {
+    /// Parses '<pubkey>@<host:port>'
+    pub fn parse(peer_str: &str) -> Result<PeerAddress, String> {
+        let split: Vec<&str> = peer_str.trim().split('@').collect();
+        if split.len() != 2 || split[1].is_empty() {
+            return Err(format!("Expected <pubkey>@<host:port>, got '{}'", peer_str));
+        }
+        let node_id = parse_node_id(split[0])?;
+        Ok(PeerAddress { node_id, addr: split[1].to_string() })
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.node_id, self.addr)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerState {
+    /// Handshake and init done
+    Connected,
+    /// Socket open but the handshake or the init messages are still going
+    Handshake,
+    /// Not connected, next attempt not before the given instant
+    Backoff(Instant),
+}
+
+impl fmt::Display for PeerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerState::Connected => write!(f, "connected"),
+            PeerState::Handshake => write!(f, "handshake"),
+            PeerState::Backoff(until) => {
+                let remaining = until.saturating_duration_since(Instant::now());
+                write!(f, "backoff until {}ms", remaining.as_millis())
+            },
+        }
+    }
+}
+
+struct PeerEntry {
+    address: PeerAddress,
+    descriptor: Option<net::SocketDescriptor>,
+    // A connection attempt is going on, before there's a descriptor
+    connecting: bool,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+pub struct PeerConnector {
+    peer_manager: Arc<net::PeerManager>,
+    channel_managers: Vec<Arc<ChannelManager>>,
+    router: Arc<Router>,
+    peers_path: Option<PathBuf>,
+    proxy: Option<String>,
+    peers: Mutex<HashMap<PublicKey, PeerEntry>>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    stopped: AtomicBool,
+}
+
+impl PeerConnector {
+
+    /// Loads the persistent peer list from peers_path if there's one
+    pub fn new(node: &Node, peers_path: Option<PathBuf>) -> PeerConnector {
+        let connector = PeerConnector {
+            peer_manager: node.peer_manager.clone(),
+            channel_managers: node.chains.iter().map(|chain_node| chain_node.channel_manager.clone()).collect(),
+            router: node.router.clone(),
+            peers_path,
+            proxy: None,
+            peers: Mutex::new(HashMap::new()),
+            min_backoff: DEFAULT_MIN_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            stopped: AtomicBool::new(false),
+        };
+        connector.load();
+        connector
+    }
+
+    pub fn set_backoff(&mut self, min_backoff: Duration, max_backoff: Duration) {
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff;
+    }
+
+    /// SOCKS5 proxy for all outbound connections, needed for .onion addresses
+    pub fn set_proxy(&mut self, proxy: Option<String>) {
+        self.proxy = proxy;
+    }
+
+    fn load(&self) {
+        let path = match &self.peers_path {
+            Some(path) => path,
+            None => return,
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+        let mut peers = self.peers.lock().unwrap();
+        let now = Instant::now();
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            match PeerAddress::parse(line) {
+                Ok(address) => {
+                    peers.insert(address.node_id, PeerEntry {
+                        address,
+                        descriptor: None,
+                        connecting: false,
+                        backoff: self.min_backoff,
+                        next_attempt: now,
+                    });
+                },
+                Err(e) => println!("Ignoring line in {:?}: {}", path, e),
+            }
+        }
+    }
+
+    fn save(&self, peers: &HashMap<PublicKey, PeerEntry>) {
+        let path = match &self.peers_path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut contents = String::new();
+        for entry in peers.values() {
+            contents.push_str(&format!("{}\n", entry.address));
+        }
+        let tmp_path = path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path)) {
+            println!("Error saving peers to {:?}: {}", path, e);
+        }
+    }
+
+    /// Adds the peer to the persistent list and connects to it right away
+    pub fn connect(&self, address: PeerAddress) -> Result<(), String> {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            let min_backoff = self.min_backoff;
+            let entry = peers.entry(address.node_id).or_insert_with(|| PeerEntry {
+                address: address.clone(),
+                descriptor: None,
+                connecting: false,
+                backoff: min_backoff,
+                next_attempt: Instant::now(),
+            });
+            entry.address = address.clone();
+            self.save(&peers);
+        }
+        self.attempt(&address)
+    }
+
+    /// Connects to a node using the addresses in its node_announcement
+    pub fn connect_announced(&self, node_id: PublicKey) -> Result<(), String> {
+        let announced = match self.router.get_addresses(&node_id) {
+            Some(announced) => announced,
+            None => return Err(format!("No node_announcement for {}", node_id)),
+        };
+        let mut last_err = format!("No address we can use in {}'s node_announcement", node_id);
+        for netaddress in announced.iter() {
+            if self.proxy.is_none() && tor::onion_host(netaddress).is_some() {
+                continue;
+            }
+            let address = PeerAddress { node_id, addr: net::netaddress_to_string(netaddress) };
+            match self.connect(address) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn is_connected(&self, node_id: &PublicKey) -> bool {
+        self.peer_manager.get_peer_node_ids().contains(node_id)
+    }
+
+    // Connects unless we're connected or connecting to the peer already
+    fn attempt(&self, address: &PeerAddress) -> Result<(), String> {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            let entry = match peers.get_mut(&address.node_id) {
+                Some(entry) => entry,
+                None => return Ok(()),
+            };
+            let in_flight = entry.connecting || entry.descriptor.as_ref().is_some_and(|d| d.is_connected());
+            if in_flight || self.is_connected(&address.node_id) {
+                return Ok(());
+            }
+            entry.connecting = true;
+        }
+        let result = net::connect_outbound_via(self.peer_manager.clone(), address.node_id, &address.addr,
+                                               self.proxy.as_deref());
+        let mut peers = self.peers.lock().unwrap();
+        let entry = match peers.get_mut(&address.node_id) {
+            Some(entry) => entry,
+            None => return Ok(()), // Removed meanwhile
+        };
+        entry.connecting = false;
+        match result {
+            Ok(descriptor) if self.stopped.load(Ordering::Acquire) => {
+                net::disconnect(&self.peer_manager, &descriptor);
+                Ok(())
+            },
+            Ok(descriptor) => {
+                entry.descriptor = Some(descriptor);
+                Ok(())
+            },
+            Err(e) => {
+                entry.descriptor = None;
+                entry.next_attempt = Instant::now() + entry.backoff;
+                Err(format!("Cannot connect to {}: {}", address, e))
+            },
+        }
+    }
+
+    fn channel_peers(&self) -> Vec<PublicKey> {
+        self.channel_managers.iter().flat_map(|channel_manager| channel_manager.list_channels()).map(|c| c.remote_network_id).collect()
+    }
+
+    fn max_backoff(&self, has_channel: bool) -> Duration {
+        if has_channel {
+            std::cmp::min(CHANNEL_PEER_MAX_BACKOFF, self.max_backoff)
+        } else {
+            self.max_backoff
+        }
+    }
+
+    /// The peers whose next attempt is due, the ones we have channels with first
+    pub fn due_peers(&self) -> Vec<PeerAddress> {
+        let channel_peers = self.channel_peers();
+        let connected = self.peer_manager.get_peer_node_ids();
+        let now = Instant::now();
+        let peers = self.peers.lock().unwrap();
+        let mut due: Vec<(bool, PeerAddress)> = peers.values()
+            .filter(|entry| !entry.connecting && entry.descriptor.is_none() && entry.next_attempt <= now)
+            .filter(|entry| !connected.contains(&entry.address.node_id))
+            .map(|entry| (channel_peers.contains(&entry.address.node_id), entry.address.clone()))
+            .collect();
+        // false sorts before true
+        due.sort_by_key(|(has_channel, _)| !has_channel);
+        due.into_iter().map(|(_, address)| address).collect()
+    }
+
+    /// Retries the connections that are due, peers we have channels with first
+    pub fn reconnect_all(&self) {
+        let channel_peers = self.channel_peers();
+        let connected = self.peer_manager.get_peer_node_ids();
+        let now = Instant::now();
+        {
+            let mut peers = self.peers.lock().unwrap();
+            for entry in peers.values_mut() {
+                let max_backoff = self.max_backoff(channel_peers.contains(&entry.address.node_id));
+                if connected.contains(&entry.address.node_id) {
+                    entry.backoff = self.min_backoff;
+                    continue;
+                }
+                if let Some(descriptor) = &entry.descriptor {
+                    if descriptor.is_connected() {
+                        continue; // Still in the handshake
+                    }
+                    // Lost the connection, wait before trying again
+                    entry.descriptor = None;
+                    entry.next_attempt = now + entry.backoff;
+                    entry.backoff = std::cmp::min(entry.backoff * 2, max_backoff);
+                }
+            }
+        }
+
+        for address in self.due_peers() {
+            if self.stopped.load(Ordering::Acquire) {
+                return;
+            }
+            if let Some(entry) = self.peers.lock().unwrap().get_mut(&address.node_id) {
+                let max_backoff = self.max_backoff(channel_peers.contains(&address.node_id));
+                entry.backoff = std::cmp::min(entry.backoff * 2, max_backoff);
+            }
+            if let Err(e) = self.attempt(&address) {
+                println!("{}", e);
+            }
+        }
+    }
+
+    pub fn get_state(&self, node_id: &PublicKey) -> Option<PeerState> {
+        let peers = self.peers.lock().unwrap();
+        let entry = peers.get(node_id)?;
+        if self.is_connected(node_id) {
+            return Some(PeerState::Connected);
+        }
+        match &entry.descriptor {
+            Some(descriptor) if descriptor.is_connected() => Some(PeerState::Handshake),
+            _ if entry.connecting => Some(PeerState::Handshake),
+            _ => Some(PeerState::Backoff(entry.next_attempt)),
+        }
+    }
+
+    pub fn list_peers(&self) -> Vec<(PeerAddress, PeerState)> {
+        let addresses: Vec<PeerAddress> = self.peers.lock().unwrap().values().map(|e| e.address.clone()).collect();
+        addresses.into_iter().filter_map(|address| {
+            let state = self.get_state(&address.node_id)?;
+            Some((address, state))
+        }).collect()
+    }
+
+    /// Keeps calling reconnect_all in the background, until stop
+    pub fn start(connector: Arc<PeerConnector>, interval: Duration) {
+        thread::spawn(move || {
+            while !connector.stopped.load(Ordering::Acquire) {
+                connector.reconnect_all();
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    /// Stops reconnecting and drops the connections we opened
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        for entry in self.peers.lock().unwrap().values_mut() {
+            if let Some(descriptor) = entry.descriptor.take() {
+                net::disconnect(&self.peer_manager, &descriptor);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Which channels our peers can open to us
+//!
+//! ChainFilter checks every open_channel against the policy before rust-lightning sees it, and
+//! answers the ones it refuses with an error message giving the reason. The confirmations of a
+//! chain are the minimum_depth rust-lightning asks of the peers funding channels to us there.
+
+use std::collections::{HashMap, HashSet};
+
+use secp256k1::key::PublicKey;
+
+/// rust-lightning refuses channels of 2^24 satoshis or more
+pub const MAX_CHANNEL_SIZE: u64 = (1 << 24) - 1;
+
+/// What a peer can open on one chain
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainPolicy {
+    /// Whether peers can open channels on the chain at all
+    pub accept: bool,
+    pub min_channel_size: u64,
+    pub max_channel_size: u64,
+    /// Confirmations of the funding transaction before a channel to us can be used
+    pub confirmations: u32,
+    /// Channels with the same peer on the chain that can't be used yet, before the new one
+    pub max_pending: usize,
+}
+
+impl Default for ChainPolicy {
+    fn default() -> Self {
+        ChainPolicy {
+            accept: true,
+            min_channel_size: 20_000,
+            max_channel_size: MAX_CHANNEL_SIZE,
+            confirmations: 6,
+            max_pending: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChannelPolicy {
+    /// By chain name, the default policy for chains that aren't here
+    pub chains: HashMap<String, ChainPolicy>,
+    /// Only these peers can open channels to us, any peer when empty
+    pub allow: HashSet<PublicKey>,
+    pub deny: HashSet<PublicKey>,
+}
+
+impl ChannelPolicy {
+    pub fn chain(&self, chain: &str) -> ChainPolicy {
+        self.chains.get(chain).cloned().unwrap_or_default()
+    }
+
+    /// Why node_id, which has pending channels with us on chain already, can't open one of
+    /// funding_satoshis there
+    pub fn check_open(&self, chain: &str, node_id: &PublicKey, funding_satoshis: u64, pending: usize) -> Result<(), String> {
+        let chain_policy = self.chain(chain);
+        if !chain_policy.accept {
+            return Err(format!("We don't accept channels on chain {}", chain));
+        }
+        if self.deny.contains(node_id) || (!self.allow.is_empty() && !self.allow.contains(node_id)) {
+            return Err("We don't accept channels from you".to_string());
+        }
+        if funding_satoshis < chain_policy.min_channel_size {
+            return Err(format!("Channel of {} sat is below our minimum of {} sat", funding_satoshis, chain_policy.min_channel_size));
+        }
+        if funding_satoshis > chain_policy.max_channel_size {
+            return Err(format!("Channel of {} sat is above our maximum of {} sat", funding_satoshis, chain_policy.max_channel_size));
+        }
+        if pending >= chain_policy.max_pending {
+            return Err(format!("Too many pending channels with us, {} already", pending));
+        }
+        Ok(())
+    }
+}
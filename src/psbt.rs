@@ -0,0 +1,74 @@
+//! BIP174 PSBTs for funding channels from external wallets
+//!
+//! The PSBT we hand out only has the funding output. The external wallet adds its inputs and
+//! change, signs, finalizes and gives it back in base64 like bitcoind's walletprocesspsbt does.
+//! All the inputs must be segwit ones: the channel's commitment transactions spend the funding
+//! transaction by txid, which a malleated signature would change.
+
+use std::convert::TryFrom;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::consensus::encode;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+
+use crate::base64;
+
+pub fn to_base64(psbt: &PartiallySignedTransaction) -> String {
+    base64::encode(&encode::serialize(psbt))
+}
+
+pub fn from_base64(encoded: &str) -> Result<PartiallySignedTransaction, String> {
+    encode::deserialize(&base64::decode(encoded)?).map_err(|e| format!("Bad PSBT: {}", e))
+}
+
+/// A PSBT with nothing but the funding output, for the external wallet to fund
+pub fn funding_psbt(output_script: &Script, value: u64) -> PartiallySignedTransaction {
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: Vec::new(),
+        output: vec![TxOut { value, script_pubkey: output_script.clone() }],
+    };
+    PartiallySignedTransaction::from_unsigned_tx(tx).expect("Nothing to be signed yet")
+}
+
+/// The index of the output paying value to output_script, which must be the only one paying
+/// to it
+pub fn find_funding_output(psbt: &PartiallySignedTransaction, output_script: &Script, value: u64) -> Result<u16, String> {
+    let outputs: Vec<(usize, &TxOut)> = psbt.global.unsigned_tx.output.iter().enumerate()
+        .filter(|(_, output)| output.script_pubkey == *output_script).collect();
+    match outputs.as_slice() {
+        [] => Err("The PSBT doesn't pay to the channel".to_string()),
+        [(_, output)] if output.value != value => Err(format!("The PSBT pays {} satoshis to the channel instead of {}", output.value, value)),
+        // rust-lightning's outpoints only go that far
+        [(index, _)] => u16::try_from(*index).map_err(|_| format!("The channel is output {} of the PSBT", index)),
+        _ => Err("The PSBT pays to the channel more than once".to_string()),
+    }
+}
+
+/// The signed transaction of a finalized PSBT with only segwit inputs
+pub fn extract_funding_transaction(psbt: PartiallySignedTransaction) -> Result<Transaction, String> {
+    if psbt.inputs.is_empty() {
+        return Err("The PSBT has no inputs".to_string());
+    }
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        let witness_empty = input.final_script_witness.as_ref().is_none_or(|witness| witness.is_empty());
+        if witness_empty {
+            if input.final_script_sig.is_some() {
+                return Err(format!("Input {} isn't segwit, the funding transaction could be malleated", index));
+            }
+            return Err(format!("Input {} isn't finalized", index));
+        }
+        // Only P2SH wrapped segwit has one, and it's a single push of a version 0 witness program
+        if let Some(script_sig) = &input.final_script_sig {
+            let bytes = script_sig.as_bytes();
+            let wrapped_program = (bytes.len() == 23 || bytes.len() == 35) && bytes[0] as usize == bytes.len() - 1
+                && bytes[1] == 0 && bytes[2] as usize == bytes.len() - 3;
+            if !bytes.is_empty() && !wrapped_program {
+                return Err(format!("Input {} isn't segwit, the funding transaction could be malleated", index));
+            }
+        }
+    }
+    Ok(psbt.extract_tx())
+}
@@ -0,0 +1,293 @@
+//! Exchange rates between chains, for the payments chain hops forward from one to another
+//!
+//! The rate from chain a to chain b is how many msat on b a msat on a is worth, kept in
+//! millionths so that what a payer pays and what a hop checks round the same way. On top of it
+//! a hop keeps a spread, in millionths of what it forwards.
+//!
+//! Our rates come from -rate=<from>:<to>:<rate>[:<spread>], and from -ratefile with one of
+//! those per line, read again whenever the file changes. A chain hop quotes its rates to the
+//! chain of each of its announced channels in the excess data of the channel's channel_updates,
+//! payers going through the channel use them instead of their own.
+//!
+//! The blocks left before an HTLC expires are compared across chains in time, at the seconds
+//! between blocks of each chain from -blockinterval.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+
+/// A rate of 1
+pub const RATE_ONE: u64 = 1_000_000;
+
+/// The largest spread, a tenth of what's forwarded
+pub const MAX_SPREAD_MILLIONTHS: u64 = RATE_ONE / 10;
+
+/// The seconds between blocks of a chain without -blockinterval, bitcoin's
+pub const DEFAULT_BLOCK_INTERVAL: u32 = 600;
+
+// What the quotes in a channel_update's excess data start with
+const QUOTES_MARKER: &[u8] = b"rates";
+// The chain_hashes of the two chains, the rate and the spread
+const QUOTE_LEN: usize = 32 + 32 + 8 + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    /// The msat on the chain forwarded to a msat on the other one is worth, in millionths
+    pub millionths: u64,
+    /// What the hop keeps, in millionths of the amount it forwards
+    pub spread_millionths: u64,
+}
+
+impl Rate {
+
+    pub fn new(millionths: u64, spread_millionths: u64) -> Rate {
+        Rate { millionths, spread_millionths }
+    }
+
+    /// What amount_msat is worth on the other chain, rounded down
+    pub fn convert(&self, amount_msat: u64) -> Result<u64, String> {
+        u64::try_from(amount_msat as u128 * self.millionths as u128 / RATE_ONE as u128)
+            .map_err(|_| format!("{} msat at a rate of {} millionths is too much", amount_msat, self.millionths))
+    }
+
+    /// The spread on forwarding amount_msat, rounded up
+    pub fn spread(&self, amount_msat: u64) -> Result<u64, String> {
+        u64::try_from((amount_msat as u128 * self.spread_millionths as u128).div_ceil(RATE_ONE as u128))
+            .map_err(|_| format!("A spread of {} millionths on {} msat is too much", self.spread_millionths, amount_msat))
+    }
+
+    /// What it takes to have amount_msat forwarded on the other chain, the spread included,
+    /// rounded up
+    pub fn cost(&self, amount_msat: u64) -> Result<u64, String> {
+        if self.millionths == 0 {
+            return Err("A rate of 0".to_string());
+        }
+        let asked = amount_msat as u128 + self.spread(amount_msat)? as u128;
+        u64::try_from((asked * RATE_ONE as u128).div_ceil(self.millionths as u128))
+            .map_err(|_| format!("{} msat at a rate of {} millionths is too much", amount_msat, self.millionths))
+    }
+
+    // What rates can be, set or quoted
+    fn check(&self) -> Result<(), String> {
+        if self.millionths == 0 {
+            return Err("A rate of 0".to_string());
+        }
+        if self.spread_millionths > MAX_SPREAD_MILLIONTHS {
+            return Err(format!("A spread over {} millionths", MAX_SPREAD_MILLIONTHS));
+        }
+        Ok(())
+    }
+}
+
+// "1.05" in millionths
+fn parse_millionths(value: &str, what: &str) -> Result<u64, String> {
+    let bad = || format!("Bad {} {}", what, value);
+    let (units, decimals) = value.split_once('.').unwrap_or((value, ""));
+    if units.is_empty() && decimals.is_empty() || decimals.len() > 6 || !(units.chars().chain(decimals.chars())).all(|c| c.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let units: u64 = if units.is_empty() { 0 } else { units.parse().map_err(|_| bad())? };
+    let decimals: u64 = if decimals.is_empty() { 0 } else { format!("{:0<6}", decimals).parse().map_err(|_| bad())? };
+    units.checked_mul(RATE_ONE).and_then(|millionths| millionths.checked_add(decimals)).ok_or_else(bad)
+}
+
+/// '<from>:<to>:<rate>[:<spread>]', like 'aaa:bbb:1.05:0.001'
+pub fn parse_rate(value: &str) -> Result<(String, String, Rate), String> {
+    let parts: Vec<&str> = value.split(':').collect();
+    if parts.len() != 3 && parts.len() != 4 {
+        return Err(format!("A rate is <from>:<to>:<rate>[:<spread>], not {}", value));
+    }
+    if parts[0].is_empty() || parts[0] == parts[1] {
+        return Err(format!("A rate is between two chains, not {}", value));
+    }
+    let millionths = parse_millionths(parts[2], "rate")?;
+    let spread_millionths = match parts.get(3) {
+        Some(spread) => parse_millionths(spread, "spread")?,
+        None => 0,
+    };
+    let rate = Rate::new(millionths, spread_millionths);
+    rate.check().map_err(|e| format!("{} in {}", e, value))?;
+    Ok((parts[0].to_string(), parts[1].to_string(), rate))
+}
+
+/// The excess data of a channel_update quoting rates, from and to the chains with those
+/// chain_hashes
+pub fn encode_quotes(quotes: &[(Sha256dHash, Sha256dHash, Rate)]) -> Vec<u8> {
+    if quotes.is_empty() {
+        return Vec::new();
+    }
+    let mut data = QUOTES_MARKER.to_vec();
+    for (from, to, rate) in quotes {
+        data.extend_from_slice(&from[..]);
+        data.extend_from_slice(&to[..]);
+        data.extend_from_slice(&rate.millionths.to_be_bytes());
+        data.extend_from_slice(&rate.spread_millionths.to_be_bytes());
+    }
+    data
+}
+
+/// The quotes in a channel_update's excess data, none if it has something else or a rate of 0
+/// or a spread over MAX_SPREAD_MILLIONTHS
+pub fn decode_quotes(data: &[u8]) -> Vec<(Sha256dHash, Sha256dHash, Rate)> {
+    let quotes = match data.strip_prefix(QUOTES_MARKER) {
+        Some(quotes) if quotes.len() % QUOTE_LEN == 0 => quotes,
+        _ => return Vec::new(),
+    };
+    let quotes: Vec<(Sha256dHash, Sha256dHash, Rate)> = quotes.chunks(QUOTE_LEN).map(|quote| {
+        let mut millionths = [0; 8];
+        millionths.copy_from_slice(&quote[64..72]);
+        let mut spread_millionths = [0; 8];
+        spread_millionths.copy_from_slice(&quote[72..80]);
+        (Sha256dHash::from_slice(&quote[..32]).unwrap(), Sha256dHash::from_slice(&quote[32..64]).unwrap(),
+         Rate::new(u64::from_be_bytes(millionths), u64::from_be_bytes(spread_millionths)))
+    }).collect();
+    if quotes.iter().any(|(_, _, rate)| rate.check().is_err()) {
+        return Vec::new();
+    }
+    quotes
+}
+
+// The chains a payment goes from and to
+type Pair = (String, String);
+
+#[derive(Default)]
+struct OwnRates {
+    configured: HashMap<Pair, Rate>,
+    // Over the configured ones
+    from_file: HashMap<Pair, Rate>,
+    file: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    // Goes up with every change
+    version: u64,
+}
+
+#[derive(Default)]
+pub struct RateTable {
+    own: RwLock<OwnRates>,
+    // The rates quoted for going through a channel in one direction, by the Router's id for the
+    // channel and direction (0 from node_id_1)
+    quotes: RwLock<HashMap<(u64, u8), HashMap<Pair, Rate>>>,
+    // Seconds between blocks, by chain
+    block_intervals: RwLock<HashMap<String, u32>>,
+}
+
+fn read_rate_file(path: &Path) -> Result<HashMap<Pair, Rate>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+    let mut rates = HashMap::new();
+    for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (from, to, rate) = parse_rate(line).map_err(|e| format!("{:?}: {}", path, e))?;
+        rates.insert((from, to), rate);
+    }
+    Ok(rates)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+impl RateTable {
+
+    pub fn new() -> RateTable {
+        RateTable::default()
+    }
+
+    /// Our rate from chain from to chain to, unless the rate file has one
+    pub fn set(&self, from: &str, to: &str, rate: Rate) {
+        let mut own = self.own.write().unwrap();
+        own.configured.insert((from.to_string(), to.to_string()), rate);
+        own.version += 1;
+    }
+
+    /// Takes the rates in path, and again whenever refresh finds it changed
+    pub fn set_file(&self, path: &Path) -> Result<(), String> {
+        let from_file = read_rate_file(path)?;
+        let mut own = self.own.write().unwrap();
+        own.from_file = from_file;
+        own.file = Some(path.to_path_buf());
+        own.modified = modified(path);
+        own.version += 1;
+        Ok(())
+    }
+
+    /// Reads the rate file again if it changed, true if it did. The rates stay as they were if
+    /// it can't be read.
+    pub fn refresh(&self) -> Result<bool, String> {
+        let path = {
+            let own = self.own.read().unwrap();
+            match &own.file {
+                Some(path) if modified(path) != own.modified => path.clone(),
+                _ => return Ok(false),
+            }
+        };
+        let from_file = read_rate_file(&path);
+        let mut own = self.own.write().unwrap();
+        own.modified = modified(&path);
+        own.from_file = from_file?;
+        own.version += 1;
+        Ok(true)
+    }
+
+    /// Changes every time our rates do
+    pub fn version(&self) -> u64 {
+        self.own.read().unwrap().version
+    }
+
+    /// Our rate, a chain is worth itself
+    pub fn get(&self, from: &str, to: &str) -> Option<Rate> {
+        if from == to {
+            return Some(Rate::new(RATE_ONE, 0));
+        }
+        let own = self.own.read().unwrap();
+        let pair = (from.to_string(), to.to_string());
+        own.from_file.get(&pair).or_else(|| own.configured.get(&pair)).cloned()
+    }
+
+    /// All our rates, as (from, to, rate)
+    pub fn list(&self) -> Vec<(String, String, Rate)> {
+        let own = self.own.read().unwrap();
+        let mut rates = own.configured.clone();
+        rates.extend(own.from_file.clone());
+        let mut list: Vec<(String, String, Rate)> = rates.into_iter().map(|((from, to), rate)| (from, to, rate)).collect();
+        list.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        list
+    }
+
+    /// The rates quoted by the node at one end of a channel for going through it, replacing the
+    /// ones it quoted before. Channels go by the Router's id, see graph.rs.
+    pub fn set_quotes(&self, router_id: u64, direction: u8, quotes: Vec<(String, String, Rate)>) {
+        let mut all_quotes = self.quotes.write().unwrap();
+        if quotes.is_empty() {
+            all_quotes.remove(&(router_id, direction));
+        } else {
+            all_quotes.insert((router_id, direction), quotes.into_iter().map(|(from, to, rate)| ((from, to), rate)).collect());
+        }
+    }
+
+    /// The seconds between blocks of chain
+    pub fn set_block_interval(&self, chain: &str, seconds: u32) {
+        self.block_intervals.write().unwrap().insert(chain.to_string(), seconds);
+    }
+
+    pub fn block_interval(&self, chain: &str) -> u32 {
+        self.block_intervals.read().unwrap().get(chain).cloned().unwrap_or(DEFAULT_BLOCK_INTERVAL)
+    }
+
+    /// The blocks on chain to lasting at least as long as blocks on chain from
+    pub fn convert_blocks(&self, from: &str, to: &str, blocks: u32) -> u32 {
+        let seconds = blocks as u64 * self.block_interval(from) as u64;
+        u32::try_from(seconds.div_ceil(self.block_interval(to) as u64)).unwrap_or(u32::MAX)
+    }
+
+    /// The rate for going through a channel in direction, the one quoted or ours
+    pub fn quote(&self, router_id: u64, direction: u8, from: &str, to: &str) -> Option<Rate> {
+        let quoted = self.quotes.read().unwrap().get(&(router_id, direction))
+            .and_then(|quotes| quotes.get(&(from.to_string(), to.to_string())).cloned());
+        quoted.or_else(|| self.get(from, to))
+    }
+}
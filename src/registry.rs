@@ -0,0 +1,412 @@
+//! The invoices we created, with their preimages, and what became of them
+//!
+//! Every invoice is a line in <datadir>/invoices: its state, preimage, the msat we received for it,
+//! the cltv_expiry of its held payment ("-" for what we don't know) and the invoice itself. The
+//! file is written durably before an invoice is handed out and before a payment is claimed, a
+//! preimage must not be lost once it's known. Lines for chains the daemon doesn't operate on
+//! anymore are kept as they are.
+//!
+//! Hold invoices are created from a payment hash alone. Their payments are held until settle
+//! brings the preimage or cancel fails them, Channels::sync takes what to do with them from
+//! take_resolved. rust-lightning 0.0.9 doesn't tell us the CLTV of the HTLCs it holds, so
+//! ChainFilter takes it from their update_add_htlc. HTLCs expiring less than
+//! HOLD_MIN_FINAL_CLTV_EXPIRY blocks after they arrive are failed, and a held payment is canceled
+//! HOLD_CANCEL_BLOCKS before its earliest HTLC expires, so that the payer doesn't have to close
+//! the channel to get its HTLC back. The held HTLCs are in the ChannelManager, the ones settled or
+//! canceled are resolved again after a restart in case they weren't before.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+use lightning::chain::keysinterface::NodeSigner;
+use rand::Rng;
+
+use crate::invoice::{Description, Invoice};
+use crate::monitor;
+
+/// The min_final_cltv_expiry of hold invoices, the blocks the preimage has to show up in
+pub const HOLD_MIN_FINAL_CLTV_EXPIRY: u64 = 80;
+
+/// A held payment is canceled when its HTLC is this close to expire
+pub const HOLD_CANCEL_BLOCKS: u64 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvoiceState {
+    /// Waiting for its payment
+    Open,
+    /// A hold invoice whose payment we hold until it's settled or canceled
+    Accepted,
+    /// Paid, the preimage is out
+    Settled,
+    /// Not paid before its expiry
+    Expired,
+    /// We don't want it paid anymore
+    Canceled,
+}
+
+impl fmt::Display for InvoiceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvoiceState::Open => write!(f, "open"),
+            InvoiceState::Accepted => write!(f, "accepted"),
+            InvoiceState::Settled => write!(f, "settled"),
+            InvoiceState::Expired => write!(f, "expired"),
+            InvoiceState::Canceled => write!(f, "canceled"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvoiceEntry {
+    pub invoice: Invoice,
+    /// The invoice as we handed it out
+    pub encoded: String,
+    /// None for hold invoices until they're settled
+    pub preimage: Option<[u8; 32]>,
+    pub state: InvoiceState,
+    /// What the payments that settled it, or are held for it, brought
+    pub received_msat: Option<u64>,
+    /// The earliest cltv_expiry of the HTLCs held for a hold invoice
+    pub htlc_expiry: Option<u32>,
+}
+
+impl fmt::Display for InvoiceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.invoice.payment_hash.to_hex(), self.invoice.chain, self.state)?;
+        if let Some(received_msat) = self.received_msat {
+            write!(f, " received_msat {}", received_msat)?;
+        }
+        write!(f, " {}", self.encoded)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// The state stored, open invoices past their expiry are expired
+fn current_state(entry: &InvoiceEntry) -> InvoiceState {
+    if entry.state == InvoiceState::Open && entry.invoice.expires_at() < now() {
+        InvoiceState::Expired
+    } else {
+        entry.state
+    }
+}
+
+fn optional<T: std::str::FromStr>(word: &str) -> Result<Option<T>, String> {
+    match word {
+        "-" => Ok(None),
+        _ => word.parse().map(Some).map_err(|_| format!("Bad number {}", word)),
+    }
+}
+
+fn parse_line(line: &str, chains: &[String]) -> Result<InvoiceEntry, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 5 {
+        return Err("Expected <state> <preimage> <received_msat> <htlc_expiry> <invoice>".to_string());
+    }
+    let state = match words[0] {
+        "open" => InvoiceState::Open,
+        "accepted" => InvoiceState::Accepted,
+        "settled" => InvoiceState::Settled,
+        "canceled" => InvoiceState::Canceled,
+        _ => return Err(format!("Unknown state {}", words[0])),
+    };
+    let invoice = Invoice::decode(words[4], chains)?;
+    let preimage = match words[1] {
+        "-" => None,
+        hex => Some(parse_preimage(hex, &invoice.payment_hash)?),
+    };
+    Ok(InvoiceEntry {
+        invoice,
+        encoded: words[4].to_string(),
+        preimage,
+        state,
+        received_msat: optional(words[2])?,
+        htlc_expiry: optional(words[3])?,
+    })
+}
+
+fn parse_preimage(hex: &str, payment_hash: &[u8; 32]) -> Result<[u8; 32], String> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|_| format!("Bad preimage {}", hex))?;
+    if bytes.len() != 32 {
+        return Err(format!("Bad preimage {}", hex));
+    }
+    let mut preimage = [0; 32];
+    preimage.copy_from_slice(&bytes);
+    if Sha256::hash(&preimage).into_inner() != *payment_hash {
+        return Err("The preimage isn't the invoice's".to_string());
+    }
+    Ok(preimage)
+}
+
+/// The payment hash of a held payment, with the preimage to claim it or None to fail it
+pub type ResolvedPayment = ([u8; 32], Option<[u8; 32]>);
+
+struct Invoices {
+    by_hash: HashMap<[u8; 32], InvoiceEntry>,
+    // Lines we can't make sense of now, for chains we don't operate on or anything else
+    other_lines: Vec<String>,
+    // The held payments to claim, with their preimage, or to fail, by chain
+    resolved: HashMap<String, Vec<ResolvedPayment>>,
+}
+
+impl Invoices {
+    fn resolve(&mut self, entry: &InvoiceEntry) {
+        let preimage = if entry.state == InvoiceState::Settled { entry.preimage } else { None };
+        self.resolved.entry(entry.invoice.chain.clone()).or_default().push((entry.invoice.payment_hash, preimage));
+    }
+}
+
+pub struct InvoiceRegistry {
+    chains: Vec<String>,
+    node_signer: Arc<dyn NodeSigner>,
+    path: Option<PathBuf>,
+    invoices: Mutex<Invoices>,
+    // Notified whenever an invoice changes state
+    changed: Condvar,
+}
+
+impl InvoiceRegistry {
+
+    /// Invoices for chains signed with node_signer's node secret, kept in path if there's one
+    pub fn new<S: AsRef<str>>(chains: &[S], node_signer: Arc<dyn NodeSigner>, path: Option<&Path>) -> Result<InvoiceRegistry, String> {
+        let chains: Vec<String> = chains.iter().map(|chain| chain.as_ref().to_string()).collect();
+        let mut invoices = Invoices { by_hash: HashMap::new(), other_lines: Vec::new(), resolved: HashMap::new() };
+        if let Some(path) = path {
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(format!("Cannot read {:?}: {}", path, e)),
+            };
+            for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                match parse_line(line, &chains) {
+                    Ok(entry) => {
+                        if entry.htlc_expiry.is_some() && entry.state != InvoiceState::Accepted {
+                            invoices.resolve(&entry);
+                        }
+                        invoices.by_hash.insert(entry.invoice.payment_hash, entry);
+                    },
+                    Err(e) => {
+                        println!("Keeping line in {:?} as it is: {}", path, e);
+                        invoices.other_lines.push(line.to_string());
+                    },
+                }
+            }
+        }
+        Ok(InvoiceRegistry { chains, node_signer, path: path.map(Path::to_path_buf), invoices: Mutex::new(invoices), changed: Condvar::new() })
+    }
+
+    fn save(&self, invoices: &Invoices) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut entries: Vec<&InvoiceEntry> = invoices.by_hash.values().collect();
+        entries.sort_by_key(|entry| entry.invoice.timestamp);
+        let mut contents = String::new();
+        for entry in entries {
+            let preimage = entry.preimage.map_or("-".to_string(), |preimage| preimage.to_hex());
+            let received_msat = entry.received_msat.map_or("-".to_string(), |msat| msat.to_string());
+            let htlc_expiry = entry.htlc_expiry.map_or("-".to_string(), |expiry| expiry.to_string());
+            contents.push_str(&format!("{} {} {} {} {}\n", entry.state, preimage, received_msat, htlc_expiry, entry.encoded));
+        }
+        for line in &invoices.other_lines {
+            contents.push_str(&format!("{}\n", line));
+        }
+        monitor::write_durably(path, contents.as_bytes()).map_err(|e| format!("Cannot save the invoices to {:?}: {}", path, e))
+    }
+
+    /// A new invoice with a random preimage, for any amount if amount_msat is None
+    pub fn create(&self, chain: &str, amount_msat: Option<u64>, description: Description, expiry: u64) -> Result<InvoiceEntry, String> {
+        let preimage: [u8; 32] = rand::thread_rng().gen();
+        self.add(chain, Sha256::hash(&preimage).into_inner(), Some(preimage), amount_msat, description, expiry)
+    }
+
+    /// A new hold invoice, whose payment waits for settle to bring the preimage of payment_hash
+    pub fn create_hold(&self, chain: &str, payment_hash: [u8; 32], amount_msat: Option<u64>, description: Description, expiry: u64) -> Result<InvoiceEntry, String> {
+        self.add(chain, payment_hash, None, amount_msat, description, expiry)
+    }
+
+    fn add(&self, chain: &str, payment_hash: [u8; 32], preimage: Option<[u8; 32]>, amount_msat: Option<u64>, description: Description,
+           expiry: u64) -> Result<InvoiceEntry, String> {
+        if !self.chains.iter().any(|c| c == chain) {
+            return Err(format!("We don't operate on chain {}", chain));
+        }
+        if amount_msat == Some(0) {
+            return Err("An invoice for 0 msat".to_string());
+        }
+        let payee = self.node_signer.get_node_id();
+        let mut invoice = Invoice::new(chain, payee, payment_hash, description);
+        invoice.amount_msat = amount_msat;
+        invoice.expiry = expiry;
+        if preimage.is_none() {
+            invoice.min_final_cltv_expiry = HOLD_MIN_FINAL_CLTV_EXPIRY;
+        }
+        let encoded = invoice.encode(&*self.node_signer)?;
+        let entry = InvoiceEntry { invoice, encoded, preimage, state: InvoiceState::Open, received_msat: None, htlc_expiry: None };
+
+        let mut invoices = self.invoices.lock().unwrap();
+        if invoices.by_hash.contains_key(&payment_hash) {
+            return Err("There's already an invoice with this payment hash".to_string());
+        }
+        invoices.by_hash.insert(entry.invoice.payment_hash, entry.clone());
+        if let Err(e) = self.save(&invoices) {
+            invoices.by_hash.remove(&entry.invoice.payment_hash);
+            return Err(e);
+        }
+        Ok(entry)
+    }
+
+    pub fn get(&self, payment_hash: &[u8; 32]) -> Option<InvoiceEntry> {
+        self.invoices.lock().unwrap().by_hash.get(payment_hash).map(|entry| InvoiceEntry { state: current_state(entry), ..entry.clone() })
+    }
+
+    /// Every invoice, oldest first
+    pub fn list(&self) -> Vec<InvoiceEntry> {
+        let invoices = self.invoices.lock().unwrap();
+        let mut entries: Vec<InvoiceEntry> = invoices.by_hash.values().map(|entry| InvoiceEntry { state: current_state(entry), ..entry.clone() }).collect();
+        entries.sort_by_key(|entry| entry.invoice.timestamp);
+        entries
+    }
+
+    /// What to do with a payment of amount_msat received on chain at height, in an HTLC expiring
+    /// at cltv_expiry: claim it with the preimage, once the invoice is settled on disk, or hold it
+    /// for a hold invoice. The payment must be failed if it doesn't pay an invoice.
+    pub fn receive(&self, chain: &str, payment_hash: &[u8; 32], amount_msat: u64, height: u32, cltv_expiry: Option<u32>)
+                   -> Result<Option<[u8; 32]>, String> {
+        let mut invoices = self.invoices.lock().unwrap();
+        let entry = invoices.by_hash.get_mut(payment_hash).ok_or("No invoice with this payment hash")?;
+        if entry.invoice.chain != chain {
+            return Err(format!("The invoice is for chain {}", entry.invoice.chain));
+        }
+        match current_state(entry) {
+            // rust-lightning holds every payment for a hash together, they're all claimed or failed
+            InvoiceState::Open | InvoiceState::Accepted => {},
+            state => return Err(format!("The invoice is {}", state)),
+        }
+        if let Some(invoice_msat) = entry.invoice.amount_msat {
+            // BOLT4 lets us take up to twice the amount, more is likely a mistake
+            if amount_msat < invoice_msat || amount_msat > 2 * invoice_msat {
+                return Err(format!("Payment of {} msat for an invoice of {} msat", amount_msat, invoice_msat));
+            }
+        }
+        // A held HTLC must leave time to get the preimage
+        let hold_expiry = match (entry.preimage, cltv_expiry) {
+            (Some(_), _) => None,
+            (None, Some(cltv_expiry)) if cltv_expiry as u64 >= height as u64 + HOLD_MIN_FINAL_CLTV_EXPIRY => Some(cltv_expiry),
+            (None, Some(cltv_expiry)) => {
+                return Err(format!("An HTLC expiring at {}, less than {} blocks after {}", cltv_expiry, HOLD_MIN_FINAL_CLTV_EXPIRY, height));
+            },
+            (None, None) => return Err("An HTLC whose expiry we don't know".to_string()),
+        };
+        let before = entry.clone();
+        entry.received_msat = Some(entry.received_msat.unwrap_or(0) + amount_msat);
+        let preimage = entry.preimage;
+        match hold_expiry {
+            None => entry.state = InvoiceState::Settled,
+            Some(cltv_expiry) => {
+                entry.state = InvoiceState::Accepted;
+                entry.htlc_expiry = Some(entry.htlc_expiry.map_or(cltv_expiry, |expiry| expiry.min(cltv_expiry)));
+            },
+        }
+        if let Err(e) = self.save(&invoices) {
+            invoices.by_hash.insert(*payment_hash, before);
+            return Err(e);
+        }
+        self.changed.notify_all();
+        Ok(preimage)
+    }
+
+    /// Settles the hold invoice whose payment hash preimage is, once its payment is held
+    pub fn settle(&self, preimage: [u8; 32]) -> Result<(), String> {
+        let payment_hash = Sha256::hash(&preimage).into_inner();
+        self.resolve(&payment_hash, |entry| match entry.state {
+            InvoiceState::Accepted => {
+                entry.preimage = Some(preimage);
+                entry.state = InvoiceState::Settled;
+                Ok(())
+            },
+            InvoiceState::Open if entry.preimage.is_none() => Err("The invoice wasn't paid yet".to_string()),
+            InvoiceState::Open => Err("The invoice isn't a hold invoice".to_string()),
+            state => Err(format!("The invoice is {}", state)),
+        })
+    }
+
+    /// Payments for an open invoice are failed once it's canceled, the held ones too
+    pub fn cancel(&self, payment_hash: &[u8; 32]) -> Result<(), String> {
+        self.resolve(payment_hash, |entry| match entry.state {
+            InvoiceState::Open | InvoiceState::Accepted => {
+                entry.state = InvoiceState::Canceled;
+                Ok(())
+            },
+            state => Err(format!("The invoice is {}", state)),
+        })
+    }
+
+    // Changes the invoice with change, and has its held payment resolved
+    fn resolve<F: FnOnce(&mut InvoiceEntry) -> Result<(), String>>(&self, payment_hash: &[u8; 32], change: F) -> Result<(), String> {
+        let mut invoices = self.invoices.lock().unwrap();
+        let entry = invoices.by_hash.get_mut(payment_hash).ok_or("No invoice with this payment hash")?;
+        let before = entry.clone();
+        entry.state = current_state(entry);
+        if let Err(e) = change(entry) {
+            *entry = before;
+            return Err(e);
+        }
+        let entry = entry.clone();
+        if let Err(e) = self.save(&invoices) {
+            invoices.by_hash.insert(*payment_hash, before);
+            return Err(e);
+        }
+        if before.state == InvoiceState::Accepted {
+            invoices.resolve(&entry);
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Cancels the payments held on chain whose HTLCs expire within HOLD_CANCEL_BLOCKS of height
+    pub fn cancel_expiring(&self, chain: &str, height: u32) {
+        let expiring: Vec<[u8; 32]> = self.invoices.lock().unwrap().by_hash.values().filter(|entry| {
+            entry.invoice.chain == chain && entry.state == InvoiceState::Accepted &&
+                entry.htlc_expiry.is_some_and(|expiry| height as u64 + HOLD_CANCEL_BLOCKS >= expiry as u64)
+        }).map(|entry| entry.invoice.payment_hash).collect();
+        for payment_hash in expiring {
+            println!("Canceling invoice {}, its payment could expire", payment_hash.to_hex());
+            if let Err(e) = self.cancel(&payment_hash) {
+                println!("Cannot cancel invoice {}: {}", payment_hash.to_hex(), e);
+            }
+        }
+    }
+
+    /// The payments held on chain that were settled or canceled since last time
+    pub fn take_resolved(&self, chain: &str) -> Vec<ResolvedPayment> {
+        self.invoices.lock().unwrap().resolved.remove(chain).unwrap_or_default()
+    }
+
+    /// Waits up to timeout for the invoice to be paid, canceled or expired, returns it as it is then
+    pub fn wait(&self, payment_hash: &[u8; 32], timeout: Duration) -> Result<InvoiceEntry, String> {
+        let deadline = Instant::now() + timeout;
+        let mut invoices = self.invoices.lock().unwrap();
+        loop {
+            let entry = invoices.by_hash.get(payment_hash).ok_or("No invoice with this payment hash")?;
+            let state = current_state(entry);
+            let now = Instant::now();
+            if state != InvoiceState::Open || now >= deadline {
+                return Ok(InvoiceEntry { state, ..entry.clone() });
+            }
+            // Expiring doesn't notify, wake up for it
+            let until_expiry = Duration::from_secs(entry.invoice.expires_at().saturating_sub(self::now()) + 1);
+            invoices = self.changed.wait_timeout(invoices, (deadline - now).min(until_expiry)).unwrap().0;
+        }
+    }
+}
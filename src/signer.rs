@@ -0,0 +1,853 @@
+//! Everything that needs the seed goes through a Signer, which can live in another process
+//!
+//! NodeKeys is the in-process signer. RemoteSigner asks a rustlnd-signer process over a Unix
+//! socket with a small protocol: every message is a 4 byte big endian length and that many
+//! bytes. Requests are a type byte, the chain's name (a length byte and the name) and the
+//! arguments; answers are 0 and the result or 1 and an error message.
+//!
+//! The node secret and the channel keys stay in the signer: the daemon asks it for the ECDH of
+//! the noise handshakes and onions, node signatures, the per-commitment points and secrets and
+//! the signatures of commitment, HTLC and closing transactions. A channel's keys are only known
+//! to the daemon by their public keys and a keys_id, which the signer derives the secrets from
+//! again. The signer never sends a secret but the per-commitment secrets revoking our
+//! commitment transactions, and the SigningPolicy refuses to sign revoked ones. It keeps the seed and the on-chain wallet keys, which closed
+//! channels pay to: the daemon only gets the wallet account's public key and asks for
+//! signatures, which the signer checks against its SigningPolicy. The socket is only for the
+//! signer's user, checked with the peer's credentials on every connection.
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::consensus::encode;
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use lightning::chain::keysinterface::{ChannelKeys, ChannelSigner, DerivedKey, KeysInterface, NodeSigner};
+use lightning::ln::msgs::UnsignedChannelAnnouncement;
+use lightning::util::ser::{Readable, Writeable};
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::{Message, Secp256k1, Signature};
+
+#[cfg(unix)]
+use crate::chain;
+use crate::keys::{self, NodeKeys};
+use crate::monitor;
+
+// Nothing we send is nearly this big
+const MAX_MESSAGE_LEN: u32 = 1_000_000;
+
+/// The wallet key an input spends and its value, see Signer::sign_wallet_transaction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WalletInput {
+    pub branch: u32,
+    pub index: u32,
+    pub value: u64,
+}
+
+/// What the daemon needs from whoever has the seed, for one chain
+pub trait Signer: Send + Sync {
+    fn get_node_id(&self) -> Result<PublicKey, String>;
+    /// See NodeSigner::node_ecdh
+    fn node_ecdh(&self, other: &PublicKey) -> Result<[u8; 32], String>;
+    fn sign_with_node_secret(&self, msg: &Message) -> Result<Signature, String>;
+    fn get_destination_script(&self) -> Result<Script, String>;
+    fn get_shutdown_pubkey(&self) -> Result<PublicKey, String>;
+    fn get_channel_keys(&self, inbound: bool) -> Result<ChannelKeys, String>;
+    /// The signer of the channel keys with keys_id that get_channel_keys gave before
+    fn get_channel_signer(&self, keys_id: &[u8; 32]) -> Result<Arc<dyn ChannelSigner>, String>;
+
+    /// The public half of the wallet account, enough to know the wallet's scripts
+    fn get_wallet_account_xpub(&self) -> Result<ExtendedPubKey, String>;
+
+    /// The witnesses of a transaction spending one wallet output per input
+    fn sign_wallet_transaction(&self, tx: &Transaction, inputs: &[WalletInput]) -> Result<Vec<Vec<Vec<u8>>>, String>;
+}
+
+impl Signer for NodeKeys {
+    fn get_node_id(&self) -> Result<PublicKey, String> {
+        Ok(NodeSigner::get_node_id(self))
+    }
+
+    fn node_ecdh(&self, other: &PublicKey) -> Result<[u8; 32], String> {
+        Ok(NodeSigner::node_ecdh(self, other))
+    }
+
+    fn sign_with_node_secret(&self, msg: &Message) -> Result<Signature, String> {
+        Ok(NodeSigner::sign_with_node_secret(self, msg))
+    }
+
+    fn get_destination_script(&self) -> Result<Script, String> {
+        Ok(KeysInterface::get_destination_script(self))
+    }
+
+    fn get_shutdown_pubkey(&self) -> Result<PublicKey, String> {
+        Ok(KeysInterface::get_shutdown_pubkey(self))
+    }
+
+    fn get_channel_keys(&self, inbound: bool) -> Result<ChannelKeys, String> {
+        Ok(KeysInterface::get_channel_keys(self, inbound))
+    }
+
+    fn get_channel_signer(&self, keys_id: &[u8; 32]) -> Result<Arc<dyn ChannelSigner>, String> {
+        Ok(KeysInterface::get_channel_signer(self, keys_id))
+    }
+
+    fn get_wallet_account_xpub(&self) -> Result<ExtendedPubKey, String> {
+        Ok(ExtendedPubKey::from_private(&Secp256k1::new(), &self.wallet_account_key()))
+    }
+
+    fn sign_wallet_transaction(&self, tx: &Transaction, inputs: &[WalletInput]) -> Result<Vec<Vec<Vec<u8>>>, String> {
+        if inputs.len() != tx.input.len() {
+            return Err(format!("{} inputs described for a transaction with {}", inputs.len(), tx.input.len()));
+        }
+        let secp_ctx = Secp256k1::new();
+        let account = self.wallet_account_key();
+        Ok(inputs.iter().enumerate().map(|(index, input)| {
+            let key = keys::wallet_key(&secp_ctx, &account, input.branch, input.index);
+            keys::p2wpkh_witness(&secp_ctx, tx, index, &key, input.value)
+        }).collect())
+    }
+}
+
+/// KeysInterface on top of a Signer, for rust-lightning
+///
+/// KeysInterface can't fail, so it panics when the signer does. Session keys and channel ids
+/// are random, they don't need the seed.
+pub struct SignerKeys {
+    signer: Arc<dyn Signer>,
+    node_id: PublicKey,
+    destination_script: Script,
+    shutdown_pubkey: PublicKey,
+}
+
+impl SignerKeys {
+    pub fn new(signer: Arc<dyn Signer>) -> Result<SignerKeys, String> {
+        Ok(SignerKeys {
+            node_id: signer.get_node_id()?,
+            destination_script: signer.get_destination_script()?,
+            shutdown_pubkey: signer.get_shutdown_pubkey()?,
+            signer,
+        })
+    }
+}
+
+impl NodeSigner for SignerKeys {
+    fn get_node_id(&self) -> PublicKey {
+        self.node_id
+    }
+
+    fn node_ecdh(&self, other: &PublicKey) -> [u8; 32] {
+        self.signer.node_ecdh(other).unwrap_or_else(|e| panic!("Signer failed: {}", e))
+    }
+
+    fn sign_with_node_secret(&self, msg: &Message) -> Signature {
+        self.signer.sign_with_node_secret(msg).unwrap_or_else(|e| panic!("Signer failed: {}", e))
+    }
+}
+
+impl KeysInterface for SignerKeys {
+    fn get_destination_script(&self) -> Script {
+        self.destination_script.clone()
+    }
+
+    fn get_shutdown_pubkey(&self) -> PublicKey {
+        self.shutdown_pubkey
+    }
+
+    fn get_channel_keys(&self, inbound: bool) -> ChannelKeys {
+        self.signer.get_channel_keys(inbound).unwrap_or_else(|e| panic!("Signer failed: {}", e))
+    }
+
+    fn get_channel_signer(&self, keys_id: &[u8; 32]) -> Arc<dyn ChannelSigner> {
+        self.signer.get_channel_signer(keys_id).unwrap_or_else(|e| panic!("Signer failed: {}", e))
+    }
+
+    fn get_session_key(&self) -> SecretKey {
+        loop {
+            if let Ok(key) = SecretKey::from_slice(&rand::thread_rng().gen::<[u8; 32]>()) {
+                return key;
+            }
+        }
+    }
+
+    fn get_channel_id(&self) -> [u8; 32] {
+        rand::thread_rng().gen()
+    }
+}
+
+/// What a signer process refuses to sign
+pub struct SigningPolicy {
+    max_sat_per_1000_weight: u64,
+    // Every wallet output we signed a spend of and the transaction spending it
+    signed_spends: Mutex<HashMap<OutPoint, Sha256dHash>>,
+    revocations_path: Option<PathBuf>,
+    revocations: Mutex<HashMap<(String, [u8; 32]), Revocations>>,
+}
+
+// The commitments of a channel we signed and revoked, commitment numbers count down so the
+// lowest is the latest
+#[derive(Clone, Copy, Default)]
+struct Revocations {
+    lowest_signed: Option<u64>,
+    lowest_released: Option<u64>,
+}
+
+fn encode_index(idx: Option<u64>) -> String {
+    idx.map(|idx| idx.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+fn decode_index(field: &str) -> Result<Option<u64>, String> {
+    if field == "-" {
+        return Ok(None);
+    }
+    field.parse().map(Some).map_err(|_| format!("Bad commitment number {}", field))
+}
+
+// One line per channel: chain, keys_id in hex, lowest commitment signed and lowest commitment
+// whose secret was released, "-" for none
+fn encode_revocations(revocations: &HashMap<(String, [u8; 32]), Revocations>) -> String {
+    let mut lines: Vec<String> = revocations.iter().map(|((chain, keys_id), revocations)| {
+        format!("{} {} {} {}\n", chain, keys_id.to_hex(), encode_index(revocations.lowest_signed), encode_index(revocations.lowest_released))
+    }).collect();
+    lines.sort();
+    lines.concat()
+}
+
+fn decode_revocations(contents: &str) -> Result<HashMap<(String, [u8; 32]), Revocations>, String> {
+    let mut revocations = HashMap::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 4 {
+            return Err(format!("Bad line '{}'", line));
+        }
+        let mut keys_id = [0; 32];
+        match Vec::<u8>::from_hex(fields[1]) {
+            Ok(ref id) if id.len() == 32 => keys_id.copy_from_slice(id),
+            _ => return Err(format!("Bad keys_id {}", fields[1])),
+        }
+        revocations.insert((fields[0].to_string(), keys_id),
+                           Revocations { lowest_signed: decode_index(fields[2])?, lowest_released: decode_index(fields[3])? });
+    }
+    Ok(revocations)
+}
+
+impl SigningPolicy {
+    /// The revocations are read from and saved to revocations_path, without it they're only
+    /// kept in memory
+    pub fn new(max_sat_per_1000_weight: u64, revocations_path: Option<PathBuf>) -> Result<SigningPolicy, String> {
+        let revocations = match &revocations_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+                decode_revocations(&contents).map_err(|e| format!("Cannot read {:?}: {}", path, e))?
+            },
+            _ => HashMap::new(),
+        };
+        Ok(SigningPolicy { max_sat_per_1000_weight, signed_spends: Mutex::new(HashMap::new()), revocations_path, revocations: Mutex::new(revocations) })
+    }
+
+    // Saves the revocations with one changed, which only counts once it's on disk
+    fn save_revocations(&self, revocations: &mut HashMap<(String, [u8; 32]), Revocations>, key: (String, [u8; 32]), changed: Revocations) -> Result<(), String> {
+        let previous = revocations.insert(key.clone(), changed);
+        if let Some(path) = &self.revocations_path {
+            if let Err(e) = monitor::write_durably(path, encode_revocations(revocations).as_bytes()) {
+                match previous {
+                    Some(previous) => revocations.insert(key, previous),
+                    None => revocations.remove(&key),
+                };
+                return Err(format!("Cannot save the revocations: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses to sign a local commitment transaction whose secret was released, it's revoked
+    pub fn check_local_commitment(&self, chain: &str, keys_id: &[u8; 32], idx: u64) -> Result<(), String> {
+        let mut revocations = self.revocations.lock().unwrap();
+        let key = (chain.to_string(), *keys_id);
+        let mut channel = revocations.get(&key).cloned().unwrap_or_default();
+        if let Some(released) = channel.lowest_released {
+            if idx >= released {
+                return Err(format!("commitment {} is revoked", idx));
+            }
+        }
+        if channel.lowest_signed.map(|signed| idx < signed).unwrap_or(true) {
+            channel.lowest_signed = Some(idx);
+            self.save_revocations(&mut revocations, key, channel)?;
+        }
+        Ok(())
+    }
+
+    /// Refuses to release the secret of a commitment unless we signed a later one to replace it
+    pub fn check_release(&self, chain: &str, keys_id: &[u8; 32], idx: u64) -> Result<(), String> {
+        let mut revocations = self.revocations.lock().unwrap();
+        let key = (chain.to_string(), *keys_id);
+        let mut channel = revocations.get(&key).cloned().unwrap_or_default();
+        if !channel.lowest_signed.map(|signed| signed < idx).unwrap_or(false) {
+            return Err(format!("no commitment after {} is signed", idx));
+        }
+        if channel.lowest_released.map(|released| idx < released).unwrap_or(true) {
+            channel.lowest_released = Some(idx);
+            self.save_revocations(&mut revocations, key, channel)?;
+        }
+        Ok(())
+    }
+
+    /// Refuses transactions paying more fees than the maximum rate and double spends of outputs
+    /// we already signed a spend of
+    pub fn check_wallet_transaction(&self, tx: &Transaction, inputs: &[WalletInput]) -> Result<(), String> {
+        let input_value: u64 = inputs.iter().map(|input| input.value).sum();
+        let output_value: u64 = tx.output.iter().map(|output| output.value).sum();
+        let fee = input_value.checked_sub(output_value).ok_or("the outputs are worth more than the inputs")?;
+        // Without witnesses, which will be P2WPKH ones
+        let weight = encode::serialize(tx).len() as u64 * 4 + 2 + inputs.len() as u64 * (1 + 1 + 72 + 1 + 33);
+        if fee > self.max_sat_per_1000_weight * weight / 1000 {
+            return Err(format!("a fee of {} is over the maximum of {} satoshis per 1000 weight",
+                               fee, self.max_sat_per_1000_weight));
+        }
+
+        let txid = tx.txid();
+        let mut signed_spends = self.signed_spends.lock().unwrap();
+        for input in &tx.input {
+            if let Some(other) = signed_spends.get(&input.previous_output) {
+                if *other != txid {
+                    return Err(format!("{} is already spent by {}", input.previous_output, other));
+                }
+            }
+        }
+        for input in &tx.input {
+            signed_spends.insert(input.previous_output, txid);
+        }
+        Ok(())
+    }
+}
+
+fn read_message<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Message of {} bytes", len)));
+    }
+    let mut message = vec![0; len as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn write_message<W: Write>(stream: &mut W, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()
+}
+
+// 0, 3, 4 and 5 were for the node secret, channel keys, session key and channel id
+const DESTINATION_SCRIPT: u8 = 1;
+const SHUTDOWN_PUBKEY: u8 = 2;
+const WALLET_ACCOUNT_XPUB: u8 = 6;
+const SIGN_WALLET_TRANSACTION: u8 = 7;
+const NODE_ID: u8 = 8;
+const NODE_ECDH: u8 = 9;
+const SIGN_WITH_NODE_SECRET: u8 = 10;
+const NEW_CHANNEL_KEYS: u8 = 11;
+// The channel requests, from here on, start with the keys_id
+const PER_COMMITMENT_POINT: u8 = 12;
+const RELEASE_PER_COMMITMENT_SECRET: u8 = 13;
+const SIGN_LOCAL_COMMITMENT: u8 = 14;
+const SIGN_REMOTE_COMMITMENT: u8 = 15;
+const SIGN_CLOSING_TRANSACTION: u8 = 16;
+const SIGN_WITH_DERIVED_KEY: u8 = 17;
+const SIGN_JUSTICE_INPUT: u8 = 18;
+const SIGN_CHANNEL_ANNOUNCEMENT: u8 = 19;
+
+fn read_bytes(reader: &mut Cursor<&[u8]>, len: usize) -> Result<Vec<u8>, String> {
+    if len as u64 > reader.get_ref().len() as u64 - reader.position() {
+        return Err("Truncated message".to_string());
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).map_err(|_| "Truncated message".to_string())?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut Cursor<&[u8]>) -> Result<u32, String> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&read_bytes(reader, 4)?);
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(reader: &mut Cursor<&[u8]>) -> Result<u64, String> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&read_bytes(reader, 8)?);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// A u32 length and that many bytes
+fn read_blob(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, String> {
+    let len = read_u32(reader)?;
+    read_bytes(reader, len as usize)
+}
+
+fn push_blob(args: &mut Vec<u8>, blob: &[u8]) {
+    args.extend_from_slice(&(blob.len() as u32).to_be_bytes());
+    args.extend_from_slice(blob);
+}
+
+fn read_transaction(reader: &mut Cursor<&[u8]>) -> Result<Transaction, String> {
+    encode::deserialize(&read_blob(reader)?).map_err(|e| e.to_string())
+}
+
+fn read_public_key(reader: &mut Cursor<&[u8]>) -> Result<PublicKey, String> {
+    PublicKey::from_slice(&read_bytes(reader, 33)?).map_err(|e| e.to_string())
+}
+
+fn derived_key_byte(key: DerivedKey) -> u8 {
+    match key {
+        DerivedKey::Payment => 0,
+        DerivedKey::DelayedPayment => 1,
+        DerivedKey::Htlc => 2,
+    }
+}
+
+fn read_derived_key(reader: &mut Cursor<&[u8]>) -> Result<DerivedKey, String> {
+    match read_bytes(reader, 1)?[0] {
+        0 => Ok(DerivedKey::Payment),
+        1 => Ok(DerivedKey::DelayedPayment),
+        2 => Ok(DerivedKey::Htlc),
+        key => Err(format!("Unknown derived key {}", key)),
+    }
+}
+
+// The arguments of a signature of the funding output
+fn funding_args(tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Vec<u8> {
+    let mut args = Vec::new();
+    push_blob(&mut args, &encode::serialize(tx));
+    push_blob(&mut args, funding_redeemscript.as_bytes());
+    args.extend_from_slice(&channel_value_satoshis.to_be_bytes());
+    args
+}
+
+fn read_funding_args(reader: &mut Cursor<&[u8]>) -> Result<(Transaction, Script, u64), String> {
+    Ok((read_transaction(reader)?, Script::from(read_blob(reader)?), read_u64(reader)?))
+}
+
+// The arguments of a signature of input of tx
+fn input_args(tx: &Transaction, input: usize, witness_script: &Script, amount: u64) -> Vec<u8> {
+    let mut args = Vec::new();
+    push_blob(&mut args, &encode::serialize(tx));
+    args.extend_from_slice(&(input as u32).to_be_bytes());
+    push_blob(&mut args, witness_script.as_bytes());
+    args.extend_from_slice(&amount.to_be_bytes());
+    args
+}
+
+fn read_input_args(reader: &mut Cursor<&[u8]>) -> Result<(Transaction, usize, Script, u64), String> {
+    Ok((read_transaction(reader)?, read_u32(reader)? as usize, Script::from(read_blob(reader)?), read_u64(reader)?))
+}
+
+// Serves a request for the channel keys keys_id, the answer is the result
+fn handle_channel_request(signer: &dyn Signer, policy: &SigningPolicy, chain: &str, request_type: u8, reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, String> {
+    let mut keys_id = [0; 32];
+    keys_id.copy_from_slice(&read_bytes(reader, 32)?);
+    let channel_signer = signer.get_channel_signer(&keys_id)?;
+    let refused = |_| "Refusing to sign".to_string();
+    let signature = match request_type {
+        PER_COMMITMENT_POINT => return Ok(channel_signer.get_per_commitment_point(read_u64(reader)?).serialize().to_vec()),
+        RELEASE_PER_COMMITMENT_SECRET => {
+            let idx = read_u64(reader)?;
+            policy.check_release(chain, &keys_id, idx).map_err(|e| format!("Refusing to release the secret of commitment {}: {}", idx, e))?;
+            return channel_signer.release_per_commitment_secret(idx).map(|secret| secret.to_vec())
+                .map_err(|_| format!("Refusing to release the secret of commitment {}", idx));
+        },
+        SIGN_LOCAL_COMMITMENT => {
+            let idx = read_u64(reader)?;
+            let (tx, funding_redeemscript, channel_value_satoshis) = read_funding_args(reader)?;
+            policy.check_local_commitment(chain, &keys_id, idx).map_err(|e| format!("Refusing to sign commitment {}: {}", idx, e))?;
+            channel_signer.sign_local_commitment(idx, &tx, &funding_redeemscript, channel_value_satoshis)
+                .map_err(|_| format!("Refusing to sign commitment {}", idx))?
+        },
+        SIGN_REMOTE_COMMITMENT => {
+            let (tx, funding_redeemscript, channel_value_satoshis) = read_funding_args(reader)?;
+            channel_signer.sign_remote_commitment(&tx, &funding_redeemscript, channel_value_satoshis).map_err(refused)?
+        },
+        SIGN_CLOSING_TRANSACTION => {
+            let (tx, funding_redeemscript, channel_value_satoshis) = read_funding_args(reader)?;
+            channel_signer.sign_closing_transaction(&tx, &funding_redeemscript, channel_value_satoshis).map_err(refused)?
+        },
+        SIGN_WITH_DERIVED_KEY => {
+            let key = read_derived_key(reader)?;
+            let per_commitment_point = read_public_key(reader)?;
+            let (tx, input, witness_script, amount) = read_input_args(reader)?;
+            channel_signer.sign_with_derived_key(key, &per_commitment_point, &tx, input, &witness_script, amount).map_err(refused)?
+        },
+        SIGN_JUSTICE_INPUT => {
+            let per_commitment_secret = SecretKey::from_slice(&read_bytes(reader, 32)?).map_err(|e| e.to_string())?;
+            let (tx, input, witness_script, amount) = read_input_args(reader)?;
+            channel_signer.sign_justice_input(&per_commitment_secret, &tx, input, &witness_script, amount).map_err(refused)?
+        },
+        SIGN_CHANNEL_ANNOUNCEMENT => {
+            let msg: UnsignedChannelAnnouncement = Readable::read(&mut Cursor::new(read_blob(reader)?)).map_err(|e| format!("Bad channel_announcement: {:?}", e))?;
+            channel_signer.sign_channel_announcement(&msg).map_err(refused)?
+        },
+        _ => return Err(format!("Unknown request {}", request_type)),
+    };
+    Ok(signature.serialize_compact().to_vec())
+}
+
+// Serves one signer request, the answer is the result without the status byte
+fn handle_request(signers: &HashMap<String, Arc<dyn Signer>>, policy: &SigningPolicy, request: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = Cursor::new(request);
+    let request_type = read_bytes(&mut reader, 1)?[0];
+    let chain_len = read_bytes(&mut reader, 1)?[0];
+    let chain = String::from_utf8(read_bytes(&mut reader, chain_len as usize)?).map_err(|_| "Bad chain name".to_string())?;
+    let signer = signers.get(&chain).ok_or(format!("Not signing for chain {}", chain))?;
+
+    match request_type {
+        DESTINATION_SCRIPT => Ok(signer.get_destination_script()?.to_bytes()),
+        SHUTDOWN_PUBKEY => Ok(signer.get_shutdown_pubkey()?.serialize().to_vec()),
+        WALLET_ACCOUNT_XPUB => Ok(signer.get_wallet_account_xpub()?.to_string().into_bytes()),
+        NODE_ID => Ok(signer.get_node_id()?.serialize().to_vec()),
+        NODE_ECDH => {
+            let other = PublicKey::from_slice(&read_bytes(&mut reader, 33)?).map_err(|e| e.to_string())?;
+            Ok(signer.node_ecdh(&other)?.to_vec())
+        },
+        SIGN_WITH_NODE_SECRET => {
+            let msg = Message::from_slice(&read_bytes(&mut reader, 32)?).map_err(|e| e.to_string())?;
+            Ok(signer.sign_with_node_secret(&msg)?.serialize_compact().to_vec())
+        },
+        NEW_CHANNEL_KEYS => {
+            let keys = signer.get_channel_keys(read_bytes(&mut reader, 1)?[0] != 0)?;
+            let mut result = keys.keys_id.to_vec();
+            for point in &[keys.funding_pubkey, keys.revocation_basepoint, keys.payment_basepoint, keys.delayed_payment_basepoint, keys.htlc_basepoint] {
+                result.extend_from_slice(&point.serialize());
+            }
+            Ok(result)
+        },
+        PER_COMMITMENT_POINT..=SIGN_CHANNEL_ANNOUNCEMENT => handle_channel_request(&**signer, policy, &chain, request_type, &mut reader),
+        SIGN_WALLET_TRANSACTION => {
+            let tx = read_transaction(&mut reader)?;
+            let mut inputs = Vec::new();
+            for _ in 0..tx.input.len() {
+                inputs.push(WalletInput { branch: read_u32(&mut reader)?, index: read_u32(&mut reader)?, value: read_u64(&mut reader)? });
+            }
+            policy.check_wallet_transaction(&tx, &inputs).map_err(|e| format!("Refusing to sign {}: {}", tx.txid(), e))?;
+            let witnesses = signer.sign_wallet_transaction(&tx, &inputs)?;
+            let mut signed = tx;
+            for (input, witness) in signed.input.iter_mut().zip(witnesses) {
+                input.witness = witness;
+            }
+            Ok(encode::serialize(&signed))
+        },
+        _ => Err(format!("Unknown request {}", request_type)),
+    }
+}
+
+/// Answers requests on stream until it's closed
+pub fn serve<S: Read + Write>(signers: &HashMap<String, Arc<dyn Signer>>, policy: &SigningPolicy, stream: &mut S) -> io::Result<()> {
+    loop {
+        let request = match read_message(stream) {
+            Ok(request) => request,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let answer = match handle_request(signers, policy, &request) {
+            Ok(mut result) => {
+                result.insert(0, 0);
+                result
+            },
+            Err(e) => {
+                println!("Signer request failed: {}", e);
+                let mut error = vec![1];
+                error.extend_from_slice(e.as_bytes());
+                error
+            },
+        };
+        write_message(stream, &answer)?;
+    }
+}
+
+/// The user on the other end of a Unix socket
+#[cfg(target_os = "linux")]
+pub(crate) fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+#[cfg(unix)]
+/// Serves the signers on a Unix socket at path in the background, for our own user only
+pub fn listen(signers: HashMap<String, Arc<dyn Signer>>, policy: SigningPolicy, path: &Path) -> std::io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    let signers = Arc::new(signers);
+    let policy = Arc::new(policy);
+    let our_uid = unsafe { libc::getuid() };
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            match peer_uid(&stream) {
+                Ok(uid) if uid == our_uid => {},
+                Ok(uid) => {
+                    println!("Refusing a signer connection from uid {}", uid);
+                    continue;
+                },
+                Err(e) => {
+                    println!("Refusing a signer connection without credentials: {}", e);
+                    continue;
+                },
+            }
+            let signers = signers.clone();
+            let policy = policy.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve(&signers, &policy, &mut stream) {
+                    println!("Signer connection failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// The socket to a rustlnd-signer, shared by a RemoteSigner and its channel signers
+#[cfg(unix)]
+struct Connection {
+    path: PathBuf,
+    chain: String,
+    stream: Mutex<Option<UnixStream>>,
+}
+
+#[cfg(unix)]
+impl Connection {
+    fn request(&self, request_type: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        let mut request = vec![request_type, self.chain.len() as u8];
+        request.extend_from_slice(self.chain.as_bytes());
+        request.extend_from_slice(args);
+
+        let mut stream = self.stream.lock().unwrap();
+        // Connects again if the signer restarted since the last request
+        let mut answer = None;
+        for _ in 0..2 {
+            if stream.is_none() {
+                *stream = Some(UnixStream::connect(&self.path)
+                    .map_err(|e| format!("Cannot connect to the signer at {:?}: {}", self.path, e))?);
+            }
+            let connection = stream.as_mut().unwrap();
+            match write_message(connection, &request).and_then(|_| read_message(connection)) {
+                Ok(message) => {
+                    answer = Some(message);
+                    break;
+                },
+                Err(_) => *stream = None,
+            }
+        }
+        let answer = answer.ok_or(format!("Lost the connection to the signer at {:?}", self.path))?;
+        match answer.split_first() {
+            Some((0, result)) => Ok(result.to_vec()),
+            Some((_, error)) => Err(format!("Signer: {}", String::from_utf8_lossy(error))),
+            None => Err("Empty answer from the signer".to_string()),
+        }
+    }
+}
+
+/// A Signer in a rustlnd-signer process
+#[cfg(unix)]
+pub struct RemoteSigner {
+    connection: Arc<Connection>,
+}
+
+#[cfg(unix)]
+impl RemoteSigner {
+    pub fn new(path: &Path, chain: &str) -> RemoteSigner {
+        RemoteSigner { connection: Arc::new(Connection { path: path.to_path_buf(), chain: chain.to_string(), stream: Mutex::new(None) }) }
+    }
+
+    fn request(&self, request_type: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        self.connection.request(request_type, args)
+    }
+}
+
+/// The ChannelSigner of channel keys in a rustlnd-signer process
+///
+/// ChannelSigner can't say why it fails, so the errors are printed. get_per_commitment_point
+/// can't fail, so it panics when the signer does.
+#[cfg(unix)]
+pub struct RemoteChannelSigner {
+    connection: Arc<Connection>,
+    keys_id: [u8; 32],
+}
+
+#[cfg(unix)]
+impl RemoteChannelSigner {
+    fn request(&self, request_type: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        let mut request = self.keys_id.to_vec();
+        request.extend_from_slice(args);
+        self.connection.request(request_type, &request)
+    }
+
+    fn sign(&self, request_type: u8, args: &[u8]) -> Result<Signature, ()> {
+        self.request(request_type, args)
+            .and_then(|answer| Signature::from_compact(&answer).map_err(|e| format!("Bad signature: {}", e)))
+            .map_err(|e| println!("Channel signer failed: {}", e))
+    }
+}
+
+#[cfg(unix)]
+impl ChannelSigner for RemoteChannelSigner {
+    fn get_per_commitment_point(&self, idx: u64) -> PublicKey {
+        self.request(PER_COMMITMENT_POINT, &idx.to_be_bytes())
+            .and_then(|answer| PublicKey::from_slice(&answer).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| panic!("Signer failed: {}", e))
+    }
+
+    fn release_per_commitment_secret(&self, idx: u64) -> Result<[u8; 32], ()> {
+        match self.request(RELEASE_PER_COMMITMENT_SECRET, &idx.to_be_bytes()) {
+            Ok(ref answer) if answer.len() == 32 => {
+                let mut secret = [0; 32];
+                secret.copy_from_slice(answer);
+                Ok(secret)
+            },
+            Ok(_) => {
+                println!("Channel signer failed: bad per commitment secret");
+                Err(())
+            },
+            Err(e) => {
+                println!("Channel signer failed: {}", e);
+                Err(())
+            },
+        }
+    }
+
+    fn sign_local_commitment(&self, idx: u64, tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+        let mut args = idx.to_be_bytes().to_vec();
+        args.extend_from_slice(&funding_args(tx, funding_redeemscript, channel_value_satoshis));
+        self.sign(SIGN_LOCAL_COMMITMENT, &args)
+    }
+
+    fn sign_remote_commitment(&self, tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+        self.sign(SIGN_REMOTE_COMMITMENT, &funding_args(tx, funding_redeemscript, channel_value_satoshis))
+    }
+
+    fn sign_closing_transaction(&self, tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+        self.sign(SIGN_CLOSING_TRANSACTION, &funding_args(tx, funding_redeemscript, channel_value_satoshis))
+    }
+
+    fn sign_with_derived_key(&self, key: DerivedKey, per_commitment_point: &PublicKey, tx: &Transaction, input: usize, witness_script: &Script, amount: u64) -> Result<Signature, ()> {
+        let mut args = vec![derived_key_byte(key)];
+        args.extend_from_slice(&per_commitment_point.serialize());
+        args.extend_from_slice(&input_args(tx, input, witness_script, amount));
+        self.sign(SIGN_WITH_DERIVED_KEY, &args)
+    }
+
+    fn sign_justice_input(&self, per_commitment_secret: &SecretKey, tx: &Transaction, input: usize, witness_script: &Script, amount: u64) -> Result<Signature, ()> {
+        let mut args = per_commitment_secret[..].to_vec();
+        args.extend_from_slice(&input_args(tx, input, witness_script, amount));
+        self.sign(SIGN_JUSTICE_INPUT, &args)
+    }
+
+    fn sign_channel_announcement(&self, msg: &UnsignedChannelAnnouncement) -> Result<Signature, ()> {
+        let mut args = Vec::new();
+        push_blob(&mut args, &msg.encode());
+        self.sign(SIGN_CHANNEL_ANNOUNCEMENT, &args)
+    }
+}
+
+#[cfg(unix)]
+impl Signer for RemoteSigner {
+    fn get_node_id(&self) -> Result<PublicKey, String> {
+        PublicKey::from_slice(&self.request(NODE_ID, &[])?).map_err(|e| e.to_string())
+    }
+
+    fn node_ecdh(&self, other: &PublicKey) -> Result<[u8; 32], String> {
+        let answer = self.request(NODE_ECDH, &other.serialize())?;
+        if answer.len() != 32 {
+            return Err("Bad shared secret from the signer".to_string());
+        }
+        let mut shared_secret = [0; 32];
+        shared_secret.copy_from_slice(&answer);
+        Ok(shared_secret)
+    }
+
+    fn sign_with_node_secret(&self, msg: &Message) -> Result<Signature, String> {
+        Signature::from_compact(&self.request(SIGN_WITH_NODE_SECRET, &msg[..])?).map_err(|e| e.to_string())
+    }
+
+    fn get_destination_script(&self) -> Result<Script, String> {
+        Ok(Script::from(self.request(DESTINATION_SCRIPT, &[])?))
+    }
+
+    fn get_shutdown_pubkey(&self) -> Result<PublicKey, String> {
+        PublicKey::from_slice(&self.request(SHUTDOWN_PUBKEY, &[])?).map_err(|e| e.to_string())
+    }
+
+    fn get_channel_keys(&self, inbound: bool) -> Result<ChannelKeys, String> {
+        let answer = self.request(NEW_CHANNEL_KEYS, &[inbound as u8])?;
+        let mut reader = Cursor::new(&answer[..]);
+        let mut keys_id = [0; 32];
+        keys_id.copy_from_slice(&read_bytes(&mut reader, 32)?);
+        Ok(ChannelKeys {
+            keys_id,
+            funding_pubkey: read_public_key(&mut reader)?,
+            revocation_basepoint: read_public_key(&mut reader)?,
+            payment_basepoint: read_public_key(&mut reader)?,
+            delayed_payment_basepoint: read_public_key(&mut reader)?,
+            htlc_basepoint: read_public_key(&mut reader)?,
+            signer: self.get_channel_signer(&keys_id)?,
+        })
+    }
+
+    fn get_channel_signer(&self, keys_id: &[u8; 32]) -> Result<Arc<dyn ChannelSigner>, String> {
+        Ok(Arc::new(RemoteChannelSigner { connection: self.connection.clone(), keys_id: *keys_id }))
+    }
+
+    fn get_wallet_account_xpub(&self) -> Result<ExtendedPubKey, String> {
+        let answer = self.request(WALLET_ACCOUNT_XPUB, &[])?;
+        let mut xpub: ExtendedPubKey = String::from_utf8_lossy(&answer).parse().map_err(|e| format!("Bad xpub: {}", e))?;
+        // The serialization has the same version bytes for testnet and regtest
+        xpub.network = chain::network_for_chain(&self.connection.chain);
+        Ok(xpub)
+    }
+
+    fn sign_wallet_transaction(&self, tx: &Transaction, inputs: &[WalletInput]) -> Result<Vec<Vec<Vec<u8>>>, String> {
+        let mut args = Vec::new();
+        push_blob(&mut args, &encode::serialize(tx));
+        for input in inputs {
+            args.extend_from_slice(&input.branch.to_be_bytes());
+            args.extend_from_slice(&input.index.to_be_bytes());
+            args.extend_from_slice(&input.value.to_be_bytes());
+        }
+        let signed: Transaction = encode::deserialize(&self.request(SIGN_WALLET_TRANSACTION, &args)?)
+            .map_err(|e| format!("Bad signed transaction: {}", e))?;
+        if signed.txid() != tx.txid() {
+            return Err("The signer signed another transaction".to_string());
+        }
+        Ok(signed.input.into_iter().map(|input| input.witness).collect())
+    }
+}
@@ -0,0 +1,113 @@
+//! Minimal SOCKS5 client (RFC 1928), enough to go through Tor
+//!
+//! Only the no authentication method and the CONNECT command are supported. Host names are
+//! always sent to the proxy unresolved so that .onion addresses (and DNS) are handled by it.
+
+use std::io::{Error, Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn reply_error(rep: u8) -> &'static str {
+    match rep {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Splits 'host:port', accepting '[v6]:port' too
+pub fn split_host_port(addr: &str) -> Result<(String, u16), String> {
+    let (host, port) = if let Some(bracketed) = addr.strip_prefix('[') {
+        // [<IPv6 address>]:port
+        match bracketed.split_once("]:") {
+            Some((host, port)) => (host, port),
+            None => return Err(format!("'{}' has no port after the bracketed host", addr)),
+        }
+    } else {
+        match addr.rsplit_once(':') {
+            Some((host, _)) if host.contains(':') || host.contains('[') || host.contains(']') => {
+                return Err(format!("'{}' needs brackets around the IPv6 address, like [::1]:9735", addr));
+            },
+            Some((host, port)) => (host, port),
+            None => return Err(format!("'{}' has no port", addr)),
+        }
+    };
+    let port = match port.parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => return Err(format!("'{}' has an invalid port", addr)),
+    };
+    if host.is_empty() {
+        return Err(format!("'{}' has no host", addr));
+    }
+    Ok((host.to_string(), port))
+}
+
+/// Asks the proxy to connect to addr, on success the stream talks to addr
+pub fn connect(proxy: &str, addr: &str) -> std::io::Result<TcpStream> {
+    let (host, port) = split_host_port(addr).map_err(Error::other)?;
+    let mut stream = crate::net::tcp_connect(proxy)?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH])?;
+    let mut method_reply = [0; 2];
+    stream.read_exact(&mut method_reply)?;
+    if method_reply[0] != SOCKS_VERSION || method_reply[1] != METHOD_NO_AUTH {
+        return Err(Error::other(format!("Proxy {} requires authentication or is not SOCKS5", proxy)));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(Error::other(format!("Host name too long: {}", host)));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Error::other(format!("Proxy {} is not SOCKS5", proxy)));
+    }
+    if reply[1] != 0 {
+        return Err(Error::other(format!("Proxy {} cannot connect to {}: {}", proxy, addr, reply_error(reply[1]))));
+    }
+    // We don't care about the address the proxy bound to, but it needs to be consumed
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        },
+        _ => return Err(Error::other(format!("Proxy {} answered with an unknown address type", proxy))),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(stream)
+}
@@ -0,0 +1,167 @@
+//! Onion addresses and onion service publishing through Tor's control port
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+use lightning::ln::msgs::NetAddress;
+
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for c in encoded.to_lowercase().bytes() {
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u16;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+    Some(data)
+}
+
+pub fn is_onion(host: &str) -> bool {
+    host.ends_with(".onion")
+}
+
+/// 'host.onion' for onion NetAddresses, None for the others
+pub fn onion_host(addr: &NetAddress) -> Option<String> {
+    match addr {
+        NetAddress::OnionV2 { addr, .. } => Some(format!("{}.onion", base32_encode(addr))),
+        NetAddress::OnionV3 { ed25519_pubkey, checksum, version, .. } => {
+            let mut data = ed25519_pubkey.to_vec();
+            data.extend_from_slice(&checksum.to_be_bytes());
+            data.push(*version);
+            Some(format!("{}.onion", base32_encode(&data)))
+        },
+        _ => None,
+    }
+}
+
+/// The inverse of onion_host
+pub fn onion_netaddress(host: &str, port: u16) -> Option<NetAddress> {
+    if !is_onion(host) {
+        return None;
+    }
+    let data = base32_decode(host.trim_end_matches(".onion"))?;
+    match data.len() {
+        10 => {
+            let mut addr = [0; 10];
+            addr.copy_from_slice(&data);
+            Some(NetAddress::OnionV2 { addr, port })
+        },
+        35 => {
+            let mut ed25519_pubkey = [0; 32];
+            ed25519_pubkey.copy_from_slice(&data[..32]);
+            Some(NetAddress::OnionV3 {
+                ed25519_pubkey,
+                checksum: u16::from_be_bytes([data[32], data[33]]),
+                version: data[34],
+                port,
+            })
+        },
+        _ => None,
+    }
+}
+
+/// An onion service that lives as long as this control connection
+pub struct OnionService {
+    _control: TcpStream,
+    pub onion_host: String,
+    pub port: u16,
+}
+
+fn send_command(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, command: &str) -> Result<Vec<String>, String> {
+    stream.write_all(format!("{}\r\n", command).as_bytes()).map_err(|e| e.to_string())?;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => return Err("Tor closed the control connection".to_string()),
+            Ok(_) => {},
+            Err(e) => return Err(e.to_string()),
+        }
+        let line = line.trim_end().to_string();
+        if line.len() < 4 {
+            return Err(format!("Unexpected answer from Tor: {}", line));
+        }
+        let last = &line[3..4] == " ";
+        if !line.starts_with("250") {
+            return Err(format!("Tor refused '{}': {}", command.split(' ').next().unwrap_or(""), line));
+        }
+        lines.push(line[4..].to_string());
+        if last {
+            return Ok(lines);
+        }
+    }
+}
+
+fn write_key(key_path: &Path, key: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(key_path)?.write_all(key.as_bytes())
+}
+
+/// Publishes an onion service on port forwarding to target through the Tor control port
+///
+/// The service key is kept in key_path so that the onion address doesn't change between runs.
+pub fn add_onion_service(control_addr: &str, password: Option<&str>, port: u16, target: &str,
+                         key_path: &Path) -> Result<OnionService, String> {
+    let mut stream = crate::net::tcp_connect(control_addr).map_err(|e| format!("Cannot connect to Tor control {}: {}", control_addr, e))?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+
+    let authenticate = match password {
+        Some(password) => format!("AUTHENTICATE \"{}\"", password.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => "AUTHENTICATE".to_string(),
+    };
+    send_command(&mut stream, &mut reader, &authenticate)?;
+
+    let key = match fs::read_to_string(key_path) {
+        Ok(key) => key.trim().to_string(),
+        Err(_) => "NEW:ED25519-V3".to_string(),
+    };
+    let answer = send_command(&mut stream, &mut reader, &format!("ADD_ONION {} Port={},{}", key, port, target))?;
+
+    let mut onion_host = None;
+    for line in answer {
+        if let Some(service_id) = line.strip_prefix("ServiceID=") {
+            onion_host = Some(format!("{}.onion", service_id));
+        } else if let Some(private_key) = line.strip_prefix("PrivateKey=") {
+            if let Err(e) = write_key(key_path, private_key) {
+                println!("Error saving onion service key to {:?}: {}", key_path, e);
+            }
+        }
+    }
+    match onion_host {
+        Some(onion_host) => Ok(OnionService { _control: stream, onion_host, port }),
+        None => Err("Tor didn't tell us the onion service id".to_string()),
+    }
+}
@@ -0,0 +1,811 @@
+//! On-chain wallet of one chain, to fund channels and get back what they pay us
+//!
+//! Coins are P2WPKH outputs to the keys of the chain's wallet account (see keys.rs). The wallet
+//! follows the chain backend block by block, from genesis the first time. With a fundings file,
+//! its coins and how far it got are saved next to it after every sync and it goes on from there
+//! on restart. Otherwise, everything but the transactions waiting to be broadcast is recovered
+//! from the seed by scanning the chain again. Reorgs aren't handled yet.
+//!
+//! Channels can also be funded by an external wallet with a PSBT (see psbt.rs) instead, or
+//! several at once by one of our transactions with an output for each (see fund_in_batch).
+//!
+//! The funding transaction of a single channel waits for the peer's funding_signed before it's
+//! broadcast. Until then it's kept in the fundings file, if there's one, with its inputs locked
+//! across restarts. If the channel goes away before that, its inputs are unlocked again.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bech32::{Bech32, FromBase32, ToBase32};
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::consensus::encode;
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use lightning::chain::chaininterface::{ConfirmationTarget, FeeEstimator};
+use lightning::chain::keysinterface::{ChannelKeys, DerivedKey, SpendableOutputDescriptor};
+use lightning::chain::transaction::OutPoint as ChannelOutPoint;
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::util::events::Event;
+use secp256k1::key::PublicKey;
+use secp256k1::{All, Secp256k1};
+
+use crate::chain::{self, ChainBackend};
+use crate::keys;
+use crate::monitor;
+use crate::psbt;
+use crate::signer::{Signer, WalletInput};
+
+/// How many unused addresses past the last used one are watched
+const GAP_LIMIT: u32 = 20;
+/// Outputs worth less than this aren't created
+pub const DUST_LIMIT: u64 = 546;
+
+// Weights for the fee estimation, signatures are assumed to take 72 bytes
+const TX_OVERHEAD_WEIGHT: u64 = (4 + 1 + 1 + 4) * 4 + 2;
+const P2WPKH_INPUT_WEIGHT: u64 = (36 + 1 + 4) * 4 + 1 + 1 + 72 + 1 + 33;
+const P2WPKH_OUTPUT_WEIGHT: u64 = (8 + 1 + 22) * 4;
+
+/// The bech32 address of a witness output script on chain
+pub fn script_to_address(chain: &str, script: &Script) -> Result<String, String> {
+    if !(script.is_v0_p2wpkh() || script.is_v0_p2wsh()) {
+        return Err(format!("{} has no bech32 address", script));
+    }
+    let version = bech32::u5::try_from_u8(0).unwrap();
+    let program = script.as_bytes()[2..].to_vec();
+    let network = match chain {
+        "main" => bitcoin_bech32::constants::Network::Bitcoin,
+        "test" => bitcoin_bech32::constants::Network::Testnet,
+        "regtest" => bitcoin_bech32::constants::Network::Regtest,
+        // bitcoin-bech32 only knows a few networks
+        _ => {
+            let mut data = vec![version];
+            data.extend_from_slice(&program.to_base32());
+            return Bech32::new(chain::bech32_hrp(chain), data).map(|address| address.to_string()).map_err(|e| e.to_string());
+        },
+    };
+    bitcoin_bech32::WitnessProgram::new(version, program, network)
+        .map(|program| program.to_address()).map_err(|e| e.to_string())
+}
+
+/// The output script of a version 0 bech32 address, which must be for chain
+pub fn address_to_script(chain: &str, address: &str) -> Result<Script, String> {
+    let bech32: Bech32 = address.parse().map_err(|e| format!("{} is not a bech32 address: {}", address, e))?;
+    if bech32.hrp() != chain::bech32_hrp(chain) {
+        return Err(format!("{} is not an address for chain {}", address, chain));
+    }
+    if bech32.data().is_empty() || bech32.data()[0].to_u8() != 0 {
+        return Err(format!("{} is not a version 0 witness address", address));
+    }
+    let program = Vec::<u8>::from_base32(&bech32.data()[1..]).map_err(|e| format!("{}: {}", address, e))?;
+    if program.len() != 20 && program.len() != 32 {
+        return Err(format!("{} has a witness program of {} bytes", address, program.len()));
+    }
+    Ok(Builder::new().push_int(0).push_slice(&program).into_script())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub output: TxOut,
+    pub height: u32,
+}
+
+// An output rust-lightning gave us with the channel signer spending it, to move to the wallet
+struct Sweep {
+    outpoint: OutPoint,
+    output: TxOut,
+    keys: ChannelKeys,
+    per_commitment_point: PublicKey,
+    // For P2WSH outputs, spent with the delay
+    witness_script: Option<Script>,
+    to_self_delay: u16,
+}
+
+// The funding transaction of a single channel, not broadcast yet
+struct PendingFunding {
+    temporary_channel_id: [u8; 32],
+    tx: Transaction,
+    // Whether the peer signed our first commitment transaction, the broadcast failed if it's
+    // still there
+    safe: bool,
+}
+
+// A channel waiting for the external wallet to fund it
+struct PsbtFunding {
+    temporary_channel_id: [u8; 32],
+    output_script: Script,
+    value: u64,
+}
+
+// Channels funded together, see fund_in_batch
+struct BatchFunding {
+    // By user_channel_id, the temporary_channel_id and funding output of the ones the peer accepted
+    channels: HashMap<u64, Option<([u8; 32], TxOut)>>,
+    tx: Option<Transaction>,
+    // Funding outputs rust-lightning didn't say can be broadcast yet
+    unsafe_txos: HashSet<ChannelOutPoint>,
+}
+
+struct WalletState {
+    // The branch and index of each script we watch
+    scripts: HashMap<Script, (u32, u32)>,
+    next_index: HashMap<u32, u32>,
+    synced_height: Option<u32>,
+    utxos: HashMap<OutPoint, Utxo>,
+    // Spent by transactions we made that aren't in a block yet
+    locked: HashSet<OutPoint>,
+    funding_transactions: HashMap<ChannelOutPoint, PendingFunding>,
+    // By user_channel_id, the ones to be funded with a PSBT and those already waiting for it
+    psbt_channels: HashSet<u64>,
+    psbt_fundings: HashMap<u64, PsbtFunding>,
+    batches: HashMap<u64, BatchFunding>,
+    sweeps: Vec<Sweep>,
+    // Those already broadcast, rust-lightning can report an output more than once
+    swept: HashSet<OutPoint>,
+}
+
+pub struct Wallet {
+    chain: String,
+    signer: Arc<dyn Signer>,
+    account: ExtendedPubKey,
+    backend: Arc<dyn ChainBackend>,
+    fee_estimator: Arc<dyn FeeEstimator>,
+    secp_ctx: Secp256k1<All>,
+    fundings_path: Option<PathBuf>,
+    synced_path: Option<PathBuf>,
+    state: Mutex<WalletState>,
+}
+
+// One line per pending funding: funding txid, output index, temporary_channel_id, safe and the
+// transaction, in hex
+fn encode_fundings(fundings: &HashMap<ChannelOutPoint, PendingFunding>) -> String {
+    let mut lines: Vec<String> = fundings.iter().map(|(funding_txo, funding)| {
+        format!("{} {} {} {} {}\n", funding_txo.txid, funding_txo.index, funding.temporary_channel_id.to_hex(),
+                funding.safe as u8, encode::serialize_hex(&funding.tx))
+    }).collect();
+    lines.sort();
+    lines.concat()
+}
+
+fn decode_fundings(contents: &str) -> Result<HashMap<ChannelOutPoint, PendingFunding>, String> {
+    let mut fundings = HashMap::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 5 {
+            return Err(format!("Bad line '{}'", line));
+        }
+        let txid = Sha256dHash::from_hex(fields[0]).map_err(|_| format!("Bad txid {}", fields[0]))?;
+        let index = fields[1].parse().map_err(|_| format!("Bad index {}", fields[1]))?;
+        let mut temporary_channel_id = [0; 32];
+        match Vec::<u8>::from_hex(fields[2]) {
+            Ok(ref id) if id.len() == 32 => temporary_channel_id.copy_from_slice(id),
+            _ => return Err(format!("Bad channel id {}", fields[2])),
+        }
+        let tx: Transaction = Vec::<u8>::from_hex(fields[4]).ok().and_then(|bytes| encode::deserialize(&bytes).ok())
+            .ok_or(format!("Bad transaction {}", fields[4]))?;
+        fundings.insert(ChannelOutPoint::new(txid, index), PendingFunding { temporary_channel_id, tx, safe: fields[3] == "1" });
+    }
+    Ok(fundings)
+}
+
+fn unspent(state: &WalletState) -> Vec<Utxo> {
+    let mut utxos: Vec<Utxo> = state.utxos.values()
+        .filter(|utxo| !state.locked.contains(&utxo.outpoint))
+        .cloned().collect();
+    utxos.sort_by_key(|utxo| (utxo.height, utxo.outpoint.txid.into_inner(), utxo.outpoint.vout));
+    utxos
+}
+
+/// Where the wallet with fundings_path saves its coins and the last block it scanned
+pub fn synced_path(fundings_path: &Path) -> PathBuf {
+    fundings_path.with_file_name(format!("{}-synced", fundings_path.file_name().unwrap().to_string_lossy()))
+}
+
+// "height <synced height>", then "next <branch> <index>" for the branches with addresses handed
+// out and "utxo <txid> <vout> <height> <value> <script>" for each coin, the script in hex
+fn encode_synced(synced_height: u32, state: &WalletState) -> String {
+    let mut lines: Vec<String> = state.next_index.iter().map(|(branch, index)| format!("next {} {}\n", branch, index)).collect();
+    lines.extend(state.utxos.values().map(|utxo| {
+        format!("utxo {} {} {} {} {}\n", utxo.outpoint.txid, utxo.outpoint.vout, utxo.height, utxo.output.value,
+                utxo.output.script_pubkey.to_bytes().to_hex())
+    }));
+    lines.sort();
+    format!("height {}\n{}", synced_height, lines.concat())
+}
+
+// What the synced file holds
+struct Synced {
+    height: u32,
+    next_index: HashMap<u32, u32>,
+    utxos: HashMap<OutPoint, Utxo>,
+}
+
+fn decode_synced(contents: &str) -> Result<Synced, String> {
+    let mut lines = contents.lines();
+    let height = match lines.next().map(|line| line.split(' ').collect::<Vec<&str>>()) {
+        Some(ref fields) if fields.len() == 2 && fields[0] == "height" => fields[1].parse().map_err(|_| format!("Bad height {}", fields[1]))?,
+        _ => return Err("No height".to_string()),
+    };
+    let mut next_index = HashMap::new();
+    let mut utxos = HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match (fields[0], fields.len()) {
+            ("next", 3) => {
+                let branch = fields[1].parse().map_err(|_| format!("Bad branch {}", fields[1]))?;
+                next_index.insert(branch, fields[2].parse().map_err(|_| format!("Bad index {}", fields[2]))?);
+            },
+            ("utxo", 6) => {
+                let txid = Sha256dHash::from_hex(fields[1]).map_err(|_| format!("Bad txid {}", fields[1]))?;
+                let outpoint = OutPoint { txid, vout: fields[2].parse().map_err(|_| format!("Bad index {}", fields[2]))? };
+                let height = fields[3].parse().map_err(|_| format!("Bad height {}", fields[3]))?;
+                let value = fields[4].parse().map_err(|_| format!("Bad value {}", fields[4]))?;
+                let script_pubkey = Script::from(Vec::<u8>::from_hex(fields[5]).map_err(|_| format!("Bad script {}", fields[5]))?);
+                utxos.insert(outpoint, Utxo { outpoint, output: TxOut { value, script_pubkey }, height });
+            },
+            _ => return Err(format!("Bad line '{}'", line)),
+        }
+    }
+    Ok(Synced { height, next_index, utxos })
+}
+
+impl Wallet {
+
+    /// The signer has the keys, the wallet only needs the public ones
+    pub fn new(chain: &str, signer: Arc<dyn Signer>, backend: Arc<dyn ChainBackend>, fee_estimator: Arc<dyn FeeEstimator>) -> Result<Wallet, String> {
+        Wallet::with_fundings_file(chain, signer, backend, fee_estimator, None)
+    }
+
+    /// Keeps the funding transactions not broadcast yet in fundings_path, picking up the ones
+    /// already there, and its coins at synced_path(fundings_path)
+    pub fn with_fundings_file(chain: &str, signer: Arc<dyn Signer>, backend: Arc<dyn ChainBackend>, fee_estimator: Arc<dyn FeeEstimator>,
+                              fundings_path: Option<&Path>) -> Result<Wallet, String> {
+        let funding_transactions = match fundings_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+                decode_fundings(&contents).map_err(|e| format!("Cannot read {:?}: {}", path, e))?
+            },
+            _ => HashMap::new(),
+        };
+        let synced_path = fundings_path.map(synced_path);
+        let (synced_height, next_index, utxos) = match &synced_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+                let synced = decode_synced(&contents).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+                (Some(synced.height), synced.next_index, synced.utxos)
+            },
+            _ => (None, HashMap::new(), HashMap::new()),
+        };
+        let locked = funding_transactions.values().flat_map(|funding| funding.tx.input.iter().map(|input| input.previous_output)).collect();
+        let wallet = Wallet {
+            chain: chain.to_string(),
+            account: signer.get_wallet_account_xpub()?,
+            signer,
+            backend,
+            fee_estimator,
+            secp_ctx: Secp256k1::new(),
+            fundings_path: fundings_path.map(Path::to_path_buf),
+            synced_path,
+            state: Mutex::new(WalletState {
+                scripts: HashMap::new(),
+                next_index,
+                synced_height,
+                utxos,
+                locked,
+                funding_transactions,
+                psbt_channels: HashSet::new(),
+                psbt_fundings: HashMap::new(),
+                batches: HashMap::new(),
+                sweeps: Vec::new(),
+                swept: HashSet::new(),
+            }),
+        };
+        {
+            let mut state = wallet.state.lock().unwrap();
+            for branch in &[keys::RECEIVE_BRANCH, keys::CHANGE_BRANCH] {
+                let end = state.next_index.get(branch).unwrap_or(&0) + GAP_LIMIT;
+                wallet.watch_up_to(&mut state, *branch, end);
+            }
+            // The close key is the only one in its branch
+            let close_script = wallet.script(keys::CLOSE_BRANCH, 0);
+            state.scripts.insert(close_script, (keys::CLOSE_BRANCH, 0));
+        }
+        Ok(wallet)
+    }
+
+    fn script(&self, branch: u32, index: u32) -> Script {
+        keys::p2wpkh_script(&keys::wallet_pubkey(&self.secp_ctx, &self.account, branch, index))
+    }
+
+    fn watch_up_to(&self, state: &mut WalletState, branch: u32, end: u32) {
+        let watched = state.scripts.values().filter(|(b, _)| *b == branch).count() as u32;
+        for index in watched..end {
+            state.scripts.insert(self.script(branch, index), (branch, index));
+        }
+    }
+
+    // A script nobody has been given yet, watching more to keep the gap
+    fn next_script(&self, state: &mut WalletState, branch: u32) -> Script {
+        let index = *state.next_index.get(&branch).unwrap_or(&0);
+        state.next_index.insert(branch, index + 1);
+        self.watch_up_to(state, branch, index + 1 + GAP_LIMIT);
+        self.script(branch, index)
+    }
+
+    pub fn get_chain(&self) -> &str {
+        &self.chain
+    }
+
+    /// A fresh address to receive coins at
+    pub fn new_address(&self) -> String {
+        let script = self.next_script(&mut self.state.lock().unwrap(), keys::RECEIVE_BRANCH);
+        script_to_address(&self.chain, &script).expect("P2WPKH scripts have addresses")
+    }
+
+    /// Confirmed coins that aren't being spent
+    pub fn list_unspent(&self) -> Vec<Utxo> {
+        unspent(&self.state.lock().unwrap())
+    }
+
+    pub fn get_balance(&self) -> u64 {
+        self.list_unspent().iter().map(|utxo| utxo.output.value).sum()
+    }
+
+    /// Outputs of closed channels waiting for their delay or a failed broadcast to be retried
+    pub fn pending_sweeps(&self) -> usize {
+        self.state.lock().unwrap().sweeps.len()
+    }
+
+    // Writes the pending fundings, the wallet goes on if it can't
+    fn save_fundings(&self, state: &WalletState) {
+        if let Some(path) = &self.fundings_path {
+            if let Err(e) = monitor::write_durably(path, encode_fundings(&state.funding_transactions).as_bytes()) {
+                println!("Error: Cannot save the funding transactions of chain {}: {}", self.chain, e);
+            }
+        }
+    }
+
+    // Writes the coins and the last block scanned, the next start scans from there
+    fn save_synced(&self, state: &WalletState) {
+        if let (Some(path), Some(height)) = (&self.synced_path, state.synced_height) {
+            if let Err(e) = monitor::write_durably(path, encode_synced(height, state).as_bytes()) {
+                println!("Error: Cannot save the wallet of chain {}: {}", self.chain, e);
+            }
+        }
+    }
+
+    /// The funding transactions not broadcast yet
+    pub fn pending_fundings(&self) -> Vec<Transaction> {
+        self.state.lock().unwrap().funding_transactions.values().map(|funding| funding.tx.clone()).collect()
+    }
+
+    /// Unlocks the inputs of the funding transactions whose channel went away before the peer
+    /// signed it, and broadcasts those the peer signed that failed before
+    pub fn check_fundings(&self, channel_manager: &ChannelManager) {
+        // Before the lock, rust-lightning's is taken after ours in handle_event
+        let channel_ids: HashSet<[u8; 32]> = channel_manager.list_channels().iter().map(|c| c.channel_id).collect();
+        let mut state = self.state.lock().unwrap();
+        let gone: Vec<ChannelOutPoint> = state.funding_transactions.iter().filter(|(funding_txo, funding)| {
+            !funding.safe && !channel_ids.contains(&funding_txo.to_channel_id()) && !channel_ids.contains(&funding.temporary_channel_id)
+        }).map(|(funding_txo, _)| *funding_txo).collect();
+        for funding_txo in &gone {
+            let funding = state.funding_transactions.remove(funding_txo).unwrap();
+            println!("The channel funded by {} on chain {} is gone, its coins can be spent again", funding_txo.txid, self.chain);
+            for input in &funding.tx.input {
+                state.locked.remove(&input.previous_output);
+            }
+        }
+        let safe: Vec<ChannelOutPoint> = state.funding_transactions.iter().filter(|(_, funding)| funding.safe).map(|(funding_txo, _)| *funding_txo).collect();
+        if !gone.is_empty() {
+            self.save_fundings(&state);
+        }
+        drop(state);
+        for funding_txo in safe {
+            self.broadcast_funding(&funding_txo);
+        }
+    }
+
+    fn broadcast_funding(&self, funding_txo: &ChannelOutPoint) {
+        let tx = match self.state.lock().unwrap().funding_transactions.get(funding_txo) {
+            Some(funding) => funding.tx.clone(),
+            None => return,
+        };
+        match self.backend.send_transaction(&tx) {
+            Ok(()) => {
+                let mut state = self.state.lock().unwrap();
+                state.funding_transactions.remove(funding_txo);
+                self.save_fundings(&state);
+            },
+            Err(e) => println!("Cannot broadcast funding transaction {}: {}", tx.txid(), e),
+        }
+    }
+
+    /// Follows the chain up to the backend's tip and retries pending sweeps
+    pub fn sync(&self) -> Result<(), String> {
+        let tip = self.backend.get_height()?;
+        let mut scanned = false;
+        loop {
+            let height = match self.state.lock().unwrap().synced_height {
+                Some(height) if height >= tip => break,
+                Some(height) => height + 1,
+                None => 0,
+            };
+            let block = self.backend.get_block(height)?;
+            let mut state = self.state.lock().unwrap();
+            // Again if it had outputs to the addresses watched because of the ones it had
+            loop {
+                let watched = state.scripts.len();
+                for tx in &block.txdata {
+                    self.scan_transaction(&mut state, tx, height);
+                }
+                if state.scripts.len() == watched {
+                    break;
+                }
+            }
+            state.synced_height = Some(height);
+            scanned = true;
+        }
+        if scanned {
+            self.save_synced(&self.state.lock().unwrap());
+        }
+        self.retry_sweeps();
+        Ok(())
+    }
+
+    fn scan_transaction(&self, state: &mut WalletState, tx: &Transaction, height: u32) {
+        for input in &tx.input {
+            state.utxos.remove(&input.previous_output);
+            state.locked.remove(&input.previous_output);
+        }
+        let txid = tx.txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            let (branch, index) = match state.scripts.get(&output.script_pubkey) {
+                Some(position) => *position,
+                None => continue,
+            };
+            if branch != keys::CLOSE_BRANCH && index >= *state.next_index.get(&branch).unwrap_or(&0) {
+                state.next_index.insert(branch, index + 1);
+                self.watch_up_to(state, branch, index + 1 + GAP_LIMIT);
+            }
+            let outpoint = OutPoint { txid, vout: vout as u32 };
+            state.utxos.insert(outpoint, Utxo { outpoint, output: output.clone(), height });
+        }
+    }
+
+    fn fee(&self, weight: u64) -> u64 {
+        self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) * weight / 1000
+    }
+
+    /// A signed transaction paying value to output_script first and the change, if any, back to
+    /// us. Its coins won't be used again unless it never makes it into a block and we restart.
+    pub fn create_transaction(&self, output_script: &Script, value: u64) -> Result<Transaction, String> {
+        self.create_transaction_with_outputs(vec![TxOut { value, script_pubkey: output_script.clone() }])
+    }
+
+    /// Like create_transaction, with the outputs first in that order
+    pub fn create_transaction_with_outputs(&self, mut output: Vec<TxOut>) -> Result<Transaction, String> {
+        if let Some(dust) = output.iter().find(|output| output.value < DUST_LIMIT) {
+            return Err(format!("{} satoshis is below the dust limit", dust.value));
+        }
+        let value: u64 = output.iter().map(|output| output.value).sum();
+        // Held until the coins are locked, so that they aren't selected twice
+        let mut state = self.state.lock().unwrap();
+        let mut utxos = unspent(&state);
+        // Largest first, for few inputs
+        utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.output.value));
+
+        let outputs_weight = TX_OVERHEAD_WEIGHT + output.iter().map(|output| (8 + 1 + output.script_pubkey.len() as u64) * 4).sum::<u64>();
+        let mut selected = Vec::new();
+        let mut selected_value = 0;
+        for utxo in utxos {
+            selected_value += utxo.output.value;
+            selected.push(utxo);
+            if selected_value >= value + self.fee(outputs_weight + P2WPKH_INPUT_WEIGHT * selected.len() as u64) {
+                break;
+            }
+        }
+        let fee_without_change = self.fee(outputs_weight + P2WPKH_INPUT_WEIGHT * selected.len() as u64);
+        if selected_value < value + fee_without_change {
+            return Err(format!("Not enough funds on chain {}: {} satoshis plus fees needed, {} available",
+                               self.chain, value, selected_value));
+        }
+
+        let fee_with_change = self.fee(outputs_weight + P2WPKH_OUTPUT_WEIGHT + P2WPKH_INPUT_WEIGHT * selected.len() as u64);
+        // Otherwise the change goes to the fee
+        if selected_value >= value + fee_with_change + DUST_LIMIT {
+            let script_pubkey = self.next_script(&mut state, keys::CHANGE_BRANCH);
+            output.push(TxOut { value: selected_value - value - fee_with_change, script_pubkey });
+        }
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: selected.iter().map(|utxo| TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: Vec::new(),
+            }).collect(),
+            output,
+        };
+        let inputs: Vec<WalletInput> = selected.iter().map(|utxo| {
+            let (branch, index) = state.scripts[&utxo.output.script_pubkey];
+            WalletInput { branch, index, value: utxo.output.value }
+        }).collect();
+        let witnesses = self.signer.sign_wallet_transaction(&tx, &inputs)?;
+        for (input, witness) in tx.input.iter_mut().zip(witnesses) {
+            input.witness = witness;
+        }
+        for utxo in &selected {
+            state.locked.insert(utxo.outpoint);
+        }
+        Ok(tx)
+    }
+
+    /// The channel opened with user_channel_id will wait for a PSBT from an external wallet
+    /// instead of being funded by this one. Must be called before ChannelManager::create_channel.
+    pub fn fund_with_psbt(&self, user_channel_id: u64) {
+        self.state.lock().unwrap().psbt_channels.insert(user_channel_id);
+    }
+
+    /// The base64 PSBT with the funding output for the external wallet, once the peer accepted
+    /// the channel
+    pub fn funding_psbt(&self, user_channel_id: u64) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let funding = state.psbt_fundings.get(&user_channel_id)?;
+        Some(psbt::to_base64(&psbt::funding_psbt(&funding.output_script, funding.value)))
+    }
+
+    /// Takes the PSBT back signed and finalized. It's broadcast once the peer signed our first
+    /// commitment transaction, before that the funds could be locked in the channel for good.
+    pub fn complete_psbt_funding(&self, channel_manager: &ChannelManager, user_channel_id: u64, signed_psbt: &str) -> Result<ChannelOutPoint, String> {
+        let signed_psbt = psbt::from_base64(signed_psbt)?;
+        let mut state = self.state.lock().unwrap();
+        let funding = state.psbt_fundings.get(&user_channel_id)
+            .ok_or(format!("Channel {} isn't waiting for a PSBT", user_channel_id))?;
+        let index = psbt::find_funding_output(&signed_psbt, &funding.output_script, funding.value)?;
+        let tx = psbt::extract_funding_transaction(signed_psbt)?;
+
+        let funding_txo = ChannelOutPoint::new(tx.txid(), index);
+        let funding = state.psbt_fundings.remove(&user_channel_id).unwrap();
+        state.funding_transactions.insert(funding_txo, PendingFunding { temporary_channel_id: funding.temporary_channel_id, tx, safe: false });
+        self.save_fundings(&state);
+        channel_manager.funding_transaction_generated(&funding.temporary_channel_id, funding_txo);
+        Ok(funding_txo)
+    }
+
+    /// Gives up on a channel waiting for a PSBT
+    pub fn cancel_psbt_funding(&self, channel_manager: &ChannelManager, user_channel_id: u64) -> Result<(), String> {
+        let funding = self.state.lock().unwrap().psbt_fundings.remove(&user_channel_id)
+            .ok_or(format!("Channel {} isn't waiting for a PSBT", user_channel_id))?;
+        channel_manager.force_close_channel(&funding.temporary_channel_id);
+        Ok(())
+    }
+
+    /// The channels opened with user_channel_ids will be funded together by one transaction,
+    /// once every peer accepted its channel. Must be called before ChannelManager::create_channel.
+    /// It's up to broadcast_batch to send it, rust-lightning only says each channel is ready.
+    pub fn fund_in_batch(&self, batch_id: u64, user_channel_ids: &[u64]) {
+        self.state.lock().unwrap().batches.insert(batch_id, BatchFunding {
+            channels: user_channel_ids.iter().map(|user_channel_id| (*user_channel_id, None)).collect(),
+            tx: None,
+            unsafe_txos: HashSet::new(),
+        });
+    }
+
+    // Funds the channels of the batch once the last one is accepted
+    fn fund_batch(&self, channel_manager: &ChannelManager, batch_id: u64) {
+        let mut channels: Vec<(u64, [u8; 32], TxOut)> = match self.state.lock().unwrap().batches.get(&batch_id) {
+            Some(batch) if batch.tx.is_none() && batch.channels.values().all(Option::is_some) => batch.channels.iter()
+                .map(|(user_channel_id, accepted)| {
+                    let (temporary_channel_id, output) = accepted.clone().unwrap();
+                    (*user_channel_id, temporary_channel_id, output)
+                }).collect(),
+            _ => return,
+        };
+        channels.sort_by_key(|(user_channel_id, _, _)| *user_channel_id);
+        match self.create_transaction_with_outputs(channels.iter().map(|(_, _, output)| output.clone()).collect()) {
+            Ok(tx) => {
+                let funding_txos: Vec<ChannelOutPoint> = (0..channels.len()).map(|index| ChannelOutPoint::new(tx.txid(), index as u16)).collect();
+                if let Some(batch) = self.state.lock().unwrap().batches.get_mut(&batch_id) {
+                    batch.unsafe_txos = funding_txos.iter().cloned().collect();
+                    batch.tx = Some(tx);
+                }
+                for ((_, temporary_channel_id, _), funding_txo) in channels.iter().zip(funding_txos) {
+                    channel_manager.funding_transaction_generated(temporary_channel_id, funding_txo);
+                }
+            },
+            Err(e) => {
+                println!("Cannot fund channel batch {}: {}", batch_id, e);
+                for (_, temporary_channel_id, _) in &channels {
+                    channel_manager.force_close_channel(temporary_channel_id);
+                }
+            },
+        }
+    }
+
+    /// Whether the batch's transaction can be broadcast, every peer signed its channel
+    pub fn batch_funded(&self, batch_id: u64) -> bool {
+        match self.state.lock().unwrap().batches.get(&batch_id) {
+            Some(batch) => batch.tx.is_some() && batch.unsafe_txos.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Sends the batch's funding transaction, once batch_funded
+    pub fn broadcast_batch(&self, batch_id: u64) -> Result<Transaction, String> {
+        let tx = self.state.lock().unwrap().batches.remove(&batch_id).and_then(|batch| batch.tx)
+            .ok_or(format!("Channel batch {} isn't funded on chain {}", batch_id, self.chain))?;
+        self.backend.send_transaction(&tx)?;
+        Ok(tx)
+    }
+
+    /// Forgets the batch, its coins can be spent again. Its channels are for the caller to close.
+    pub fn cancel_batch(&self, batch_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tx) = state.batches.remove(&batch_id).and_then(|batch| batch.tx) {
+            for input in &tx.input {
+                state.locked.remove(&input.previous_output);
+            }
+        }
+    }
+
+    // Sends the sweeps that are ready, keeping the rest for the next sync
+    fn retry_sweeps(&self) {
+        let sweeps: Vec<Sweep> = self.state.lock().unwrap().sweeps.drain(..).collect();
+        for sweep in sweeps {
+            let tx = match self.sweep_transaction(&sweep) {
+                Ok(Some(tx)) => tx,
+                Ok(None) => {
+                    println!("Not sweeping {} on chain {}, it's dust", sweep.outpoint, self.chain);
+                    continue;
+                },
+                Err(e) => {
+                    println!("Cannot sweep {} on chain {}: {}", sweep.outpoint, self.chain, e);
+                    self.state.lock().unwrap().sweeps.push(sweep);
+                    continue;
+                },
+            };
+            match self.backend.send_transaction(&tx) {
+                Ok(()) => {
+                    println!("Swept {} on chain {} with {}", sweep.outpoint, self.chain, tx.txid());
+                    self.state.lock().unwrap().swept.insert(sweep.outpoint);
+                },
+                Err(_) => self.state.lock().unwrap().sweeps.push(sweep),
+            }
+        }
+    }
+
+    // None for dust
+    fn sweep_transaction(&self, sweep: &Sweep) -> Result<Option<Transaction>, String> {
+        let witness_weight = match &sweep.witness_script {
+            Some(script) => 1 + 1 + 72 + 1 + 1 + 3 + script.len() as u64,
+            None => 1 + 1 + 72 + 1 + 33,
+        };
+        let weight = TX_OVERHEAD_WEIGHT + (36 + 1 + 4) * 4 + witness_weight + P2WPKH_OUTPUT_WEIGHT;
+        let value = match sweep.output.value.checked_sub(self.fee(weight)).filter(|value| *value >= DUST_LIMIT) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let script_pubkey = self.next_script(&mut self.state.lock().unwrap(), keys::CHANGE_BRANCH);
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sweep.outpoint,
+                script_sig: Script::new(),
+                sequence: match sweep.witness_script {
+                    Some(_) => sweep.to_self_delay as u32,
+                    None => 0xffff_ffff,
+                },
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut { value, script_pubkey }],
+        };
+        let refused = |_| "the signer refused to sign".to_string();
+        tx.input[0].witness = match &sweep.witness_script {
+            // <local_delayedsig> 0 <witnessScript>, see SpendableOutputDescriptor
+            Some(script) => {
+                let signature = sweep.keys.signer.sign_with_derived_key(DerivedKey::DelayedPayment, &sweep.per_commitment_point, &tx, 0, script, sweep.output.value)
+                    .map_err(refused)?;
+                vec![keys::witness_signature(&signature), Vec::new(), script.to_bytes()]
+            },
+            None => {
+                let public_key = sweep.keys.derive_public_key(DerivedKey::Payment, &sweep.per_commitment_point)
+                    .map_err(|e| format!("Bad per commitment point: {}", e))?;
+                let signature = sweep.keys.signer.sign_with_derived_key(DerivedKey::Payment, &sweep.per_commitment_point, &tx, 0, &keys::p2wpkh_script_code(&public_key), sweep.output.value)
+                    .map_err(refused)?;
+                vec![keys::witness_signature(&signature), public_key.serialize().to_vec()]
+            },
+        };
+        Ok(Some(tx))
+    }
+
+    /// Funds channels and sweeps what closed channels pay us, returns false for the events
+    /// that aren't the wallet's business
+    pub fn handle_event(&self, channel_manager: &ChannelManager, event: &Event) -> bool {
+        match event {
+            Event::FundingGenerationReady { temporary_channel_id, channel_value_satoshis, output_script, user_channel_id } => {
+                let mut state = self.state.lock().unwrap();
+                if state.psbt_channels.remove(user_channel_id) {
+                    println!("Channel {} on chain {} is waiting for a PSBT funding it", user_channel_id, self.chain);
+                    state.psbt_fundings.insert(*user_channel_id, PsbtFunding {
+                        temporary_channel_id: *temporary_channel_id,
+                        output_script: output_script.clone(),
+                        value: *channel_value_satoshis,
+                    });
+                    return true;
+                }
+                let batch_id = state.batches.iter().find(|(_, batch)| batch.channels.contains_key(user_channel_id)).map(|(batch_id, _)| *batch_id);
+                if let Some(batch_id) = batch_id {
+                    let output = TxOut { value: *channel_value_satoshis, script_pubkey: output_script.clone() };
+                    state.batches.get_mut(&batch_id).unwrap().channels.insert(*user_channel_id, Some((*temporary_channel_id, output)));
+                    drop(state);
+                    self.fund_batch(channel_manager, batch_id);
+                    return true;
+                }
+                drop(state);
+                match self.create_transaction(output_script, *channel_value_satoshis) {
+                    Ok(tx) => {
+                        let funding_txo = ChannelOutPoint::new(tx.txid(), 0);
+                        let mut state = self.state.lock().unwrap();
+                        state.funding_transactions.insert(funding_txo, PendingFunding { temporary_channel_id: *temporary_channel_id, tx, safe: false });
+                        self.save_fundings(&state);
+                        drop(state);
+                        channel_manager.funding_transaction_generated(temporary_channel_id, funding_txo);
+                    },
+                    Err(e) => {
+                        println!("Cannot fund channel: {}", e);
+                        channel_manager.force_close_channel(temporary_channel_id);
+                    },
+                }
+                true
+            },
+            Event::FundingBroadcastSafe { funding_txo, .. } => {
+                let mut state = self.state.lock().unwrap();
+                if state.batches.values_mut().any(|batch| batch.unsafe_txos.remove(funding_txo)) {
+                    return true;
+                }
+                match state.funding_transactions.get_mut(funding_txo) {
+                    Some(funding) => funding.safe = true,
+                    None => {
+                        println!("Unknown funding transaction {}", funding_txo.txid);
+                        return true;
+                    },
+                }
+                self.save_fundings(&state);
+                drop(state);
+                self.broadcast_funding(funding_txo);
+                true
+            },
+            Event::SpendableOutputs { outputs } => {
+                for output in outputs {
+                    let sweep = match output {
+                        // Pays to the close key, the wallet already watches it
+                        SpendableOutputDescriptor::StaticOutput { .. } => continue,
+                        SpendableOutputDescriptor::DynamicOutputP2WSH { outpoint, keys, per_commitment_point, witness_script, to_self_delay, output } => {
+                            Sweep { outpoint: *outpoint, output: output.clone(), keys: keys.clone(), per_commitment_point: *per_commitment_point,
+                                    witness_script: Some(witness_script.clone()), to_self_delay: *to_self_delay }
+                        },
+                        SpendableOutputDescriptor::DynamicOutputP2WPKH { outpoint, keys, per_commitment_point, output } => {
+                            Sweep { outpoint: *outpoint, output: output.clone(), keys: keys.clone(), per_commitment_point: *per_commitment_point,
+                                    witness_script: None, to_self_delay: 0 }
+                        },
+                    };
+                    let mut state = self.state.lock().unwrap();
+                    if !state.swept.contains(&sweep.outpoint) && state.sweeps.iter().all(|s| s.outpoint != sweep.outpoint) {
+                        state.sweeps.push(sweep);
+                    }
+                }
+                self.retry_sweeps();
+                true
+            },
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Our node_announcement, signed with the node key and sent to our peers
+
+mod common;
+
+use common::{connect, logger, new_channels, sync_until};
+use rustlnd::announce::{self, NodeInfo};
+use rustlnd::channels::ChannelState;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::node::Node;
+use rustlnd::wallet;
+
+use lightning::ln::msgs::{NetAddress, RoutingMessageHandler};
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+
+use std::sync::Arc;
+
+// The alias and rgb of node_id in node's network graph, with the announcement's timestamp
+fn announced(node: &Node, node_id: &PublicKey) -> Option<(String, [u8; 3], u32)> {
+    node.router.get_next_node_announcements(None, 255).iter().map(announce::node_announcement_fields)
+        .find(|(id, _, _, _)| id == node_id).map(|(_, timestamp, rgb, alias)| (alias, rgb, timestamp))
+}
+
+#[test]
+fn test_node_announcement() {
+    assert_eq!(announce::parse_rgb("ff8000"), Ok([0xff, 0x80, 0]));
+    assert_eq!(announce::parse_rgb("#FF8000"), Ok([0xff, 0x80, 0]));
+    for bad in ["", "ff80", "ff800000", "#gg8000", "pink"] {
+        assert!(announce::parse_rgb(bad).is_err(), "{}", bad);
+    }
+    assert_eq!(announce::rgb_to_string(&[0xff, 0x80, 0]), "ff8000");
+    assert!(announce::check_alias(&"a".repeat(32)).is_ok());
+    assert!(announce::check_alias(&"a".repeat(33)).is_err());
+    // 32 bytes, not 32 characters
+    assert!(announce::check_alias(&"é".repeat(17)).is_err());
+
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    let node_secret = SecretKey::from_slice(&bytes).unwrap();
+    let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &node_secret);
+    let info = NodeInfo {
+        alias: "café".to_string(),
+        rgb: [1, 2, 3],
+        addresses: vec![NetAddress::IPv6 { addr: [1; 16], port: 9735 }, NetAddress::IPv4 { addr: [10, 0, 0, 1], port: 9735 }],
+    };
+    let features = Node::new_random(&["regtest"], logger()).peer_manager.node_features();
+    let msg = announce::sign_node_announcement(&node_secret, &features, &info, 1234).unwrap();
+    assert_eq!(announce::node_announcement_fields(&msg), (node_id, 1234, [1, 2, 3], "café".to_string()));
+
+    let two_ipv4 = NodeInfo { addresses: vec![NetAddress::IPv4 { addr: [10, 0, 0, 1], port: 1 }, NetAddress::IPv4 { addr: [10, 0, 0, 2], port: 1 }], ..info.clone() };
+    assert!(announce::sign_node_announcement(&node_secret, &features, &two_ipv4, 1234).is_err());
+    let long_alias = NodeInfo { alias: "a".repeat(33), ..info };
+    assert!(announce::sign_node_announcement(&node_secret, &features, &long_alias, 1234).is_err());
+}
+
+#[test]
+fn test_announce_with_channels() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let node_a = Node::new_random(&["regtest"], logger());
+    let node_b = Node::new_random(&["regtest"], logger());
+    let channels_a = new_channels(&node_a, &[&chain]);
+    let channels_b = new_channels(&node_b, &[&chain]);
+    let all = [&*channels_a, &*channels_b];
+    let address = NetAddress::IPv4 { addr: [203, 0, 113, 7], port: 9735 };
+    node_a.announcer.set_info(NodeInfo { alias: "alice".to_string(), rgb: [0xff, 0x80, 0], addresses: vec![address.clone()] }).unwrap();
+    assert!(node_a.announcer.set_info(NodeInfo { alias: "a".repeat(33), ..node_a.announcer.info() }).is_err());
+    // Nothing to announce without channels
+    assert_eq!(node_a.announcer.announce(), Ok(false));
+
+    connect(&node_a, &node_b);
+    let wallet_a = channels_a.get_wallet("regtest").unwrap();
+    chain.faucet(&wallet::address_to_script("regtest", &wallet_a.new_address()).unwrap(), 1_000_000);
+    chain.mine_blocks(1);
+    channels_a.sync().unwrap();
+    channels_a.open_channel("regtest", node_b.get_our_node_id(), 500_000, 0, false).unwrap();
+    assert!(sync_until(&all, || chain.get_mempool().len() == 1));
+    chain.mine_blocks(6);
+    assert!(sync_until(&all, || channels_a.list_channels().iter().any(|c| c.state == ChannelState::Open)));
+    assert!(sync_until(&all, || announced(&node_a, &node_a.get_our_node_id()).is_some()));
+    let (alias, rgb, timestamp) = announced(&node_a, &node_a.get_our_node_id()).unwrap();
+    assert_eq!((alias.as_str(), rgb), ("alice", [0xff, 0x80, 0]));
+    assert_eq!(node_a.router.get_addresses(&node_a.get_our_node_id()), Some(vec![address.clone()]));
+    assert_eq!(node_a.announcer.announce(), Ok(false));
+
+    // Announced again when it changes, the peer gets it
+    assert!(sync_until(&all, || node_b.graph.channels("regtest").len() == 1));
+    node_a.announcer.set_info(NodeInfo { alias: "alice2".to_string(), ..node_a.announcer.info() }).unwrap();
+    assert_eq!(node_a.announcer.announce(), Ok(true));
+    assert_eq!(node_a.announcer.announce(), Ok(false));
+    let (alias, _, new_timestamp) = announced(&node_a, &node_a.get_our_node_id()).unwrap();
+    assert_eq!(alias, "alice2");
+    assert!(new_timestamp > timestamp);
+    assert!(sync_until(&all, || announced(&node_b, &node_a.get_our_node_id()) == announced(&node_a, &node_a.get_our_node_id())));
+    assert_eq!(node_b.router.get_addresses(&node_a.get_our_node_id()), Some(vec![address]));
+
+    // A node connecting later gets it with the channel
+    let node_c = Node::new_random(&["regtest"], logger());
+    let channels_c = new_channels(&node_c, &[&chain]);
+    connect(&node_c, &node_a);
+    assert!(sync_until(&[&*channels_a, &*channels_c], || node_c.graph.channels("regtest").len() == 1));
+    assert!(sync_until(&[&*channels_a, &*channels_c], || announced(&node_c, &node_a.get_our_node_id()).is_some()));
+    assert_eq!(announced(&node_c, &node_a.get_our_node_id()), announced(&node_a, &node_a.get_our_node_id()));
+    assert!(node_a.peer_manager.get_peer_node_ids().contains(&node_c.get_our_node_id()));
+}
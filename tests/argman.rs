@@ -316,3 +316,83 @@ fn test_get_by_category() {
     assert!(g_args.parse_args_vec(raw_args));
     println!("{:?}", g_args.get_by_category("-cat1", "-aaa"));
 }
+
+#[test]
+fn test_multi_unique_dedup() {
+    let raw_args = vec!["binname".to_string(), "-aaa=x".to_string(), "-aaa=y".to_string(), "-aaa=x".to_string()];
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_multi_unique("-aaa", vec!["default".to_string()], "Unique multi arg");
+    assert!(g_args.parse_args_vec(raw_args));
+    assert_eq!(g_args.get_multi("-aaa"), &["x".to_string(), "y".to_string()]);
+}
+
+#[test]
+fn test_multi_keeps_repeated() {
+    let raw_args = vec!["binname".to_string(), "-aaa=x".to_string(), "-aaa=x".to_string()];
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_multi("-aaa", vec![], "Multi arg");
+    assert!(g_args.parse_args_vec(raw_args));
+    assert_eq!(g_args.get_multi("-aaa").len(), 2);
+}
+
+#[test]
+fn test_validator() {
+    fn is_number(value: &str) -> Result<(), String> {
+        value.parse::<u32>().map(|_| ()).map_err(|e| e.to_string())
+    }
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_multi_unique("-aaa", vec![], "Numbers");
+    g_args.set_validator("-aaa", is_number);
+    assert!(g_args.parse_args_vec(vec!["binname".to_string(), "-aaa=1".to_string(), "-aaa=2".to_string()]));
+
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_multi_unique("-aaa", vec![], "Numbers");
+    g_args.set_validator("-aaa", is_number);
+    assert!(!g_args.parse_args_vec(vec!["binname".to_string(), "-aaa=1".to_string(), "-aaa=two".to_string()]));
+}
+
+#[test]
+#[should_panic(expected = "Argument -aaa is not defined.")]
+fn test_validator_undefined() {
+    let mut g_args = argman::ArgMan::new();
+    g_args.set_validator("-aaa", |_| Ok(()));
+}
+
+#[test]
+fn test_sensitive() {
+    let raw_args = vec!["binname".to_string(), "-aaa=secret".to_string()];
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_unset("-aaa", "A password");
+    g_args.set_sensitive("-aaa");
+    assert!(g_args.parse_args_vec(raw_args));
+    assert_eq!(g_args.get("-aaa"), "secret");
+}
+
+#[test]
+fn test_category_defaults_kept() {
+    let raw_args = vec!["binname".to_string(), "-cat1.-aaa=myval".to_string()];
+    let mut g_args = argman::ArgMan::new();
+    let mut default_map: HashMap<String, String> = HashMap::new();
+    default_map.insert("-cat2".to_string(), "default2".to_string());
+    g_args.add_arg_with_category("-aaa", default_map, "Simple string arg");
+    assert!(g_args.parse_args_vec(raw_args));
+    assert_eq!(g_args.get_by_category("-cat1", "-aaa"), "myval");
+    assert_eq!(g_args.get_by_category("-cat2", "-aaa"), "default2");
+}
+
+#[test]
+fn test_get_by_category_or_common() {
+    let mut default_map: HashMap<String, String> = HashMap::new();
+    default_map.insert("".to_string(), "default".to_string());
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_with_category("-aaa", default_map.clone(), "Simple string arg");
+    assert!(g_args.parse_args_vec(vec!["binname".to_string(), "-cat1.-aaa=myval".to_string()]));
+    assert_eq!(g_args.get_by_category_or_common("-cat1", "-aaa"), "myval");
+    assert_eq!(g_args.get_by_category_or_common("-cat2", "-aaa"), "default");
+
+    let mut g_args = argman::ArgMan::new();
+    g_args.add_arg_with_category("-aaa", default_map, "Simple string arg");
+    assert!(g_args.parse_args_vec(vec!["binname".to_string(), "-aaa=common".to_string(), "-cat1.-aaa=myval".to_string()]));
+    assert_eq!(g_args.get_by_category_or_common("-cat1", "-aaa"), "myval");
+    assert_eq!(g_args.get_by_category_or_common("-cat2", "-aaa"), "common");
+}
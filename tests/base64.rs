@@ -0,0 +1,21 @@
+//! Base64 encoding
+
+use rustlnd::base64;
+
+#[test]
+fn test_base64() {
+    // RFC 4648
+    let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="),
+                   ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+    for (data, encoded) in vectors.iter() {
+        assert_eq!(base64::encode(data.as_bytes()), *encoded);
+        assert_eq!(base64::decode(encoded).unwrap(), data.as_bytes());
+    }
+    let all: Vec<u8> = (0..=255).collect();
+    assert_eq!(base64::decode(&base64::encode(&all)).unwrap(), all);
+
+    assert!(base64::decode("Zm9").is_err());
+    assert!(base64::decode("Zg==Zm9v").is_err());
+    assert!(base64::decode("Zm9v!A==").is_err());
+    assert!(base64::decode("Z===").is_err());
+}
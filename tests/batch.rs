@@ -0,0 +1,95 @@
+//! Opening batches of channels, to several peers and chains, all or nothing
+
+mod common;
+
+use common::{connect, fund, new_channels, new_node_with_policy, sync_until};
+use rustlnd::channels::{BatchOpen, ChannelState};
+use rustlnd::control::Control;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::node::Node;
+use rustlnd::peers::PeerConnector;
+use rustlnd::policy::{ChainPolicy, ChannelPolicy};
+
+
+use std::sync::Arc;
+use std::{thread, time};
+
+#[test]
+fn test_batch_open() {
+    let regtest = Arc::new(MemoryChain::new("regtest"));
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let node_a = new_node_with_policy(&["regtest", "aaa"], ChannelPolicy::default());
+    let node_b = new_node_with_policy(&["regtest", "aaa"], ChannelPolicy::default());
+    let node_c = new_node_with_policy(&["regtest"], ChannelPolicy::default());
+    let channels_a = new_channels(&node_a, &[&regtest, &aaa]);
+    let channels_b = new_channels(&node_b, &[&regtest, &aaa]);
+    let channels_c = new_channels(&node_c, &[&regtest]);
+    connect(&node_b, &node_a);
+    connect(&node_b, &node_c);
+    fund(&regtest, &channels_b, "regtest", 500_000);
+    fund(&aaa, &channels_b, "aaa", 300_000);
+
+    let peer_connector = Arc::new(PeerConnector::new(&node_b, None));
+    let control = Control::new(&node_b, peer_connector, Some(channels_b.clone()));
+    let answer = control.handle_command(&format!("openchannels regtest {} 100000 regtest {} 150000 aaa {} 120000",
+                                                 node_a.get_our_node_id(), node_c.get_our_node_id(), node_a.get_our_node_id()));
+    assert!(answer.starts_with("user_channel_ids "), "{}", answer);
+    assert!(control.handle_command("openchannels regtest").starts_with("error: wrong number of arguments"));
+
+    // One transaction per chain
+    let all = [&*channels_a, &*channels_b, &*channels_c];
+    assert!(sync_until(&all, || regtest.get_mempool().len() == 1 && aaa.get_mempool().len() == 1));
+    let regtest_funding = regtest.get_mempool().remove(0);
+    let mut values: Vec<u64> = regtest_funding.output.iter().map(|output| output.value).collect();
+    values.truncate(2);
+    assert_eq!(values, vec![100_000, 150_000]);
+    assert_eq!(aaa.get_mempool()[0].output[0].value, 120_000);
+
+    regtest.mine_blocks(6);
+    aaa.mine_blocks(6);
+    assert!(sync_until(&all, || {
+        channels_b.list_channels().iter().filter(|c| c.state == ChannelState::Open).count() == 3
+    }));
+    let opened = channels_b.list_channels();
+    assert!(opened.iter().filter(|c| c.chain == "regtest").all(|c| c.funding_txo.unwrap().txid == regtest_funding.txid()));
+    assert_eq!(channels_c.list_channels()[0].value, 150_000);
+}
+
+#[test]
+fn test_batch_refused() {
+    let regtest = Arc::new(MemoryChain::new("regtest"));
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let mut policy = ChannelPolicy::default();
+    policy.chains.insert("regtest".to_string(), ChainPolicy { min_channel_size: 200_000, ..ChainPolicy::default() });
+    let node_a = new_node_with_policy(&["regtest", "aaa"], ChannelPolicy::default());
+    let node_b = new_node_with_policy(&["regtest", "aaa"], ChannelPolicy::default());
+    let node_c = new_node_with_policy(&["regtest"], policy);
+    let channels_a = new_channels(&node_a, &[&regtest, &aaa]);
+    let channels_b = new_channels(&node_b, &[&regtest, &aaa]);
+    let channels_c = new_channels(&node_c, &[&regtest]);
+    connect(&node_b, &node_a);
+    connect(&node_b, &node_c);
+    fund(&regtest, &channels_b, "regtest", 500_000);
+    fund(&aaa, &channels_b, "aaa", 300_000);
+
+    // C refuses its channel, A's are never funded
+    let open = |chain: &str, node: &Node, value| BatchOpen { chain: chain.to_string(), node_id: node.get_our_node_id(), value, push_msat: 0 };
+    let batch = [open("regtest", &node_a, 100_000), open("regtest", &node_c, 100_000), open("aaa", &node_a, 100_000)];
+    assert_eq!(channels_b.open_batch(&batch).unwrap().len(), 3);
+    let all = [&*channels_a, &*channels_b, &*channels_c];
+    assert!(sync_until(&all, || node_b.chains.iter().all(|chain_node| chain_node.channel_manager.list_channels().is_empty())));
+    for _ in 0..3 {
+        channels_b.sync().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    assert!(regtest.get_mempool().is_empty() && aaa.get_mempool().is_empty());
+    assert_eq!(channels_b.get_wallet("regtest").unwrap().get_balance(), 500_000);
+    assert_eq!(channels_b.get_wallet("aaa").unwrap().get_balance(), 300_000);
+
+    // The coins can fund the next batch
+    let batch = [open("regtest", &node_a, 200_000), open("regtest", &node_c, 250_000)];
+    channels_b.open_batch(&batch).unwrap();
+    assert!(sync_until(&all, || regtest.get_mempool().len() == 1));
+    assert!(channels_b.open_batch(&[open("bbb", &node_a, 100_000)]).unwrap_err().contains("chain bbb"));
+    assert!(channels_b.open_batch(&[]).is_err());
+}
@@ -0,0 +1,50 @@
+//! BIP39 test vectors (passphrase "TREZOR") from the BIPs repo
+
+use rustlnd::bip39;
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+
+const VECTORS: &[(&str, &str, &str)] = &[
+    ("00000000000000000000000000000000",
+     "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+     "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"),
+    ("7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+     "legal winner thank year wave sausage worth useful legal winner thank yellow",
+     "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607"),
+    ("80808080808080808080808080808080",
+     "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+     "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8"),
+    ("ffffffffffffffffffffffffffffffff",
+     "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+     "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069"),
+    ("9e885d952ad362caeb4efe34a8e91bd2",
+     "ozone drill grab fiber curtain grace pudding thank cruise elder eight picnic",
+     "274ddc525802f7c828d8ef7ddbcdc5304e87ac3535913611fbbfa986d0c9e5476c91689f9c8a54fd55bd38606aa6a8595ad213d4c9c9f9aca3fb217069a41028"),
+    ("f585c11aec520db57dd353c69554b21a89b20fb0650966fa0a9d6f74fd989d8f",
+     "void come effort suffer camp survey warrior heavy shoot primary clutch crush open amazing screen patrol group space point ten exist slush involve unfold",
+     "01f5bced59dec48e362f2c45b5de68b9fd6c92c6634f44d6d40aab69056506f0e35524a518034ddc1192e1dacd32c1ed3eaa3c3b131c88ed8e7e54c49a5d0998"),
+];
+
+#[test]
+fn test_vectors() {
+    for (entropy_hex, mnemonic, seed_hex) in VECTORS {
+        let entropy = Vec::<u8>::from_hex(entropy_hex).unwrap();
+        assert_eq!(&bip39::entropy_to_mnemonic(&entropy).unwrap(), mnemonic);
+        assert_eq!(bip39::mnemonic_to_entropy(mnemonic).unwrap(), entropy);
+        assert_eq!(&bip39::mnemonic_to_seed(mnemonic, "TREZOR").unwrap().to_hex(), seed_hex);
+        // Any case, like mnemonic_to_entropy
+        assert_eq!(&bip39::mnemonic_to_seed(&mnemonic.to_uppercase(), "TREZOR").unwrap().to_hex(), seed_hex);
+    }
+}
+
+#[test]
+fn test_invalid_mnemonics() {
+    // Wrong checksum
+    assert!(bip39::mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon").is_err());
+    // Not in the wordlist
+    assert!(bip39::mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon bitcoin").is_err());
+    // Wrong number of words
+    assert!(bip39::mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").is_err());
+    assert!(bip39::entropy_to_mnemonic(&[0; 15]).is_err());
+    assert!(bip39::mnemonic_to_seed("abandon", "pässphrase").is_err());
+}
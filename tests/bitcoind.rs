@@ -0,0 +1,72 @@
+//! bitcoind's JSON-RPC interface, against a stand-in serving an in-memory chain
+
+mod common;
+
+use common::bitcoind_stand_in;
+
+use rustlnd::bitcoind::{BitcoindRpc, Json};
+use rustlnd::chain::ChainBackend;
+use rustlnd::memorychain::MemoryChain;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+
+use std::sync::Arc;
+
+#[test]
+fn test_json() {
+    let parsed = Json::parse(r#" {"result": [1, -2.5e3, "a\"b\\c\n\u00e9", true, false, null, {}], "error" : null} "#).unwrap();
+    assert_eq!(parsed.get("error"), Some(&Json::Null));
+    assert_eq!(parsed.get("result"), Some(&Json::Array(vec![
+        Json::Number("1".to_string()),
+        Json::Number("-2.5e3".to_string()),
+        Json::String("a\"b\\c\né".to_string()),
+        Json::Bool(true),
+        Json::Bool(false),
+        Json::Null,
+        Json::Object(vec![]),
+    ])));
+    assert_eq!(Json::parse(&parsed.to_json()).unwrap(), parsed);
+    assert_eq!(Json::String("\u{1}".to_string()).to_json(), "\"\\u0001\"");
+
+    assert!(Json::parse("").is_err());
+    assert!(Json::parse("[1, 2").is_err());
+    assert!(Json::parse("{\"a\" 1}").is_err());
+    assert!(Json::parse("nul").is_err());
+    assert!(Json::parse("1 2").is_err());
+}
+
+#[test]
+fn test_chain_backend() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let tx = chain.faucet(&Script::from(vec![0; 22]), 10_000);
+    chain.mine_blocks(3);
+    let addr = bitcoind_stand_in(chain.clone());
+    let rpc = BitcoindRpc::new(&addr.to_string(), "user", "pass");
+
+    assert_eq!(rpc.get_height().unwrap(), 3);
+    assert_eq!(rpc.get_block(1).unwrap(), chain.get_block(1).unwrap());
+    assert_eq!(rpc.get_block(1).unwrap().txdata, vec![tx.clone()]);
+    assert!(rpc.get_block(4).unwrap_err().contains("out of range"));
+    assert_eq!(rpc.call("nosuchmethod", vec![]).unwrap(), Err((-32601, "Method not found".to_string())));
+
+    // Transactions bitcoind already has aren't an error
+    rpc.send_transaction(&tx).unwrap();
+    let unconfirmed = chain.faucet(&Script::from(vec![1; 22]), 20_000);
+    rpc.send_transaction(&unconfirmed).unwrap();
+    let spend = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn { previous_output: OutPoint { txid: unconfirmed.txid(), vout: 0 }, script_sig: Script::new(), sequence: 0xffff_ffff, witness: Vec::new() }],
+        output: vec![TxOut { value: 10_000, script_pubkey: Script::from(vec![2; 22]) }],
+    };
+    rpc.send_transaction(&spend).unwrap();
+    assert_eq!(chain.get_mempool().len(), 2);
+    let mut double_spend = spend.clone();
+    double_spend.output[0].value = 9_000;
+    assert!(rpc.send_transaction(&double_spend).unwrap_err().contains(&double_spend.txid().to_string()));
+
+    let wrong = BitcoindRpc::new(&addr.to_string(), "user", "wrong");
+    assert!(wrong.get_height().unwrap_err().contains("-rpcuser and -rpcpass"));
+    assert!(BitcoindRpc::new("127.0.0.1:1", "user", "pass").get_height().unwrap_err().contains("Cannot connect"));
+}
@@ -0,0 +1,107 @@
+//! Learning which chains peers support from their init messages and how they answer channel opens
+
+mod common;
+
+use common::wait_for;
+use rustlnd::chain;
+use rustlnd::logger::PrintLogger;
+use rustlnd::net;
+use rustlnd::node::Node;
+
+use lightning::ln::msgs::{self, ChannelMessageHandler, ErrorAction};
+use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+use lightning::util::logger::Level;
+use lightning::util::ser::{Readable, Writeable};
+
+use std::io::Cursor;
+
+use std::sync::Arc;
+
+fn new_node(chains: &[&str]) -> Node {
+    Node::new_random(chains, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+// common::connect, waiting for both sides
+fn connect(node_a: &Node, node_b: &Node) {
+    common::connect(node_b, node_a);
+    assert!(wait_for(|| node_b.peer_manager.get_peer_node_ids().contains(&node_a.get_our_node_id())));
+}
+
+fn open_channel(opener: &Node, peer: &Node) {
+    opener.chains[0].channel_manager.create_channel(peer.get_our_node_id(), 100_000, 0, 0).unwrap();
+    opener.peer_manager.process_events();
+}
+
+#[test]
+fn test_chain_hashes() {
+    assert_eq!(chain::chain_hash("regtest"), chain::internal_chain_hash("regtest"));
+    assert_ne!(chain::chain_hash("aaa"), chain::internal_chain_hash("aaa"));
+    assert_ne!(chain::chain_hash("aaa"), chain::chain_hash("bbb"));
+}
+
+#[test]
+fn test_same_custom_chain_accepted() {
+    let node_a = new_node(&["aaa"]);
+    let node_b = new_node(&["aaa", "bbb"]);
+    connect(&node_a, &node_b);
+    // Known from the init messages
+    let (aaa, bbb) = (chain::chain_hash("aaa"), chain::chain_hash("bbb"));
+    let peer_chains = node_b.chain_filter.get_peer_chains(&node_a.get_our_node_id());
+    assert!(peer_chains.from_init);
+    assert!(peer_chains.supported.contains(&aaa));
+    assert!(peer_chains.unsupported.contains(&bbb));
+    assert_eq!(node_b.chain_filter.has_common_chain(&node_a.get_our_node_id()), Some(true));
+    assert!(node_b.chain_filter.check_open(&node_a.get_our_node_id(), "bbb").is_err());
+    assert!(node_a.chain_filter.get_peer_chains(&node_b.get_our_node_id()).supported.contains(&aaa));
+
+    open_channel(&node_b, &node_a);
+    assert!(wait_for(|| node_a.chains[0].channel_manager.list_channels().len() == 1));
+    assert_eq!(node_b.chain_filter.has_common_chain(&node_a.get_our_node_id()), Some(true));
+}
+
+#[test]
+fn test_no_common_chain_disconnected() {
+    // Both are regtest for rust-lightning, but not on the wire
+    let node_a = new_node(&["aaa"]);
+    let node_b = new_node(&["regtest"]);
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    let descriptor = net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| !descriptor.is_connected()));
+    assert!(node_a.peer_manager.get_peer_node_ids().is_empty());
+    assert!(node_b.peer_manager.get_peer_node_ids().is_empty());
+}
+
+#[test]
+fn test_refused_open_without_init_networks() {
+    // A peer that doesn't list its chains, talking to the ChainFilter directly
+    let node = new_node(&["regtest"]);
+    let peer = new_node(&["regtest"]).get_our_node_id();
+    node.chain_filter.peer_connected(&peer);
+    assert_eq!(node.chain_filter.has_common_chain(&peer), None);
+    assert!(node.chain_filter.check_open(&peer, "regtest").is_ok());
+
+    node.chains[0].channel_manager.create_channel(peer, 100_000, 0, 0).unwrap();
+    let temporary_channel_id = node.chain_filter.get_and_clear_pending_msg_events().iter().find_map(|event| match event {
+        MessageSendEvent::SendOpenChannel { msg, .. } => Some(msg.encode()[32..64].to_vec()),
+        _ => None,
+    }).unwrap();
+    // Any error to our open, whatever it says
+    let mut error = temporary_channel_id;
+    error.extend_from_slice(&[0, 2]);
+    error.extend_from_slice(b"no");
+    node.chain_filter.handle_error(&peer, &msgs::ErrorMessage::read(&mut Cursor::new(error)).unwrap());
+
+    let regtest = chain::chain_hash("regtest");
+    assert!(node.chain_filter.get_peer_chains(&peer).unsupported.contains(&regtest));
+    assert_eq!(node.chain_filter.has_common_chain(&peer), Some(false));
+    assert!(node.chain_filter.check_open(&peer, "regtest").is_err());
+    let disconnects = node.chain_filter.get_and_clear_pending_msg_events().iter().filter(|event| {
+        matches!(event, MessageSendEvent::HandleError { node_id, action: Some(ErrorAction::DisconnectPeer { .. }) } if *node_id == peer)
+    }).count();
+    assert_eq!(disconnects, 1);
+
+    // And again when it comes back
+    node.chain_filter.peer_disconnected(&peer, false);
+    node.chain_filter.peer_connected(&peer);
+    assert_eq!(node.chain_filter.get_and_clear_pending_msg_events().len(), 1);
+}
@@ -0,0 +1,208 @@
+//! Opening and closing channels between two nodes on an in-memory chain
+
+mod common;
+
+use common::{connect, fund, new_channels, sync_until};
+use rustlnd::channels::{self, ChannelState, Channels};
+use rustlnd::chain::ChainBackend;
+use rustlnd::logger::PrintLogger;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::node::Node;
+
+use lightning::util::logger::Level;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+fn new_node(seed: &[u8], chains: &[&str]) -> Node {
+    Node::new(chains, seed, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+#[test]
+fn test_short_channel_id() {
+    assert_eq!(channels::short_channel_id_to_string((539_268 << 40) | (845 << 16) | 1), "539268x845x1");
+}
+
+#[test]
+fn test_open_and_close() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let node_a = new_node(&[20; 32], &["regtest"]);
+    let node_b = new_node(&[21; 32], &["regtest"]);
+    let channels_a = new_channels(&node_a, &[&chain]);
+    let channels_b = new_channels(&node_b, &[&chain]);
+    let node_id_a = node_a.get_our_node_id();
+
+    assert!(channels_b.open_channel("regtest", node_id_a, 100_000, 0, false).unwrap_err().contains("Not connected"));
+    assert!(channels_b.open_channel("aaa", node_id_a, 100_000, 0, false).unwrap_err().contains("chain aaa"));
+    connect(&node_b, &node_a);
+    fund(&chain, &channels_b, "regtest", 300_000);
+
+    let user_channel_id = channels_b.open_channel("regtest", node_id_a, 100_000, 0, false).unwrap();
+    assert!(sync_until(&[&channels_a, &channels_b], || chain.get_mempool().len() == 1));
+    let funding_txid = chain.get_mempool()[0].txid();
+    let listed = channels_b.list_channels();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].user_channel_id, user_channel_id);
+    assert_eq!(listed[0].node_id, node_id_a);
+    assert_eq!(listed[0].value, 100_000);
+    assert_eq!(listed[0].state, ChannelState::Pending);
+    assert_eq!(listed[0].funding_txo.unwrap().txid, funding_txid);
+    assert_eq!(listed[0].confirmations, 0);
+    let channel_id = listed[0].channel_id;
+
+    chain.mine_blocks(1);
+    assert!(sync_until(&[&channels_a, &channels_b], || channels_a.list_channels()[0].confirmations == 1));
+    assert_eq!(channels_a.list_channels()[0].channel_id, channel_id);
+    assert_eq!(channels_a.list_channels()[0].state, ChannelState::Pending);
+    assert_eq!(channels_b.list_channels()[0].confirmations, 1);
+
+    // Open once the funding transaction is 6 blocks deep for both sides
+    chain.mine_blocks(4);
+    for _ in 0..3 {
+        channels_a.sync().unwrap();
+        channels_b.sync().unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    assert_eq!(channels_b.list_channels()[0].state, ChannelState::Pending);
+    chain.mine_blocks(1);
+    assert!(sync_until(&[&channels_a, &channels_b], || {
+        channels_a.list_channels()[0].state == ChannelState::Open && channels_b.list_channels()[0].state == ChannelState::Open
+    }));
+    let open = channels_b.list_channels().remove(0);
+    assert_eq!(open.short_channel_id.map(|id| id >> 40), Some(2));
+    assert!(open.to_string().ends_with(&format!("open user_channel_id {} 2x0x0", user_channel_id)));
+
+    assert!(channels_b.close_channel(&[0; 32], false).is_err());
+    let balance_b = channels_b.get_wallet("regtest").unwrap().get_balance();
+    channels_b.close_channel(&channel_id, false).unwrap();
+    assert!(sync_until(&[&channels_a, &channels_b], || {
+        channels_a.list_channels()[0].state == ChannelState::Closed && channels_b.list_channels()[0].state == ChannelState::Closed
+    }));
+    // The closing transaction spends the funding output
+    let mempool = chain.get_mempool();
+    assert_eq!(mempool.len(), 1);
+    assert_eq!(mempool[0].input[0].previous_output.txid, funding_txid);
+    assert!(channels_b.list_channels()[0].to_string().ends_with("closed user_channel_id 1 waiting for the closing transaction"));
+
+    // What B gets back pays to its wallet right away
+    chain.mine_blocks(1);
+    assert!(sync_until(&[&channels_a, &channels_b], || !channels_b.list_channels()[0].outputs.is_empty()));
+    let closed = channels_b.list_channels().remove(0);
+    assert_eq!(closed.closing_tx, Some((mempool[0].txid(), 8)));
+    assert_eq!(closed.outputs.len(), 1);
+    assert_eq!(closed.outputs[0].available_at, 8);
+    assert_eq!(closed.outputs[0].swept_at, Some(8));
+    assert_eq!(closed.funds_available_at(), None);
+    assert!(closed.to_string().ends_with(&format!("closing_tx {} at height 8 swept", mempool[0].txid())));
+    assert_eq!(channels_b.get_wallet("regtest").unwrap().get_balance(), balance_b + closed.outputs[0].value);
+    assert_eq!(channels_a.list_channels()[0].closing_tx, closed.closing_tx);
+    assert!(channels_a.list_channels()[0].outputs.is_empty());
+}
+
+// A channel from b to a with push_msat for a, open, returns its channel_id
+fn open_channel(chain: &MemoryChain, channels_a: &Channels, channels_b: &Channels, node_a: &Node, push_msat: u64) -> [u8; 32] {
+    channels_b.open_channel("regtest", node_a.get_our_node_id(), 100_000, push_msat, false).unwrap();
+    assert!(sync_until(&[channels_a, channels_b], || chain.get_mempool().len() == 1));
+    chain.mine_blocks(6);
+    assert!(sync_until(&[channels_a, channels_b], || {
+        channels_a.list_channels()[0].state == ChannelState::Open && channels_b.list_channels()[0].state == ChannelState::Open
+    }));
+    channels_b.list_channels()[0].channel_id
+}
+
+#[test]
+fn test_force_close() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let node_a = new_node(&[24; 32], &["regtest"]);
+    let node_b = new_node(&[25; 32], &["regtest"]);
+    let channels_a = new_channels(&node_a, &[&chain]);
+    let channels_b = new_channels(&node_b, &[&chain]);
+    connect(&node_b, &node_a);
+    fund(&chain, &channels_b, "regtest", 300_000);
+    let channel_id = open_channel(&chain, &channels_a, &channels_b, &node_a, 30_000_000);
+    let wallet_a = channels_a.get_wallet("regtest").unwrap();
+    let wallet_b = channels_b.get_wallet("regtest").unwrap();
+    let balance_b = wallet_b.get_balance();
+
+    // A broadcasts its commitment transaction
+    channels_a.close_channel(&channel_id, true).unwrap();
+    assert_eq!(channels_a.list_channels()[0].state, ChannelState::Closed);
+    assert_eq!(chain.get_mempool().len(), 1);
+    let commitment_txid = chain.get_mempool()[0].txid();
+    chain.mine_blocks(1);
+    let height = chain.get_height().unwrap();
+    assert!(sync_until(&[&channels_a, &channels_b], || {
+        !channels_a.list_channels()[0].outputs.is_empty() && !channels_b.list_channels()[0].outputs.is_empty()
+    }));
+
+    // A's output waits for the delay B asked for
+    let closed_a = channels_a.list_channels().remove(0);
+    assert_eq!(closed_a.closing_tx, Some((commitment_txid, height)));
+    assert_eq!(closed_a.outputs.len(), 1);
+    assert_eq!(closed_a.outputs[0].value, 30_000);
+    let available_at = height + 6 * 24 * 7;
+    assert_eq!(closed_a.funds_available_at(), Some(available_at));
+    assert!(closed_a.to_string().ends_with(&format!("closing_tx {} at height {} funds available at height {}",
+                                                    commitment_txid, height, available_at)));
+
+    // B's output in A's commitment transaction is B's to take right away
+    let closed_b = channels_b.list_channels().remove(0);
+    assert_eq!(closed_b.state, ChannelState::Closed);
+    assert_eq!(closed_b.closing_tx, Some((commitment_txid, height)));
+    assert_eq!(closed_b.outputs.len(), 1);
+    assert_eq!(closed_b.funds_available_at(), Some(height));
+    assert_eq!(chain.get_mempool().len(), 1);
+    chain.mine_blocks(1);
+    assert!(sync_until(&[&channels_a, &channels_b], || channels_b.list_channels()[0].funds_available_at().is_none()));
+    assert_eq!(channels_b.list_channels()[0].outputs[0].swept_at, Some(height + 1));
+    assert!(wallet_b.get_balance() > balance_b + closed_b.outputs[0].value - 1_000);
+    assert_eq!(wallet_b.pending_sweeps(), 0);
+    assert_eq!(wallet_a.pending_sweeps(), 1);
+
+    chain.mine_blocks(available_at - 2 - chain.get_height().unwrap());
+    channels_a.sync().unwrap();
+    assert!(chain.get_mempool().is_empty());
+    // The sweep can be in the block at available_at
+    chain.mine_blocks(1);
+    channels_a.sync().unwrap();
+    assert_eq!(chain.get_mempool().len(), 1);
+    assert_eq!(wallet_a.pending_sweeps(), 0);
+    chain.mine_blocks(1);
+    channels_a.sync().unwrap();
+    let swept = channels_a.list_channels().remove(0);
+    assert_eq!(swept.outputs[0].swept_at, Some(available_at));
+    assert_eq!(swept.funds_available_at(), None);
+    assert!(swept.to_string().ends_with("swept"));
+    assert!(wallet_a.get_balance() > 29_000);
+}
+
+#[test]
+fn test_channels_on_two_chains() {
+    let regtest = Arc::new(MemoryChain::new("regtest"));
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let node_a = new_node(&[22; 32], &["regtest", "aaa"]);
+    let node_b = new_node(&[23; 32], &["regtest", "aaa"]);
+    let channels_a = new_channels(&node_a, &[&regtest, &aaa]);
+    let channels_b = new_channels(&node_b, &[&regtest, &aaa]);
+    connect(&node_b, &node_a);
+    fund(&regtest, &channels_b, "regtest", 300_000);
+    fund(&aaa, &channels_b, "aaa", 300_000);
+
+    channels_b.open_channel("regtest", node_a.get_our_node_id(), 100_000, 0, false).unwrap();
+    channels_b.open_channel("aaa", node_a.get_our_node_id(), 150_000, 0, false).unwrap();
+    assert!(sync_until(&[&channels_a, &channels_b], || regtest.get_mempool().len() == 1 && aaa.get_mempool().len() == 1));
+    regtest.mine_blocks(6);
+    aaa.mine_blocks(6);
+    assert!(sync_until(&[&channels_a, &channels_b], || {
+        channels_a.list_channels().iter().filter(|c| c.state == ChannelState::Open).count() == 2
+    }));
+
+    let listed = channels_a.list_channels();
+    assert_eq!(listed.len(), 2);
+    assert_eq!((listed[0].chain.as_str(), listed[0].value), ("regtest", 100_000));
+    assert_eq!((listed[1].chain.as_str(), listed[1].value), ("aaa", 150_000));
+    // Each chain's funding transaction only pays to its own chain's channel
+    assert!(regtest.get_transaction(&listed[0].funding_txo.unwrap().txid).is_some());
+    assert!(aaa.get_transaction(&listed[1].funding_txo.unwrap().txid).is_some());
+    assert!(aaa.get_transaction(&listed[0].funding_txo.unwrap().txid).is_none());
+}
@@ -0,0 +1,278 @@
+//! Helpers shared by the tests, each test file uses some of them
+#![allow(dead_code)]
+
+use rustlnd::base64;
+use rustlnd::bitcoind::Json;
+use rustlnd::chain::ChainBackend;
+use rustlnd::channels::{ChannelState, Channels, PaymentFailure};
+use rustlnd::control;
+use rustlnd::keys;
+use rustlnd::logger::PrintLogger;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::{self, Node};
+use rustlnd::policy::ChannelPolicy;
+use rustlnd::wallet;
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin_hashes::hex::FromHex;
+use lightning::chain::keysinterface::NodeSigner;
+use lightning::util::logger::{Level, Logger};
+use rand::Rng;
+use secp256k1::key::PublicKey;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{self, Duration};
+
+pub fn logger() -> Arc<dyn Logger> {
+    Arc::new(PrintLogger::new(Level::Info))
+}
+
+/// A new empty directory in the temp dir
+pub fn new_datadir(test_name: &str) -> PathBuf {
+    let random: u64 = rand::thread_rng().gen();
+    let datadir = std::env::temp_dir().join(format!("rustlnd-{}-{:x}", test_name, random));
+    fs::create_dir_all(&datadir).unwrap();
+    datadir
+}
+
+/// Whether condition becomes true within 10 seconds
+pub fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+    for _ in 0..200 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    false
+}
+
+/// wait_for, syncing the nodes with their chains before every check
+pub fn sync_until<F: Fn() -> bool>(nodes: &[&Channels], condition: F) -> bool {
+    wait_for(|| {
+        for channels in nodes {
+            channels.sync().unwrap();
+        }
+        condition()
+    })
+}
+
+/// A node with a random seed and no datadir
+pub fn new_node_with_policy(chains: &[&str], policy: ChannelPolicy) -> Node {
+    let seed: [u8; 32] = rand::thread_rng().gen();
+    Node::with_policy(chains, node::seed_signers(chains, &seed, logger()), None, policy, logger()).unwrap()
+}
+
+/// A regtest node saving its channels in datadir
+pub fn load_node(seed: &[u8], datadir: &Path) -> Node {
+    Node::with_datadir(&["regtest"], node::seed_signers(&["regtest"], seed, logger()), datadir, logger()).unwrap()
+}
+
+/// Channels on the chains, synced with them
+pub fn new_channels(node: &Node, backends: &[&Arc<MemoryChain>]) -> Arc<Channels> {
+    let backends: Vec<Arc<dyn ChainBackend>> = backends.iter().map(|chain| (*chain).clone() as Arc<dyn ChainBackend>).collect();
+    let channels = Channels::new(node, backends).unwrap();
+    channels.sync().unwrap();
+    Arc::new(channels)
+}
+
+/// Connects from to to and waits until to has from as a peer
+pub fn connect(from: &Node, to: &Node) {
+    let addr = net::listen(to.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(from.peer_manager.clone(), to.get_our_node_id(), &addr.to_string()).unwrap();
+    assert!(wait_for(|| to.peer_manager.get_peer_node_ids().contains(&from.get_our_node_id())));
+}
+
+/// Sends value to a new address of the wallet for chain_name and confirms it
+pub fn fund(chain: &MemoryChain, channels: &Channels, chain_name: &str, value: u64) {
+    let wallet = channels.get_wallet(chain_name).unwrap();
+    let balance = wallet.get_balance();
+    chain.faucet(&wallet::address_to_script(chain_name, &wallet.new_address()).unwrap(), value);
+    chain.mine_blocks(1);
+    channels.sync().unwrap();
+    assert_eq!(wallet.get_balance(), balance + value);
+}
+
+/// Funds opener and opens a channel to node_id deep enough to be announced, returns its short
+/// channel id
+pub fn open(chain: &MemoryChain, chain_name: &str, all: &[&Channels], opener: &Channels, node_id: PublicKey, value: u64) -> u64 {
+    fund(chain, opener, chain_name, 1_000_000);
+    let open_before = opener.list_channels().iter().filter(|c| c.chain == chain_name && c.state == ChannelState::Open).count();
+    opener.open_channel(chain_name, node_id, value, 0, false).unwrap();
+    assert!(sync_until(all, || chain.get_mempool().len() == 1));
+    // Deep enough to be announced
+    chain.mine_blocks(6);
+    assert!(sync_until(all, || {
+        opener.list_channels().iter().filter(|c| c.chain == chain_name && c.state == ChannelState::Open).count() == open_before + 1
+    }));
+    opener.list_channels().iter().filter(|c| c.chain == chain_name && c.node_id == node_id)
+        .filter_map(|c| c.short_channel_id).max().unwrap()
+}
+
+/// Pays invoice from payer, syncing everyone while it's paid
+pub fn pay(payer: &Arc<Channels>, invoice: &str, others: &[&Channels]) -> Result<[u8; 32], PaymentFailure> {
+    pay_while(payer, invoice, others, || {})
+}
+
+/// pay, calling meanwhile between syncs
+pub fn pay_while<F: FnMut()>(payer: &Arc<Channels>, invoice: &str, others: &[&Channels], mut meanwhile: F) -> Result<[u8; 32], PaymentFailure> {
+    let paying_channels = payer.clone();
+    let invoice = invoice.to_string();
+    let paying = thread::spawn(move || paying_channels.pay(&invoice, None, Duration::from_secs(20)));
+    while !paying.is_finished() {
+        payer.sync().unwrap();
+        for channels in others {
+            channels.sync().unwrap();
+        }
+        meanwhile();
+        thread::sleep(time::Duration::from_millis(20));
+    }
+    paying.join().unwrap()
+}
+
+/// The regtest monitors in datadir by file name
+pub fn monitor_files(datadir: &Path) -> HashMap<String, Vec<u8>> {
+    fs::read_dir(datadir.join("monitors").join("regtest")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| (path.file_name().unwrap().to_str().unwrap().to_string(), fs::read(&path).unwrap()))
+        .collect()
+}
+
+fn error(code: i64, message: &str) -> Json {
+    Json::Object(vec![("code".to_string(), Json::Number(code.to_string())), ("message".to_string(), Json::String(message.to_string()))])
+}
+
+fn answer(chain: &MemoryChain, method: &str, params: &[Json]) -> Result<Json, Json> {
+    match method {
+        "getblockcount" => Ok(Json::Number(chain.get_height().unwrap().to_string())),
+        "getblockhash" => {
+            let height = params[0].as_i64().unwrap() as u32;
+            let block = chain.get_block(height).map_err(|_| error(-8, "Block height out of range"))?;
+            Ok(Json::String(block.bitcoin_hash().to_string()))
+        },
+        "getblock" => {
+            assert_eq!(params[1], Json::Number("0".to_string()));
+            let hash = params[0].as_str().unwrap();
+            (0..=chain.get_height().unwrap()).map(|height| chain.get_block(height).unwrap())
+                .find(|block| block.bitcoin_hash().to_string() == hash)
+                .map(|block| Json::String(encode::serialize_hex(&block)))
+                .ok_or(error(-5, "Block not found"))
+        },
+        "sendrawtransaction" => {
+            let tx: Transaction = encode::deserialize(&Vec::<u8>::from_hex(params[0].as_str().unwrap()).unwrap()).unwrap();
+            match chain.get_transaction(&tx.txid()) {
+                Some((_, Some(_))) => return Err(error(-27, "Transaction already in block chain")),
+                Some((_, None)) => return Err(error(-26, "txn-already-in-mempool")),
+                None => {},
+            }
+            chain.send_transaction(&tx).map(|_| Json::String(tx.txid().to_string())).map_err(|e| error(-26, &e))
+        },
+        _ => Err(error(-32601, "Method not found")),
+    }
+}
+
+/// Serves the chain with the few calls the daemon makes, as user:pass
+pub fn bitcoind_stand_in(chain: Arc<MemoryChain>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let authorization = format!("Authorization: Basic {}", base64::encode(b"user:pass"));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_string());
+            }
+            if !headers.contains(&authorization) {
+                stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").unwrap();
+                continue;
+            }
+            let length: usize = headers.iter().find_map(|h| h.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let request = Json::parse(&String::from_utf8(body).unwrap()).unwrap();
+            let params = match request.get("params") {
+                Some(Json::Array(params)) => params.clone(),
+                _ => panic!("No params"),
+            };
+            let (status, result, error) = match answer(&chain, request.get("method").unwrap().as_str().unwrap(), &params) {
+                Ok(result) => ("200 OK", result, Json::Null),
+                Err(error) => ("500 Internal Server Error", Json::Null, error),
+            };
+            let response = Json::Object(vec![
+                ("result".to_string(), result),
+                ("error".to_string(), error),
+                ("id".to_string(), request.get("id").unwrap().clone()),
+            ]).to_json();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, response.len(), response).unwrap();
+        }
+    });
+    addr
+}
+
+/// What the seeds of daemon_datadir are encrypted with
+pub const DAEMON_PASS: &str = "correct horse";
+
+/// A new datadir with a seed and the walletpass file to open it, and the node id of the seed
+pub fn daemon_datadir(test_name: &str) -> (PathBuf, PublicKey) {
+    let datadir = new_datadir(test_name);
+    fs::write(datadir.join("walletpass"), DAEMON_PASS).unwrap();
+    let seed = keys::generate_seed(&keys::seed_path(&datadir), DAEMON_PASS).unwrap();
+    let node_id = seed.node_keys("", "regtest", logger()).unwrap().get_node_id();
+    (datadir, node_id)
+}
+
+/// A rustlnd process, killed even if the test fails
+pub struct DaemonProcess(pub Child);
+
+impl Drop for DaemonProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A local address nothing listens on
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// The daemon's answer to command on the control socket of datadir
+pub fn daemon_call(datadir: &Path, command: &str) -> String {
+    control::call(&control::socket_path(datadir), command).unwrap()
+}
+
+/// The daemon of daemon_datadir, following the regtest chain served at rpc and listening on p2p
+pub fn start_daemon(datadir: &Path, rpc: SocketAddr, p2p: SocketAddr) -> DaemonProcess {
+    let child = Command::new(env!("CARGO_BIN_EXE_rustlnd"))
+        .arg(format!("-datadir={}", datadir.display()))
+        .arg(format!("-walletpassfile={}", datadir.join("walletpass").display()))
+        .arg("-chain=regtest")
+        .arg(format!("regtest.-rpchost={}", rpc))
+        .arg("regtest.-rpcuser=user")
+        .arg("regtest.-rpcpass=pass")
+        .arg(format!("-p2phost={}", p2p))
+        .arg("-dev_sleep=0")
+        .stdout(Stdio::null())
+        .spawn().unwrap();
+    let process = DaemonProcess(child);
+    // Listening to peers once it answers unlocked
+    let control_socket = control::socket_path(datadir);
+    assert!(wait_for(|| control::call(&control_socket, "listchannels").map(|answer| !answer.starts_with("error")).unwrap_or(false)));
+    process
+}
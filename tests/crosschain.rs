@@ -0,0 +1,177 @@
+//! Payments crossing chains at a node with channels on both, at the rates the hop quotes
+
+mod common;
+
+use common::{connect, logger, new_channels, new_datadir, open, pay, sync_until, wait_for};
+use rustlnd::channels::{ChannelState, PaymentFailure};
+use rustlnd::invoice::Description;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::{self, Node};
+use rustlnd::rates::{Rate, RATE_ONE};
+use rustlnd::registry::InvoiceState;
+
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+use rand::Rng;
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::thread;
+
+#[test]
+fn test_cross_chain_payment() {
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let bbb = Arc::new(MemoryChain::new("bbb"));
+    let payer = Node::new_random(&["aaa", "bbb"], logger());
+    let hop = Node::new_random(&["aaa", "bbb"], logger());
+    let payee = Node::new_random(&["bbb"], logger());
+    let channels_payer = new_channels(&payer, &[&aaa, &bbb]);
+    let channels_hop = new_channels(&hop, &[&aaa, &bbb]);
+    let channels_payee = new_channels(&payee, &[&bbb]);
+    let all = [&*channels_payer, &*channels_hop, &*channels_payee];
+    connect(&payer, &hop);
+    connect(&hop, &payee);
+    let on_aaa = open(&aaa, "aaa", &all, &channels_payer, hop.get_our_node_id(), 500_000);
+    let short_channel_id = open(&bbb, "bbb", &all, &channels_hop, payee.get_our_node_id(), 500_000);
+    // Both funded at the same height, the short_channel_ids are the same
+    assert_eq!(on_aaa, short_channel_id);
+    // The channel on bbb, announced by the hop, is in the payer's network graph too
+    assert!(sync_until(&all, || payer.router.get_route(&payee.get_our_node_id(), None, &[], 10_000_000, 9).is_ok()));
+
+    let invoice = || payee.invoices.create("bbb", Some(10_000_000), Description::Direct("across".to_string()), 3600).unwrap();
+    let others = [&*channels_hop, &*channels_payee];
+    // The hop quotes for its side of the channel to the payee
+    let direction = if hop.get_our_node_id().serialize()[..] < payee.get_our_node_id().serialize()[..] { 0 } else { 1 };
+    // Rates go by the Router's id for the channel
+    let quote = |node: &Node| node.rates.quote(node.graph.router_id(1, short_channel_id), direction, "aaa", "bbb");
+    let quoted = |rate| sync_until(&all, || quote(&payer) == Some(rate));
+
+    // Without a rate the payer can't tell what to pay
+    let entry = invoice();
+    assert!(matches!(pay(&channels_payer, &entry.encoded, &others), Err(PaymentFailure::NoRoute { attempts: 1, .. })));
+
+    // Without one the hop doesn't forward
+    payer.rates.set("aaa", "bbb", Rate::new(2 * RATE_ONE, 0));
+    assert!(matches!(pay(&channels_payer, &entry.encoded, &others), Err(PaymentFailure::NoRoute { attempts: 1, .. })));
+    assert_eq!(payee.invoices.get(&entry.invoice.payment_hash).unwrap().state, InvoiceState::Open);
+
+    // The payer pays at the rate the hop quotes, spread included
+    let rate = Rate::new(2 * RATE_ONE, 10_000);
+    hop.rates.set("aaa", "bbb", rate);
+    assert!(quoted(rate));
+    assert_eq!(pay(&channels_payer, &entry.encoded, &others), Ok(entry.preimage.unwrap()));
+    let settled = payee.invoices.get(&entry.invoice.payment_hash).unwrap();
+    assert_eq!((settled.state, settled.received_msat), (InvoiceState::Settled, Some(10_000_000)));
+
+    // Without the spread it underpays
+    payer.rates.set_quotes(payer.graph.router_id(1, short_channel_id), direction, vec![("aaa".to_string(), "bbb".to_string(), Rate::new(2 * RATE_ONE, 0))]);
+    let entry = invoice();
+    assert!(matches!(pay(&channels_payer, &entry.encoded, &others), Err(PaymentFailure::NoRoute { attempts: 1, .. })));
+    assert_eq!(payee.invoices.get(&entry.invoice.payment_hash).unwrap().state, InvoiceState::Open);
+
+    // New rates from the hop's rate file are quoted again
+    let random: u64 = rand::thread_rng().gen();
+    let path = std::env::temp_dir().join(format!("rustlnd-crosschain-rates-{:x}", random));
+    fs::write(&path, "aaa:bbb:1.5:0.002\n").unwrap();
+    hop.rates.set_file(&path).unwrap();
+    assert!(quoted(Rate::new(3 * RATE_ONE / 2, 2000)));
+    assert_eq!(pay(&channels_payer, &entry.encoded, &others), Ok(entry.preimage.unwrap()));
+    thread::sleep(Duration::from_millis(20));
+    fs::write(&path, "aaa:bbb:1.25\n").unwrap();
+    assert!(quoted(Rate::new(5 * RATE_ONE / 4, 0)));
+    let entry = invoice();
+    assert_eq!(pay(&channels_payer, &entry.encoded, &others), Ok(entry.preimage.unwrap()));
+    fs::remove_file(&path).unwrap();
+
+    // With blocks four times as fast on aaa, the hop wants the blocks left on bbb four times
+    // over on aaa, and the payer has to know
+    hop.rates.set_block_interval("aaa", 150);
+    let entry = invoice();
+    assert!(matches!(pay(&channels_payer, &entry.encoded, &others), Err(PaymentFailure::NoRoute { attempts: 1, .. })));
+    assert_eq!(payee.invoices.get(&entry.invoice.payment_hash).unwrap().state, InvoiceState::Open);
+    payer.rates.set_block_interval("aaa", 150);
+    assert_eq!(pay(&channels_payer, &entry.encoded, &others), Ok(entry.preimage.unwrap()));
+
+    // A node that connects later gets the quoting channel with the rest of the network graph, its
+    // channel_updates are only taken for channels the Router knows
+    let late = Node::new_random(&["aaa", "bbb"], logger());
+    let channels_late = new_channels(&late, &[&aaa, &bbb]);
+    connect(&late, &hop);
+    assert!(sync_until(&[&*channels_late, &*channels_hop], || {
+        quote(&late) == Some(Rate::new(5 * RATE_ONE / 4, 0))
+    }));
+    assert!(channels_payer.list_channels().iter().chain(channels_hop.list_channels().iter()).all(|c| c.state == ChannelState::Open));
+}
+
+// The forwards file of a hop, without its trailing newline
+fn forwards_file(datadir: &Path) -> String {
+    fs::read_to_string(datadir.join("forwards")).unwrap_or_default().trim_end().to_string()
+}
+
+#[test]
+fn test_forward_across_restart() {
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let bbb = Arc::new(MemoryChain::new("bbb"));
+    let datadir = new_datadir("forward-restart");
+    let load_hop = || Node::with_datadir(&["aaa", "bbb"], node::seed_signers(&["aaa", "bbb"], &[47; 32], logger()), &datadir, logger()).unwrap();
+    let payer = Node::new_random(&["aaa", "bbb"], logger());
+    let hop = load_hop();
+    let payee = Node::new_random(&["bbb"], logger());
+    let channels_payer = new_channels(&payer, &[&aaa, &bbb]);
+    let channels_hop = new_channels(&hop, &[&aaa, &bbb]);
+    let channels_payee = new_channels(&payee, &[&bbb]);
+    // The hop connects out so that it can drop its connections
+    let connect_hop = |hop: &Node| {
+        [&payer, &payee].iter().map(|peer| {
+            let addr = net::listen(peer.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+            let descriptor = net::connect_outbound(hop.peer_manager.clone(), peer.get_our_node_id(), &addr.to_string()).unwrap();
+            assert!(wait_for(|| peer.peer_manager.get_peer_node_ids().contains(&hop.get_our_node_id())));
+            descriptor
+        }).collect::<Vec<_>>()
+    };
+    let descriptors = connect_hop(&hop);
+    let all = [&*channels_payer, &*channels_hop, &*channels_payee];
+    open(&aaa, "aaa", &all, &channels_payer, hop.get_our_node_id(), 500_000);
+    let short_channel_id = open(&bbb, "bbb", &all, &channels_hop, payee.get_our_node_id(), 500_000);
+    let direction = if hop.get_our_node_id().serialize()[..] < payee.get_our_node_id().serialize()[..] { 0 } else { 1 };
+    let rate = Rate::new(2 * RATE_ONE, 10_000);
+    hop.rates.set("aaa", "bbb", rate);
+    assert!(sync_until(&all, || {
+        payer.router.get_route(&payee.get_our_node_id(), None, &[], 10_000_000, 9).is_ok()
+            && payer.rates.quote(payer.graph.router_id(1, short_channel_id), direction, "aaa", "bbb") == Some(rate)
+    }));
+
+    // The payee holds the payment, the forward is in flight
+    let preimage: [u8; 32] = rand::thread_rng().gen();
+    let payment_hash = Sha256::hash(&preimage).into_inner();
+    let entry = payee.invoices.create_hold("bbb", payment_hash, Some(10_000_000), Description::Direct("held".to_string()), 3600).unwrap();
+    let paying_channels = channels_payer.clone();
+    let paying = thread::spawn(move || paying_channels.pay(&entry.encoded, None, Duration::from_secs(30)));
+    assert!(sync_until(&all, || payee.invoices.get(&payment_hash).unwrap().state == InvoiceState::Accepted));
+    assert_eq!(forwards_file(&datadir), format!("{} aaa bbb -", payment_hash.to_hex()));
+
+    // The hop gets the preimage and is gone before it handles its PaymentSent
+    payee.invoices.settle(preimage).unwrap();
+    assert!(sync_until(&[&*channels_payer, &*channels_payee], || forwards_file(&datadir).ends_with(&preimage.to_hex())));
+    thread::sleep(Duration::from_millis(300));
+    for descriptor in &descriptors {
+        net::disconnect(&hop.peer_manager, descriptor);
+    }
+    assert!(wait_for(|| payer.peer_manager.get_peer_node_ids().is_empty() && payee.peer_manager.get_peer_node_ids().is_empty()));
+
+    // Back up, it claims the HTLC from the payer
+    let hop = load_hop();
+    let channels_hop = new_channels(&hop, &[&aaa, &bbb]);
+    assert_eq!(forwards_file(&datadir), "");
+    connect_hop(&hop);
+    let all = [&*channels_payer, &*channels_hop, &*channels_payee];
+    assert!(sync_until(&all, || paying.is_finished()));
+    assert_eq!(paying.join().unwrap(), Ok(preimage));
+    assert!(channels_payer.list_channels().iter().chain(channels_hop.list_channels().iter()).all(|c| c.state == ChannelState::Open));
+
+    fs::remove_dir_all(datadir).unwrap();
+}
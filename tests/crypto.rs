@@ -0,0 +1,63 @@
+//! scrypt and ChaCha20-Poly1305 against other implementations (RFC 7914 vectors, python's cryptography)
+
+use rustlnd::crypto;
+
+use bitcoin_hashes::hex::ToHex;
+
+fn scrypt_hex(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32, len: usize) -> String {
+    let mut output = vec![0; len];
+    crypto::scrypt(password, salt, log_n, r, p, &mut output).unwrap();
+    output.to_hex()
+}
+
+#[test]
+fn test_scrypt() {
+    assert_eq!(scrypt_hex(b"", b"", 4, 1, 1, 64),
+               "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906");
+    assert_eq!(scrypt_hex(b"password", b"NaCl", 10, 8, 16, 64),
+               "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b3731622eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640");
+    let salt: Vec<u8> = (0..16).collect();
+    assert_eq!(scrypt_hex(b"correct horse", &salt, 14, 8, 1, 32),
+               "25b376840366f4d3b0e21e414476676e3cd0e89af4430356a234ce0b65a021b5");
+    assert!(crypto::scrypt(b"", b"", 40, 8, 1, &mut [0; 32]).is_err());
+}
+
+#[test]
+fn test_chacha20poly1305() {
+    let mut key = [0; 32];
+    key.iter_mut().enumerate().for_each(|(i, k)| *k = i as u8);
+    let mut nonce = [0; 12];
+    nonce.iter_mut().enumerate().for_each(|(i, n)| *n = i as u8);
+    let aad = b"rustlnd seed";
+
+    let range = |len: u8| -> Vec<u8> { (0..len).collect() };
+    let vectors: Vec<(Vec<u8>, &str)> = vec![
+        (vec![], "20de8601e89133310f937d1db24952aa"),
+        (b"a".to_vec(), "e83c088395812991066567144edff4e2c1"),
+        (range(16), "89fa0a032d12a347bf8a35f89410006c866a52b37f38fd50b97678c8e8e548d8"),
+        (vec![0xff; 64], "7604f7ffd6e85abf487cc00c67e2f19c368f4d18ae8b52461968d03a8a3f59c3137fd30c19e14e67c8cd89279a6b70dc817b568b02d74764ed4726f86fb06129ccb117baccf45603b8cb0b7041489390"),
+        (range(200), "89fa0a032d12a347bf8a35f89410006cd961a0f44561bbaefe8e35de69ddb823cca10ed0c23b97bf1f1b5cf349b9a10c4eb59b47c91d8eac2a81e33cac72a0e93939fe8ea1516aae8c5f07f7543192be8a8f15613b3fa669560eaa5584205ce02dbf0e9093bc4193d93299dccefcd9ef991e244bfd28368f37144ea40542c13921e7413e2db659ebc19af5f1a0b1e5b524e015e19ffa5ac9aa783daa38863ea69f18234b19e1324126d7ccbbcb1c41af6ee514f9a3eecca260a6542adb8b2aaf19869d4ca738c9d89ac8429272973597ccf3968619ce6fce"),
+    ];
+    for (plaintext, sealed_hex) in vectors {
+        let sealed = crypto::encrypt(&key, &nonce, aad, &plaintext);
+        assert_eq!(sealed.to_hex(), sealed_hex);
+        assert_eq!(crypto::decrypt(&key, &nonce, aad, &sealed), Some(plaintext));
+    }
+}
+
+#[test]
+fn test_chacha20poly1305_tampering() {
+    let key = [1; 32];
+    let nonce = [2; 12];
+    let sealed = crypto::encrypt(&key, &nonce, b"aad", b"secret");
+
+    assert!(crypto::decrypt(&[3; 32], &nonce, b"aad", &sealed).is_none());
+    assert!(crypto::decrypt(&key, &[3; 12], b"aad", &sealed).is_none());
+    assert!(crypto::decrypt(&key, &nonce, b"other", &sealed).is_none());
+    for i in 0..sealed.len() {
+        let mut tampered = sealed.clone();
+        tampered[i] ^= 1;
+        assert!(crypto::decrypt(&key, &nonce, b"aad", &tampered).is_none());
+    }
+    assert!(crypto::decrypt(&key, &nonce, b"aad", &sealed[..10]).is_none());
+}
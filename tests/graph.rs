@@ -0,0 +1,147 @@
+//! The network graph by chain: funding outputs looked up on the right chain, chain hops
+
+mod common;
+
+use common::{connect, logger, new_channels, open, sync_until};
+use rustlnd::chain::{self};
+use rustlnd::control::Control;
+use rustlnd::graph::{self, NetworkGraph};
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::node::Node;
+use rustlnd::peers::PeerConnector;
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::BitcoinHash;
+use lightning::chain::chaininterface::{ChainError, ChainWatchInterface};
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+
+use std::sync::Arc;
+
+fn random_key() -> PublicKey {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&bytes).unwrap())
+}
+
+#[test]
+fn test_check_funding() {
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let bbb = Arc::new(MemoryChain::new("bbb"));
+    let graph = NetworkGraph::new(&["aaa", "bbb"]);
+    let regtest = genesis_block(Network::Regtest).header.bitcoin_hash();
+    let main = genesis_block(Network::Bitcoin).header.bitcoin_hash();
+    assert_eq!(graph.chains_with_hash(&chain::chain_hash("bbb")), vec![1]);
+    assert_eq!(graph.chains_with_hash(&regtest), vec![0, 1]);
+    assert!(graph.chains_with_hash(&main).is_empty());
+
+    let (key_1, key_2) = (random_key(), random_key());
+    let script = graph::funding_script(&[key_1, key_2]);
+    assert_eq!(graph::funding_script(&[key_2, key_1]), script);
+    bbb.faucet(&script, 100_000);
+    bbb.mine_blocks(1);
+    // The only transaction of block 1
+    let short_channel_id = 1 << 40;
+    assert!(graph.check_funding(&regtest, short_channel_id, &[key_1, key_2]).is_err());
+
+    graph.set_backend(0, aaa.clone());
+    graph.set_backend(1, bbb.clone());
+    assert!(graph.check_funding(&main, short_channel_id, &[key_1, key_2]).is_err());
+    assert!(graph.check_funding(&regtest, short_channel_id, &[key_1, random_key()]).is_err());
+    assert!(graph.check_funding(&regtest, short_channel_id + 1, &[key_1, key_2]).is_err());
+    assert!(graph.check_funding(&chain::chain_hash("aaa"), short_channel_id, &[key_1, key_2]).is_err());
+    assert_eq!(graph.check_funding(&regtest, short_channel_id, &[key_2, key_1]), Ok((1, short_channel_id)));
+    assert_eq!(graph.check_funding(&chain::chain_hash("bbb"), short_channel_id, &[key_2, key_1]), Ok((1, short_channel_id)));
+    assert!(graph.check_funding(&chain::chain_hash("bbb"), short_channel_id, &[key_1, key_2]).is_err());
+    assert!(graph.check_funding(&chain::chain_hash("aaa"), short_channel_id, &[key_2, key_1]).is_err());
+
+    // What the Router checks the announced keys against, in their order
+    assert!(matches!(graph.get_chain_utxo(regtest, short_channel_id), Ok((_, 100_000))));
+    assert!(matches!(graph.get_chain_utxo(chain::chain_hash("aaa"), short_channel_id), Err(ChainError::NotWatched)));
+    assert!(matches!(graph.get_chain_utxo(regtest, short_channel_id + 1), Err(ChainError::UnknownTx)));
+
+    graph.add_channel(short_channel_id, [key_1, key_2]);
+    assert_eq!(graph.channel_chain(short_channel_id), Some(1));
+    assert_eq!(graph.channels("bbb").len(), 1);
+    assert!(graph.channels("aaa").is_empty());
+    assert_eq!(graph.nodes().len(), 2);
+    assert!(graph.nodes().iter().all(|node| node.chains == vec!["bbb".to_string()] && !node.is_chain_hop()));
+
+    // The same short_channel_id on aaa, the Router gets another id for it
+    let (key_3, key_4) = (random_key(), random_key());
+    aaa.faucet(&graph::funding_script(&[key_3, key_4]), 200_000);
+    aaa.mine_blocks(1);
+    let (index, router_id) = graph.check_funding(&regtest, short_channel_id, &[key_3, key_4]).unwrap();
+    assert_eq!(index, 0);
+    assert_ne!(router_id, short_channel_id);
+    assert_eq!(graph.check_funding(&regtest, short_channel_id, &[key_3, key_4]), Ok((0, router_id)));
+    assert_eq!(graph.check_funding(&regtest, short_channel_id, &[key_2, key_1]), Ok((1, short_channel_id)));
+    assert!(matches!(graph.get_chain_utxo(regtest, router_id), Ok((_, 200_000))));
+    graph.add_channel(router_id, [key_3, key_4]);
+    assert_eq!(graph.channel_chain(router_id), Some(0));
+    assert_eq!((graph.router_id(0, short_channel_id), graph.router_id(1, short_channel_id)), (router_id, short_channel_id));
+    assert_eq!(graph.short_channel_id(router_id), short_channel_id);
+    assert_eq!(graph.channels("aaa")[0].short_channel_id, short_channel_id);
+    // channel_updates go by their chain_hash, rust-lightning's is the first chain's
+    assert_eq!(graph.update_router_id(&chain::chain_hash("bbb"), short_channel_id), Some(short_channel_id));
+    assert_eq!(graph.update_router_id(&chain::chain_hash("aaa"), short_channel_id), Some(router_id));
+    assert_eq!(graph.update_router_id(&regtest, short_channel_id), Some(router_id));
+
+    graph.remove_channel(short_channel_id);
+    assert_eq!(graph.channel_chain(short_channel_id), None);
+    graph.remove_channel(router_id);
+    assert!(graph.nodes().is_empty());
+}
+
+#[test]
+fn test_chain_hops() {
+    let aaa = Arc::new(MemoryChain::new("aaa"));
+    let bbb = Arc::new(MemoryChain::new("bbb"));
+    let payer = Node::new_random(&["aaa", "bbb"], logger());
+    let hop = Node::new_random(&["aaa", "bbb"], logger());
+    let payee = Node::new_random(&["bbb"], logger());
+    // Only follows aaa, where nothing is at the short_channel_id of the channel on bbb
+    let watcher = Node::new_random(&["aaa"], logger());
+    let channels_payer = new_channels(&payer, &[&aaa, &bbb]);
+    let channels_hop = new_channels(&hop, &[&aaa, &bbb]);
+    let channels_payee = new_channels(&payee, &[&bbb]);
+    let channels_watcher = new_channels(&watcher, &[&aaa]);
+    let all = [&*channels_payer, &*channels_hop, &*channels_payee, &*channels_watcher];
+    connect(&payer, &hop);
+    connect(&hop, &payee);
+    connect(&watcher, &hop);
+    let on_aaa = open(&aaa, "aaa", &all, &channels_payer, hop.get_our_node_id(), 500_000);
+    let on_bbb = open(&bbb, "bbb", &all, &channels_hop, payee.get_our_node_id(), 500_000);
+    // Funded at the same height of both chains
+    assert_eq!(on_aaa, on_bbb);
+
+    let router_ids = |node: &Node| (node.graph.router_id(0, on_aaa), node.graph.router_id(1, on_bbb));
+    assert!(sync_until(&all, || {
+        let (on_aaa, on_bbb) = router_ids(&payer);
+        on_aaa != on_bbb && payer.graph.channel_chain(on_aaa) == Some(0) && payer.graph.channel_chain(on_bbb) == Some(1)
+    }));
+    let nodes = payer.graph.nodes();
+    assert_eq!(nodes.len(), 3);
+    let node = |node_id: PublicKey| nodes.iter().find(|node| node.node_id == node_id).unwrap().clone();
+    assert!(node(hop.get_our_node_id()).is_chain_hop());
+    assert_eq!(node(payer.get_our_node_id()).chains, vec!["aaa".to_string()]);
+    assert_eq!(node(payee.get_our_node_id()).chains, vec!["bbb".to_string()]);
+    assert_eq!(payer.graph.chain_nodes("bbb"), {
+        let mut node_ids = vec![hop.get_our_node_id(), payee.get_our_node_id()];
+        node_ids.sort();
+        node_ids
+    });
+
+    assert!(sync_until(&all, || watcher.graph.channel_chain(on_aaa) == Some(0)));
+    assert_eq!(watcher.graph.channels("aaa").len(), 1);
+    assert!(watcher.router.get_route(&payee.get_our_node_id(), None, &[], 10_000, 9).is_err());
+
+    let control = Control::new(&payer, Arc::new(PeerConnector::new(&payer, None)), Some(channels_payer.clone()));
+    let answer = control.handle_command("listgraph");
+    assert_eq!(answer.lines().count(), 3);
+    assert!(answer.lines().any(|line| line == format!("{} aaa bbb hop", hop.get_our_node_id())));
+    assert!(answer.lines().any(|line| line == format!("{} bbb", payee.get_our_node_id())));
+    assert_eq!(control.handle_command("listgraph bbb").lines().count(), 1);
+    assert!(control.handle_command("listgraph ccc").starts_with("error: "));
+}
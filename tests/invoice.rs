@@ -0,0 +1,164 @@
+//! BOLT11 invoices, the spec's test vectors and invoices for our own chains
+
+mod common;
+
+use common::logger;
+use rustlnd::invoice::{Description, Fallback, Invoice, RouteHop};
+use rustlnd::node::Node;
+
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+
+
+const PRIVATE_KEY: &str = "e126f68f7eafcc8b74f54d269fe206be715000f94dac067d1c04a8ca3b2db734";
+const PAYEE: &str = "03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad";
+const PAYMENT_HASH: &str = "0001020304050607080900010203040506070809000102030405060708090102";
+const TIMESTAMP: u64 = 1496314658;
+const LONG_DESCRIPTION: &str = "One piece of chocolate cake, one icecream cone, one pickle, one slice of swiss cheese, one slice of salami, one lollypop, one piece of cherry pie, one sausage, one cupcake, and one slice of watermelon";
+
+const CHAINS: [&str; 3] = ["main", "test", "regtest"];
+
+fn pubkey(hex: &str) -> PublicKey {
+    PublicKey::from_slice(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+}
+
+fn hash(hex: &str) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Vec::<u8>::from_hex(hex).unwrap());
+    hash
+}
+
+fn spec_invoice(chain: &str, amount_msat: Option<u64>, description: Description) -> Invoice {
+    let mut invoice = Invoice::new(chain, pubkey(PAYEE), hash(PAYMENT_HASH), description);
+    invoice.amount_msat = amount_msat;
+    invoice.timestamp = TIMESTAMP;
+    invoice
+}
+
+fn description_hash() -> Description {
+    Description::Hash(Sha256::hash(LONG_DESCRIPTION.as_bytes()).into_inner())
+}
+
+// Decodes to invoice and, unless the fields are in another order, it's what we encode
+fn check_vector(encoded: &str, invoice: &Invoice, same_order: bool) {
+    assert_eq!(&Invoice::decode(encoded, &CHAINS).unwrap(), invoice);
+    assert_eq!(Invoice::decode(&encoded.to_uppercase(), &CHAINS).unwrap(), *invoice);
+    let secret = SecretKey::from_slice(&Vec::<u8>::from_hex(PRIVATE_KEY).unwrap()).unwrap();
+    if same_order {
+        assert_eq!(invoice.encode(&secret).unwrap(), encoded);
+    }
+}
+
+#[test]
+fn test_spec_vectors() {
+    // Please make a donation of any amount
+    check_vector("lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq8rkx3yf5tcsyz3d73gafnh3cax9rn449d9p5uxz9ezhhypd0elx87sjle52x86fux2ypatgddc6k63n7erqz25le42c4u4ecky03ylcqca784w",
+                 &spec_invoice("main", None, Description::Direct("Please consider supporting this project".to_string())), true);
+
+    // $3 for a cup of coffee within one minute
+    let mut coffee = spec_invoice("main", Some(250_000_000), Description::Direct("1 cup coffee".to_string()));
+    coffee.expiry = 60;
+    check_vector("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp",
+                 &coffee, true);
+    let mut nonsense = spec_invoice("main", Some(250_000_000), Description::Direct("ナンセンス 1杯".to_string()));
+    nonsense.expiry = 60;
+    check_vector("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpuyk0sg5g70me25alkluzd2x62aysf2pyy8edtjeevuv4p2d5p76r4zkmneet7uvyakky2zr4cusd45tftc9c5fh0nnqpnl2jfll544esqchsrny",
+                 &nonsense, true);
+
+    // The description's hash
+    check_vector("lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqscc6gd6ql3jrc5yzme8v4ntcewwz5cnw92tz0pc8qcuufvq7khhr8wpald05e92xw006sq94mg8v2ndf4sefvf9sygkshp5zfem29trqq2yxxz7",
+                 &spec_invoice("main", Some(2_000_000_000), description_hash()), true);
+
+    // On testnet with a P2PKH fallback address
+    let mut testnet = spec_invoice("test", Some(2_000_000_000), description_hash());
+    testnet.fallbacks.push(Fallback { version: 17, program: Vec::<u8>::from_hex("3172b5654f6683c8fb146959d347ce303cae4ca7").unwrap() });
+    check_vector("lntb20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfpp3x9et2e20v6pu37c5d9vax37wxq72un98kmzzhznpurw9sgl2v0nklu2g4d0keph5t7tj9tcqd8rexnd07ux4uv2cjvcqwaxgj7v4uwn5wmypjd5n69z2xm3xgksg28nwht7f6zspwp3f9t",
+                 &testnet, false);
+
+    // A private route through two channels
+    let mut routed = spec_invoice("main", Some(2_000_000_000), description_hash());
+    routed.fallbacks.push(Fallback { version: 17, program: Vec::<u8>::from_hex("04b61f7dc1ea0dc99424464cc4064dc564d91e89").unwrap() });
+    routed.route_hints.push(vec![
+        RouteHop {
+            pubkey: pubkey("029e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255"),
+            short_channel_id: 0x0102030405060708,
+            fee_base_msat: 1,
+            fee_proportional_millionths: 20,
+            cltv_expiry_delta: 3,
+        },
+        RouteHop {
+            pubkey: pubkey("039e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255"),
+            short_channel_id: 0x030405060708090a,
+            fee_base_msat: 2,
+            fee_proportional_millionths: 30,
+            cltv_expiry_delta: 4,
+        },
+    ]);
+    check_vector("lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqsfpp3qjmp7lwpagxun9pygexvgpjdc4jdj85fr9yq20q82gphp2nflc7jtzrcazrra7wwgzxqc8u7754cdlpfrmccae92qgzqvzq2ps8pqqqqqqpqqqqq9qqqvpeuqafqxu92d8lr6fvg0r5gv0heeeqgcrqlnm6jhphu9y00rrhy4grqszsvpcgpy9qqqqqqgqqqqq7qqzqj9n4evl6mr5aj9f58zp6fyjzup6ywn3x6sk8akg5v4tgn2q8g4fhx05wf6juaxu9760yp46454gpg5mtzgerlzezqcqvjnhjh8z3g2qqdhhwkj",
+                 &routed, true);
+}
+
+#[test]
+fn test_bad_invoices() {
+    let coffee = "lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp";
+    assert!(Invoice::decode(coffee, &["test", "regtest"]).unwrap_err().contains("unknown prefix lnbc2500u"));
+    // Bad checksum
+    assert!(Invoice::decode(&coffee.replace("srp", "srq"), &CHAINS).is_err());
+    // Mixed case
+    assert!(Invoice::decode(&coffee.replace("lnbc", "LNBC"), &CHAINS).is_err());
+
+    // Another amount isn't what was signed, the signature is someone else's
+    let secret = SecretKey::from_slice(&Vec::<u8>::from_hex(PRIVATE_KEY).unwrap()).unwrap();
+    let mut invoice = spec_invoice("main", Some(250_000_000), Description::Direct("1 cup coffee".to_string()));
+    let signed: bech32::Bech32 = invoice.encode(&secret).unwrap().parse().unwrap();
+    let tampered = bech32::Bech32::new("lnbc3500u".to_string(), signed.data().to_vec()).unwrap().to_string();
+    let decoded = Invoice::decode(&tampered, &CHAINS).unwrap();
+    assert_eq!(decoded.amount_msat, Some(350_000_000));
+    assert_ne!(decoded.payee, invoice.payee);
+
+    // Only the payee signs
+    let other_secret = SecretKey::from_slice(&[1; 32]).unwrap();
+    assert!(invoice.encode(&other_secret).is_err());
+    invoice.description = Description::Direct("x".repeat(640));
+    assert!(invoice.encode(&secret).unwrap_err().contains("640 bytes"));
+}
+
+#[test]
+fn test_amounts() {
+    let secret = SecretKey::from_slice(&Vec::<u8>::from_hex(PRIVATE_KEY).unwrap()).unwrap();
+    let amounts = [(100_000_000_000, "lnbcrt11"), (1_000, "lnbcrt10n1"), (1, "lnbcrt10p1"), (123_456, "lnbcrt1234560p1"), (3_000_000, "lnbcrt30u1")];
+    for (amount_msat, hrp) in amounts.iter() {
+        let invoice = spec_invoice("regtest", Some(*amount_msat), Description::Direct(String::new()));
+        let encoded = invoice.encode(&secret).unwrap();
+        assert!(encoded.starts_with(hrp), "{}", encoded);
+        assert_eq!(Invoice::decode(&encoded, &CHAINS).unwrap(), invoice);
+    }
+}
+
+#[test]
+fn test_our_chains() {
+    let seed = [7; 32];
+    let node = Node::new(&["aaa", "bbb", "regtest"], &seed, logger());
+    let mut invoice = Invoice::new("aaa", node.get_our_node_id(), [3; 32], Description::Direct("coffee on aaa".to_string()));
+    invoice.amount_msat = Some(42_000);
+    invoice.min_final_cltv_expiry = 144;
+    invoice.route_hints.push(vec![RouteHop {
+        pubkey: PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[2; 32]).unwrap()),
+        short_channel_id: 42 << 40 | 1 << 16,
+        fee_base_msat: 1000,
+        fee_proportional_millionths: 100,
+        cltv_expiry_delta: 40,
+    }]);
+    let encoded = node.sign_invoice(&invoice).unwrap();
+    assert!(encoded.starts_with("lnaaa420n1"), "{}", encoded);
+    assert_eq!(Invoice::decode(&encoded, &["aaa", "bbb"]).unwrap(), invoice);
+    assert!(Invoice::decode(&encoded, &["bbb", "regtest"]).is_err());
+
+    invoice.chain = "regtest".to_string();
+    assert!(node.sign_invoice(&invoice).unwrap().starts_with("lnbcrt420n1"));
+    invoice.chain = "ccc".to_string();
+    assert!(node.sign_invoice(&invoice).unwrap_err().contains("Unknown chain ccc"));
+}
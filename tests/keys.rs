@@ -0,0 +1,220 @@
+//! Seed generation, storage and the keys derived from it
+
+mod common;
+
+use common::new_datadir;
+use rustlnd::bip39;
+use rustlnd::control::{self, Control};
+use rustlnd::keys::{self, NodeKeys};
+use rustlnd::logger::PrintLogger;
+use rustlnd::node::Node;
+
+use bitcoin_hashes::hex::ToHex;
+use lightning::chain::keysinterface::{KeysInterface, NodeSigner};
+use lightning::util::logger::Level;
+
+use std::fs;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+const PASS: &str = "correct horse";
+
+fn new_keys(seed: &[u8], chain: &str) -> NodeKeys {
+    NodeKeys::new(seed, chain, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+#[test]
+fn test_seed_generated_once() {
+    let datadir = new_datadir("seed-once");
+    let seed = keys::load_or_generate_seed(&datadir, PASS).unwrap();
+    assert_eq!(keys::load_or_generate_seed(&datadir, PASS).unwrap(), seed);
+    assert_eq!(keys::load_seed(&keys::seed_path(&datadir), PASS).unwrap(), seed);
+
+    // An existing seed is never replaced
+    assert!(keys::generate_seed(&keys::seed_path(&datadir), PASS).is_err());
+    assert_eq!(keys::load_seed(&keys::seed_path(&datadir), PASS).unwrap(), seed);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(keys::seed_path(&datadir)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_corrupt_seed_not_replaced() {
+    let datadir = new_datadir("seed-corrupt");
+    fs::write(keys::seed_path(&datadir), "not a seed").unwrap();
+    assert!(keys::load_or_generate_seed(&datadir, PASS).is_err());
+    assert_eq!(fs::read_to_string(keys::seed_path(&datadir)).unwrap(), "not a seed");
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_same_node_different_chain_keys() {
+    let seed = [7; 32];
+    let aaa = new_keys(&seed, "aaa");
+    let bbb = new_keys(&seed, "bbb");
+    assert_eq!(aaa.get_node_id(), bbb.get_node_id());
+    assert_ne!(aaa.get_destination_script(), bbb.get_destination_script());
+    assert_ne!(aaa.get_shutdown_pubkey(), bbb.get_shutdown_pubkey());
+
+    assert_eq!(new_keys(&seed, "aaa").get_destination_script(), aaa.get_destination_script());
+    assert_ne!(new_keys(&[8; 32], "aaa").get_node_id(), aaa.get_node_id());
+}
+
+#[test]
+fn test_node_id_survives_restart() {
+    let datadir = new_datadir("seed-restart");
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let seed = keys::load_or_generate_seed(&datadir, PASS).unwrap();
+    let node_id = Node::new(&["regtest"], &keys::bip32_seed(&seed.entropy, "").unwrap(), logger.clone()).get_our_node_id();
+    let seed = keys::load_or_generate_seed(&datadir, PASS).unwrap();
+    let restarted = Node::new(&["regtest"], &keys::bip32_seed(&seed.entropy, "").unwrap(), logger).get_our_node_id();
+    assert_eq!(node_id, restarted);
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_restore_from_mnemonic() {
+    let datadir = new_datadir("seed-restore");
+    let entropy = keys::load_or_generate_seed(&datadir, PASS).unwrap().entropy;
+    let mnemonic = keys::load_seed(&keys::seed_path(&datadir), PASS).unwrap().mnemonic().unwrap();
+    assert_eq!(mnemonic, bip39::entropy_to_mnemonic(&entropy).unwrap());
+    assert_eq!(mnemonic.split(' ').count(), 24);
+    // Nothing but the encrypted seed on disk
+    let files: Vec<_> = fs::read_dir(&datadir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(files, vec![keys::SEED_FILE]);
+
+    // Never over an existing seed
+    assert!(keys::restore_seed(&keys::seed_path(&datadir), &mnemonic, PASS).is_err());
+
+    let restored_datadir = new_datadir("seed-restored");
+    let restored = keys::restore_seed(&keys::seed_path(&restored_datadir), &mnemonic.to_uppercase(), PASS).unwrap().entropy;
+    assert_eq!(restored, entropy);
+    assert_eq!(keys::load_seed(&keys::seed_path(&restored_datadir), PASS).unwrap().entropy, entropy);
+
+    let seed = keys::bip32_seed(&entropy, "").unwrap();
+    assert_eq!(new_keys(&seed, "aaa").get_node_id(), new_keys(&keys::bip32_seed(&restored, "").unwrap(), "aaa").get_node_id());
+    // The passphrase makes it a different node
+    assert_ne!(new_keys(&seed, "aaa").get_node_id(), new_keys(&keys::bip32_seed(&entropy, "x").unwrap(), "aaa").get_node_id());
+
+    fs::remove_dir_all(&datadir).unwrap();
+    fs::remove_dir_all(&restored_datadir).unwrap();
+}
+
+#[test]
+fn test_wallet_keys_per_chain() {
+    let seed = [7; 32];
+    assert_ne!(keys::chain_index("aaa"), keys::chain_index("bbb"));
+    assert!(keys::chain_index("regtest") < 0x8000_0000);
+    let aaa = new_keys(&seed, "aaa").wallet_account_key();
+    assert_eq!(aaa, new_keys(&seed, "aaa").wallet_account_key());
+    assert_ne!(aaa, new_keys(&seed, "bbb").wallet_account_key());
+    assert_eq!(aaa.depth, 4);
+}
+
+#[test]
+fn test_wrong_passphrase() {
+    let datadir = new_datadir("seed-passphrase");
+    let path = keys::seed_path(&datadir);
+    let entropy = keys::load_or_generate_seed(&datadir, PASS).unwrap().entropy;
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(!contents.contains(&entropy.to_hex()));
+
+    let err = keys::load_seed(&path, "wrong horse").unwrap_err();
+    assert!(err.contains("wrong passphrase"), "{}", err);
+    assert!(keys::load_or_generate_seed(&datadir, "").is_err());
+    // Nothing gets rewritten on failure
+    assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+    assert_eq!(keys::load_seed(&path, PASS).unwrap().entropy, entropy);
+
+    // Same seed, same passphrase, fresh salt and nonce
+    let other_datadir = new_datadir("seed-passphrase-other");
+    keys::restore_seed(&keys::seed_path(&other_datadir), &bip39::entropy_to_mnemonic(&entropy).unwrap(), PASS).unwrap();
+    assert_ne!(fs::read_to_string(keys::seed_path(&other_datadir)).unwrap(), contents);
+
+    fs::remove_dir_all(&datadir).unwrap();
+    fs::remove_dir_all(&other_datadir).unwrap();
+}
+
+#[test]
+fn test_empty_passphrase_refused() {
+    let datadir = new_datadir("seed-empty-passphrase");
+    assert!(keys::load_or_generate_seed(&datadir, "").is_err());
+    assert!(!keys::seed_path(&datadir).exists());
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_passphrase_file() {
+    let datadir = new_datadir("seed-passfile");
+    let path = datadir.join("walletpass");
+    fs::write(&path, "correct horse\nbattery staple\n").unwrap();
+    assert_eq!(keys::read_passphrase_file(&path).unwrap(), "correct horse");
+    assert!(keys::read_passphrase_file(&datadir.join("missing")).is_err());
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_unlock_over_socket() {
+    let datadir = new_datadir("seed-unlock");
+    let path = keys::seed_path(&datadir);
+    let seed = keys::load_or_generate_seed(&datadir, PASS).unwrap();
+
+    let control = Arc::new(Control::new_locked());
+    let socket = control::socket_path(&datadir);
+    control::listen(control.clone(), &socket).unwrap();
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    let unlock = |passphrase: &str| control::call(&socket, &format!("unlock {}", passphrase)).unwrap();
+    assert_eq!(unlock("whatever"), "error: not waiting for a passphrase");
+
+    let unlocking_control = control.clone();
+    let unlocking = thread::spawn(move || unlocking_control.wait_for_unlock(|passphrase| keys::load_seed(&path, passphrase)));
+    // wait_for_unlock may not be waiting yet
+    while unlock("wrong horse") == "error: not waiting for a passphrase" {
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(control::call(&socket, "listpeers").unwrap().contains("locked"));
+    assert!(control::call(&socket, "unlock").unwrap().starts_with("error: "));
+    let start = Instant::now();
+    let answer = unlock("wrong horse");
+    assert!(answer.starts_with("error: ") && answer.contains("wrong passphrase"), "{}", answer);
+    assert!(start.elapsed() >= control::UNLOCK_RETRY_DELAY);
+    assert_eq!(unlock(PASS), "ok");
+    assert_eq!(unlocking.join().unwrap(), seed);
+    assert_eq!(unlock(PASS), "error: not waiting for a passphrase");
+
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_show_mnemonic() {
+    let (datadir, _) = common::daemon_datadir("seed-show");
+    let seed = keys::load_seed(&keys::seed_path(&datadir), common::DAEMON_PASS).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rustlnd"))
+        .arg(format!("-datadir={}", datadir.display()))
+        .arg(format!("-walletpassfile={}", datadir.join("walletpass").display()))
+        .arg("-chain=regtest")
+        .arg(format!("regtest.-rpchost={}", common::free_addr()))
+        .arg("regtest.-rpcuser=user")
+        .arg("regtest.-rpcpass=pass")
+        .arg("-show-mnemonic")
+        .output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&seed.mnemonic().unwrap()), "{}", stdout);
+    // Printed and gone, the daemon didn't start
+    assert!(!stdout.contains("The daemon is not running"), "{}", stdout);
+    for entry in fs::read_dir(&datadir).unwrap() {
+        let contents = fs::read(entry.unwrap().path()).unwrap_or_default();
+        assert!(!String::from_utf8_lossy(&contents).contains(&seed.mnemonic().unwrap()));
+    }
+    fs::remove_dir_all(&datadir).unwrap();
+}
@@ -0,0 +1,136 @@
+//! Channel monitors kept in the datadir, punishing a revoked commitment across a restart
+
+mod common;
+
+use common::{load_node, logger, monitor_files, new_channels, new_datadir, sync_until, wait_for};
+use rustlnd::channels::ChannelState;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::{self, Node};
+use rustlnd::wallet;
+
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use lightning::chain::keysinterface::KeysInterface;
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::channelmanager::{ChannelManager, ChannelManagerReadArgs};
+use lightning::ln::channelmonitor::ChannelMonitor;
+use lightning::util::config::UserConfig;
+use lightning::util::ser::{ReadableArgs, Writeable};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::{thread, time};
+
+// The monitor files in datadir, by name
+#[test]
+fn test_justice_after_restart() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let datadir_a = new_datadir("justice-a");
+    let datadir_b = new_datadir("justice-b");
+    let node_a = load_node(&[30; 32], &datadir_a);
+    let node_b = load_node(&[31; 32], &datadir_b);
+    let channels_a = new_channels(&node_a, &[&chain]);
+    let channels_b = new_channels(&node_b, &[&chain]);
+    let addr = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let wallet_b = channels_b.get_wallet("regtest").unwrap();
+    chain.faucet(&wallet::address_to_script("regtest", &wallet_b.new_address()).unwrap(), 300_000);
+    chain.mine_blocks(1);
+    channels_b.sync().unwrap();
+    assert_eq!(wallet_b.get_balance(), 300_000);
+    channels_b.open_channel("regtest", node_a.get_our_node_id(), 100_000, 30_000_000, false).unwrap();
+    assert!(sync_until(&[&channels_a, &channels_b], || chain.get_mempool().len() == 1));
+    chain.mine_blocks(6);
+    assert!(sync_until(&[&channels_a, &channels_b], || {
+        channels_a.list_channels()[0].state == ChannelState::Open && channels_b.list_channels()[0].state == ChannelState::Open
+    }));
+    let channel_id = channels_b.list_channels()[0].channel_id;
+    let funding_txo = channels_b.list_channels()[0].funding_txo.unwrap();
+    assert_eq!(monitor_files(&datadir_a).len(), 1);
+    let stale_b = monitor_files(&datadir_b).remove(&format!("{}_{}", funding_txo.txid, funding_txo.index)).unwrap();
+
+    // A new fee means new commitment transactions, B revokes the one it had
+    let opened_a = monitor_files(&datadir_a);
+    node_b.chains[0].channel_manager.update_fee(channel_id, 500).unwrap();
+    channels_b.sync().unwrap();
+    assert!(wait_for(|| monitor_files(&datadir_a) != opened_a));
+    let mut last = (monitor_files(&datadir_a), monitor_files(&datadir_b));
+    loop {
+        thread::sleep(time::Duration::from_millis(300));
+        let current = (monitor_files(&datadir_a), monitor_files(&datadir_b));
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+
+    // A restarts with only what's in its datadir
+    drop(channels_a);
+    drop(node_a);
+    let node_a = load_node(&[30; 32], &datadir_a);
+    let channels_a = new_channels(&node_a, &[&chain]);
+    assert_eq!(channels_a.list_channels()[0].channel_id, channel_id);
+
+    // B broadcasts the revoked commitment transaction, as a ChannelManager that lost its channel
+    // does with the monitor it's given
+    let mut stale_monitor = Cursor::new(&stale_b[4..]);
+    let chain_b = &node_b.chains[0];
+    let (_, stale_monitor) = <(Sha256dHash, ChannelMonitor)>::read(&mut stale_monitor, (logger(), chain_b.keys_manager.clone() as Arc<dyn KeysInterface>)).unwrap();
+    let mut stale_monitors: HashMap<OutPoint, &ChannelMonitor> = HashMap::new();
+    stale_monitors.insert(funding_txo, &stale_monitor);
+    let empty_manager = Node::new_random(&["regtest"], logger()).chains[0].channel_manager.encode();
+    <(Sha256dHash, ChannelManager)>::read(&mut Cursor::new(empty_manager), ChannelManagerReadArgs {
+        keys_manager: chain_b.keys_manager.clone() as Arc<dyn KeysInterface>,
+        fee_estimator: chain_b.fee_estimator.clone(),
+        monitor: chain_b.monitor.clone(),
+        chain_monitor: chain_b.chain_watcher.clone(),
+        tx_broadcaster: chain.clone(),
+        logger: logger(),
+        default_config: UserConfig::new(),
+        channel_monitors: &stale_monitors,
+    }).unwrap();
+    assert_eq!(chain.get_mempool().len(), 1);
+    let revoked = chain.get_mempool().remove(0);
+    assert_eq!(revoked.input[0].previous_output, funding_txo.into_bitcoin_outpoint());
+
+    // A takes B's output too, with the revocation key
+    let to_local = revoked.output.iter().position(|output| output.script_pubkey.is_v0_p2wsh()).unwrap() as u32;
+    chain.mine_blocks(1);
+    assert!(sync_until(&[&channels_a], || {
+        chain.get_mempool().iter().flat_map(|tx| tx.input.iter())
+            .any(|input| input.previous_output.txid == revoked.txid() && input.previous_output.vout == to_local)
+    }));
+    chain.mine_blocks(1);
+    let wallet_a = channels_a.get_wallet("regtest").unwrap();
+    assert!(sync_until(&[&channels_a], || wallet_a.get_balance() > 95_000));
+
+    fs::remove_dir_all(datadir_a).unwrap();
+    fs::remove_dir_all(datadir_b).unwrap();
+}
+
+#[test]
+fn test_monitors_reloaded() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let datadir = new_datadir("monitors");
+    let node = load_node(&[32; 32], &datadir);
+    assert!(node.chains[0].monitor.rescan_height().is_none());
+    drop(node);
+
+    // Leftovers of a write that didn't finish are ignored
+    fs::write(datadir.join("monitors").join("regtest").join("00_0.tmp"), b"partial").unwrap();
+    let node = load_node(&[32; 32], &datadir);
+    assert!(node.chains[0].monitor.rescan_height().is_none());
+    let channels = new_channels(&node, &[&chain]);
+    assert!(channels.list_channels().is_empty());
+    drop(node);
+
+    fs::write(datadir.join("monitors").join("regtest").join("00_0"), b"corrupt").unwrap();
+    let err = Node::with_datadir(&["regtest"], node::seed_signers(&["regtest"], &[32; 32], logger()), &datadir, logger()).err().unwrap();
+    assert!(err.contains("Corrupt channel monitor"));
+
+    fs::remove_dir_all(datadir).unwrap();
+}
@@ -0,0 +1,131 @@
+//! Nodes on the same machine talking to each other through the loopback interface, in this process and as rustlnd daemons
+
+mod common;
+
+use common::{bitcoind_stand_in, daemon_call, daemon_datadir, free_addr, start_daemon, wait_for};
+use rustlnd::logger::PrintLogger;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::Node;
+
+use lightning::util::logger::Level;
+use secp256k1::key::PublicKey;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::{thread, time};
+
+fn new_node() -> Node {
+    Node::new_random(&["regtest"], Arc::new(PrintLogger::new(Level::Trace)))
+}
+
+fn is_peer(node: &Node, node_id: &PublicKey) -> bool {
+    node.peer_manager.get_peer_node_ids().contains(node_id)
+}
+
+#[test]
+fn test_loopback_init_exchange() {
+    let node_a = new_node();
+    let node_b = new_node();
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+
+    // Both sides only list the other one after the handshake and the init messages
+    assert!(wait_for(|| is_peer(&node_a, &node_b.get_our_node_id())));
+    assert!(wait_for(|| is_peer(&node_b, &node_a.get_our_node_id())));
+}
+
+#[test]
+fn test_two_daemons() {
+    let rpc = bitcoind_stand_in(Arc::new(MemoryChain::new("regtest")));
+    let (datadir_a, node_id_a) = daemon_datadir("daemon-a");
+    let (datadir_b, node_id_b) = daemon_datadir("daemon-b");
+    let p2p_a = free_addr();
+    let process_a = start_daemon(&datadir_a, rpc, p2p_a);
+    let process_b = start_daemon(&datadir_b, rpc, free_addr());
+
+    assert_eq!(daemon_call(&datadir_b, &format!("connect {}@{}", node_id_a, p2p_a)), "ok");
+    assert!(wait_for(|| daemon_call(&datadir_b, "listpeers") == format!("{}@{} connected", node_id_a, p2p_a)));
+    // a only knows b's chains from its init message
+    let peer_chains = format!("peerchains {}", node_id_b);
+    assert!(wait_for(|| daemon_call(&datadir_a, &peer_chains).starts_with("supported: regtest\n")));
+
+    drop((process_a, process_b));
+    fs::remove_dir_all(&datadir_a).unwrap();
+    fs::remove_dir_all(&datadir_b).unwrap();
+}
+
+#[test]
+fn test_loopback_disconnect() {
+    let node_a = new_node();
+    let node_b = new_node();
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+
+    let descriptor = net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| is_peer(&node_a, &node_b.get_our_node_id())));
+
+    net::disconnect(&node_b.peer_manager, &descriptor);
+    assert!(!is_peer(&node_b, &node_a.get_our_node_id()));
+    assert!(wait_for(|| !is_peer(&node_a, &node_b.get_our_node_id())));
+}
+
+#[test]
+fn test_loopback_wrong_node_id() {
+    let node_a = new_node();
+    let node_b = new_node();
+    let node_c = new_node();
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+
+    // node_a cannot complete act two for node_c's key
+    net::connect_outbound(node_b.peer_manager.clone(), node_c.get_our_node_id(), &addr_a.to_string()).unwrap();
+    thread::sleep(time::Duration::from_millis(500));
+    assert!(node_a.peer_manager.get_peer_node_ids().is_empty());
+    assert!(node_b.peer_manager.get_peer_node_ids().is_empty());
+}
+
+#[test]
+fn test_listen_several_addresses() {
+    let node_a = new_node();
+    let node_b = new_node();
+    let node_c = new_node();
+    let addr_v4 = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    let addr_other = match net::listen(node_a.peer_manager.clone(), "[::1]:0") {
+        Ok(listener) => listener.local_addr(),
+        // No IPv6 in this environment
+        Err(_) => net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr(),
+    };
+
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_v4.to_string()).unwrap();
+    net::connect_outbound(node_c.peer_manager.clone(), node_a.get_our_node_id(), &addr_other.to_string()).unwrap();
+    assert!(wait_for(|| is_peer(&node_a, &node_b.get_our_node_id())));
+    assert!(wait_for(|| is_peer(&node_a, &node_c.get_our_node_id())));
+}
+
+#[test]
+fn test_parse_netaddress() {
+    assert_eq!(net::netaddress_to_string(&net::parse_netaddress("1.2.3.4:9735").unwrap()), "1.2.3.4:9735");
+    assert_eq!(net::netaddress_to_string(&net::parse_netaddress("[2001:db8::1]:9735").unwrap()), "[2001:db8::1]:9735");
+    assert!(net::parse_netaddress("example.com:9735").is_err());
+    assert!(net::parse_netaddress("1.2.3.4").is_err());
+    assert!(net::parse_netaddress("notbase32!.onion:9735").is_err());
+}
+
+#[test]
+fn test_announce_addresses() {
+    let bound: Vec<SocketAddr> = vec!["127.0.0.1:9735".parse().unwrap(), "8.8.8.8:9735".parse().unwrap(),
+                                      "9.9.9.9:9735".parse().unwrap(), "[::1]:9735".parse().unwrap()];
+    // Only public ones, and one per type
+    let addresses = net::announce_addresses(&[], &bound, None).unwrap();
+    let addresses: Vec<String> = addresses.iter().map(net::netaddress_to_string).collect();
+    assert_eq!(addresses, vec!["8.8.8.8:9735".to_string()]);
+
+    // Behind NAT: we bind locally and announce what we were told to
+    let announce = vec!["1.2.3.4:9735".to_string(), "[2001:db8::1]:9735".to_string()];
+    let addresses = net::announce_addresses(&announce, &bound, None).unwrap();
+    assert_eq!(addresses.len(), 2);
+
+    let announce = vec!["1.2.3.4:9735".to_string(), "5.6.7.8:9735".to_string()];
+    assert!(net::announce_addresses(&announce, &bound, None).is_err());
+}
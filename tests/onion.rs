@@ -0,0 +1,68 @@
+//! Onions peeled hop by hop, and what a hop can't peel
+
+use rustlnd::onion::{self, HopPayload, Peeled, MAX_HOPS, PACKET_LEN};
+
+use rand::Rng;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+
+fn random_key() -> SecretKey {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    SecretKey::from_slice(&bytes).unwrap()
+}
+
+fn payload(i: u64) -> HopPayload {
+    HopPayload { short_channel_id: i << 40 | 1, amt_to_forward: 1000 * i, outgoing_cltv_value: 100 + i as u32 }
+}
+
+fn route(len: usize) -> (Vec<SecretKey>, Vec<(PublicKey, HopPayload)>) {
+    let secp_ctx = Secp256k1::new();
+    let secrets: Vec<SecretKey> = (0..len).map(|_| random_key()).collect();
+    let hops = secrets.iter().enumerate().map(|(i, secret)| {
+        let payload = if i == len - 1 { HopPayload { short_channel_id: 0, ..payload(i as u64) } } else { payload(i as u64) };
+        (PublicKey::from_secret_key(&secp_ctx, secret), payload)
+    }).collect();
+    (secrets, hops)
+}
+
+#[test]
+fn test_peel() {
+    let payment_hash = [0x42; 32];
+    for len in [1, 2, 5, MAX_HOPS] {
+        let (secrets, hops) = route(len);
+        let mut packet = onion::construct(&random_key(), &hops, &payment_hash).unwrap();
+        for (i, secret) in secrets.iter().enumerate() {
+            assert_eq!(packet.len(), PACKET_LEN);
+            match onion::peel(secret, &packet, &payment_hash).unwrap() {
+                Peeled::Forward { payload, next_packet } => {
+                    assert!(i < len - 1);
+                    assert_eq!(payload, hops[i].1);
+                    packet = next_packet;
+                },
+                Peeled::Final(payload) => {
+                    assert_eq!(i, len - 1);
+                    assert_eq!(payload, hops[i].1);
+                },
+            }
+        }
+    }
+}
+
+#[test]
+fn test_bad_onions() {
+    let payment_hash = [0x42; 32];
+    let (secrets, hops) = route(3);
+    let packet = onion::construct(&random_key(), &hops, &payment_hash).unwrap();
+    assert_eq!(onion::peel(&secrets[1], &packet, &payment_hash), Err("Bad onion HMAC".to_string()));
+    assert_eq!(onion::peel(&secrets[0], &packet, &[0x43; 32]), Err("Bad onion HMAC".to_string()));
+    let mut tampered = packet.clone();
+    tampered[100] ^= 1;
+    assert_eq!(onion::peel(&secrets[0], &tampered, &payment_hash), Err("Bad onion HMAC".to_string()));
+    let mut version = packet.clone();
+    version[0] = 1;
+    assert!(onion::peel(&secrets[0], &version, &payment_hash).is_err());
+    assert!(onion::peel(&secrets[0], &packet[1..], &payment_hash).is_err());
+
+    assert!(onion::construct(&random_key(), &[], &payment_hash).is_err());
+    assert!(onion::construct(&random_key(), &route(MAX_HOPS + 1).1, &payment_hash).is_err());
+}
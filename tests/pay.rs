@@ -0,0 +1,162 @@
+//! Paying invoices through routes in the network graph, trying others when one fails, and hold invoices
+
+mod common;
+
+use common::{connect, fund, logger, new_channels, pay, pay_while, sync_until};
+use rustlnd::channels::{ChannelState, Channels, PaymentFailure};
+use rustlnd::chain::ChainBackend;
+use rustlnd::invoice::{Description, Invoice};
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::node::Node;
+
+use rustlnd::registry::{InvoiceState, HOLD_CANCEL_BLOCKS};
+
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+use secp256k1::key::PublicKey;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+fn open(chain: &MemoryChain, all: &[&Channels], opener: &Channels, node_id: PublicKey, value: u64) {
+    let open_before = opener.list_channels().iter().filter(|c| c.state == ChannelState::Open).count();
+    opener.open_channel("regtest", node_id, value, 0, false).unwrap();
+    assert!(sync_until(all, || chain.get_mempool().len() == 1));
+    // Deep enough to be announced
+    chain.mine_blocks(6);
+    assert!(sync_until(all, || opener.list_channels().iter().filter(|c| c.state == ChannelState::Open).count() == open_before + 1));
+}
+
+fn invoice(payee: &Node, amount_msat: Option<u64>) -> ([u8; 32], String) {
+    let entry = payee.invoices.create("regtest", amount_msat, Description::Direct("test".to_string()), 3600).unwrap();
+    (entry.preimage.unwrap(), entry.encoded)
+}
+
+#[test]
+fn test_pay() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let nodes: Vec<Node> = (0..3).map(|_| Node::new_random(&["regtest"], logger())).collect();
+    let (node_a, node_b, node_c) = (&nodes[0], &nodes[1], &nodes[2]);
+    let channels_a = new_channels(node_a, &[&chain]);
+    let channels_b = new_channels(node_b, &[&chain]);
+    let channels_c = new_channels(node_c, &[&chain]);
+    let all = [&*channels_a, &*channels_b, &*channels_c];
+    connect(node_a, node_b);
+    connect(node_b, node_c);
+    fund(&chain, &channels_a, "regtest", 1_000_000);
+    fund(&chain, &channels_b, "regtest", 1_000_000);
+    fund(&chain, &channels_c, "regtest", 1_000_000);
+
+    // C funds the only channel between B and C, B can't send through it
+    open(&chain, &all, &channels_a, node_b.get_our_node_id(), 500_000);
+    open(&chain, &all, &channels_c, node_b.get_our_node_id(), 300_000);
+    assert!(sync_until(&all, || node_a.router.get_addresses(&node_c.get_our_node_id()).is_some()));
+    let (_, encoded) = invoice(node_c, Some(20_000_000));
+    match pay(&channels_a, &encoded, &[&channels_b, &channels_c]) {
+        Err(PaymentFailure::NoRoute { attempts: 1, .. }) => {},
+        result => panic!("{:?}", result),
+    }
+
+    // Another channel that can
+    open(&chain, &all, &channels_b, node_c.get_our_node_id(), 300_000);
+    assert!(sync_until(&all, || node_a.router.get_route(&node_c.get_our_node_id(), None, &[], 20_000_000, 9).is_ok()));
+    let (preimage, encoded) = invoice(node_c, Some(20_000_000));
+    let payment_hash = Invoice::decode(&encoded, &["regtest"]).unwrap().payment_hash;
+    assert_eq!(pay(&channels_a, &encoded, &[&channels_b, &channels_c]), Ok(preimage));
+    let settled = node_c.invoices.get(&payment_hash).unwrap();
+    assert_eq!((settled.state, settled.received_msat), (InvoiceState::Settled, Some(20_000_000)));
+
+    // C fails the payments of canceled invoices, and the second payment of a settled one
+    let (_, encoded) = invoice(node_c, Some(5_000_000));
+    node_c.invoices.cancel(&Invoice::decode(&encoded, &["regtest"]).unwrap().payment_hash).unwrap();
+    assert_eq!(pay(&channels_a, &encoded, &[&channels_b, &channels_c]), Err(PaymentFailure::Rejected { attempts: 1 }));
+    let (_, encoded) = invoice(node_c, Some(5_000_000));
+    let invoice_c = Invoice::decode(&encoded, &["regtest"]).unwrap();
+    assert!(pay(&channels_a, &encoded, &[&channels_b, &channels_c]).is_ok());
+    assert_eq!(pay(&channels_a, &encoded, &[&channels_b, &channels_c]), Err(PaymentFailure::Rejected { attempts: 1 }));
+    assert_eq!(node_c.invoices.get(&invoice_c.payment_hash).unwrap().received_msat, Some(5_000_000));
+}
+
+#[test]
+fn test_hold_invoices() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let nodes: Vec<Node> = (0..3).map(|_| Node::new_random(&["regtest"], logger())).collect();
+    let (node_a, node_b, node_c) = (&nodes[0], &nodes[1], &nodes[2]);
+    let channels_a = new_channels(node_a, &[&chain]);
+    let channels_b = new_channels(node_b, &[&chain]);
+    let channels_c = new_channels(node_c, &[&chain]);
+    let all = [&*channels_a, &*channels_b, &*channels_c];
+    connect(node_a, node_b);
+    connect(node_b, node_c);
+    fund(&chain, &channels_a, "regtest", 1_000_000);
+    fund(&chain, &channels_b, "regtest", 1_000_000);
+    open(&chain, &all, &channels_a, node_b.get_our_node_id(), 500_000);
+    open(&chain, &all, &channels_b, node_c.get_our_node_id(), 500_000);
+    assert!(sync_until(&all, || node_a.router.get_route(&node_c.get_our_node_id(), None, &[], 5_000_000, 9).is_ok()));
+    let hold = |preimage: [u8; 32]| {
+        let payment_hash = Sha256::hash(&preimage).into_inner();
+        (payment_hash, node_c.invoices.create_hold("regtest", payment_hash, Some(5_000_000), Description::Direct("swap".to_string()), 3600).unwrap().encoded)
+    };
+    let accepted = |payment_hash: &[u8; 32]| node_c.invoices.get(payment_hash).unwrap().state == InvoiceState::Accepted;
+
+    // The payment waits for the preimage
+    let (payment_hash, encoded) = hold([1; 32]);
+    let mut held_syncs = 0;
+    let paid = pay_while(&channels_a, &encoded, &[&channels_b, &channels_c], || {
+        if accepted(&payment_hash) {
+            held_syncs += 1;
+            if held_syncs == 20 {
+                node_c.invoices.settle([1; 32]).unwrap();
+            }
+        }
+    });
+    assert_eq!(paid, Ok([1; 32]));
+    assert!(held_syncs >= 20);
+    assert_eq!(node_c.invoices.get(&payment_hash).unwrap().state, InvoiceState::Settled);
+
+    // Or is failed when canceled
+    let (payment_hash, encoded) = hold([2; 32]);
+    let failed = pay_while(&channels_a, &encoded, &[&channels_b, &channels_c], || {
+        if accepted(&payment_hash) {
+            node_c.invoices.cancel(&payment_hash).unwrap();
+        }
+    });
+    assert_eq!(failed, Err(PaymentFailure::Rejected { attempts: 1 }));
+
+    // Or before its HTLC expires
+    let (payment_hash, encoded) = hold([3; 32]);
+    let failed = pay_while(&channels_a, &encoded, &[&channels_b, &channels_c], || {
+        if accepted(&payment_hash) {
+            chain.mine_blocks(1);
+        }
+    });
+    assert_eq!(failed, Err(PaymentFailure::Rejected { attempts: 1 }));
+    let canceled = node_c.invoices.get(&payment_hash).unwrap();
+    assert_eq!(canceled.state, InvoiceState::Canceled);
+    assert_eq!(canceled.htlc_expiry.unwrap() - chain.get_height().unwrap(), HOLD_CANCEL_BLOCKS as u32);
+    assert!(channels_a.list_channels().iter().all(|c| c.state == ChannelState::Open));
+}
+
+#[test]
+fn test_unpayable_invoices() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let node_a = Node::new_random(&["regtest"], logger());
+    let node_b = Node::new_random(&["regtest", "aaa"], logger());
+    let channels_a = Channels::new(&node_a, vec![chain.clone() as Arc<dyn ChainBackend>]).unwrap();
+    let timeout = Duration::from_secs(1);
+
+    let (_, any_amount) = invoice(&node_b, None);
+    assert!(matches!(channels_a.pay(&any_amount, None, timeout), Err(PaymentFailure::Invoice(e)) if e.contains("any amount")));
+    assert_eq!(channels_a.pay(&any_amount, Some(1000), timeout), Err(PaymentFailure::NoRoute { attempts: 0, reason: "No usable channels".to_string() }));
+    let (_, encoded) = invoice(&node_b, Some(1000));
+    assert!(matches!(channels_a.pay(&encoded, Some(2000), timeout), Err(PaymentFailure::Invoice(e)) if e.contains("for 1000 msat")));
+
+    let mut expired = Invoice::new("regtest", node_b.get_our_node_id(), [1; 32], Description::Direct(String::new()));
+    expired.timestamp -= 7200;
+    let encoded = node_b.sign_invoice(&expired).unwrap();
+    assert_eq!(channels_a.pay(&encoded, Some(1000), timeout), Err(PaymentFailure::Invoice("The invoice expired".to_string())));
+
+    let on_aaa = Invoice::new("aaa", node_b.get_our_node_id(), [1; 32], Description::Direct(String::new()));
+    let encoded = node_b.sign_invoice(&on_aaa).unwrap();
+    assert!(matches!(channels_a.pay(&encoded, Some(1000), timeout), Err(PaymentFailure::Invoice(e)) if e.contains("unknown prefix lnaaa")));
+}
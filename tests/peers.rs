@@ -0,0 +1,137 @@
+//! Outbound connections that survive restarts of either side
+
+mod common;
+
+use common::{bitcoind_stand_in, daemon_call, daemon_datadir, free_addr, new_datadir, start_daemon, wait_for};
+use rustlnd::control::Control;
+use rustlnd::logger::PrintLogger;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::Node;
+use rustlnd::peers::{PeerAddress, PeerConnector, PeerState};
+
+use lightning::util::logger::Level;
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{thread, time};
+
+fn new_node(seed: &[u8; 32]) -> Node {
+    Node::new(&["regtest"], seed, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+fn new_connector(node: &Node, datadir: &Path) -> Arc<PeerConnector> {
+    let mut connector = PeerConnector::new(node, Some(datadir.join("peers")));
+    connector.set_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    let connector = Arc::new(connector);
+    PeerConnector::start(connector.clone(), Duration::from_millis(20));
+    connector
+}
+
+#[test]
+fn test_parse_peer_address() {
+    let node = new_node(&[1; 32]);
+    let peer_str = format!("{}@localhost:9999", node.get_our_node_id());
+    let address = PeerAddress::parse(&peer_str).unwrap();
+    assert_eq!(address.node_id, node.get_our_node_id());
+    assert_eq!(address.addr, "localhost:9999");
+    assert_eq!(peer_str, address.to_string());
+
+    assert!(PeerAddress::parse("localhost:9999").is_err());
+    assert!(PeerAddress::parse("02aa@localhost:9999").is_err());
+    assert!(PeerAddress::parse(&format!("{}@", node.get_our_node_id())).is_err());
+}
+
+#[test]
+fn test_connect_unreachable_peer_backs_off() {
+    let node_a = new_node(&[2; 32]);
+    let node_b = new_node(&[3; 32]);
+    let datadir = new_datadir("unreachable");
+    let connector = new_connector(&node_b, &datadir);
+    let control = Control::new(&node_b, connector.clone(), None);
+
+    // Nobody listens on port 1
+    let answer = control.handle_command(&format!("connect {}@127.0.0.1:1", node_a.get_our_node_id()));
+    assert!(answer.starts_with("error: "));
+    match connector.get_state(&node_a.get_our_node_id()) {
+        Some(PeerState::Backoff(_)) => {},
+        state => panic!("Unexpected state {:?}", state),
+    }
+    assert!(control.handle_command("listpeers").contains("backoff until"));
+
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_reconnect_after_restarts() {
+    let rpc = bitcoind_stand_in(Arc::new(MemoryChain::new("regtest")));
+    let (datadir_a, node_id_a) = daemon_datadir("reconnect-a");
+    let (datadir_b, node_id_b) = daemon_datadir("reconnect-b");
+    let (p2p_a, p2p_b) = (free_addr(), free_addr());
+    let process_a = start_daemon(&datadir_a, rpc, p2p_a);
+    let process_b = start_daemon(&datadir_b, rpc, p2p_b);
+    let connected = format!("{}@{} connected", node_id_a, p2p_a);
+    let b_is_peer_of_a = |datadir_a: &Path| daemon_call(datadir_a, &format!("peerchains {}", node_id_b)).starts_with("supported: regtest\n");
+    assert_eq!(daemon_call(&datadir_b, &format!("connect {}@{}", node_id_a, p2p_a)), "ok");
+    assert!(wait_for(|| daemon_call(&datadir_b, "listpeers") == connected));
+
+    // Kill a, b backs off and keeps trying until a is back on the same address
+    drop(process_a);
+    assert!(wait_for(|| daemon_call(&datadir_b, "listpeers").contains("backoff until")));
+    let process_a = start_daemon(&datadir_a, rpc, p2p_a);
+    assert!(wait_for(|| daemon_call(&datadir_b, "listpeers") == connected));
+    assert!(wait_for(|| b_is_peer_of_a(&datadir_a)));
+
+    // Stop b, it remembers a from its datadir
+    let mut process_b = process_b;
+    assert_eq!(daemon_call(&datadir_b, "stop"), "ok");
+    assert!(process_b.0.wait().unwrap().success());
+    let process_b = start_daemon(&datadir_b, rpc, p2p_b);
+    assert!(wait_for(|| daemon_call(&datadir_b, "listpeers") == connected));
+    assert!(wait_for(|| b_is_peer_of_a(&datadir_a)));
+
+    drop((process_a, process_b));
+    fs::remove_dir_all(&datadir_a).unwrap();
+    fs::remove_dir_all(&datadir_b).unwrap();
+}
+
+#[test]
+fn test_connect_once() {
+    let node_a = new_node(&[7; 32]);
+    let listener_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap();
+    let node_b = new_node(&[8; 32]);
+    let connector_b = Arc::new(PeerConnector::new(&node_b, None));
+    let address = PeerAddress { node_id: node_a.get_our_node_id(), addr: listener_a.local_addr().to_string() };
+    connector_b.connect(address.clone()).unwrap();
+    // Still in the handshake or done, either way there's no second connection
+    connector_b.connect(address).unwrap();
+    assert!(wait_for(|| connector_b.get_state(&node_a.get_our_node_id()) == Some(PeerState::Connected)));
+    thread::sleep(time::Duration::from_millis(200));
+    assert_eq!(connector_b.get_state(&node_a.get_our_node_id()), Some(PeerState::Connected));
+
+    connector_b.stop();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().is_empty()));
+    connector_b.reconnect_all();
+    thread::sleep(time::Duration::from_millis(200));
+    assert!(node_a.peer_manager.get_peer_node_ids().is_empty());
+}
+
+#[test]
+fn test_channel_peers_first() {
+    let node_a = new_node(&[9; 32]);
+    let node_b = new_node(&[10; 32]);
+    let node_c = new_node(&[11; 32]);
+    let mut connector = PeerConnector::new(&node_b, None);
+    connector.set_backoff(Duration::from_millis(10), Duration::from_millis(100));
+    // Nobody listens on port 1
+    for node in &[&node_a, &node_c] {
+        assert!(connector.connect(PeerAddress { node_id: node.get_our_node_id(), addr: "127.0.0.1:1".to_string() }).is_err());
+    }
+    // Not funded, but a channel with c all the same
+    node_b.chains[0].channel_manager.create_channel(node_c.get_our_node_id(), 100_000, 0, 0).unwrap();
+    thread::sleep(time::Duration::from_millis(20));
+    let due: Vec<_> = connector.due_peers().iter().map(|address| address.node_id).collect();
+    assert_eq!(due, vec![node_c.get_our_node_id(), node_a.get_our_node_id()]);
+}
@@ -0,0 +1,125 @@
+//! Which channels peers can open to us, refusing the others with the reason
+
+mod common;
+
+use common::{new_node_with_policy, sync_until, wait_for};
+use rustlnd::channels::{ChannelState, Channels};
+use rustlnd::chain::ChainBackend;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::Node;
+use rustlnd::policy::{ChainPolicy, ChannelPolicy};
+use rustlnd::wallet;
+
+use lightning::ln::msgs::{ChannelMessageHandler, ErrorAction, OpenChannel};
+use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+use lightning::util::ser::Writeable;
+use secp256k1::key::PublicKey;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+// The open_channel opener would send to peer
+fn open_channel_msg(opener: &Node, peer: &Node, chain_index: usize, value: u64) -> OpenChannel {
+    opener.chains[chain_index].channel_manager.create_channel(peer.get_our_node_id(), value, 0, 0).unwrap();
+    match opener.chain_filter.get_and_clear_pending_msg_events().remove(0) {
+        MessageSendEvent::SendOpenChannel { msg, .. } => msg,
+        _ => panic!("No open_channel"),
+    }
+}
+
+// The reason in the error message peer answers with, None if it accepts the channel
+fn refusal(peer: &Node, opener: &Node, msg: &OpenChannel) -> Option<String> {
+    match peer.chain_filter.handle_open_channel(&opener.get_our_node_id(), msg) {
+        Ok(()) => None,
+        Err(e) => match e.action {
+            // channel_id and length before the data
+            Some(ErrorAction::SendErrorMessage { msg }) => Some(String::from_utf8(msg.encode()[34..].to_vec()).unwrap()),
+            _ => panic!("No error message"),
+        },
+    }
+}
+
+#[test]
+fn test_check_open() {
+    let node_id = PublicKey::from_secret_key(&secp256k1::Secp256k1::new(), &secp256k1::key::SecretKey::from_slice(&[1; 32]).unwrap());
+    let mut policy = ChannelPolicy::default();
+    assert!(policy.check_open("regtest", &node_id, 100_000, 0).is_ok());
+    assert!(policy.check_open("regtest", &node_id, 19_999, 0).unwrap_err().contains("below our minimum of 20000 sat"));
+    assert!(policy.check_open("regtest", &node_id, 1 << 24, 0).unwrap_err().contains("above our maximum"));
+    assert!(policy.check_open("regtest", &node_id, 100_000, 5).unwrap_err().contains("Too many pending channels"));
+
+    policy.chains.insert("aaa".to_string(), ChainPolicy { accept: false, ..ChainPolicy::default() });
+    assert!(policy.check_open("aaa", &node_id, 100_000, 0).unwrap_err().contains("on chain aaa"));
+    assert!(policy.check_open("regtest", &node_id, 100_000, 0).is_ok());
+
+    policy.deny.insert(node_id);
+    assert!(policy.check_open("regtest", &node_id, 100_000, 0).is_err());
+    policy.deny.clear();
+    let other_id = PublicKey::from_secret_key(&secp256k1::Secp256k1::new(), &secp256k1::key::SecretKey::from_slice(&[2; 32]).unwrap());
+    policy.allow.insert(other_id);
+    assert!(policy.check_open("regtest", &node_id, 100_000, 0).is_err());
+    assert!(policy.check_open("regtest", &other_id, 100_000, 0).is_ok());
+}
+
+#[test]
+fn test_refused_opens() {
+    let mut policy = ChannelPolicy::default();
+    policy.chains.insert("aaa".to_string(), ChainPolicy { min_channel_size: 200_000, max_pending: 1, ..ChainPolicy::default() });
+    policy.chains.insert("bbb".to_string(), ChainPolicy { accept: false, ..ChainPolicy::default() });
+    let node_a = new_node_with_policy(&["aaa", "bbb"], policy);
+    let node_b = new_node_with_policy(&["aaa", "bbb"], ChannelPolicy::default());
+
+    let small = open_channel_msg(&node_b, &node_a, 0, 100_000);
+    assert_eq!(refusal(&node_a, &node_b, &small).unwrap(), "Channel of 100000 sat is below our minimum of 200000 sat");
+    let on_bbb = open_channel_msg(&node_b, &node_a, 1, 300_000);
+    assert_eq!(refusal(&node_a, &node_b, &on_bbb).unwrap(), "We don't accept channels on chain bbb");
+    assert!(node_a.chains.iter().all(|chain_node| chain_node.channel_manager.list_channels().is_empty()));
+
+    // One channel waiting for its funding transaction at a time
+    let first = open_channel_msg(&node_b, &node_a, 0, 300_000);
+    assert_eq!(refusal(&node_a, &node_b, &first), None);
+    let second = open_channel_msg(&node_b, &node_a, 0, 300_000);
+    assert_eq!(refusal(&node_a, &node_b, &second).unwrap(), "Too many pending channels with us, 1 already");
+    assert_eq!(node_a.chains[0].channel_manager.list_channels().len(), 1);
+
+    // Only the peers allowed
+    let mut policy = ChannelPolicy::default();
+    policy.allow.insert(node_a.get_our_node_id());
+    let node_c = new_node_with_policy(&["aaa"], policy);
+    let msg = open_channel_msg(&node_b, &node_c, 0, 300_000);
+    assert_eq!(refusal(&node_c, &node_b, &msg).unwrap(), "We don't accept channels from you");
+}
+
+#[test]
+fn test_confirmations() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let mut policy = ChannelPolicy::default();
+    policy.chains.insert("regtest".to_string(), ChainPolicy { confirmations: 3, ..ChainPolicy::default() });
+    let node_a = new_node_with_policy(&["regtest"], policy);
+    let node_b = new_node_with_policy(&["regtest"], ChannelPolicy::default());
+    let channels_a = Channels::new(&node_a, vec![chain.clone() as Arc<dyn ChainBackend>]).unwrap();
+    let channels_b = Channels::new(&node_b, vec![chain.clone() as Arc<dyn ChainBackend>]).unwrap();
+    let addr = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let wallet_b = channels_b.get_wallet("regtest").unwrap();
+    chain.faucet(&wallet::address_to_script("regtest", &wallet_b.new_address()).unwrap(), 300_000);
+    chain.mine_blocks(1);
+    channels_b.sync().unwrap();
+    channels_b.open_channel("regtest", node_a.get_our_node_id(), 100_000, 0, false).unwrap();
+    assert!(sync_until(&[&channels_a, &channels_b], || chain.get_mempool().len() == 1));
+    chain.mine_blocks(2);
+    for _ in 0..3 {
+        channels_a.sync().unwrap();
+        channels_b.sync().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    assert_eq!(channels_b.list_channels()[0].state, ChannelState::Pending);
+    chain.mine_blocks(1);
+    assert!(sync_until(&[&channels_a, &channels_b], || {
+        channels_a.list_channels()[0].state == ChannelState::Open && channels_b.list_channels()[0].state == ChannelState::Open
+    }));
+    assert_eq!(channels_b.list_channels()[0].confirmations, 3);
+}
@@ -0,0 +1,192 @@
+//! Outbound connections through a SOCKS5 proxy and onion services, with local stand-ins for Tor
+
+mod common;
+
+use common::{new_datadir, wait_for};
+use rustlnd::logger::PrintLogger;
+use rustlnd::net;
+use rustlnd::node::Node;
+use rustlnd::peers::{PeerAddress, PeerConnector, PeerState};
+use rustlnd::{socks5, tor};
+
+use lightning::ln::msgs::NetAddress;
+use lightning::util::logger::Level;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::thread;
+
+fn new_node(seed: &[u8; 32]) -> Node {
+    Node::new(&["regtest"], seed, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+/// SOCKS5 server that only knows the hosts in its map, records what it was asked for
+fn socks5_stand_in(hosts: HashMap<String, SocketAddr>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let thread_requested = requested.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut client = stream.unwrap();
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            client.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0; 5];
+            client.read_exact(&mut request).unwrap();
+            // Onion addresses can only travel as domain names
+            assert_eq!(request[..4], [5, 1, 0, 3]);
+            let mut host = vec![0; request[4] as usize];
+            client.read_exact(&mut host).unwrap();
+            let mut port = [0; 2];
+            client.read_exact(&mut port).unwrap();
+            let target = format!("{}:{}", String::from_utf8(host).unwrap(), u16::from_be_bytes(port));
+            thread_requested.lock().unwrap().push(target.clone());
+
+            let server = match hosts.get(&target) {
+                Some(addr) => TcpStream::connect(addr).unwrap(),
+                None => {
+                    client.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+                    continue;
+                },
+            };
+            client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+            let (mut client_read, mut server_write) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || io::copy(&mut client_read, &mut server_write));
+            let (mut server_read, mut client_write) = (server, client);
+            thread::spawn(move || io::copy(&mut server_read, &mut client_write));
+        }
+    });
+    (addr, requested)
+}
+
+/// Tor control port that accepts any password and always publishes the same service
+fn tor_control_stand_in() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let thread_commands = commands.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut writer = stream.unwrap();
+            let client_commands = thread_commands.clone();
+            // Onion services live as long as their control connection
+            thread::spawn(move || {
+                let reader = BufReader::new(writer.try_clone().unwrap());
+                for line in reader.lines() {
+                    let line = line.unwrap();
+                    client_commands.lock().unwrap().push(line.clone());
+                    if line.starts_with("AUTHENTICATE") {
+                        writer.write_all(b"250 OK\r\n").unwrap();
+                    } else if line.starts_with("ADD_ONION NEW:ED25519-V3") {
+                        writer.write_all(b"250-ServiceID=exampleonionservice\r\n250-PrivateKey=ED25519-V3:c2VjcmV0\r\n250 OK\r\n").unwrap();
+                    } else if line.starts_with("ADD_ONION ") {
+                        writer.write_all(b"250-ServiceID=exampleonionservice\r\n250 OK\r\n").unwrap();
+                    } else {
+                        writer.write_all(b"510 Unrecognized command\r\n").unwrap();
+                    }
+                }
+            });
+        }
+    });
+    (addr, commands)
+}
+
+#[test]
+fn test_onion_netaddress_roundtrip() {
+    let v3 = NetAddress::OnionV3 { ed25519_pubkey: [7; 32], checksum: 0xbeef, version: 3, port: 9735 };
+    let host = tor::onion_host(&v3).unwrap();
+    assert_eq!(56 + ".onion".len(), host.len());
+    assert_eq!(Some(v3.clone()), tor::onion_netaddress(&host, 9735));
+    assert_eq!(format!("{}:9735", host), net::netaddress_to_string(&v3));
+
+    let v2 = NetAddress::OnionV2 { addr: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10], port: 9735 };
+    let host = tor::onion_host(&v2).unwrap();
+    assert_eq!(16 + ".onion".len(), host.len());
+    assert_eq!(Some(v2), tor::onion_netaddress(&host, 9735));
+
+    assert!(tor::onion_host(&NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }).is_none());
+    assert_eq!(None, tor::onion_netaddress("localhost", 9735));
+}
+
+#[test]
+fn test_split_host_port() {
+    assert_eq!(Ok(("localhost".to_string(), 9735)), socks5::split_host_port("localhost:9735"));
+    assert_eq!(Ok(("::1".to_string(), 9735)), socks5::split_host_port("[::1]:9735"));
+    assert!(socks5::split_host_port("localhost").is_err());
+    assert!(socks5::split_host_port(":9735").is_err());
+    assert!(socks5::split_host_port("localhost:port").is_err());
+    assert_eq!(Ok(("2001:db8::1".to_string(), 9735)), socks5::split_host_port("[2001:db8::1]:9735"));
+    // Without brackets the port can't be told apart
+    assert!(socks5::split_host_port("2001:db8::1").is_err());
+    assert!(socks5::split_host_port("2001:db8::1:9735").is_err());
+    assert!(socks5::split_host_port("[::1]").is_err());
+    assert!(socks5::split_host_port("[::1:9735").is_err());
+    assert!(socks5::split_host_port("[]:9735").is_err());
+}
+
+#[test]
+fn test_connect_onion_through_proxy() {
+    let node_a = new_node(&[10; 32]);
+    let listener_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap();
+    let onion = tor::onion_host(&NetAddress::OnionV3 { ed25519_pubkey: [10; 32], checksum: 0, version: 3, port: 9735 }).unwrap();
+    let mut hosts = HashMap::new();
+    hosts.insert(format!("{}:9735", onion), listener_a.local_addr());
+    let (proxy_addr, requested) = socks5_stand_in(hosts);
+
+    let datadir = new_datadir("proxy");
+    let node_b = new_node(&[11; 32]);
+    let address = PeerAddress { node_id: node_a.get_our_node_id(), addr: format!("{}:9735", onion) };
+
+    // Without a proxy we don't even try
+    let connector = PeerConnector::new(&node_b, None);
+    assert!(connector.connect(address.clone()).is_err());
+
+    let mut connector = PeerConnector::new(&node_b, Some(datadir.join("peers")));
+    connector.set_proxy(Some(proxy_addr.to_string()));
+    connector.set_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    let connector = Arc::new(connector);
+    connector.connect(address).unwrap();
+    assert!(wait_for(|| connector.get_state(&node_a.get_our_node_id()) == Some(PeerState::Connected)));
+    assert_eq!(vec![format!("{}:9735", onion)], *requested.lock().unwrap());
+
+    // Hosts the proxy cannot reach are reported as errors
+    let unknown = PeerAddress { node_id: new_node(&[12; 32]).get_our_node_id(), addr: "unknown.onion:9735".to_string() };
+    let err = connector.connect(unknown).unwrap_err();
+    assert!(err.contains("host unreachable"), "{}", err);
+
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_add_onion_service_keeps_key() {
+    let (control_addr, commands) = tor_control_stand_in();
+    let datadir = new_datadir("torcontrol");
+    let key_path = datadir.join("onion_key");
+
+    let service = tor::add_onion_service(&control_addr.to_string(), Some("pass\"word"), 9735, "127.0.0.1:9999", &key_path).unwrap();
+    assert_eq!("exampleonionservice.onion", service.onion_host);
+    assert_eq!("ED25519-V3:c2VjcmV0", fs::read_to_string(&key_path).unwrap());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&key_path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    let service = tor::add_onion_service(&control_addr.to_string(), None, 9735, "127.0.0.1:9999", &key_path).unwrap();
+    assert_eq!("exampleonionservice.onion", service.onion_host);
+    assert_eq!(vec![
+        "AUTHENTICATE \"pass\\\"word\"".to_string(),
+        "ADD_ONION NEW:ED25519-V3 Port=9735,127.0.0.1:9999".to_string(),
+        "AUTHENTICATE".to_string(),
+        "ADD_ONION ED25519-V3:c2VjcmV0 Port=9735,127.0.0.1:9999".to_string(),
+    ], *commands.lock().unwrap());
+
+    fs::remove_dir_all(&datadir).unwrap();
+}
@@ -0,0 +1,73 @@
+//! PSBTs for channel funding by external wallets
+
+use rustlnd::psbt;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+
+#[test]
+fn test_bip174_psbt() {
+    // From BIP174's test vectors: a PSBT with one P2PKH input and two outputs
+    let encoded = "cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUAAAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAAAAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8USIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6efkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA";
+    let decoded = psbt::from_base64(encoded).unwrap();
+    assert_eq!(decoded.inputs.len(), 1);
+    assert_eq!(decoded.outputs.len(), 2);
+    assert!(decoded.inputs[0].non_witness_utxo.is_some());
+    assert_eq!(psbt::to_base64(&decoded), encoded);
+
+    assert!(psbt::from_base64("cHNidP8=").is_err());
+    assert!(psbt::from_base64("not a psbt").is_err());
+}
+
+fn funded_psbt(funding_script: &Script, funding_value: u64) -> PartiallySignedTransaction {
+    let mut funded = psbt::from_base64(&psbt::to_base64(&psbt::funding_psbt(funding_script, funding_value))).unwrap();
+    // What an external wallet adds: change before the funding output and an input
+    funded.global.unsigned_tx.output.insert(0, TxOut { value: 5_000, script_pubkey: Script::from(vec![0; 22]) });
+    funded.outputs.insert(0, Default::default());
+    funded.global.unsigned_tx.input.push(TxIn {
+        previous_output: OutPoint::default(),
+        script_sig: Script::new(),
+        sequence: 0xffff_ffff,
+        witness: Vec::new(),
+    });
+    funded.inputs.push(Default::default());
+    funded
+}
+
+#[test]
+fn test_funding_psbt() {
+    let funding_script = Script::from(vec![0; 34]);
+    let unfunded = psbt::funding_psbt(&funding_script, 100_000);
+    assert!(unfunded.global.unsigned_tx.input.is_empty());
+    assert_eq!(psbt::find_funding_output(&unfunded, &funding_script, 100_000), Ok(0));
+    assert!(psbt::extract_funding_transaction(unfunded).unwrap_err().contains("no inputs"));
+
+    let mut funded = funded_psbt(&funding_script, 100_000);
+    assert_eq!(psbt::find_funding_output(&funded, &funding_script, 100_000), Ok(1));
+    assert!(psbt::find_funding_output(&funded, &funding_script, 90_000).unwrap_err().contains("100000 satoshis"));
+    assert!(psbt::find_funding_output(&funded, &Script::from(vec![1; 34]), 100_000).unwrap_err().contains("doesn't pay"));
+    assert!(psbt::extract_funding_transaction(funded.clone()).unwrap_err().contains("isn't finalized"));
+
+    funded.inputs[0].final_script_sig = Some(Script::from(vec![0x02, 0x30, 0x01]));
+    assert!(psbt::extract_funding_transaction(funded.clone()).unwrap_err().contains("isn't segwit"));
+    funded.inputs[0].final_script_witness = Some(vec![vec![0x30; 72], vec![0x02; 33]]);
+    assert!(psbt::extract_funding_transaction(funded.clone()).unwrap_err().contains("isn't segwit"));
+
+    // P2SH wrapped P2WPKH
+    let mut wrapped = vec![22, 0, 20];
+    wrapped.extend_from_slice(&[0; 20]);
+    funded.inputs[0].final_script_sig = Some(Script::from(wrapped));
+    let tx = psbt::extract_funding_transaction(funded.clone()).unwrap();
+    assert_eq!(tx.input[0].witness.len(), 2);
+    assert_eq!(tx.output[1].value, 100_000);
+
+    funded.inputs[0].final_script_sig = None;
+    let tx = psbt::extract_funding_transaction(funded.clone()).unwrap();
+    assert!(tx.input[0].script_sig.is_empty());
+
+    let mut twice = funded;
+    twice.global.unsigned_tx.output.push(TxOut { value: 100_000, script_pubkey: funding_script.clone() });
+    twice.outputs.push(Default::default());
+    assert!(psbt::find_funding_output(&twice, &funding_script, 100_000).unwrap_err().contains("more than once"));
+}
@@ -0,0 +1,126 @@
+//! Rates between chains, how they round, the rate file and the quotes in channel_updates
+
+use rustlnd::chain;
+use rustlnd::rates::{self, Rate, RateTable, RATE_ONE};
+
+use rand::Rng;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(rates::parse_rate("aaa:bbb:1.05"), Ok(("aaa".to_string(), "bbb".to_string(), Rate::new(1_050_000, 0))));
+    assert_eq!(rates::parse_rate("aaa:bbb:2:0.001"), Ok(("aaa".to_string(), "bbb".to_string(), Rate::new(2_000_000, 1000))));
+    assert_eq!(rates::parse_rate("aaa:bbb:.5").unwrap().2, Rate::new(500_000, 0));
+    assert_eq!(rates::parse_rate("aaa:bbb:0.000001").unwrap().2, Rate::new(1, 0));
+    for bad in ["aaa:bbb", "aaa:bbb:1:0:0", "aaa:aaa:1", ":bbb:1", "aaa:bbb:0", "aaa:bbb:-1", "aaa:bbb:1.0000001", "aaa:bbb:.",
+                "aaa:bbb:1,5", "aaa:bbb:1:x", "aaa:bbb:99999999999999", "aaa:bbb:1:0.100001"] {
+        assert!(rates::parse_rate(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_rounding() {
+    let rate = Rate::new(1_050_000, 1000);
+    assert_eq!(rate.convert(1_000_000), Ok(1_050_000));
+    assert_eq!(rate.convert(1), Ok(1));
+    assert_eq!(rate.spread(1_000_000), Ok(1000));
+    assert_eq!(rate.spread(1), Ok(1));
+    assert_eq!(rate.spread(0), Ok(0));
+    // What the payer pays is always enough for what the hop asks
+    for amount_msat in [1, 999, 1_000_000, 123_456_789] {
+        let cost = rate.cost(amount_msat).unwrap();
+        let asked = amount_msat + rate.spread(amount_msat).unwrap();
+        assert!(rate.convert(cost).unwrap() >= asked);
+        assert!(rate.convert(cost - 1).unwrap() < asked);
+    }
+
+    // Amounts that don't fit are errors, not wrapped around
+    assert!(Rate::new(2 * RATE_ONE, 0).convert(u64::MAX).is_err());
+    assert!(Rate::new(1, 0).cost(u64::MAX / 2).is_err());
+    assert!(Rate::new(RATE_ONE, RATE_ONE).spread(u64::MAX).is_ok());
+    assert!(Rate::new(RATE_ONE, 2 * RATE_ONE).spread(u64::MAX).is_err());
+    assert!(Rate::new(0, 0).cost(1).is_err());
+}
+
+#[test]
+fn test_block_intervals() {
+    let table = RateTable::new();
+    assert_eq!(table.block_interval("aaa"), rates::DEFAULT_BLOCK_INTERVAL);
+    assert_eq!(table.convert_blocks("aaa", "bbb", 10), 10);
+    table.set_block_interval("aaa", 150);
+    // At least as long, rounded up
+    assert_eq!(table.convert_blocks("bbb", "aaa", 10), 40);
+    assert_eq!(table.convert_blocks("aaa", "bbb", 10), 3);
+    assert_eq!(table.convert_blocks("aaa", "bbb", 0), 0);
+    table.set_block_interval("bbb", 1);
+    assert_eq!(table.convert_blocks("aaa", "bbb", u32::MAX), u32::MAX);
+}
+
+#[test]
+fn test_rate_table() {
+    let table = RateTable::new();
+    assert_eq!(table.get("aaa", "aaa"), Some(Rate::new(RATE_ONE, 0)));
+    assert_eq!(table.get("aaa", "bbb"), None);
+    let version = table.version();
+    table.set("aaa", "bbb", Rate::new(2 * RATE_ONE, 0));
+    table.set("bbb", "aaa", Rate::new(RATE_ONE / 2, 0));
+    assert!(table.version() > version);
+    assert_eq!(table.get("aaa", "bbb"), Some(Rate::new(2 * RATE_ONE, 0)));
+
+    // The file's rates go over the others
+    let random: u64 = rand::thread_rng().gen();
+    let path = std::env::temp_dir().join(format!("rustlnd-rates-{:x}", random));
+    fs::write(&path, "# aaa to bbb\naaa:bbb:3:0.01\n\n").unwrap();
+    table.set_file(&path).unwrap();
+    assert_eq!(table.get("aaa", "bbb"), Some(Rate::new(3 * RATE_ONE, 10_000)));
+    assert_eq!(table.list().len(), 2);
+    assert_eq!(table.refresh(), Ok(false));
+
+    thread::sleep(Duration::from_millis(20));
+    let version = table.version();
+    fs::write(&path, "aaa:bbb:4\n").unwrap();
+    assert_eq!(table.refresh(), Ok(true));
+    assert!(table.version() > version);
+    assert_eq!(table.get("aaa", "bbb"), Some(Rate::new(4 * RATE_ONE, 0)));
+
+    // A broken file leaves the rates as they were
+    thread::sleep(Duration::from_millis(20));
+    fs::write(&path, "aaa:bbb\n").unwrap();
+    assert!(table.refresh().is_err());
+    assert_eq!(table.get("aaa", "bbb"), Some(Rate::new(4 * RATE_ONE, 0)));
+    assert_eq!(table.refresh(), Ok(false));
+    fs::remove_file(&path).unwrap();
+    assert!(RateTable::new().set_file(&path).is_err());
+}
+
+#[test]
+fn test_quotes() {
+    let quotes = vec![
+        (chain::chain_hash("aaa"), chain::chain_hash("bbb"), Rate::new(2 * RATE_ONE, 100)),
+        (chain::chain_hash("regtest"), chain::chain_hash("bbb"), Rate::new(RATE_ONE, 0)),
+    ];
+    let encoded = rates::encode_quotes(&quotes);
+    assert_eq!(rates::decode_quotes(&encoded), quotes);
+    assert!(rates::encode_quotes(&[]).is_empty());
+    assert!(rates::decode_quotes(&encoded[..encoded.len() - 1]).is_empty());
+    assert!(rates::decode_quotes(b"something else").is_empty());
+    // A rate of 0 or a spread over the maximum spoils them all
+    for bad in [Rate::new(0, 0), Rate::new(RATE_ONE, rates::MAX_SPREAD_MILLIONTHS + 1)] {
+        let mut bad_quotes = quotes.clone();
+        bad_quotes.push((chain::chain_hash("aaa"), chain::chain_hash("regtest"), bad));
+        assert!(rates::decode_quotes(&rates::encode_quotes(&bad_quotes)).is_empty());
+    }
+
+    // Quoted for a channel in one direction, ours for the others
+    let table = RateTable::new();
+    table.set("aaa", "bbb", Rate::new(RATE_ONE, 0));
+    table.set_quotes(42, 1, vec![("aaa".to_string(), "bbb".to_string(), Rate::new(2 * RATE_ONE, 100))]);
+    assert_eq!(table.quote(42, 1, "aaa", "bbb"), Some(Rate::new(2 * RATE_ONE, 100)));
+    assert_eq!(table.quote(42, 0, "aaa", "bbb"), Some(Rate::new(RATE_ONE, 0)));
+    assert_eq!(table.quote(42, 1, "bbb", "aaa"), None);
+    table.set_quotes(42, 1, Vec::new());
+    assert_eq!(table.quote(42, 1, "aaa", "bbb"), Some(Rate::new(RATE_ONE, 0)));
+}
@@ -0,0 +1,194 @@
+//! The invoice registry, what payments settle an invoice, hold invoices and what's left of them after a restart
+
+mod common;
+
+use common::new_datadir;
+use rustlnd::invoice::{Description, Invoice};
+use rustlnd::registry::{InvoiceRegistry, InvoiceState, HOLD_CANCEL_BLOCKS, HOLD_MIN_FINAL_CLTV_EXPIRY};
+
+use lightning::chain::keysinterface::NodeSigner;
+
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+use secp256k1::key::SecretKey;
+
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn node_signer() -> Arc<dyn NodeSigner> {
+    Arc::new(SecretKey::from_slice(&[0x42; 32]).unwrap())
+}
+
+fn description() -> Description {
+    Description::Direct("coffee".to_string())
+}
+
+#[test]
+fn test_settle() {
+    let registry = InvoiceRegistry::new(&["regtest", "aaa"], node_signer(), None).unwrap();
+    let entry = registry.create("regtest", Some(1000), description(), 3600).unwrap();
+    let payment_hash = entry.invoice.payment_hash;
+    assert_eq!(Sha256::hash(&entry.preimage.unwrap()).into_inner(), payment_hash);
+    assert_eq!(entry.state, InvoiceState::Open);
+    assert_eq!(Invoice::decode(&entry.encoded, &["regtest"]).unwrap(), entry.invoice);
+
+    assert!(registry.receive("regtest", &[0; 32], 1000, 100, None).unwrap_err().contains("No invoice"));
+    assert!(registry.receive("aaa", &payment_hash, 1000, 100, None).unwrap_err().contains("for chain regtest"));
+    assert!(registry.receive("regtest", &payment_hash, 999, 100, None).unwrap_err().contains("999 msat"));
+    assert!(registry.receive("regtest", &payment_hash, 2001, 100, None).unwrap_err().contains("2001 msat"));
+    assert_eq!(registry.get(&payment_hash).unwrap().state, InvoiceState::Open);
+    assert_eq!(registry.receive("regtest", &payment_hash, 1500, 100, None), Ok(entry.preimage));
+    let settled = registry.get(&payment_hash).unwrap();
+    assert_eq!((settled.state, settled.received_msat), (InvoiceState::Settled, Some(1500)));
+    assert_eq!(registry.receive("regtest", &payment_hash, 1500, 100, None), Err("The invoice is settled".to_string()));
+    assert_eq!(registry.cancel(&payment_hash), Err("The invoice is settled".to_string()));
+
+    // Any amount
+    let any_amount = registry.create("aaa", None, description(), 3600).unwrap();
+    assert!(registry.receive("aaa", &any_amount.invoice.payment_hash, 1, 100, None).is_ok());
+
+    let canceled = registry.create("regtest", Some(1000), description(), 3600).unwrap();
+    registry.cancel(&canceled.invoice.payment_hash).unwrap();
+    assert_eq!(registry.receive("regtest", &canceled.invoice.payment_hash, 1000, 100, None), Err("The invoice is canceled".to_string()));
+
+    let expired = registry.create("regtest", Some(1000), description(), 0).unwrap();
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(registry.receive("regtest", &expired.invoice.payment_hash, 1000, 100, None), Err("The invoice is expired".to_string()));
+    assert_eq!(registry.cancel(&expired.invoice.payment_hash), Err("The invoice is expired".to_string()));
+
+    let states: Vec<InvoiceState> = registry.list().iter().map(|entry| entry.state).collect();
+    assert_eq!(states.len(), 4);
+    for state in [InvoiceState::Settled, InvoiceState::Canceled, InvoiceState::Expired] {
+        assert!(states.contains(&state));
+    }
+
+    assert!(registry.create("bbb", None, description(), 3600).unwrap_err().contains("chain bbb"));
+    assert!(registry.create("regtest", Some(0), description(), 3600).is_err());
+}
+
+#[test]
+fn test_persistence() {
+    let datadir = new_datadir("registry");
+    let path = datadir.join("invoices");
+    let registry = InvoiceRegistry::new(&["regtest", "aaa"], node_signer(), Some(&path)).unwrap();
+    let open = registry.create("regtest", Some(1000), description(), 3600).unwrap();
+    let settled = registry.create("regtest", None, description(), 3600).unwrap();
+    registry.receive("regtest", &settled.invoice.payment_hash, 1234, 100, None).unwrap();
+    let canceled = registry.create("regtest", Some(1000), description(), 3600).unwrap();
+    registry.cancel(&canceled.invoice.payment_hash).unwrap();
+    let on_aaa = registry.create("aaa", Some(1000), description(), 3600).unwrap();
+    drop(registry);
+
+    let registry = InvoiceRegistry::new(&["regtest", "aaa"], node_signer(), Some(&path)).unwrap();
+    assert_eq!(registry.get(&open.invoice.payment_hash), Some(open.clone()));
+    let settled = registry.get(&settled.invoice.payment_hash).unwrap();
+    assert_eq!((settled.state, settled.received_msat), (InvoiceState::Settled, Some(1234)));
+    assert_eq!(registry.get(&canceled.invoice.payment_hash).unwrap().state, InvoiceState::Canceled);
+    assert_eq!(registry.get(&on_aaa.invoice.payment_hash), Some(on_aaa.clone()));
+    drop(registry);
+
+    // Without aaa its invoice is kept for later
+    let registry = InvoiceRegistry::new(&["regtest"], node_signer(), Some(&path)).unwrap();
+    assert_eq!(registry.list().len(), 3);
+    assert!(registry.get(&on_aaa.invoice.payment_hash).is_none());
+    registry.receive("regtest", &open.invoice.payment_hash, 1000, 100, None).unwrap();
+    drop(registry);
+    let registry = InvoiceRegistry::new(&["regtest", "aaa"], node_signer(), Some(&path)).unwrap();
+    assert_eq!(registry.get(&on_aaa.invoice.payment_hash), Some(on_aaa));
+    assert_eq!(registry.get(&open.invoice.payment_hash).unwrap().state, InvoiceState::Settled);
+    fs::remove_dir_all(datadir).unwrap();
+}
+
+#[test]
+fn test_wait() {
+    let registry = Arc::new(InvoiceRegistry::new(&["regtest"], node_signer(), None).unwrap());
+    let entry = registry.create("regtest", Some(1000), description(), 3600).unwrap();
+    let payment_hash = entry.invoice.payment_hash;
+    assert!(registry.wait(&[0; 32], Duration::from_secs(1)).is_err());
+    assert_eq!(registry.wait(&payment_hash, Duration::from_millis(100)).unwrap().state, InvoiceState::Open);
+
+    let settling = registry.clone();
+    let settler = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        settling.receive("regtest", &payment_hash, 1000, 100, None).unwrap();
+    });
+    let start = Instant::now();
+    assert_eq!(registry.wait(&payment_hash, Duration::from_secs(10)).unwrap().state, InvoiceState::Settled);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    settler.join().unwrap();
+
+    // Expiring ends the wait too
+    let expiring = registry.create("regtest", Some(1000), description(), 1).unwrap();
+    let start = Instant::now();
+    assert_eq!(registry.wait(&expiring.invoice.payment_hash, Duration::from_secs(10)).unwrap().state, InvoiceState::Expired);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_hold() {
+    let datadir = new_datadir("registry-hold");
+    let path = datadir.join("invoices");
+    let registry = InvoiceRegistry::new(&["regtest", "aaa"], node_signer(), Some(&path)).unwrap();
+    let preimage = [7; 32];
+    let payment_hash = Sha256::hash(&preimage).into_inner();
+    let hold = registry.create_hold("regtest", payment_hash, Some(1000), description(), 3600).unwrap();
+    assert_eq!((hold.preimage, hold.invoice.min_final_cltv_expiry), (None, HOLD_MIN_FINAL_CLTV_EXPIRY));
+    assert!(registry.create_hold("regtest", payment_hash, None, description(), 3600).unwrap_err().contains("already"));
+    assert_eq!(registry.settle(preimage), Err("The invoice wasn't paid yet".to_string()));
+
+    // HTLCs expiring too soon or whose expiry we don't know aren't held
+    let expiry = 100 + HOLD_MIN_FINAL_CLTV_EXPIRY as u32;
+    assert!(registry.receive("regtest", &payment_hash, 1000, 100, Some(expiry - 1)).unwrap_err().contains("less than 80 blocks"));
+    assert!(registry.receive("regtest", &payment_hash, 1000, 100, None).is_err());
+    assert_eq!(registry.get(&payment_hash).unwrap().state, InvoiceState::Open);
+
+    // Held, as many payments as rust-lightning holds for the hash, canceled before the first expires
+    assert_eq!(registry.receive("regtest", &payment_hash, 1000, 100, Some(expiry + 5)), Ok(None));
+    assert_eq!(registry.receive("regtest", &payment_hash, 1200, 101, Some(expiry + 1)), Ok(None));
+    let accepted = registry.get(&payment_hash).unwrap();
+    assert_eq!((accepted.state, accepted.received_msat, accepted.htlc_expiry), (InvoiceState::Accepted, Some(2200), Some(expiry + 1)));
+    assert_eq!(registry.wait(&payment_hash, Duration::from_secs(10)).unwrap().state, InvoiceState::Accepted);
+    assert!(registry.take_resolved("regtest").is_empty());
+
+    assert_eq!(registry.settle([8; 32]), Err("No invoice with this payment hash".to_string()));
+    registry.settle(preimage).unwrap();
+    assert_eq!(registry.get(&payment_hash).unwrap().preimage, Some(preimage));
+    assert!(registry.take_resolved("aaa").is_empty());
+    assert_eq!(registry.take_resolved("regtest"), vec![(payment_hash, Some(preimage))]);
+    assert!(registry.take_resolved("regtest").is_empty());
+    assert_eq!(registry.settle(preimage), Err("The invoice is settled".to_string()));
+
+    let ordinary = registry.create("regtest", Some(1000), description(), 3600).unwrap();
+    assert_eq!(registry.settle(ordinary.preimage.unwrap()), Err("The invoice isn't a hold invoice".to_string()));
+
+    // Canceled by hand, and before the HTLC expires
+    let canceled = registry.create_hold("regtest", [1; 32], None, description(), 3600).unwrap();
+    registry.receive("regtest", &[1; 32], 1000, 100, Some(expiry)).unwrap();
+    registry.cancel(&[1; 32]).unwrap();
+    let expiring = registry.create_hold("aaa", [2; 32], None, description(), 3600).unwrap();
+    registry.receive("aaa", &[2; 32], 1000, 100, Some(expiry + 10)).unwrap();
+    let cancel_height = expiry + 10 - HOLD_CANCEL_BLOCKS as u32;
+    registry.cancel_expiring("aaa", cancel_height - 1);
+    registry.cancel_expiring("regtest", cancel_height);
+    assert_eq!(registry.get(&[2; 32]).unwrap().state, InvoiceState::Accepted);
+    registry.cancel_expiring("aaa", cancel_height);
+    assert_eq!(registry.get(&[2; 32]).unwrap().state, InvoiceState::Canceled);
+    assert_eq!(registry.take_resolved("regtest"), vec![([1; 32], None)]);
+    assert_eq!(registry.take_resolved("aaa"), vec![([2; 32], None)]);
+    drop(registry);
+
+    // What was resolved is resolved again after a restart, what's held stays held
+    let registry = InvoiceRegistry::new(&["regtest", "aaa"], node_signer(), Some(&path)).unwrap();
+    registry.create_hold("regtest", [3; 32], None, description(), 3600).unwrap();
+    registry.receive("regtest", &[3; 32], 1000, 100, Some(expiry)).unwrap();
+    let mut resolved = registry.take_resolved("regtest");
+    resolved.sort();
+    assert_eq!(resolved, vec![([1; 32], None), (payment_hash, Some(preimage))]);
+    assert_eq!(registry.take_resolved("aaa"), vec![([2; 32], None)]);
+    assert_eq!(registry.get(&[3; 32]).unwrap().state, InvoiceState::Accepted);
+    assert_eq!(registry.get(&canceled.invoice.payment_hash).unwrap().received_msat, Some(1000));
+    assert_eq!(registry.get(&expiring.invoice.payment_hash).unwrap().state, InvoiceState::Canceled);
+    fs::remove_dir_all(datadir).unwrap();
+}
@@ -0,0 +1,92 @@
+//! The daemon killed in the middle of a payment goes on with it from its datadir
+
+mod common;
+
+use common::{bitcoind_stand_in, daemon_call, daemon_datadir, free_addr, fund, logger, monitor_files, new_channels, start_daemon, sync_until, wait_for};
+use rustlnd::channels::ChannelState;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::Node;
+
+use bitcoin_hashes::hex::ToHex;
+use lightning::ln::channelmanager::PaymentHash;
+use lightning::util::events::{Event, EventsProvider};
+use rand::Rng;
+use secp256k1::key::PublicKey;
+
+use std::fs;
+use std::sync::Arc;
+
+fn pay(node: &Node, to: &PublicKey, amount_msat: u64) -> PaymentHash {
+    let channel_manager = &node.chains[0].channel_manager;
+    let route = node.router.get_route(to, Some(&channel_manager.list_usable_channels()), &[], amount_msat, 40).unwrap();
+    let payment_hash = PaymentHash(rand::thread_rng().gen());
+    channel_manager.send_payment(route, payment_hash).unwrap();
+    node.peer_manager.process_events();
+    payment_hash
+}
+
+fn payment_failed(node: &Node, payment_hash: &PaymentHash) -> bool {
+    node.chains[0].channel_manager.get_and_clear_pending_events().iter().any(|event| match event {
+        Event::PaymentFailed { payment_hash: failed, .. } => failed == payment_hash,
+        _ => false,
+    })
+}
+
+#[test]
+fn test_killed_mid_payment() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let rpc = bitcoind_stand_in(chain.clone());
+    let (datadir_a, node_id_a) = daemon_datadir("killed-a");
+    let p2p = free_addr();
+    let process = start_daemon(&datadir_a, rpc, p2p);
+    let list_a = || daemon_call(&datadir_a, "listchannels");
+
+    let node_b = Node::new_random(&["regtest"], logger());
+    let channels_b = new_channels(&node_b, &[&chain]);
+    net::connect_outbound(node_b.peer_manager.clone(), node_id_a, &p2p.to_string()).unwrap();
+    assert!(wait_for(|| node_b.peer_manager.get_peer_node_ids().contains(&node_id_a)));
+
+    fund(&chain, &channels_b, "regtest", 300_000);
+    channels_b.open_channel("regtest", node_id_a, 100_000, 0, false).unwrap();
+    assert!(sync_until(&[&channels_b], || chain.get_mempool().len() == 1));
+    chain.mine_blocks(6);
+    assert!(sync_until(&[&channels_b], || {
+        channels_b.list_channels()[0].state == ChannelState::Open && list_a().contains(" open ")
+    }));
+    let channel_id = channels_b.list_channels()[0].channel_id;
+
+    // A is killed once the HTLC reached its monitor, B waits for it
+    let monitors_before = monitor_files(&datadir_a);
+    let payment_hash = pay(&node_b, &node_id_a, 1_000_000);
+    assert!(sync_until(&[&channels_b], || monitor_files(&datadir_a) != monitors_before));
+    drop(process);
+    assert!(wait_for(|| !node_b.peer_manager.get_peer_node_ids().contains(&node_id_a)));
+    chain.mine_blocks(2);
+
+    let process = start_daemon(&datadir_a, rpc, p2p);
+    let restarted = list_a();
+    assert_eq!(restarted.lines().count(), 1);
+    assert!(restarted.contains(&channel_id.to_hex()));
+    assert!(chain.get_mempool().is_empty());
+
+    // A has no invoice for it, the payment fails back to B once they talk again (B isn't synced
+    // from here on, Channels would take the PaymentFailed events)
+    net::connect_outbound(node_b.peer_manager.clone(), node_id_a, &p2p.to_string()).unwrap();
+    assert!(wait_for(|| payment_failed(&node_b, &payment_hash)));
+    assert!(sync_until(&[&channels_b], || {
+        list_a().contains(" open ") && node_b.chains[0].channel_manager.list_usable_channels().len() == 1
+    }));
+
+    // And the channel goes on
+    let payment_hash = pay(&node_b, &node_id_a, 2_000_000);
+    assert!(wait_for(|| payment_failed(&node_b, &payment_hash)));
+    assert!(chain.get_mempool().is_empty());
+
+    // Stopping saves it all and exits
+    let mut process = process;
+    assert_eq!(daemon_call(&datadir_a, "stop").trim(), "ok");
+    assert!(process.0.wait().unwrap().success());
+
+    fs::remove_dir_all(datadir_a).unwrap();
+}
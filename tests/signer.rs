@@ -0,0 +1,253 @@
+//! The daemon's keys in a rustlnd-signer process
+
+mod common;
+
+use common::{new_datadir, wait_for};
+use rustlnd::chain::{ChainBackend, FixedFeeEstimator};
+use rustlnd::keys::{self, NodeKeys};
+use rustlnd::logger::PrintLogger;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::node::Node;
+use rustlnd::signer::{RemoteSigner, Signer, SigningPolicy, WalletInput};
+use rustlnd::wallet::{self, Wallet};
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::util::bip143::SighashComponents;
+use lightning::chain::keysinterface::{DerivedKey, KeysInterface, NodeSigner};
+use lightning::util::logger::Level;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::{Message, Secp256k1};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+
+const PASS: &str = "correct horse";
+
+// Kills the signer process even if the test fails
+struct SignerProcess(Child);
+
+impl Drop for SignerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_signer(datadir: &Path, args: &[&str]) -> (SignerProcess, PathBuf) {
+    fs::write(datadir.join("walletpass"), PASS).unwrap();
+    let socket = datadir.join("signer.sock");
+    let _ = fs::remove_file(&socket);
+    let child = Command::new(env!("CARGO_BIN_EXE_rustlnd-signer"))
+        .arg(format!("-datadir={}", datadir.display()))
+        .arg(format!("-walletpassfile={}", datadir.join("walletpass").display()))
+        .args(args)
+        .stdout(Stdio::null())
+        .spawn().unwrap();
+    let process = SignerProcess(child);
+    assert!(wait_for(|| socket.exists()));
+    (process, socket)
+}
+
+fn local_keys(datadir: &Path, chain: &str) -> NodeKeys {
+    let seed = keys::load_seed(&keys::seed_path(datadir), PASS).unwrap();
+    NodeKeys::new(&keys::bip32_seed(&seed.entropy, "").unwrap(), chain, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+fn new_remote_signer(socket: &Path, chain: &str) -> RemoteSigner {
+    RemoteSigner::new(socket, chain)
+}
+
+fn dummy_transaction() -> bitcoin::Transaction {
+    bitcoin::Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![bitcoin::TxIn {
+            previous_output: Default::default(),
+            script_sig: Script::new(),
+            sequence: 0xffff_ffff,
+            witness: Vec::new(),
+        }],
+        output: vec![bitcoin::TxOut { value: 10_000, script_pubkey: Script::new() }],
+    }
+}
+
+// Sends a request the way RemoteSigner does and returns the answer
+fn raw_request(socket: &Path, request: &[u8]) -> Vec<u8> {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    stream.write_all(&(request.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(request).unwrap();
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut answer = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut answer).unwrap();
+    answer
+}
+
+#[test]
+fn test_same_keys_as_in_process() {
+    let datadir = new_datadir("signer-keys");
+    let (_process, socket) = start_signer(&datadir, &["-chain=regtest", "-chain=aaa"]);
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+
+    for chain in &["regtest", "aaa"] {
+        let local = local_keys(&datadir, chain);
+        let remote = Arc::new(new_remote_signer(&socket, chain));
+        // The wallet is the signer's
+        assert_eq!(remote.get_destination_script().unwrap(), KeysInterface::get_destination_script(&local));
+        assert_eq!(remote.get_shutdown_pubkey().unwrap(), KeysInterface::get_shutdown_pubkey(&local));
+        assert_eq!(remote.get_wallet_account_xpub().unwrap(), Signer::get_wallet_account_xpub(&local).unwrap());
+        // So is the node secret, the daemon only gets its ECDH and signatures
+        let other = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[3; 32]).unwrap());
+        let msg = Message::from_slice(&[5; 32]).unwrap();
+        assert_eq!(remote.get_node_id().unwrap(), NodeSigner::get_node_id(&local));
+        assert_eq!(remote.node_ecdh(&other).unwrap(), NodeSigner::node_ecdh(&local, &other));
+        assert_eq!(remote.sign_with_node_secret(&msg).unwrap(), NodeSigner::sign_with_node_secret(&local, &msg));
+        // And the channel keys, the daemon only gets their public keys and signatures
+        let channel_keys = remote.get_channel_keys(false).unwrap();
+        assert_ne!(channel_keys.keys_id, remote.get_channel_keys(false).unwrap().keys_id);
+        let local_signer = KeysInterface::get_channel_signer(&local, &channel_keys.keys_id);
+        let remote_signer = remote.get_channel_signer(&channel_keys.keys_id).unwrap();
+        let idx = (1 << 48) - 2;
+        assert_eq!(remote_signer.get_per_commitment_point(idx), local_signer.get_per_commitment_point(idx));
+        let tx = dummy_transaction();
+        let funding_redeemscript = Script::from(vec![1; 71]);
+        let signature = remote_signer.sign_local_commitment(idx, &tx, &funding_redeemscript, 20_000).unwrap();
+        assert_eq!(signature, local_signer.sign_local_commitment(idx, &tx, &funding_redeemscript, 20_000).unwrap());
+        let sighash = SighashComponents::new(&tx).sighash_all(&tx.input[0], &funding_redeemscript, 20_000);
+        assert!(Secp256k1::new().verify(&Message::from_slice(&sighash[..]).unwrap(), &signature, &channel_keys.funding_pubkey).is_ok());
+        assert_eq!(remote_signer.release_per_commitment_secret(idx + 1).unwrap(), local_signer.release_per_commitment_secret(idx + 1).unwrap());
+        let point = local_signer.get_per_commitment_point(idx);
+        assert_eq!(remote_signer.sign_with_derived_key(DerivedKey::Htlc, &point, &tx, 0, &funding_redeemscript, 1_000).unwrap(),
+                   local_signer.sign_with_derived_key(DerivedKey::Htlc, &point, &tx, 0, &funding_redeemscript, 1_000).unwrap());
+        assert!(remote_signer.sign_with_derived_key(DerivedKey::Htlc, &point, &tx, 1, &funding_redeemscript, 1_000).is_err());
+
+        let node = Node::with_signers(&[chain], vec![remote as Arc<dyn Signer>], logger.clone()).unwrap();
+        assert_eq!(node.get_our_node_id(), NodeSigner::get_node_id(&local));
+    }
+
+    let unknown = new_remote_signer(&socket, "bbb");
+    assert!(unknown.get_shutdown_pubkey().unwrap_err().contains("Not signing for chain bbb"));
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_remote_wallet_signing() {
+    let datadir = new_datadir("signer-wallet");
+    let (_process, socket) = start_signer(&datadir, &["-chain=regtest", "-maxfeerate=1000"]);
+    let signer = Arc::new(new_remote_signer(&socket, "regtest"));
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = Wallet::new("regtest", signer.clone(), chain.clone(), Arc::new(FixedFeeEstimator::new(253))).unwrap();
+
+    let address = wallet.new_address();
+    let funding = chain.faucet(&wallet::address_to_script("regtest", &address).unwrap(), 100_000);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+
+    let destination = Script::from(vec![0; 34]).to_v0_p2wsh();
+    let tx = wallet.create_transaction(&destination, 50_000).unwrap();
+    // Signatures made in the other process are good for the chain
+    chain.send_transaction(&tx).unwrap();
+
+    // The signer remembers what it signed
+    let input = WalletInput { branch: keys::RECEIVE_BRANCH, index: 0, value: 100_000 };
+    let mut double_spend = tx.clone();
+    double_spend.output[0].script_pubkey = Script::from(vec![1; 34]).to_v0_p2wsh();
+    let err = signer.sign_wallet_transaction(&double_spend, &[input]).unwrap_err();
+    assert!(err.contains("already spent"), "{}", err);
+    // Signing the same transaction again is fine
+    assert!(signer.sign_wallet_transaction(&tx, &[input]).is_ok());
+
+    // Too much fee
+    let other_funding = chain.faucet(&wallet::address_to_script("regtest", &wallet.new_address()).unwrap(), 100_000);
+    let mut expensive = tx.clone();
+    expensive.input[0].previous_output.txid = other_funding.txid();
+    expensive.output.truncate(1);
+    let input = WalletInput { branch: keys::RECEIVE_BRANCH, index: 1, value: 100_000 };
+    let err = signer.sign_wallet_transaction(&expensive, &[input]).unwrap_err();
+    assert!(err.contains("over the maximum"), "{}", err);
+    assert_ne!(funding.txid(), other_funding.txid());
+
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_signer_restart() {
+    let datadir = new_datadir("signer-restart");
+    let (process, socket) = start_signer(&datadir, &[]);
+    let signer = new_remote_signer(&socket, "regtest");
+    let shutdown_pubkey = signer.get_shutdown_pubkey().unwrap();
+    drop(process);
+    assert!(signer.get_shutdown_pubkey().is_err());
+
+    // Same seed, and the signer connects again by itself
+    let (_process, _socket) = start_signer(&datadir, &[]);
+    assert_eq!(signer.get_shutdown_pubkey().unwrap(), shutdown_pubkey);
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_revoked_commitment() {
+    let datadir = new_datadir("signer-revoked");
+    let (process, socket) = start_signer(&datadir, &[]);
+    let signer = new_remote_signer(&socket, "regtest");
+    let keys_id = signer.get_channel_keys(false).unwrap().keys_id;
+    let channel_signer = signer.get_channel_signer(&keys_id).unwrap();
+    let tx = dummy_transaction();
+    let funding_redeemscript = Script::from(vec![1; 71]);
+    let idx = (1 << 48) - 1;
+
+    // Nothing replaces the first commitment yet
+    assert!(channel_signer.release_per_commitment_secret(idx).is_err());
+    assert!(channel_signer.sign_local_commitment(idx, &tx, &funding_redeemscript, 20_000).is_ok());
+    assert!(channel_signer.sign_local_commitment(idx - 1, &tx, &funding_redeemscript, 20_000).is_ok());
+    assert!(channel_signer.release_per_commitment_secret(idx).is_ok());
+    assert!(channel_signer.sign_local_commitment(idx, &tx, &funding_redeemscript, 20_000).is_err());
+    assert!(channel_signer.sign_local_commitment(idx - 1, &tx, &funding_redeemscript, 20_000).is_ok());
+    assert!(channel_signer.release_per_commitment_secret(idx - 1).is_err());
+
+    // The signer still knows after a restart
+    drop(process);
+    let (_process, _socket) = start_signer(&datadir, &[]);
+    assert!(channel_signer.sign_local_commitment(idx, &tx, &funding_redeemscript, 20_000).is_err());
+    assert!(channel_signer.sign_local_commitment(idx - 2, &tx, &funding_redeemscript, 20_000).is_ok());
+    assert!(channel_signer.release_per_commitment_secret(idx - 1).is_ok());
+    assert!(channel_signer.sign_local_commitment(idx - 1, &tx, &funding_redeemscript, 20_000).is_err());
+
+    // Another channel's commitments aren't revoked
+    let other = signer.get_channel_signer(&signer.get_channel_keys(false).unwrap().keys_id).unwrap();
+    assert!(other.sign_local_commitment(idx, &tx, &funding_redeemscript, 20_000).is_ok());
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_no_secrets_sent() {
+    let datadir = new_datadir("signer-secrets");
+    let (_process, socket) = start_signer(&datadir, &[]);
+    assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // What used to be the node secret, channel keys, session key and channel id requests
+    for request_type in &[0, 3, 4, 5] {
+        let mut request = vec![*request_type, 7];
+        request.extend_from_slice(b"regtest");
+        request.push(0);
+        let answer = raw_request(&socket, &request);
+        assert_eq!(answer[0], 1);
+        assert!(String::from_utf8_lossy(&answer[1..]).contains("Unknown request"));
+    }
+    fs::remove_dir_all(&datadir).unwrap();
+}
+
+#[test]
+fn test_policy() {
+    let policy = SigningPolicy::new(1000, None).unwrap();
+    let tx = dummy_transaction();
+    let input = |value| WalletInput { branch: 0, index: 0, value };
+    assert!(policy.check_wallet_transaction(&tx, &[input(9_000)]).is_err());
+    assert!(policy.check_wallet_transaction(&tx, &[input(100_000)]).is_err());
+    assert!(policy.check_wallet_transaction(&tx, &[input(10_100)]).is_ok());
+}
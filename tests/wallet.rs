@@ -0,0 +1,490 @@
+//! The per chain wallet against an in-memory chain
+
+mod common;
+
+use common::wait_for;
+use rustlnd::chain::{ChainBackend, FixedFeeEstimator};
+use rustlnd::keys::{self, NodeKeys};
+use rustlnd::logger::PrintLogger;
+use rustlnd::memorychain::MemoryChain;
+use rustlnd::net;
+use rustlnd::node::Node;
+use rustlnd::psbt;
+use rustlnd::wallet::{self, Wallet};
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin_hashes::hex::FromHex;
+use lightning::chain::keysinterface::{DerivedKey, InMemoryChannelKeys, KeysInterface, SpendableOutputDescriptor};
+use lightning::util::events::{Event, EventsProvider};
+use lightning::util::logger::Level;
+use secp256k1::key::{PublicKey, SecretKey};
+use rand::Rng;
+use secp256k1::Secp256k1;
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+fn new_keys(seed: &[u8], chain: &str) -> NodeKeys {
+    NodeKeys::new(seed, chain, Arc::new(PrintLogger::new(Level::Info)))
+}
+
+fn new_wallet(seed: &[u8], chain: &Arc<MemoryChain>, chain_name: &str) -> Wallet {
+    Wallet::new(chain_name, Arc::new(new_keys(seed, chain_name)), chain.clone(), Arc::new(FixedFeeEstimator::new(253))).unwrap()
+}
+
+fn fund(chain: &MemoryChain, wallet: &Wallet, value: u64) {
+    let address = wallet.new_address();
+    chain.faucet(&wallet::address_to_script(wallet.get_chain(), &address).unwrap(), value);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+}
+
+#[test]
+fn test_addresses() {
+    // BIP173's P2WPKH example
+    let script = Script::from(Vec::<u8>::from_hex("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap());
+    assert_eq!(wallet::script_to_address("main", &script).unwrap(), "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+    assert_eq!(wallet::address_to_script("main", "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4").unwrap(), script);
+    assert!(wallet::script_to_address("test", &script).unwrap().starts_with("tb1q"));
+    assert!(wallet::script_to_address("regtest", &script).unwrap().starts_with("bcrt1q"));
+
+    let aaa = wallet::script_to_address("aaa", &script).unwrap();
+    assert!(aaa.starts_with("aaa1q"));
+    assert_eq!(wallet::address_to_script("aaa", &aaa).unwrap(), script);
+    assert!(wallet::address_to_script("bbb", &aaa).is_err());
+    assert!(wallet::address_to_script("main", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5").is_err());
+
+    let chain = Arc::new(MemoryChain::new("aaa"));
+    let wallet = new_wallet(&[1; 32], &chain, "aaa");
+    let first = wallet.new_address();
+    assert!(first.starts_with("aaa1q"));
+    assert_ne!(wallet.new_address(), first);
+    // Same seed, same addresses
+    assert_eq!(new_wallet(&[1; 32], &chain, "aaa").new_address(), first);
+}
+
+#[test]
+fn test_spend_and_change() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[2; 32], &chain, "regtest");
+    wallet.sync().unwrap();
+    assert_eq!(wallet.get_balance(), 0);
+
+    fund(&chain, &wallet, 60_000);
+    fund(&chain, &wallet, 50_000);
+    assert_eq!(wallet.get_balance(), 110_000);
+    assert_eq!(wallet.list_unspent().len(), 2);
+
+    let destination = keys::p2wpkh_script(&PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[9; 32]).unwrap()));
+    assert!(wallet.create_transaction(&destination, 200_000).is_err());
+    assert!(wallet.create_transaction(&destination, 100).is_err());
+
+    let tx = wallet.create_transaction(&destination, 70_000).unwrap();
+    assert_eq!(tx.input.len(), 2);
+    assert_eq!(tx.output.len(), 2);
+    assert_eq!(tx.output[0].value, 70_000);
+    // The coins are taken even before the transaction is in a block
+    assert_eq!(wallet.get_balance(), 0);
+    // The chain checks the signatures
+    chain.send_transaction(&tx).unwrap();
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    let change = tx.output[1].value;
+    assert_eq!(wallet.get_balance(), change);
+    assert!(change < 40_000 && change > 39_000);
+    assert!(chain.is_unspent(&OutPoint { txid: tx.txid(), vout: 0 }));
+
+    // Everything can be found again from the seed
+    let restored = new_wallet(&[2; 32], &chain, "regtest");
+    restored.sync().unwrap();
+    assert_eq!(restored.list_unspent(), wallet.list_unspent());
+}
+
+#[test]
+fn test_concurrent_spends_use_different_coins() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = Arc::new(new_wallet(&[12; 32], &chain, "regtest"));
+    for _ in 0..4 {
+        fund(&chain, &wallet, 30_000);
+    }
+    let destination = keys::p2wpkh_script(&PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[9; 32]).unwrap()));
+    let spends: Vec<_> = (0..8).map(|_| {
+        let (wallet, destination) = (wallet.clone(), destination.clone());
+        thread::spawn(move || wallet.create_transaction(&destination, 20_000))
+    }).collect();
+    let txs: Vec<_> = spends.into_iter().filter_map(|spend| spend.join().unwrap().ok()).collect();
+    assert_eq!(txs.len(), 4);
+    let inputs: HashSet<OutPoint> = txs.iter().flat_map(|tx| tx.input.iter().map(|input| input.previous_output)).collect();
+    assert_eq!(inputs.len(), 4);
+    for tx in &txs {
+        chain.send_transaction(tx).unwrap();
+    }
+}
+
+#[test]
+fn test_gap_limit() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[3; 32], &chain, "regtest");
+    let addresses: Vec<String> = (0..30).map(|_| wallet.new_address()).collect();
+    // Beyond the gap of a wallet that hasn't handed out any addresses
+    chain.faucet(&wallet::address_to_script("regtest", &addresses[29]).unwrap(), 10_000);
+    chain.faucet(&wallet::address_to_script("regtest", &addresses[19]).unwrap(), 20_000);
+    chain.mine_blocks(1);
+
+    wallet.sync().unwrap();
+    assert_eq!(wallet.get_balance(), 30_000);
+    // Finding the payment to the 20th address makes the restored wallet watch up to the 40th
+    let restored = new_wallet(&[3; 32], &chain, "regtest");
+    restored.sync().unwrap();
+    assert_eq!(restored.get_balance(), 30_000);
+}
+
+#[test]
+fn test_fund_channel() {
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let node_a = Node::new_random(&["regtest"], logger.clone());
+    let node_b = Node::new(&["regtest"], &[4; 32], logger);
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[4; 32], &chain, "regtest");
+    fund(&chain, &wallet, 150_000);
+
+    node_b.chains[0].channel_manager.create_channel(node_a.get_our_node_id(), 100_000, 0, 42).unwrap();
+    node_b.peer_manager.process_events();
+    assert!(wait_for(|| {
+        for event in node_b.chains[0].channel_manager.get_and_clear_pending_events() {
+            assert!(wallet.handle_event(&node_b.chains[0].channel_manager, &event));
+        }
+        node_b.peer_manager.process_events();
+        !chain.get_mempool().is_empty()
+    }));
+
+    let funding_tx = chain.get_mempool().pop().unwrap();
+    assert_eq!(funding_tx.output[0].value, 100_000);
+    assert!(funding_tx.output[0].script_pubkey.is_v0_p2wsh());
+    let channels = node_b.chains[0].channel_manager.list_channels();
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].channel_id[..31], funding_tx.txid()[..31]);
+    assert_eq!(wallet.get_balance(), 0);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    assert_eq!(wallet.get_balance(), funding_tx.output[1].value);
+}
+
+#[test]
+fn test_funding_kept_until_signed_or_gone() {
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let node_a = Node::new_random(&["regtest"], logger.clone());
+    let node_b = Node::new(&["regtest"], &[4; 32], logger);
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let random: u64 = rand::thread_rng().gen();
+    let path = std::env::temp_dir().join(format!("rustlnd-fundings-{:x}", random));
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let new_wallet = || Wallet::with_fundings_file("regtest", Arc::new(new_keys(&[4; 32], "regtest")), chain.clone(),
+                                                   Arc::new(FixedFeeEstimator::new(253)), Some(&path)).unwrap();
+    let wallet = new_wallet();
+    fund(&chain, &wallet, 150_000);
+
+    // Funded, but node_a never gets the funding_created
+    node_b.chains[0].channel_manager.create_channel(node_a.get_our_node_id(), 100_000, 0, 42).unwrap();
+    node_b.peer_manager.process_events();
+    assert!(wait_for(|| {
+        node_b.chains[0].channel_manager.get_and_clear_pending_events().iter().any(|event| {
+            wallet.handle_event(&node_b.chains[0].channel_manager, event)
+        })
+    }));
+    assert_eq!(wallet.pending_fundings().len(), 1);
+    assert_eq!(wallet.get_balance(), 0);
+
+    // Its coins stay locked after a restart
+    let wallet = new_wallet();
+    wallet.sync().unwrap();
+    assert_eq!(wallet.pending_fundings().len(), 1);
+    assert_eq!(wallet.get_balance(), 0);
+    wallet.check_fundings(&node_b.chains[0].channel_manager);
+    assert_eq!(wallet.pending_fundings().len(), 1);
+    assert!(chain.get_mempool().is_empty());
+
+    // Until the channel goes away
+    let channel_id = node_b.chains[0].channel_manager.list_channels()[0].channel_id;
+    node_b.chains[0].channel_manager.force_close_channel(&channel_id);
+    wallet.check_fundings(&node_b.chains[0].channel_manager);
+    assert!(wallet.pending_fundings().is_empty());
+    assert_eq!(wallet.get_balance(), 150_000);
+    assert!(new_wallet().pending_fundings().is_empty());
+    assert!(chain.get_mempool().is_empty());
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(wallet::synced_path(&path)).unwrap();
+}
+
+// The chain, counting the blocks asked for
+struct CountingChain {
+    chain: Arc<MemoryChain>,
+    blocks: AtomicUsize,
+}
+
+impl ChainBackend for CountingChain {
+    fn get_height(&self) -> Result<u32, String> {
+        self.chain.get_height()
+    }
+
+    fn get_block(&self, height: u32) -> Result<Block, String> {
+        self.blocks.fetch_add(1, Ordering::SeqCst);
+        self.chain.get_block(height)
+    }
+
+    fn send_transaction(&self, tx: &Transaction) -> Result<(), String> {
+        self.chain.send_transaction(tx)
+    }
+}
+
+#[test]
+fn test_restart_goes_on_from_synced_height() {
+    let random: u64 = rand::thread_rng().gen();
+    let path = std::env::temp_dir().join(format!("rustlnd-fundings-{:x}", random));
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let new_wallet = || {
+        let counting = Arc::new(CountingChain { chain: chain.clone(), blocks: AtomicUsize::new(0) });
+        let wallet = Wallet::with_fundings_file("regtest", Arc::new(new_keys(&[13; 32], "regtest")), counting.clone(),
+                                                Arc::new(FixedFeeEstimator::new(253)), Some(&path)).unwrap();
+        (wallet, counting)
+    };
+    let (wallet, _) = new_wallet();
+    fund(&chain, &wallet, 60_000);
+    let address = wallet.new_address();
+    chain.mine_blocks(5);
+    wallet.sync().unwrap();
+
+    // The coins are back without scanning, and the address isn't handed out again
+    let (restarted, counting) = new_wallet();
+    assert_eq!(restarted.list_unspent(), wallet.list_unspent());
+    assert_ne!(restarted.new_address(), address);
+    restarted.sync().unwrap();
+    assert_eq!(counting.blocks.load(Ordering::SeqCst), 0);
+
+    // Payments to it are still seen, from the next block on
+    chain.faucet(&wallet::address_to_script("regtest", &address).unwrap(), 40_000);
+    chain.mine_blocks(1);
+    restarted.sync().unwrap();
+    assert_eq!(counting.blocks.load(Ordering::SeqCst), 1);
+    assert_eq!(restarted.get_balance(), 100_000);
+    let (restarted, counting) = new_wallet();
+    restarted.sync().unwrap();
+    assert_eq!(counting.blocks.load(Ordering::SeqCst), 0);
+    assert_eq!(restarted.get_balance(), 100_000);
+
+    std::fs::remove_file(wallet::synced_path(&path)).unwrap();
+}
+
+#[test]
+fn test_fund_channel_with_psbt() {
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let node_a = Node::new_random(&["regtest"], logger.clone());
+    let node_b = Node::new(&["regtest"], &[11; 32], logger);
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[11; 32], &chain, "regtest");
+    fund(&chain, &wallet, 150_000);
+    // The external wallet's coin
+    let secp_ctx = Secp256k1::new();
+    let external_key = SecretKey::from_slice(&[12; 32]).unwrap();
+    let external_script = keys::p2wpkh_script(&PublicKey::from_secret_key(&secp_ctx, &external_key));
+    let external_tx = chain.faucet(&external_script, 300_000);
+    chain.mine_blocks(1);
+
+    wallet.fund_with_psbt(43);
+    assert_eq!(wallet.funding_psbt(43), None);
+    node_b.chains[0].channel_manager.create_channel(node_a.get_our_node_id(), 200_000, 0, 43).unwrap();
+    node_b.peer_manager.process_events();
+    assert!(wait_for(|| {
+        for event in node_b.chains[0].channel_manager.get_and_clear_pending_events() {
+            assert!(wallet.handle_event(&node_b.chains[0].channel_manager, &event));
+        }
+        node_b.peer_manager.process_events();
+        wallet.funding_psbt(43).is_some()
+    }));
+
+    let unfunded = wallet.funding_psbt(43).unwrap();
+    let err = wallet.complete_psbt_funding(&node_b.chains[0].channel_manager, 43, &unfunded).unwrap_err();
+    assert!(err.contains("no inputs"), "{}", err);
+    assert!(wallet.complete_psbt_funding(&node_b.chains[0].channel_manager, 44, &unfunded).is_err());
+
+    // The external wallet adds its input and change before the funding output, and signs
+    let mut funded = psbt::from_base64(&unfunded).unwrap();
+    let funding_output = funded.global.unsigned_tx.output[0].clone();
+    assert_eq!(funding_output.value, 200_000);
+    let mut tx = funded.global.unsigned_tx.clone();
+    tx.input.push(bitcoin::TxIn {
+        previous_output: OutPoint { txid: external_tx.txid(), vout: 0 },
+        script_sig: Script::new(),
+        sequence: 0xffff_ffff,
+        witness: Vec::new(),
+    });
+    tx.output.insert(0, bitcoin::TxOut { value: 99_000, script_pubkey: external_script.clone() });
+    let witness = keys::p2wpkh_witness(&secp_ctx, &tx, 0, &external_key, 300_000);
+    funded = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+    funded.inputs[0].final_script_witness = Some(witness);
+
+    // The wrong amount is refused
+    let mut wrong = funded.clone();
+    wrong.global.unsigned_tx.output[1].value = 150_000;
+    let err = wallet.complete_psbt_funding(&node_b.chains[0].channel_manager, 43, &psbt::to_base64(&wrong)).unwrap_err();
+    assert!(err.contains("150000 satoshis"), "{}", err);
+
+    let funding_txo = wallet.complete_psbt_funding(&node_b.chains[0].channel_manager, 43, &psbt::to_base64(&funded)).unwrap();
+    assert_eq!(funding_txo.index, 1);
+    assert_eq!(wallet.funding_psbt(43), None);
+    // Broadcast only once node_a signed our commitment transaction
+    assert!(wait_for(|| {
+        for event in node_b.chains[0].channel_manager.get_and_clear_pending_events() {
+            assert!(wallet.handle_event(&node_b.chains[0].channel_manager, &event));
+        }
+        node_b.peer_manager.process_events();
+        !chain.get_mempool().is_empty()
+    }));
+    let funding_tx = chain.get_mempool().pop().unwrap();
+    assert_eq!(funding_tx.txid(), funding_txo.txid);
+    assert_eq!(funding_tx.output[1], funding_output);
+    assert_eq!(node_b.chains[0].channel_manager.list_channels()[0].channel_id, funding_txo.to_channel_id());
+    // Our own coins weren't touched
+    assert_eq!(wallet.get_balance(), 150_000);
+}
+
+#[test]
+fn test_cancel_psbt_funding() {
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let node_a = Node::new_random(&["regtest"], logger.clone());
+    let node_b = Node::new(&["regtest"], &[13; 32], logger);
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[13; 32], &chain, "regtest");
+    wallet.fund_with_psbt(45);
+    node_b.chains[0].channel_manager.create_channel(node_a.get_our_node_id(), 200_000, 0, 45).unwrap();
+    node_b.peer_manager.process_events();
+    assert!(wait_for(|| {
+        for event in node_b.chains[0].channel_manager.get_and_clear_pending_events() {
+            wallet.handle_event(&node_b.chains[0].channel_manager, &event);
+        }
+        node_b.peer_manager.process_events();
+        wallet.funding_psbt(45).is_some()
+    }));
+    wallet.cancel_psbt_funding(&node_b.chains[0].channel_manager, 45).unwrap();
+    assert!(node_b.chains[0].channel_manager.list_channels().is_empty());
+    assert!(wallet.cancel_psbt_funding(&node_b.chains[0].channel_manager, 45).is_err());
+}
+
+#[test]
+fn test_not_enough_funds_for_channel() {
+    let logger = Arc::new(PrintLogger::new(Level::Info));
+    let node_a = Node::new_random(&["regtest"], logger.clone());
+    let node_b = Node::new(&["regtest"], &[5; 32], logger);
+    let addr_a = net::listen(node_a.peer_manager.clone(), "127.0.0.1:0").unwrap().local_addr();
+    net::connect_outbound(node_b.peer_manager.clone(), node_a.get_our_node_id(), &addr_a.to_string()).unwrap();
+    assert!(wait_for(|| node_a.peer_manager.get_peer_node_ids().contains(&node_b.get_our_node_id())));
+
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[5; 32], &chain, "regtest");
+    fund(&chain, &wallet, 50_000);
+
+    node_b.chains[0].channel_manager.create_channel(node_a.get_our_node_id(), 100_000, 0, 42).unwrap();
+    node_b.peer_manager.process_events();
+    // The channel is given up on instead of waiting for a funding transaction forever
+    assert!(wait_for(|| {
+        for event in node_b.chains[0].channel_manager.get_and_clear_pending_events() {
+            wallet.handle_event(&node_b.chains[0].channel_manager, &event);
+        }
+        node_b.chains[0].channel_manager.list_channels().is_empty()
+    }));
+    assert_eq!(wallet.get_balance(), 50_000);
+}
+
+#[test]
+fn test_sweep_spendable_outputs() {
+    let node = Node::new_random(&["regtest"], Arc::new(PrintLogger::new(Level::Info)));
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let wallet = new_wallet(&[6; 32], &chain, "regtest");
+    let secp_ctx = Secp256k1::new();
+
+    let channel_keys = InMemoryChannelKeys::new(SecretKey::from_slice(&[5; 32]).unwrap(), SecretKey::from_slice(&[6; 32]).unwrap(),
+                                                SecretKey::from_slice(&[7; 32]).unwrap(), SecretKey::from_slice(&[8; 32]).unwrap(),
+                                                SecretKey::from_slice(&[9; 32]).unwrap(), [10; 32]).channel_keys([11; 32]);
+    let per_commitment_point = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[12; 32]).unwrap());
+    // A to_remote output of a commitment transaction
+    let remote_key = channel_keys.derive_public_key(DerivedKey::Payment, &per_commitment_point).unwrap();
+    let remote_tx = chain.faucet(&keys::p2wpkh_script(&remote_key), 20_000);
+    // A delayed to_local output, with a script that would check the delayed key and the delay
+    let witness_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_1).into_script();
+    let delayed_tx = chain.faucet(&witness_script.to_v0_p2wsh(), 30_000);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    assert_eq!(wallet.get_balance(), 0);
+
+    let event = Event::SpendableOutputs { outputs: vec![
+        SpendableOutputDescriptor::DynamicOutputP2WPKH {
+            outpoint: OutPoint { txid: remote_tx.txid(), vout: 0 },
+            keys: channel_keys.clone(),
+            per_commitment_point,
+            output: remote_tx.output[0].clone(),
+        },
+        SpendableOutputDescriptor::DynamicOutputP2WSH {
+            outpoint: OutPoint { txid: delayed_tx.txid(), vout: 0 },
+            keys: channel_keys,
+            per_commitment_point,
+            witness_script,
+            to_self_delay: 5,
+            output: delayed_tx.output[0].clone(),
+        },
+        // Already the wallet's
+        SpendableOutputDescriptor::StaticOutput {
+            outpoint: OutPoint { txid: remote_tx.txid(), vout: 0 },
+            output: bitcoin::TxOut { value: 1, script_pubkey: node.chains[0].keys_manager.get_destination_script() },
+        },
+    ]};
+    assert!(wallet.handle_event(&node.chains[0].channel_manager, &event));
+    assert_eq!(wallet.pending_sweeps(), 1);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    let swept = wallet.get_balance();
+    assert!(swept > 19_000 && swept < 20_000);
+
+    // One confirmation so far, 5 needed
+    chain.mine_blocks(2);
+    wallet.sync().unwrap();
+    assert_eq!(wallet.pending_sweeps(), 1);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    assert_eq!(wallet.pending_sweeps(), 0);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    assert!(wallet.get_balance() > swept + 29_000);
+}
+
+#[test]
+fn test_closes_pay_to_the_wallet() {
+    let chain = Arc::new(MemoryChain::new("regtest"));
+    let keys = new_keys(&[10; 32], "regtest");
+    let wallet = new_wallet(&[10; 32], &chain, "regtest");
+    assert_eq!(keys.get_destination_script(), keys::p2wpkh_script(&keys.get_shutdown_pubkey()));
+    chain.faucet(&keys.get_destination_script(), 25_000);
+    chain.mine_blocks(1);
+    wallet.sync().unwrap();
+    assert_eq!(wallet.get_balance(), 25_000);
+}